@@ -0,0 +1,163 @@
+#[macro_use]
+extern crate criterion;
+extern crate serde_json;
+extern crate spawn_task_port;
+
+use criterion::Criterion;
+use spawn_task_port::{CommandSpawnWithTask, MachPortBroker, SpawnOptions, TrailerType};
+use std::env;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// The benchmarks, in the order the results table lists them. The first is
+/// the one the others are compared to.
+const PLAIN_SPAWN: &'static str = "plain spawn";
+const SPAWN_WITH_TASK_PORT: &'static str = "spawn_with_task_port";
+const BROKER_SPAWN: &'static str = "MachPortBroker::spawn";
+const SPAWN_WITHOUT_AUDIT: &'static str = "spawn_with_task_port without audit";
+const BENCHMARKS: [&'static str; 4] =
+    [PLAIN_SPAWN, SPAWN_WITH_TASK_PORT, BROKER_SPAWN, SPAWN_WITHOUT_AUDIT];
+
+fn test_process_path() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|p| {
+            p.parent().map(|p| {
+                p.with_file_name("test")
+                    .with_extension(env::consts::EXE_EXTENSION)
+            })
+        })
+        .unwrap()
+}
+
+/// The baseline: spawn the helper and wait for it, without a handshake.
+fn plain_spawn(c: &mut Criterion) {
+    let path = test_process_path();
+    c.bench_function(PLAIN_SPAWN, move |b| {
+        b.iter(|| {
+            let mut child = Command::new(&path)
+                .stdin(Stdio::null())
+                .spawn()
+                .unwrap();
+            child.wait().unwrap();
+        })
+    });
+}
+
+/// The same as `plain_spawn`, but also fetch the child's task port.
+fn spawn_with_task_port(c: &mut Criterion) {
+    let path = test_process_path();
+    c.bench_function(SPAWN_WITH_TASK_PORT, move |b| {
+        b.iter(|| {
+            let (mut child, _task_port) = Command::new(&path)
+                .stdin(Stdio::null())
+                .spawn_with_task_port()
+                .unwrap();
+            child.wait().unwrap();
+        })
+    });
+}
+
+/// The same as `spawn_with_task_port`, but through a broker that
+/// registered its service once, rather than a new service per spawn.
+fn broker_spawn(c: &mut Criterion) {
+    let path = test_process_path();
+    let broker = MachPortBroker::new().unwrap();
+    c.bench_function(BROKER_SPAWN, move |b| {
+        b.iter(|| {
+            let (mut child, _task_port) = broker.spawn(Command::new(&path).stdin(Stdio::null()))
+                .unwrap();
+            child.wait().unwrap();
+        })
+    });
+}
+
+/// The same as `spawn_with_task_port`, but without asking for the audit
+/// trailer or checking the sender against it, to show what that costs.
+fn spawn_without_audit(c: &mut Criterion) {
+    let path = test_process_path();
+    let mut options = SpawnOptions::new();
+    options.verify_audit(false).trailer(TrailerType::None);
+    c.bench_function(SPAWN_WITHOUT_AUDIT, move |b| {
+        b.iter(|| {
+            let (mut child, _task_port) = Command::new(&path)
+                .stdin(Stdio::null())
+                .spawn_get_task_port_with(&options)
+                .unwrap();
+            child.wait().unwrap();
+        })
+    });
+}
+
+/// Where criterion saves its results.
+fn criterion_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("CRITERION_HOME") {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = env::var_os("CARGO_TARGET_DIR") {
+        return PathBuf::from(dir).join("criterion");
+    }
+    // This runs as `target/<profile>/deps/handshake-<hash>`.
+    let exe = env::current_exe().unwrap();
+    exe.ancestors().nth(3).unwrap().join("criterion")
+}
+
+/// The mean time of the last run of `benchmark`, in nanoseconds, if it has
+/// been run.
+fn mean_ns(benchmark: &str) -> Option<f64> {
+    // Criterion replaces the characters that can't go in a file name.
+    let dir: String = benchmark.chars()
+        .map(|c| if "?\"/\\*<>:|^".contains(c) { '_' } else { c })
+        .collect();
+    let file = File::open(criterion_dir().join(dir).join("new/estimates.json")).ok()?;
+    let estimates: serde_json::Value = serde_json::from_reader(file).ok()?;
+    estimates["mean"]["point_estimate"].as_f64()
+}
+
+/// The first line of what `program` prints when run with `args`.
+fn first_line(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|stdout| stdout.lines().next().map(str::to_owned))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Print the results as a Markdown table for the README, noting the
+/// machine and the toolchain they were measured with.
+fn print_table() {
+    let plain = match mean_ns(PLAIN_SPAWN) {
+        Some(plain) => plain,
+        None => return,
+    };
+    println!("Measured on {}, macOS {}, with {}:",
+             first_line("sysctl", &["-n", "machdep.cpu.brand_string"]),
+             first_line("sw_vers", &["-productVersion"]),
+             first_line(&env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned()), &["-V"]));
+    println!();
+    println!("| Benchmark | Mean | Over a plain spawn |");
+    println!("|-----------|-----:|-------------------:|");
+    for benchmark in BENCHMARKS.iter() {
+        if let Some(mean) = mean_ns(benchmark) {
+            println!("| `{}` | {:.0} µs | {:+.0} µs |",
+                     benchmark,
+                     mean / 1000.0,
+                     (mean - plain) / 1000.0);
+        }
+    }
+}
+
+criterion_group!(benches,
+                 plain_spawn,
+                 spawn_with_task_port,
+                 broker_spawn,
+                 spawn_without_audit);
+
+fn main() {
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+    print_table();
+}
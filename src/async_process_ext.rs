@@ -0,0 +1,154 @@
+//! Support for `async_process::Command`, which smol and async-std use,
+//! enabled by the `async-process` feature.
+//!
+//! `async_process::Command` has no `pre_exec`, so the check-in hook is
+//! added to a `std::process::Command` first, which is then converted into
+//! an `AsyncTaskPortCommand`. The `async_process::Command` it wraps can
+//! still be configured before spawning; its stdio in particular must be,
+//! since async-process ignores what the `std::process::Command` had.
+//!
+//! Waiting for the check-in doesn't need a thread of its own: as with
+//! `tokio::process::Command`, the `TaskPortReceiver`'s kqueue is watched
+//! by a reactor, here async-io's, which async-process already depends on,
+//! and the message is only received once it has arrived. That works under
+//! any executor.
+
+use std::future::Future;
+use std::io::Result;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::pin::Pin;
+use std::process;
+use std::task::{Context, Poll};
+
+use async_io::Async;
+use async_process::{Child, Command};
+
+use {SpawnOptions, SpawnTaskPortError, TaskPort, TaskPortReceiver, pre_exec_hook};
+
+/// A `TaskPortReceiver`'s kqueue, for async-io to watch. It doesn't own
+/// the fd, which the receiver closes.
+struct KqueueFd(RawFd);
+
+impl AsFd for KqueueFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // `Receiving` drops its `Async` before the receiver.
+        unsafe { BorrowedFd::borrow_raw(self.0) }
+    }
+}
+
+/// An extension to `std::process::Command` to turn it into an
+/// `async_process::Command` that gets back access to its child's Mach task
+/// port without blocking the executor.
+pub trait AsyncCommandSpawnWithTask {
+    /// Register for the child's check-in, and convert the command into an
+    /// `async_process::Command` that sends it.
+    fn into_async_with_task_port(self) -> Result<AsyncTaskPortCommand>;
+}
+
+impl AsyncCommandSpawnWithTask for process::Command {
+    fn into_async_with_task_port(mut self) -> Result<AsyncTaskPortCommand> {
+        let (receiver, check_in) = TaskPortReceiver::register_for(&mut self, &SpawnOptions::new())?;
+        unsafe {
+            self.pre_exec(pre_exec_hook(check_in));
+        }
+        Ok(AsyncTaskPortCommand {
+            command: Command::from(self),
+            receiver: receiver,
+        })
+    }
+}
+
+/// An `async_process::Command` whose child sends its task port back, for
+/// spawning once.
+pub struct AsyncTaskPortCommand {
+    command: Command,
+    receiver: TaskPortReceiver,
+}
+
+impl AsyncTaskPortCommand {
+    /// The command, to configure further before spawning it.
+    pub fn command_mut(&mut self) -> &mut Command {
+        &mut self.command
+    }
+
+    /// Executes the command as a child process, returning a future that
+    /// resolves to both the `Child` as well as a `TaskPort` that owns the
+    /// process' Mach task port once the child has checked in.
+    pub fn spawn_with_task_port(self) -> AsyncSpawnWithTaskPort {
+        AsyncSpawnWithTaskPort { state: Some(self.spawn()) }
+    }
+
+    /// Spawn the command, and start watching for its check-in.
+    fn spawn(self) -> Result<Receiving> {
+        let AsyncTaskPortCommand { mut command, mut receiver } = self;
+        let child = command.spawn().map_err(SpawnTaskPortError::from_spawn)?;
+        receiver.set_pid(child.id());
+        // Nothing reads from the kqueue, so it can stay blocking.
+        let fd = Async::new_nonblocking(KqueueFd(receiver.as_raw_fd()))?;
+        Ok(Receiving {
+            child: child,
+            fd: fd,
+            receiver: receiver,
+        })
+    }
+}
+
+/// The future returned by `AsyncCommandSpawnWithTask::spawn_with_task_port`.
+pub struct AsyncSpawnWithTaskPort {
+    /// Either the error spawning failed with, or the spawned child.
+    state: Option<Result<Receiving>>,
+}
+
+struct Receiving {
+    child: Child,
+    /// Deregistered before `receiver` closes the kqueue.
+    fd: Async<KqueueFd>,
+    receiver: TaskPortReceiver,
+}
+
+impl Receiving {
+    /// Return the task port if the child has checked in, or arrange for
+    /// `cx` to be woken once it has.
+    fn poll_recv(&self, cx: &mut Context) -> Poll<Result<TaskPort>> {
+        loop {
+            if let Some(task_port) = self.receiver.try_recv()? {
+                return Poll::Ready(Ok(task_port));
+            }
+            match self.fd.poll_readable(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(())) => {}
+            }
+        }
+    }
+}
+
+impl Future for AsyncSpawnWithTaskPort {
+    type Output = Result<(Child, TaskPort)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let received = match this.state {
+            Some(Ok(ref receiving)) => {
+                match receiving.poll_recv(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(received) => Some(received),
+                }
+            }
+            Some(Err(_)) => None,
+            None => panic!("`AsyncSpawnWithTaskPort` polled after completion"),
+        };
+        Poll::Ready(match (this.state.take().unwrap(), received) {
+            (Err(e), _) => Err(e),
+            (Ok(receiving), Some(Ok(task_port))) => Ok((receiving.child, task_port)),
+            (Ok(mut receiving), Some(Err(e))) => {
+                // Like `spawn_with_task_port`, don't leave a child behind
+                // that the caller never gets.
+                let _ = receiving.child.kill();
+                Err(e)
+            }
+            (Ok(_), None) => unreachable!(),
+        })
+    }
+}
@@ -0,0 +1,200 @@
+//! Optional accounting of what each spawn leaves in the parent.
+//!
+//! A handshake registers a name in the bootstrap namespace, which is shared
+//! with every process in the session, and allocates a port for it, plus a
+//! kqueue and its notifications for spawns that return a
+//! `TaskPortReceiver`. Whether those outlive the spawn depends on its
+//! options: by default the name is unregistered, and its port destroyed,
+//! once the handshake is over.
+//!
+//! When auditing is enabled with `set_enabled(true)`, each spawn keeps a
+//! `SpawnAudit` listing everything it created and whether it has been
+//! released since. `last_spawn_audit` returns the one for the most recent
+//! spawn on the current thread; it is a handle that keeps being updated,
+//! so it can be kept and checked again later, say once a receiver has been
+//! dropped.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The audit of the last spawn on this thread.
+    static LAST: RefCell<Option<SpawnAudit>> = RefCell::new(None);
+}
+
+/// Turn auditing on or off for all threads. It is off by default.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Whether auditing is turned on.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// The audit of the most recent spawn on the current thread that ran with
+/// auditing enabled.
+pub fn last_spawn_audit() -> Option<SpawnAudit> {
+    LAST.with(|last| last.borrow().clone())
+}
+
+/// The kinds of thing a spawn can leave in the parent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ResourceKind {
+    /// A name registered with the bootstrap server.
+    ServiceName,
+    /// The receive right the check-in arrives on.
+    ReceiveRight,
+    /// The send right the service name was registered with.
+    SendRight,
+    /// A kqueue watching for the check-in.
+    Kqueue,
+    /// A kqueue filter, such as `EVFILT_MACHPORT` on the receive right.
+    Notification,
+}
+
+/// One thing a spawn created.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Resource {
+    /// What it is.
+    pub kind: ResourceKind,
+    /// Which one it is: the service name, port name or descriptor.
+    pub name: String,
+    /// Whether it has been released since.
+    pub released: bool,
+}
+
+#[derive(Debug, Default)]
+struct Record {
+    pid: Option<u32>,
+    resources: Vec<Resource>,
+}
+
+/// What one spawn created in the parent, kept up to date as it is
+/// released.
+#[derive(Clone)]
+pub struct SpawnAudit(Arc<Mutex<Record>>);
+
+impl SpawnAudit {
+    fn with<T, F: FnOnce(&mut Record) -> T>(&self, f: F) -> T {
+        // A panic while holding the lock can't leave a record inconsistent.
+        let mut record = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut record)
+    }
+
+    /// The pid of the child, once it was spawned.
+    pub fn pid(&self) -> Option<u32> {
+        self.with(|record| record.pid)
+    }
+
+    /// Everything the spawn created, in the order it did.
+    pub fn resources(&self) -> Vec<Resource> {
+        self.with(|record| record.resources.clone())
+    }
+
+    /// What the spawn created and hasn't released yet.
+    pub fn outstanding(&self) -> Vec<Resource> {
+        self.with(|record| record.resources.iter().filter(|r| !r.released).cloned().collect())
+    }
+
+    /// Whether everything the spawn created has been released.
+    pub fn is_clean(&self) -> bool {
+        self.with(|record| record.resources.iter().all(|r| r.released))
+    }
+}
+
+impl fmt::Debug for SpawnAudit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.with(|record| {
+            f.debug_struct("SpawnAudit")
+                .field("pid", &record.pid)
+                .field("resources", &record.resources)
+                .finish()
+        })
+    }
+}
+
+/// The crate's end of a spawn's audit, which does nothing when auditing is
+/// off.
+#[derive(Clone, Debug)]
+pub(crate) struct Recorder(Option<SpawnAudit>);
+
+impl Recorder {
+    /// Start auditing a spawn, if auditing is on, making it the current
+    /// thread's last.
+    pub(crate) fn begin() -> Recorder {
+        if !is_enabled() {
+            return Recorder(None);
+        }
+        let audit = SpawnAudit(Arc::new(Mutex::new(Record::default())));
+        LAST.with(|last| *last.borrow_mut() = Some(audit.clone()));
+        Recorder(Some(audit))
+    }
+
+    pub(crate) fn set_pid(&self, pid: u32) {
+        if let Some(ref audit) = self.0 {
+            audit.with(|record| record.pid = Some(pid));
+        }
+    }
+
+    /// Note that the spawn created `name`, of `kind`.
+    pub(crate) fn created<S: ToString>(&self, kind: ResourceKind, name: S) {
+        if let Some(ref audit) = self.0 {
+            audit.with(|record| {
+                record.resources.push(Resource {
+                    kind: kind,
+                    name: name.to_string(),
+                    released: false,
+                })
+            });
+        }
+    }
+
+    /// Note that everything of `kind` the spawn created has been released.
+    pub(crate) fn released(&self, kind: ResourceKind) {
+        if let Some(ref audit) = self.0 {
+            audit.with(|record| {
+                for resource in record.resources.iter_mut().filter(|r| r.kind == kind) {
+                    resource.released = true;
+                }
+            });
+        }
+    }
+
+    /// Note that everything the spawn created has been released.
+    pub(crate) fn released_all(&self) {
+        if let Some(ref audit) = self.0 {
+            audit.with(|record| {
+                for resource in &mut record.resources {
+                    resource.released = true;
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_what_is_released() {
+        let audit = SpawnAudit(Arc::new(Mutex::new(Record::default())));
+        let recorder = Recorder(Some(audit.clone()));
+        recorder.created(ResourceKind::ServiceName, "com.example.spawn");
+        recorder.created(ResourceKind::ReceiveRight, "0x1103");
+        recorder.created(ResourceKind::SendRight, "0x1103");
+        recorder.released(ResourceKind::SendRight);
+        assert!(!audit.is_clean());
+        assert_eq!(audit.outstanding().len(), 2);
+        recorder.released_all();
+        assert!(audit.is_clean());
+        assert_eq!(audit.resources()[0].name, "com.example.spawn");
+    }
+}
@@ -0,0 +1,14 @@
+//! A child that does nothing until its stdin is closed, for examples and
+//! tests to spawn. See `spawn_task_port::helper`.
+
+#[macro_use]
+extern crate spawn_task_port;
+
+use std::io::{self, Read};
+
+child_entry! {
+    fn main() {
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+//! Spawn a command and inspect it through its task port.
+//!
+//! Built with the `cli` feature.
+
+extern crate spawn_task_port;
+
+use spawn_task_port::{ChildWithTask, CommandSpawnWithTask, Module};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+
+const USAGE: &'static str = "\
+usage: spawn-task-portctl <action> [options] -- <program> [args...]
+
+actions:
+  info      print the task's basic info and CPU times
+  vm        print a summary of the task's memory
+  modules   list the images loaded into the task
+  threads   list the task's threads
+  sample    sample the task's threads and print the hottest addresses
+  crash     run the program and report how it exits
+
+options:
+  --delay <ms>      wait this long after spawning before inspecting [1000]
+  --count <n>       take this many samples [100]
+  --interval <ms>   sample this often [10]";
+
+const ACTIONS: &'static [&'static str] = &["info", "vm", "modules", "threads", "sample", "crash"];
+
+struct Options {
+    action: String,
+    delay: Duration,
+    count: u32,
+    interval: Duration,
+    command: Vec<String>,
+}
+
+fn usage_error(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{}\n\n{}", message, USAGE))
+}
+
+fn parse_number(option: &str, value: Option<String>) -> Result<u64> {
+    value.and_then(|value| value.parse().ok())
+        .ok_or_else(|| usage_error(&format!("{} needs a number", option)))
+}
+
+fn parse_args() -> Result<Options> {
+    let mut args = env::args().skip(1);
+    let action = args.next().ok_or_else(|| usage_error("no action given"))?;
+    if !ACTIONS.contains(&&action[..]) {
+        return Err(usage_error(&format!("unknown action `{}`", action)));
+    }
+    let mut options = Options {
+        action: action,
+        delay: Duration::from_millis(1000),
+        count: 100,
+        interval: Duration::from_millis(10),
+        command: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--delay" => options.delay = Duration::from_millis(parse_number(&arg, args.next())?),
+            "--count" => options.count = parse_number(&arg, args.next())? as u32,
+            "--interval" => {
+                options.interval = Duration::from_millis(parse_number(&arg, args.next())?)
+            }
+            "--" => {
+                options.command = args.by_ref().collect();
+            }
+            _ => return Err(usage_error(&format!("unknown option `{}`", arg))),
+        }
+    }
+    if options.command.is_empty() {
+        return Err(usage_error("no program given"));
+    }
+    Ok(options)
+}
+
+fn spawn(options: &Options) -> Result<ChildWithTask> {
+    Command::new(&options.command[0])
+        .args(&options.command[1..])
+        .spawn_with_task()
+}
+
+fn print_info(child: &ChildWithTask) -> Result<()> {
+    let info = child.task_port().basic_info()?;
+    let times = child.task_port().thread_times()?;
+    println!("pid:              {}", child.id());
+    println!("virtual size:     {}", info.virtual_size);
+    println!("resident size:    {}", info.resident_size);
+    println!("peak resident:    {}", info.resident_size_max);
+    println!("suspend count:    {}", info.suspend_count);
+    println!("user time:        {:?}", info.user_time + times.user_time);
+    println!("system time:      {:?}", info.system_time + times.system_time);
+    Ok(())
+}
+
+fn print_vm(child: &ChildWithTask) -> Result<()> {
+    let vm = child.task_port().vm_info()?;
+    println!("footprint:        {}", vm.phys_footprint);
+    println!("resident:         {} (peak {})", vm.resident_size, vm.resident_size_peak);
+    println!("anonymous:        {}", vm.internal);
+    println!("file-backed:      {}", vm.external);
+    println!("compressed:       {}", vm.compressed);
+    println!("virtual:          {} in {} regions", vm.virtual_size, vm.region_count);
+    println!("page size:        {}", vm.page_size);
+    Ok(())
+}
+
+fn print_modules(child: &ChildWithTask) -> Result<()> {
+    for module in child.task_port().modules()? {
+        println!("{:#018x} {}", module.load_address, module.path);
+    }
+    Ok(())
+}
+
+fn print_threads(child: &ChildWithTask) -> Result<()> {
+    for thread in child.task_port().threads()? {
+        let name = thread.name()?.unwrap_or_default();
+        println!("{:>10} {:#018x} {}", thread.id()?, thread.program_counter()?, name);
+    }
+    Ok(())
+}
+
+/// The module `address` most likely belongs to: the last one loaded
+/// below it.
+fn find_module(modules: &[Module], address: u64) -> Option<&Module> {
+    modules.iter()
+        .filter(|module| module.load_address <= address)
+        .max_by_key(|module| module.load_address)
+}
+
+fn sample(child: &ChildWithTask, options: &Options) -> Result<()> {
+    let task = child.task_port();
+    let mut counts = HashMap::new();
+    let mut total = 0;
+    for _ in 0..options.count {
+        // Suspend the task so every thread is sampled at the same moment.
+        if task.suspend().is_err() {
+            // The task has exited.
+            break;
+        }
+        for thread in task.threads().unwrap_or_default() {
+            if let Ok(pc) = thread.program_counter() {
+                *counts.entry(pc).or_insert(0) += 1;
+                total += 1;
+            }
+        }
+        let _ = task.resume();
+        thread::sleep(options.interval);
+    }
+    let modules = task.modules().unwrap_or_default();
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by_key(|&(_, count)| Reverse(count));
+    println!("{} samples", total);
+    for (pc, count) in counts.into_iter().take(20) {
+        let location = match find_module(&modules, pc) {
+            Some(module) => format!("{}+{:#x}", module.path, pc - module.load_address),
+            None => String::new(),
+        };
+        println!("{:>6} {:#018x} {}", count, pc, location);
+    }
+    Ok(())
+}
+
+fn watch_crash(child: &mut ChildWithTask) -> Result<()> {
+    child.watch_for_crash()?;
+    child.wait()?;
+    let details = child.exit_details()?.expect("child has exited");
+    println!("status:           {}", details.status);
+    if let Some(crash) = details.crash {
+        if let Some(exception) = crash.exception {
+            println!("exception:        {:?}", exception);
+        }
+        println!("codes:            {:#x}, {:#x}", crash.code, crash.subcode);
+        println!("signal:           {}", crash.signal);
+        if let Some(thread_id) = crash.thread_id {
+            println!("crashed thread:   {}", thread_id);
+        }
+    }
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<i32> {
+    let mut child = spawn(options)?;
+    let result = match &options.action[..] {
+        "sample" => sample(&child, options),
+        "crash" => watch_crash(&mut child),
+        action => {
+            thread::sleep(options.delay);
+            match action {
+                "info" => print_info(&child),
+                "vm" => print_vm(&child),
+                "modules" => print_modules(&child),
+                _ => print_threads(&child),
+            }
+        }
+    };
+    if let Err(e) = result {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e);
+    }
+    let status = child.wait()?;
+    Ok(status.code().unwrap_or(1))
+}
+
+fn main() {
+    let code = match parse_args().and_then(|options| run(&options)) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("spawn-task-portctl: {}", e);
+            2
+        }
+    };
+    process::exit(code);
+}
@@ -1,6 +1,126 @@
-use std::io::{self, Read};
+extern crate libc;
+extern crate spawn_task_port;
+
+use spawn_task_port::RingBufferProducer;
+use std::arch::asm;
+use std::env;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Make Mach trap 200, which is past the end of the kernel's trap table.
+#[cfg(target_arch = "aarch64")]
+fn make_bad_trap() {
+    unsafe {
+        asm!("svc 0x80", in("x16") -200i64, lateout("x0") _);
+    }
+}
+
+/// Make Mach trap 200, which is past the end of the kernel's trap table.
+#[cfg(target_arch = "x86_64")]
+fn make_bad_trap() {
+    unsafe {
+        asm!("syscall",
+             inlateout("rax") 0x100_0000u64 | 200 => _,
+             lateout("rcx") _,
+             lateout("r11") _);
+    }
+}
 
 fn main() {
+    // When spawned by a `ForkServer`, this only returns in forked workers.
+    spawn_task_port::fork_server::serve().unwrap();
+    if env::args().nth(1).as_ref().map(|arg| &arg[..]) == Some("fork-worker") {
+        // Exit after one line, rather than at the end of the stdin that the
+        // fork server's workers all share.
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).unwrap();
+        return;
+    }
     let mut s = String::new();
     io::stdin().read_to_string(&mut s).unwrap();
+    match env::args().nth(1).as_ref().map(|arg| &arg[..]) {
+        Some("abort") => process::abort(),
+        Some("bad-trap") => make_bad_trap(),
+        Some("ring") => {
+            // stdin is the address of a ring buffer to push to.
+            let address = s.trim().parse().unwrap();
+            let mut producer = unsafe { RingBufferProducer::from_address(address).unwrap() };
+            for i in 0..10 {
+                assert!(producer.push(format!("record {}", i).as_bytes()));
+            }
+        }
+        Some("check-in") => {
+            // stdin is the service name to check in with, as a launchd
+            // helper would get from its plist.
+            spawn_task_port::check_in_with_service(s.trim()).unwrap();
+        }
+        Some("send-task-port") => {
+            // stdin is the service name to check in with, as any program
+            // not spawned through the crate might be given it.
+            spawn_task_port::child::send_task_port(s.trim()).unwrap();
+        }
+        Some("daemonize") => {
+            // Double-fork like a daemon, and check in from the grandchild.
+            for _ in 0..2 {
+                match unsafe { libc::fork() } {
+                    -1 => panic!("fork failed"),
+                    0 => {}
+                    _ => process::exit(0),
+                }
+                unsafe { libc::setsid() };
+            }
+            assert!(spawn_task_port::daemon::check_in().unwrap());
+            thread::sleep(Duration::from_secs(10));
+        }
+        Some("spawn-descendant") => {
+            // Spawn a grandchild, and only exit once it has checked in, so
+            // that it still has this process as its parent when it does.
+            let mut grandchild = Command::new(env::current_exe().unwrap())
+                .arg("descendant")
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut line = String::new();
+            BufReader::new(grandchild.stdout.take().unwrap()).read_line(&mut line).unwrap();
+            assert_eq!(line.trim(), "checked in");
+            // Exit without waiting, leaving the grandchild running for the
+            // test to find and terminate once this process has been reaped.
+            mem::forget(grandchild);
+        }
+        Some("descendant") => {
+            assert!(spawn_task_port::descendant::check_in().unwrap());
+            println!("checked in");
+            thread::sleep(Duration::from_secs(10));
+        }
+        Some("exec") => {
+            // Execute another image, as a wrapper script would.
+            let err = Command::new(env::current_exe().unwrap()).arg("exec-check-in").exec();
+            panic!("exec failed: {}", err);
+        }
+        Some("exec-check-in") => {
+            assert!(spawn_task_port::exec_check_in::check_in().unwrap());
+            println!("checked in");
+            thread::sleep(Duration::from_secs(10));
+        }
+        Some("child-check-in") => {
+            assert!(spawn_task_port::child::check_in().unwrap());
+            println!("checked in");
+            thread::sleep(Duration::from_secs(10));
+        }
+        Some("heartbeat") => {
+            assert!(spawn_task_port::start_heartbeat(Duration::from_millis(10)).unwrap());
+            thread::sleep(Duration::from_millis(500));
+        }
+        Some("shutdown") => {
+            let listener = spawn_task_port::listen_for_shutdown().unwrap().unwrap();
+            let deadline = listener.wait(Some(Duration::from_secs(10))).unwrap();
+            assert!(deadline.is_some());
+        }
+        _ => {}
+    }
 }
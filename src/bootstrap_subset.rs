@@ -0,0 +1,80 @@
+//! A bootstrap namespace for a child of its own.
+//!
+//! The parent normally registers the name its child checks in with in its
+//! own bootstrap namespace, which is the per-user one everything else in
+//! the session shares. A `BootstrapSubset` is a namespace launchd creates
+//! on top of it: look-ups fall through to the namespace below, but names
+//! registered in it are only visible to processes whose bootstrap port is
+//! the subset. `SpawnOptions::bootstrap_port` registers the name in the
+//! subset and spawns the child with the subset as its bootstrap port, so
+//! the name never shows up anywhere else, and goes away with the subset.
+
+use std::fmt;
+use std::io::Result;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+use mach::traps::mach_task_self;
+
+use {MachPort, allocate_server_port, mach_port_mod_refs};
+
+extern "C" {
+    fn bootstrap_subset(bp: mach_port_t,
+                        requestor_port: mach_port_t,
+                        subset_port: *mut mach_port_t)
+                        -> kern_return_t;
+}
+
+/// A subset of this process' bootstrap namespace, which lasts until it is
+/// dropped.
+///
+/// Children spawned into it keep it as their bootstrap port, and their
+/// look-ups of names registered in it fail once it is gone, so keep it for
+/// as long as they might need them.
+pub struct BootstrapSubset {
+    port: MachPort,
+    /// launchd destroys the subset once this port's receive right is gone.
+    requestor: MachPort,
+}
+
+impl BootstrapSubset {
+    /// Create a new subset of this process' bootstrap namespace.
+    pub fn new() -> Result<BootstrapSubset> {
+        let requestor = allocate_server_port()?;
+        unsafe {
+            let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+            ktry!(task_get_special_port(mach_task_self(),
+                                        TASK_BOOTSTRAP_PORT,
+                                        &mut bootstrap_port));
+            let bootstrap_port = MachPort(bootstrap_port);
+            let mut port: mach_port_t = MACH_PORT_NULL;
+            ktry!(bootstrap_subset(bootstrap_port.0, requestor.0, &mut port));
+            Ok(BootstrapSubset {
+                port: MachPort(port),
+                requestor: requestor,
+            })
+        }
+    }
+
+    /// The subset's bootstrap port, for `SpawnOptions::bootstrap_port`. It
+    /// remains owned by the `BootstrapSubset`.
+    pub fn as_raw(&self) -> mach_port_t {
+        self.port.0
+    }
+}
+
+impl fmt::Debug for BootstrapSubset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BootstrapSubset").field("port", &self.port.0).finish()
+    }
+}
+
+impl Drop for BootstrapSubset {
+    fn drop(&mut self) {
+        // `requestor` then deallocates its send right.
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.requestor.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
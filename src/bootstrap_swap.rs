@@ -0,0 +1,301 @@
+//! Getting a child's task port without registering anything with launchd.
+//!
+//! A child inherits its parent's bootstrap port across `fork`, and nothing
+//! else: it has no other way to reach the parent over Mach. Instead of
+//! registering a service for the child to look up, the parent can lend the
+//! child a port of its own in place of the bootstrap port. The parent sets
+//! its own `TASK_BOOTSTRAP_PORT` to a fresh port just while it spawns the
+//! child, and puts the real one back right after. Between `fork` and
+//! `exec`, the child sends its task port to what it believes is its
+//! bootstrap port, along with a reply port, and the parent replies with the
+//! real bootstrap port, which the child installs before it executes.
+//!
+//! Since nothing is registered, this works in sandboxes that deny both
+//! `bootstrap_register2` and `bootstrap_check_in`, and leaves nothing
+//! behind in the bootstrap namespace.
+//!
+//! The parent's `TASK_BOOTSTRAP_PORT` is per process. libSystem keeps its
+//! own copy for the parent's bootstrap look-ups, so those still work while
+//! it is swapped, and swaps by this module are serialized, but any other
+//! process spawned from another thread meanwhile inherits the borrowed
+//! port instead of the real one. Don't spawn from other threads while this
+//! runs.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use libc;
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::mach_port::mach_port_allocate;
+use mach::message::{MACH_MSGH_BITS, MACH_MSGH_BITS_COMPLEX, MACH_MSG_TYPE_COPY_SEND,
+                    MACH_MSG_TYPE_MAKE_SEND_ONCE, MACH_MSG_TYPE_MOVE_SEND_ONCE, MACH_RCV_MSG,
+                    MACH_MSG_TIMEOUT_NONE, mach_msg, mach_msg_body_t, mach_msg_destroy,
+                    mach_msg_header_t, mach_msg_port_descriptor_t, mach_msg_send,
+                    mach_msg_trailer_t};
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+use mach::traps::mach_task_self;
+
+use diagnostics;
+use trailer::{RawTrailer, TrailerType};
+use {MachPort, SpawnTaskPortError, SpawnedProcess, TaskPort, allocate_server_port,
+     mach_port_mod_refs, receive_options};
+
+extern "C" {
+    fn task_set_special_port(task: mach_port_t,
+                             which_port: c_int,
+                             special_port: mach_port_t)
+                             -> kern_return_t;
+}
+
+/// The `msgh_id` of the child's request, carrying its task port.
+const SWAP_MSG_ID: c_int = 0x5354_4253;
+/// The `msgh_id` of the parent's reply, carrying the real bootstrap port.
+const SWAP_REPLY_MSG_ID: c_int = 0x5354_4252;
+
+/// Whether a spawn has swapped the parent's bootstrap port.
+static SWAPPED: AtomicBool = AtomicBool::new(false);
+
+/// Ownership of the parent's bootstrap port, for one spawn at a time.
+struct SwapLock;
+
+impl SwapLock {
+    fn acquire() -> SwapLock {
+        while SWAPPED.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err() {
+            thread::yield_now();
+        }
+        SwapLock
+    }
+}
+
+impl Drop for SwapLock {
+    fn drop(&mut self) {
+        SWAPPED.store(false, Ordering::Release);
+    }
+}
+
+#[repr(C)]
+struct SwapRequest {
+    header: mach_msg_header_t,
+    body: mach_msg_body_t,
+    task_port: mach_msg_port_descriptor_t,
+    pid: c_int,
+}
+
+#[repr(C)]
+struct ReceivedSwapRequest {
+    request: SwapRequest,
+    /// Only filled in as far as `receive_options` asked for.
+    trailer: RawTrailer,
+}
+
+#[repr(C)]
+struct SwapReply {
+    header: mach_msg_header_t,
+    body: mach_msg_body_t,
+    bootstrap_port: mach_msg_port_descriptor_t,
+}
+
+#[repr(C)]
+struct ReceivedSwapReply {
+    reply: SwapReply,
+    trailer: mach_msg_trailer_t,
+}
+
+/// Send our task port to the port standing in for our bootstrap port, and
+/// install the real bootstrap port the parent sends back.
+///
+/// This runs in the child process between `fork` and `exec`, so it makes
+/// nothing but system calls.
+unsafe fn swap_back() -> Result<()> {
+    let mut parent_port: mach_port_t = MACH_PORT_NULL;
+    ktry_child!(task_get_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, &mut parent_port));
+    let parent_port = MachPort(parent_port);
+    let mut reply_port: mach_port_t = MACH_PORT_NULL;
+    ktry_child!(mach_port_allocate(mach_task_self(), MACH_PORT_RIGHT_RECEIVE, &mut reply_port));
+    let mut request = SwapRequest {
+        header: mach_msg_header_t {
+            msgh_bits: MACH_MSGH_BITS(MACH_MSG_TYPE_COPY_SEND, MACH_MSG_TYPE_MAKE_SEND_ONCE) |
+                       MACH_MSGH_BITS_COMPLEX,
+            msgh_size: mem::size_of::<SwapRequest>() as u32,
+            msgh_remote_port: parent_port.0,
+            msgh_local_port: reply_port,
+            msgh_voucher_port: MACH_PORT_NULL,
+            msgh_id: SWAP_MSG_ID,
+        },
+        body: mach_msg_body_t { msgh_descriptor_count: 1 },
+        task_port: mach_msg_port_descriptor_t::new(mach_task_self(), MACH_MSG_TYPE_COPY_SEND),
+        pid: libc::getpid(),
+    };
+    ktry_child!(mach_msg_send(&mut request.header));
+    let mut reply: ReceivedSwapReply = mem::zeroed();
+    ktry_child!(mach_msg(&mut reply.reply.header,
+                         MACH_RCV_MSG,
+                         0,
+                         mem::size_of::<ReceivedSwapReply>() as u32,
+                         reply_port,
+                         MACH_MSG_TIMEOUT_NONE,
+                         MACH_PORT_NULL));
+    // If the parent drops the request instead, a send-once notification
+    // arrives in its place.
+    if reply.reply.header.msgh_id != SWAP_REPLY_MSG_ID {
+        return Err(Error::from_raw_os_error(libc::EPROTO));
+    }
+    ktry_child!(task_set_special_port(mach_task_self(),
+                                      TASK_BOOTSTRAP_PORT,
+                                      reply.reply.bootstrap_port.name));
+    Ok(())
+}
+
+/// Wait on `port` for a child's request, and reply to it with
+/// `bootstrap_port`, returning the task port it carried and the pid of the
+/// process that sent it.
+fn respond(port: mach_port_t, bootstrap_port: mach_port_t) -> Result<(MachPort, u32)> {
+    const CALL: &'static str = "mach_msg(MACH_RCV_MSG)";
+    loop {
+        let mut msg: ReceivedSwapRequest = unsafe { mem::zeroed() };
+        let kr = unsafe {
+            mach_msg(&mut msg.request.header,
+                     receive_options(TrailerType::Audit),
+                     0,
+                     mem::size_of::<ReceivedSwapRequest>() as u32,
+                     port,
+                     MACH_MSG_TIMEOUT_NONE,
+                     MACH_PORT_NULL)
+        };
+        if kr != KERN_SUCCESS {
+            return Err(SpawnTaskPortError::from_call(CALL, kr).into());
+        }
+        if msg.request.header.msgh_id != SWAP_MSG_ID {
+            // Something else that inherited the port while it was lent out
+            // mistook it for the bootstrap server.
+            unsafe { mach_msg_destroy(&mut msg.request.header) };
+            continue;
+        }
+        let task_port = MachPort(msg.request.task_port.name);
+        let sender = msg.trailer
+            .parse()
+            .audit_token()
+            .map_or(msg.request.pid as u32, |token| token.pid());
+        let mut reply = SwapReply {
+            header: mach_msg_header_t {
+                msgh_bits: MACH_MSGH_BITS(MACH_MSG_TYPE_MOVE_SEND_ONCE, 0) |
+                           MACH_MSGH_BITS_COMPLEX,
+                msgh_size: mem::size_of::<SwapReply>() as u32,
+                msgh_remote_port: msg.request.header.msgh_remote_port,
+                msgh_local_port: MACH_PORT_NULL,
+                msgh_voucher_port: MACH_PORT_NULL,
+                msgh_id: SWAP_REPLY_MSG_ID,
+            },
+            body: mach_msg_body_t { msgh_descriptor_count: 1 },
+            bootstrap_port: mach_msg_port_descriptor_t::new(bootstrap_port,
+                                                            MACH_MSG_TYPE_COPY_SEND),
+        };
+        unsafe {
+            ktry!(mach_msg_send(&mut reply.header));
+        }
+        return Ok((task_port, sender));
+    }
+}
+
+/// Run `spawn` with the process' bootstrap port set to `port`, so that
+/// whatever it spawns inherits `port` as its bootstrap port, for good.
+///
+/// This serializes with the swap, and likewise affects processes other
+/// threads spawn meanwhile.
+pub(crate) fn with_bootstrap_port<T, F>(port: mach_port_t, spawn: F) -> Result<T>
+    where F: FnOnce() -> Result<T>
+{
+    let _lock = SwapLock::acquire();
+    let bootstrap_port = unsafe {
+        let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+        ktrace!(task_get_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, &mut bootstrap_port));
+        MachPort(bootstrap_port)
+    };
+    unsafe {
+        ktrace!(task_set_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, port));
+    }
+    let spawned = spawn();
+    unsafe {
+        task_set_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, bootstrap_port.0);
+    }
+    spawned
+}
+
+/// An extension to `std::process::Command` to get a child's task port by
+/// lending it a port in place of its bootstrap port, without registering
+/// anything with launchd.
+pub trait BootstrapSwapSpawnWithTask {
+    /// Executes the command as a child process, returning both the `Child`
+    /// as well as a `TaskPort` that owns the process' Mach task port.
+    ///
+    /// The parent's bootstrap port is swapped while the child is spawned,
+    /// so no other thread should spawn processes meanwhile.
+    fn spawn_with_task_port_by_swap(&mut self) -> Result<(Child, TaskPort)>;
+}
+
+impl BootstrapSwapSpawnWithTask for Command {
+    fn spawn_with_task_port_by_swap(&mut self) -> Result<(Child, TaskPort)> {
+        diagnostics::record_handshake(|| {
+            let port = allocate_server_port()?;
+            let _lock = SwapLock::acquire();
+            let bootstrap_port = unsafe {
+                let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+                ktrace!(task_get_special_port(mach_task_self(),
+                                              TASK_BOOTSTRAP_PORT,
+                                              &mut bootstrap_port));
+                MachPort(bootstrap_port)
+            };
+            unsafe {
+                ktrace!(task_set_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, port.0));
+            }
+            // `spawn` doesn't return until the child has executed, which it
+            // only does once it has heard back, so reply from another
+            // thread.
+            let responder = {
+                let (port, bootstrap_port) = (port.0, bootstrap_port.0);
+                thread::spawn(move || respond(port, bootstrap_port))
+            };
+            let spawned = unsafe { self.pre_exec(|| swap_back()) }.spawn();
+            unsafe {
+                task_set_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, bootstrap_port.0);
+            }
+            if spawned.is_err() {
+                // Wake the responder, which may never get a request.
+                unsafe {
+                    mach_port_mod_refs(mach_task_self(), port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+                }
+            }
+            let responded = responder.join()
+                .unwrap_or_else(|_| {
+                    Err(Error::new(ErrorKind::Other, "the responding thread panicked"))
+                });
+            let mut child = spawned.map_err(|e| Error::from(SpawnTaskPortError::from_spawn(e)))?;
+            unsafe {
+                mach_port_mod_refs(mach_task_self(), port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+            }
+            let (task_port, sender) = match responded {
+                Ok(responded) => responded,
+                Err(e) => {
+                    child.abandon();
+                    return Err(e);
+                }
+            };
+            if sender != child.id() {
+                child.abandon();
+                return Err(SpawnTaskPortError::AuditMismatch {
+                        expected: child.id(),
+                        actual: sender,
+                    }
+                    .into());
+            }
+            Ok((child, unsafe { TaskPort::from_raw(task_port.into_raw()) }))
+        })
+    }
+}
@@ -0,0 +1,758 @@
+//! A long-lived broker for spawning many children against one port.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::ops::RangeInclusive;
+use std::os::raw::c_int;
+use std::process::{Child, Command};
+use std::os::unix::process::CommandExt;
+use std::sync::{Mutex, TryLockError};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use mach::message::{MACH_RCV_TOO_LARGE, mach_msg_destroy};
+use mach::port::MACH_PORT_RIGHT_RECEIVE;
+use mach::traps::mach_task_self;
+
+use daemon;
+use descendant;
+use diagnostics;
+use exec_check_in;
+use middleware;
+use registration::create_service;
+use {ChildCheckIn, DAEMON_MSG_ID, DESCENDANT_MSG_ID, EXEC_MSG_ID, MachPort, Reaper, RecvMessage,
+     ServiceName, SpawnTaskPortError, SpawnedProcess, TASK_PORT_MSG_ID, TaskPort,
+     mach_port_mod_refs, pre_exec_hook, receive_task_port_timeout};
+
+/// What a check-in is matched to its spawn by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum CheckInKey {
+    /// The pid of a child that checked in itself.
+    Pid(c_int),
+    /// The token that a daemon was spawned with.
+    Daemon(u32),
+    /// The pid of the parent of a descendant that checked in unprompted,
+    /// which no spawn waits for.
+    Descendant(u32),
+    /// The pid of a process that checked in again after executing a new
+    /// image, which no spawn waits for either.
+    Exec(c_int),
+}
+
+impl CheckInKey {
+    /// The `msgh_id`s that a check-in for this kind of key carries.
+    fn msg_ids(&self) -> RangeInclusive<c_int> {
+        match *self {
+            CheckInKey::Pid(_) => TASK_PORT_MSG_ID..=TASK_PORT_MSG_ID,
+            CheckInKey::Daemon(_) => DAEMON_MSG_ID..=DAEMON_MSG_ID,
+            CheckInKey::Descendant(_) => DESCENDANT_MSG_ID..=DESCENDANT_MSG_ID,
+            CheckInKey::Exec(_) => EXEC_MSG_ID..=EXEC_MSG_ID,
+        }
+    }
+
+    /// The key of a received check-in, along with the pid of the process
+    /// that sent it, or `None` if it isn't shaped like a check-in, no kind
+    /// of waiter expects its `msgh_id`, or it came from some other process
+    /// than it claims.
+    fn route(msg: &RecvMessage) -> Option<(CheckInKey, c_int)> {
+        if !msg.is_check_in() {
+            return None;
+        }
+        let sender = verified_sender(msg)?;
+        [CheckInKey::Pid(sender),
+         CheckInKey::Daemon(msg.token),
+         CheckInKey::Descendant(msg.token),
+         CheckInKey::Exec(sender)]
+            .iter()
+            .cloned()
+            .find(|key| key.msg_ids().contains(&msg.header.msgh_id))
+            .map(|key| (key, sender))
+    }
+}
+
+/// The pid of the process that sent `msg`, or `None` if the kernel's audit
+/// trailer says it wasn't the process the message claims. Anyone who knows
+/// the service name can send to it, so the pid in the message alone can't
+/// be trusted where the kernel gives a better answer.
+fn verified_sender(msg: &RecvMessage) -> Option<c_int> {
+    match msg.audit_pid() {
+        Some(pid) if pid as c_int != msg.pid => None,
+        _ => Some(msg.pid),
+    }
+}
+
+/// Something `MachPortBroker::poll` reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum BrokerEvent {
+    /// The task port of the process with this pid was remembered without a
+    /// spawn waiting for it: it is a descendant that checked in with
+    /// `descendant::check_in`, or a new image a child executed.
+    Registered(u32),
+    /// The process with this pid exited, and its task port was forgotten.
+    Invalidated(u32),
+}
+
+/// How long a spawn waits for its check-in before checking that it can
+/// still arrive.
+const CHECK_IN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The fewest task ports that are worth checking for exited children.
+const MIN_PRUNE_AT: usize = 64;
+
+/// A task port the broker remembers.
+struct Remembered {
+    task_port: TaskPort,
+    /// The pids of the process' parent, its parent's parent and so on, as
+    /// far as the broker knows them. They stay known after the processes
+    /// themselves have exited.
+    ancestors: Vec<u32>,
+}
+
+/// The task ports the broker remembers.
+struct TaskPorts {
+    by_pid: HashMap<u32, Remembered>,
+    /// How many to let pile up before dropping those of exited children,
+    /// so that spawning many short-lived children doesn't leak, and doesn't
+    /// cost a check of every child on each spawn either.
+    prune_at: usize,
+    /// What happened since the last `poll`, once there has been one.
+    events: Option<Vec<BrokerEvent>>,
+}
+
+impl TaskPorts {
+    /// Remember `task_port` as the task port of the process `pid`, whose
+    /// parent is `parent` if it isn't the broker's own process.
+    fn insert(&mut self, pid: u32, parent: Option<u32>, task_port: TaskPort) {
+        if self.by_pid.len() >= self.prune_at {
+            self.forget_exited();
+            self.prune_at = MIN_PRUNE_AT.max(self.by_pid.len() * 2);
+        }
+        let ancestors = match parent {
+            Some(parent) => {
+                let mut ancestors = vec![parent];
+                if let Some(remembered) = self.by_pid.get(&parent) {
+                    ancestors.extend_from_slice(&remembered.ancestors);
+                }
+                ancestors
+            }
+            None => Vec::new(),
+        };
+        self.by_pid.insert(pid,
+                           Remembered {
+                               task_port: task_port,
+                               ancestors: ancestors,
+                           });
+    }
+
+    /// Replace the task port of the process `pid` with that of the image
+    /// it has executed since, keeping its place in the process tree.
+    fn replace(&mut self, pid: u32, task_port: TaskPort) {
+        match self.by_pid.get_mut(&pid) {
+            Some(remembered) => remembered.task_port = task_port,
+            None => self.insert(pid, None, task_port),
+        }
+    }
+
+    /// The task port of the child `pid`, unless it has exited, in which
+    /// case it is forgotten. A task port outlives its process, so its pid
+    /// may already belong to someone else.
+    fn get(&mut self, pid: u32) -> Option<&TaskPort> {
+        let exited = match self.by_pid.get(&pid) {
+            Some(remembered) => remembered.task_port.pid().ok() != Some(pid),
+            None => return None,
+        };
+        if exited {
+            self.by_pid.remove(&pid);
+            self.record(BrokerEvent::Invalidated(pid));
+            return None;
+        }
+        self.by_pid.get(&pid).map(|remembered| &remembered.task_port)
+    }
+
+    /// Forget the task ports of the children that have exited.
+    fn forget_exited(&mut self) {
+        let mut exited = self.by_pid
+            .iter()
+            .filter(|&(&child, known)| known.task_port.pid().ok() != Some(child))
+            .map(|(&child, _)| child)
+            .collect::<Vec<_>>();
+        exited.sort();
+        for child in exited {
+            self.by_pid.remove(&child);
+            self.record(BrokerEvent::Invalidated(child));
+        }
+    }
+
+    /// Keep `event` for the next `poll`, if anyone polls.
+    fn record(&mut self, event: BrokerEvent) {
+        if let Some(ref mut events) = self.events {
+            events.push(event);
+        }
+    }
+
+    /// The pids of the remembered descendants of `pid` that haven't
+    /// exited, in order.
+    fn descendants(&mut self, pid: u32) -> Vec<u32> {
+        let mut pids = self.by_pid
+            .iter()
+            .filter(|&(_, remembered)| remembered.ancestors.contains(&pid))
+            .map(|(&child, _)| child)
+            .collect::<Vec<_>>();
+        pids.retain(|&child| self.get(child).is_some());
+        pids.sort();
+        pids
+    }
+}
+
+/// The parts of the broker that are only touched while receiving.
+struct BrokerState {
+    /// The receive buffer, reused for every check-in.
+    msg: RecvMessage,
+    /// Task ports that arrived while waiting for a different child, along
+    /// with the pid of the process that sent them.
+    pending: HashMap<CheckInKey, (c_int, MachPort)>,
+    /// The number of messages that no waiter expected.
+    rejected: usize,
+    /// How many times `force_release_all` has run, so that spawns waiting
+    /// at the time can tell that their check-in may be gone.
+    releases: u64,
+}
+
+/// A broker that owns a single receive right registered with the bootstrap
+/// server once, for spawning many short-lived children, and remembers the
+/// task port of each child that checks in.
+///
+/// `CommandSpawnWithTask::spawn_with_task_port` allocates a port and
+/// registers a new bootstrap service for every child it spawns. When
+/// spawning children at a high rate (in a fuzzing harness, for example),
+/// use a `MachPortBroker` instead: the service is registered once when the
+/// broker is created, and each spawn only costs the child's look-up and
+/// the check-in message itself.
+///
+/// A broker can be shared between threads. Check-ins are matched to the
+/// child that sent them by pid, as the kernel's audit trailer reports it,
+/// so concurrent spawns through the same broker each get the right task
+/// port, and a process that learns the service name can't check in on
+/// another's behalf.
+///
+/// The broker keeps a send right to every child's task port until the child
+/// has exited, so code that only learns a child's pid later can get its
+/// task port back with `task_port_for_pid`, as Chromium's broker allows.
+///
+/// A broker that only needs its children's task ports can hand their
+/// `Child` handles to its `Reaper` with `reap_on_exit`, or spawn them with
+/// `spawn_reaped` in the first place, so that they don't pile up as
+/// zombies.
+///
+/// Programs that daemonize by double-forking can be spawned with
+/// `spawn_daemon`, as long as the daemon calls `daemon::check_in`.
+///
+/// Children spawned with `spawn_with_descendants` pass the broker's service
+/// name on to their own children, and any descendant that calls
+/// `descendant::check_in` has its task port remembered along with its
+/// place in the process tree, for `descendants` to list.
+///
+/// Children spawned with `spawn_following_execs` check in again each time
+/// they execute a new image, if they call `exec_check_in::check_in` or
+/// have a library that does inserted, and `task_port_for_pid` then returns
+/// the new image's task port.
+///
+/// Every kind of check-in the broker waits for expects its own range of
+/// `msgh_id`s, and messages outside all of them, or not laid out like a
+/// check-in, or too large to be one, are destroyed rather than being
+/// mistaken for a check-in, so other traffic sent to the broker's service
+/// can't derail a handshake. The kernel's own receive-side message
+/// filtering is reserved for sandbox policies, so this happens after the
+/// message has been received; `rejected` counts them.
+///
+/// Sandboxed apps can only look up the services their entitlements list,
+/// such as those named after an app group, so a random name is out of
+/// reach of their children. `with_service_name` registers a fixed name
+/// instead, once, for every child the broker spawns.
+///
+/// Programs built around a frame loop, such as game engines, can call
+/// `poll` once a frame instead of running a thread to find out about
+/// descendants checking in and children exiting.
+pub struct MachPortBroker {
+    port: MachPort,
+    check_in: ChildCheckIn,
+    state: Mutex<BrokerState>,
+    /// The token for the next `spawn_daemon`.
+    next_token: AtomicU32,
+    /// The task ports of children that have checked in, by pid.
+    task_ports: Mutex<TaskPorts>,
+    /// Started by the first child handed over to it.
+    reaper: Mutex<Option<Reaper>>,
+}
+
+impl MachPortBroker {
+    /// Create a broker, allocating its port and registering it with the
+    /// bootstrap server.
+    pub fn new() -> Result<MachPortBroker> {
+        MachPortBroker::with_name(ServiceName::random()?)
+    }
+
+    /// Create a broker registered as `name` rather than a random name, for
+    /// children that may only look up names they are entitled to.
+    ///
+    /// The name stays registered for as long as the broker lives, so only
+    /// one broker at a time can use it. Names longer than 127 bytes fail
+    /// with `ErrorKind::InvalidInput`.
+    pub fn with_service_name(name: &str) -> Result<MachPortBroker> {
+        let name = ServiceName::new(name).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput,
+                           format!("invalid bootstrap service name {:?}", name))
+            })?;
+        MachPortBroker::with_name(name)
+    }
+
+    fn with_name(name: ServiceName) -> Result<MachPortBroker> {
+        let port = create_service(name.as_c_str())?;
+        Ok(MachPortBroker {
+            port: port,
+            check_in: ChildCheckIn::new(name),
+            state: Mutex::new(BrokerState {
+                msg: unsafe { mem::zeroed() },
+                pending: HashMap::new(),
+                rejected: 0,
+                releases: 0,
+            }),
+            next_token: AtomicU32::new(1),
+            task_ports: Mutex::new(TaskPorts {
+                by_pid: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+                events: None,
+            }),
+            reaper: Mutex::new(None),
+        })
+    }
+
+    /// Executes `command` as a child process, returning both the `Child`
+    /// as well as a `TaskPort` that owns the process' Mach task port.
+    pub fn spawn(&self, command: &mut Command) -> Result<(Child, TaskPort)> {
+        self.spawn_using(command, |command| command.spawn())
+    }
+
+    /// Like `spawn`, but `spawn` is given `command` with the child's
+    /// `pre_exec` hook already installed, and should spawn it in whatever
+    /// way the caller needs.
+    pub(crate) fn spawn_using<T, F>(&self,
+                                    command: &mut Command,
+                                    spawn: F)
+                                    -> Result<(T, TaskPort)>
+        where T: SpawnedProcess,
+              F: FnOnce(&mut Command) -> Result<T>
+    {
+        diagnostics::record_handshake(|| {
+            let mut context = middleware::pre_register(command)?;
+            middleware::post_register(&mut context, self.service_name().as_str())?;
+            let mut child = spawn(unsafe { command.pre_exec(pre_exec_hook(self.check_in)) })
+                .map_err(SpawnTaskPortError::from_spawn)?;
+            context.set_pid(child.pid());
+            let exited = || Error::new(ErrorKind::Other, "the child exited before checking in");
+            let received = self.receive_for_pid(child.pid() as c_int, || {
+                if child.has_exited()? {
+                    return Err(exited());
+                }
+                Ok(())
+            });
+            let task_port = match received.and_then(|task_port| {
+                middleware::post_receive(&context, &task_port)?;
+                Ok(task_port)
+            }) {
+                Ok(task_port) => task_port,
+                Err(e) => {
+                    self.forget_pid(child.pid());
+                    child.abandon();
+                    return Err(e);
+                }
+            };
+            Ok((child, task_port))
+        })
+    }
+
+    /// Like `spawn`, but hand the `Child` to the broker to be reaped when
+    /// it exits, returning only its pid and task port.
+    pub fn spawn_reaped(&self, command: &mut Command) -> Result<(u32, TaskPort)> {
+        let (child, task_port) = self.spawn(command)?;
+        let pid = child.id();
+        self.reap_on_exit(child)?;
+        Ok((pid, task_port))
+    }
+
+    /// Executes `command`, which must daemonize and then call
+    /// `daemon::check_in`, returning the daemon's pid and task port.
+    ///
+    /// The process that `command` starts doesn't check in itself, and is
+    /// handed to the broker to be reaped. This blocks until the daemon has
+    /// checked in, so make sure the program does.
+    pub fn spawn_daemon(&self, command: &mut Command) -> Result<(u32, TaskPort)> {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let child = command.env(daemon::SERVICE_NAME_VAR, self.service_name().as_str())
+            .env(daemon::TOKEN_VAR, token.to_string())
+            .spawn()?;
+        self.reap_on_exit(child)?;
+        // There's no telling whether a daemon we don't know the pid of is
+        // still coming.
+        let (pid, task_port) = self.receive_matching(CheckInKey::Daemon(token), || Ok(()))?;
+        Ok((pid as u32, task_port))
+    }
+
+    /// Like `spawn`, but let the child's descendants check in with the
+    /// broker too, by calling `descendant::check_in`.
+    pub fn spawn_with_descendants(&self, command: &mut Command) -> Result<(Child, TaskPort)> {
+        self.spawn(command.env(descendant::SERVICE_NAME_VAR, self.service_name().as_str()))
+    }
+
+    /// Like `spawn`, but keep `task_port_for_pid` up to date with the task
+    /// port of whatever image the child is running, by letting each image
+    /// it executes check in with `exec_check_in::check_in`.
+    ///
+    /// The task port this returns is that of the image `command` starts,
+    /// which stops working once that image executes another, so get the
+    /// current one from `task_port_for_pid` when it is needed.
+    ///
+    /// The service name is passed on to every image and every descendant,
+    /// but a check-in only replaces the task port of the process the
+    /// kernel says sent it.
+    pub fn spawn_following_execs(&self, command: &mut Command) -> Result<(Child, TaskPort)> {
+        self.spawn(command.env(exec_check_in::SERVICE_NAME_VAR, self.service_name().as_str()))
+    }
+
+    /// Give up `child`, to be reaped by the broker when it exits.
+    pub fn reap_on_exit(&self, child: Child) -> Result<()> {
+        let mut reaper = self.reaper.lock().unwrap_or_else(|e| e.into_inner());
+        if reaper.is_none() {
+            *reaper = Some(Reaper::new()?);
+        }
+        reaper.as_ref().unwrap().adopt(child)
+    }
+
+    /// What a child needs to check in with this broker.
+    #[cfg(feature = "duct")]
+    pub(crate) fn check_in(&self) -> ChildCheckIn {
+        self.check_in
+    }
+
+    /// The bootstrap service name that children check in with.
+    pub(crate) fn service_name(&self) -> &ServiceName {
+        &self.check_in.name
+    }
+
+    /// Block until the process `pid` checks in, returning its task port.
+    /// `still_coming` is called whenever nothing has arrived for a while,
+    /// and gives up by failing.
+    pub(crate) fn receive_for_pid<F>(&self, pid: c_int, still_coming: F) -> Result<TaskPort>
+        where F: FnMut() -> Result<()>
+    {
+        self.receive_matching(CheckInKey::Pid(pid), still_coming).map(|(_, task_port)| task_port)
+    }
+
+    /// Block until the check-in matching `key` arrives, returning the
+    /// sender's pid and task port, which is also remembered for
+    /// `task_port_for_pid`.
+    ///
+    /// Every `CHECK_IN_INTERVAL` without it, this lets other threads have a
+    /// turn at receiving, and calls `still_coming`, which fails if the
+    /// check-in never will arrive. It also fails if `force_release_all`
+    /// runs meanwhile.
+    fn receive_matching<F>(&self,
+                           key: CheckInKey,
+                           mut still_coming: F)
+                           -> Result<(c_int, TaskPort)>
+        where F: FnMut() -> Result<()>
+    {
+        let mut releases = None;
+        loop {
+            {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                if *releases.get_or_insert(state.releases) != state.releases {
+                    return Err(Error::new(ErrorKind::Other,
+                                          "the broker released its task ports during a spawn"));
+                }
+                // Another thread may already have received this check-in.
+                if let Some((pid, task_port)) = state.pending.remove(&key) {
+                    let task_port = TaskPort::from_port(task_port);
+                    // Remember it before letting go of `state`, so that a
+                    // check-in from an image the child has executed since
+                    // can't be received in between, only to be overwritten
+                    // by this one.
+                    self.task_ports
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(pid as u32, None, task_port.try_clone()?);
+                    return Ok((pid, task_port));
+                }
+                match self.receive_one(&mut state, Some(CHECK_IN_INTERVAL)) {
+                    Ok(()) => continue,
+                    Err(ref e) if is_timeout(e) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            still_coming()?;
+        }
+    }
+
+    /// Receive one message, waiting at most `timeout` if given. Check-ins
+    /// from descendants and after an `exec` are remembered straight away,
+    /// and others are left in `pending` for the thread that spawned their
+    /// child.
+    fn receive_one(&self, state: &mut BrokerState, timeout: Option<Duration>) -> Result<()> {
+        let task_port = match unsafe {
+            receive_task_port_timeout(self.port.0, &mut state.msg, timeout)
+        } {
+            Ok(task_port) => task_port,
+            // The kernel has already destroyed a message too large for the
+            // buffer, and it was no check-in anyway.
+            Err(ref e) if is_too_large(e) => {
+                state.rejected += 1;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let (sender_key, sender) = match CheckInKey::route(&state.msg) {
+            Some(routed) => routed,
+            None => {
+                // The message needn't be shaped like a check-in, so let the
+                // kernel's description of it say what to release.
+                unsafe { mach_msg_destroy(&mut state.msg.header) };
+                state.rejected += 1;
+                return Ok(());
+            }
+        };
+        // `route` got the pid from the message and the audit trailer rather
+        // than `pid_for_task`, which fails if the child has already exited.
+        let task_port = MachPort(task_port);
+        match sender_key {
+            CheckInKey::Descendant(parent) => {
+                let mut task_ports = self.task_ports.lock().unwrap_or_else(|e| e.into_inner());
+                task_ports.insert(sender as u32, Some(parent), TaskPort::from_port(task_port));
+                task_ports.record(BrokerEvent::Registered(sender as u32));
+            }
+            CheckInKey::Exec(sender) => {
+                // Every image the child executes can check in, so only let
+                // one replace its own task port.
+                let mut task_ports = self.task_ports.lock().unwrap_or_else(|e| e.into_inner());
+                task_ports.replace(sender as u32, TaskPort::from_port(task_port));
+                task_ports.record(BrokerEvent::Registered(sender as u32));
+            }
+            _ => {
+                state.pending.insert(sender_key, (sender, task_port));
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive whatever check-ins are queued, waiting up to `timeout` for
+    /// the first, unless another thread is receiving, in which case it
+    /// will.
+    fn receive_available(&self, timeout: Duration) -> Result<()> {
+        let mut state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(()),
+        };
+        let mut timeout = timeout;
+        loop {
+            match self.receive_one(&mut state, Some(timeout)) {
+                Ok(()) => timeout = Duration::from_millis(0),
+                Err(ref e) if is_timeout(e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Receive whatever check-ins are already queued, unless another thread
+    /// is receiving, in which case it will.
+    fn receive_queued(&self) -> Result<()> {
+        self.receive_available(Duration::from_millis(0))
+    }
+
+    /// Receive whatever has arrived, waiting up to `timeout` for the first
+    /// message, check which children have exited, and return what happened
+    /// since the last call, without blocking for longer than that.
+    ///
+    /// Check-ins for spawns in progress are left for them. If another
+    /// thread is already waiting for a check-in, this doesn't wait, and
+    /// reports what that thread has received instead. Nothing is recorded
+    /// for `poll` until it is first called, so the first call only reports
+    /// what arrives during it, and which children turn out to have exited.
+    pub fn poll(&self, timeout: Duration) -> Result<Vec<BrokerEvent>> {
+        self.task_ports
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .events
+            .get_or_insert_with(Vec::new);
+        self.receive_available(timeout)?;
+        let mut task_ports = self.task_ports.lock().unwrap_or_else(|e| e.into_inner());
+        task_ports.forget_exited();
+        Ok(task_ports.events.as_mut().map_or_else(Vec::new, mem::take))
+    }
+
+    /// The number of task ports that have been received but not yet handed
+    /// to the thread that spawned their child.
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).pending.len()
+    }
+
+    /// Get another send right to the task port of the child or descendant
+    /// `pid`, if it checked in with this broker and hasn't exited since.
+    pub fn task_port_for_pid(&self, pid: u32) -> Result<Option<TaskPort>> {
+        self.receive_queued()?;
+        let mut task_ports = self.task_ports.lock().unwrap_or_else(|e| e.into_inner());
+        match task_ports.get(pid) {
+            Some(task_port) => task_port.try_clone().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Forget the task port of the child `pid`, returning the broker's send
+    /// right to it, if it had one.
+    pub fn forget_pid(&self, pid: u32) -> Option<TaskPort> {
+        self.task_ports
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .by_pid
+            .remove(&pid)
+            .map(|remembered| remembered.task_port)
+    }
+
+    /// The pids and task ports of the descendants of `pid` that have
+    /// checked in with `descendant::check_in` and haven't exited, ordered
+    /// by pid. Descendants whose parents have already exited are still
+    /// listed, as long as their parents checked in.
+    pub fn descendants(&self, pid: u32) -> Result<Vec<(u32, TaskPort)>> {
+        self.receive_queued()?;
+        let mut task_ports = self.task_ports.lock().unwrap_or_else(|e| e.into_inner());
+        let mut descendants = Vec::new();
+        for child in task_ports.descendants(pid) {
+            let task_port = task_ports.by_pid[&child].task_port.try_clone()?;
+            descendants.push((child, task_port));
+        }
+        Ok(descendants)
+    }
+
+    /// The number of messages received that weren't a check-in any waiter
+    /// expected, and were destroyed.
+    pub fn rejected(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).rejected
+    }
+
+    /// The number of task ports the broker is holding on to, whether their
+    /// children have exited or not. Exited children's are only dropped
+    /// once enough have piled up, so this can run ahead of the number of
+    /// live children, but it shouldn't keep growing if that doesn't.
+    pub fn remembered(&self) -> usize {
+        self.task_ports.lock().unwrap_or_else(|e| e.into_inner()).by_pid.len()
+    }
+
+    /// Deallocate every send right the broker holds, both the task ports it
+    /// remembers and those still pending, returning how many there were.
+    ///
+    /// This is for shutdown paths that want the port namespace back before
+    /// the broker itself is dropped. Spawns that are waiting for their
+    /// child's check-in when this is called fail, since it may have been
+    /// released. Task ports handed out earlier are
+    /// separate rights, and stay valid.
+    pub fn force_release_all(&self) -> usize {
+        let pending = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.releases += 1;
+            state.pending.drain().count()
+        };
+        let remembered = self.task_ports
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .by_pid
+            .drain()
+            .count();
+        pending + remembered
+    }
+}
+
+impl Drop for MachPortBroker {
+    fn drop(&mut self) {
+        // Destroy the receive right, which also makes the bootstrap server
+        // drop our registration. `self.port` then deallocates the send
+        // right. Ignore failures, there's not much that can be done here.
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
+
+fn is_too_large(error: &Error) -> bool {
+    SpawnTaskPortError::from_io(error).and_then(SpawnTaskPortError::kern_return) ==
+    Some(MACH_RCV_TOO_LARGE)
+}
+
+fn is_timeout(error: &Error) -> bool {
+    matches!(SpawnTaskPortError::from_io(error), Some(&SpawnTaskPortError::ReceiveTimeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mach::message::{MACH_MSG_PORT_DESCRIPTOR, MACH_MSGH_BITS_COMPLEX};
+    use trailer::RawTrailer;
+    use SendMessage;
+
+    /// An empty buffer with a check-in's layout.
+    fn check_in_msg() -> RecvMessage {
+        let mut msg: RecvMessage = unsafe { mem::zeroed() };
+        msg.header.msgh_bits = MACH_MSGH_BITS_COMPLEX;
+        msg.header.msgh_size = mem::size_of::<SendMessage>() as u32;
+        msg.body.msgh_descriptor_count = 1;
+        msg.task_port.type_ = MACH_MSG_PORT_DESCRIPTOR as u8;
+        msg
+    }
+
+    #[test]
+    fn routes_check_ins_by_msg_id() {
+        let mut msg = check_in_msg();
+        msg.pid = 42;
+        msg.token = 7;
+        msg.header.msgh_id = TASK_PORT_MSG_ID;
+        assert_eq!(CheckInKey::route(&msg), Some((CheckInKey::Pid(42), 42)));
+        msg.header.msgh_id = DAEMON_MSG_ID;
+        assert_eq!(CheckInKey::route(&msg), Some((CheckInKey::Daemon(7), 42)));
+        msg.header.msgh_id = DESCENDANT_MSG_ID;
+        assert_eq!(CheckInKey::route(&msg), Some((CheckInKey::Descendant(7), 42)));
+        msg.header.msgh_id = EXEC_MSG_ID;
+        assert_eq!(CheckInKey::route(&msg), Some((CheckInKey::Exec(42), 42)));
+        msg.header.msgh_id = 0x5354_4842;
+        assert_eq!(CheckInKey::route(&msg), None);
+    }
+
+    #[test]
+    fn rejects_messages_not_shaped_like_check_ins() {
+        let mut msg = check_in_msg();
+        assert_eq!(CheckInKey::route(&msg), Some((CheckInKey::Pid(0), 0)));
+        msg.header.msgh_bits = 0;
+        assert_eq!(CheckInKey::route(&msg), None);
+        let mut msg = check_in_msg();
+        msg.body.msgh_descriptor_count = 2;
+        assert_eq!(CheckInKey::route(&msg), None);
+        let mut msg = check_in_msg();
+        msg.header.msgh_size -= 4;
+        assert_eq!(CheckInKey::route(&msg), None);
+    }
+
+    #[test]
+    fn rejects_check_ins_for_other_pids() {
+        let mut msg = check_in_msg();
+        msg.pid = 42;
+        msg.header.msgh_id = TASK_PORT_MSG_ID;
+        msg.trailer = RawTrailer::with_audit_pid(42);
+        assert_eq!(CheckInKey::route(&msg), Some((CheckInKey::Pid(42), 42)));
+        msg.trailer = RawTrailer::with_audit_pid(43);
+        assert_eq!(CheckInKey::route(&msg), None);
+        // Nor can another image replace a child's task port after an exec.
+        msg.header.msgh_id = EXEC_MSG_ID;
+        assert_eq!(CheckInKey::route(&msg), None);
+        msg.trailer = RawTrailer::with_audit_pid(42);
+        assert_eq!(CheckInKey::route(&msg), Some((CheckInKey::Exec(42), 42)));
+    }
+}
@@ -0,0 +1,374 @@
+//! Runtime detection of the Mach features this crate can use.
+//!
+//! What is available depends both on the macOS version and on how the
+//! current process is signed and sandboxed, so rather than assuming, each
+//! capability is probed by actually trying it where that is cheap.
+//!
+//! The rest of the crate also checks the running OS version here, rather
+//! than at compile time, wherever the Mach interfaces it uses changed
+//! between releases:
+//!
+//! | Since  | Change                                             |
+//! |--------|----------------------------------------------------|
+//! | 10.5   | Audit trailers                                     |
+//! | 10.9   | Oldest supported release                           |
+//! | 10.11  | `TASK_VM_INFO` gains `phys_footprint`              |
+//! | 10.12  | `getentropy`; older releases read `/dev/urandom`   |
+//! | 10.13.4| `kern.osproductversion`; older use `kern.osrelease`|
+//! | 11.0   | Task read and inspect ports, identity tokens       |
+//! | 13.0   | `mach_msg2`                                        |
+//!
+//! `MACH_TASK_BASIC_INFO` and `bootstrap_register2` are available, and
+//! behave the same, throughout the supported range.
+
+use std::fmt;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use mach::kern_return::KERN_SUCCESS;
+use mach::message::{MACH_MSG_TIMEOUT_NONE, MACH_MSGH_BITS, MACH_MSG_TYPE_MAKE_SEND, MACH_RCV_MSG,
+                    MACH_SEND_MSG, mach_msg, mach_msg_header_t};
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::task::task_get_special_port;
+use mach::traps::mach_task_self;
+
+use identity;
+use privileged;
+use registration::check_in_service;
+use trailer::RawTrailer;
+use {HostExceptionMonitor, MachPort, ServiceName, TrailerType, allocate_server_port,
+     mach_port_mod_refs, register_service};
+
+/// `TASK_INSPECT_PORT` and `TASK_READ_PORT` from `<mach/task_special_ports.h>`,
+/// which only exist on macOS 11 and later.
+const TASK_INSPECT_PORT: c_int = 5;
+const TASK_READ_PORT: c_int = 6;
+
+/// Code signing status flags from `<kern/cs_blobs.h>`.
+pub(crate) const CS_OPS_STATUS: c_uint = 0;
+pub(crate) const CS_OPS_ENTITLEMENTS_BLOB: c_uint = 7;
+const CS_GET_TASK_ALLOW: u32 = 0x0000_0004;
+pub(crate) const CS_RUNTIME: u32 = 0x0001_0000;
+
+extern "C" {
+    fn sysctlbyname(name: *const c_char,
+                    oldp: *mut c_void,
+                    oldlenp: *mut usize,
+                    newp: *mut c_void,
+                    newlen: usize)
+                    -> c_int;
+    pub(crate) fn csops(pid: c_int, ops: c_uint, useraddr: *mut c_void, usersize: usize) -> c_int;
+}
+
+/// The oldest macOS release this crate supports.
+///
+/// Nothing in CI runs this old a release, so before changing anything that
+/// is gated on the OS version, smoke test it by hand on a 10.9 machine or
+/// VM: `cargo test` there runs `test_os_version_gating`, which exercises
+/// the fallbacks listed in the module documentation, along with the rest
+/// of the handshake tests.
+pub const OLDEST_SUPPORTED: OsVersion = OsVersion { major: 10, minor: 9, patch: 0 };
+
+/// The first release with audit trailers, which predates every supported
+/// release but documents where the requirement comes from.
+pub(crate) const AUDIT_TRAILERS: OsVersion = OsVersion { major: 10, minor: 5, patch: 0 };
+
+/// The first release whose `TASK_VM_INFO` includes `phys_footprint`.
+pub(crate) const TASK_VM_INFO_REV1: OsVersion = OsVersion { major: 10, minor: 11, patch: 0 };
+
+/// A macOS version number, such as 10.15.7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl OsVersion {
+    pub fn new(major: u32, minor: u32, patch: u32) -> OsVersion {
+        OsVersion { major: major, minor: minor, patch: patch }
+    }
+
+    /// The version of macOS this process is running on, or `None` if it
+    /// can't be determined.
+    pub fn current() -> Option<OsVersion> {
+        sysctl_string(b"kern.osproductversion\0")
+            .and_then(|v| OsVersion::parse(&v))
+            .or_else(|| {
+                sysctl_string(b"kern.osrelease\0").and_then(|v| OsVersion::from_darwin(&v))
+            })
+    }
+
+    /// Map a Darwin kernel release such as `"15.6.0"` to the macOS
+    /// version it shipped with. Only the release itself can be recovered
+    /// this way, not its point updates. This is only needed before
+    /// 10.13.4, which added `kern.osproductversion`.
+    fn from_darwin(s: &str) -> Option<OsVersion> {
+        let darwin = OsVersion::parse(s)?;
+        match darwin.major {
+            // Darwin 5 through 19 are Mac OS X 10.1 through 10.15.
+            5..=19 => Some(OsVersion::new(10, darwin.major - 4, 0)),
+            // Darwin 20 is macOS 11, and so on.
+            major if major >= 20 => Some(OsVersion::new(major - 9, 0, 0)),
+            _ => None,
+        }
+    }
+
+    /// Parse a version such as `"14.2.1"` or `"10.15"`.
+    fn parse(s: &str) -> Option<OsVersion> {
+        let mut parts = s.trim().split('.').map(|p| p.parse::<u32>());
+        let major = match parts.next() {
+            Some(Ok(major)) => major,
+            _ => return None,
+        };
+        let mut next = || match parts.next() {
+            None => Some(0),
+            Some(Ok(n)) => Some(n),
+            Some(Err(_)) => None,
+        };
+        let minor = next()?;
+        let patch = next()?;
+        Some(OsVersion::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for OsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// The running OS version packed by `pack`, 0 if it hasn't been looked up
+/// yet, or `u32::MAX` if it couldn't be determined.
+static RUNNING_VERSION: AtomicU32 = AtomicU32::new(0);
+
+fn pack(v: OsVersion) -> u32 {
+    (v.major.min(0xffff) << 16) | (v.minor.min(0xff) << 8) | v.patch.min(0xff)
+}
+
+/// The running OS version, looked up once and cached.
+pub(crate) fn running_version() -> Option<OsVersion> {
+    match RUNNING_VERSION.load(Ordering::Relaxed) {
+        0 => {
+            let version = OsVersion::current();
+            RUNNING_VERSION.store(version.map_or(u32::MAX, pack), Ordering::Relaxed);
+            version
+        }
+        u32::MAX => None,
+        packed => Some(OsVersion::new(packed >> 16, (packed >> 8) & 0xff, packed & 0xff)),
+    }
+}
+
+/// Whether the running OS is at least `version`. If the version can't be
+/// determined, assume a recent release.
+pub(crate) fn running_at_least(version: OsVersion) -> bool {
+    match running_version() {
+        Some(running) => running >= version,
+        None => true,
+    }
+}
+
+/// Read a string-valued sysctl. `name` must be NUL-terminated.
+fn sysctl_string(name: &[u8]) -> Option<String> {
+    let mut buf = [0u8; 64];
+    let mut len = buf.len();
+    let ret = unsafe {
+        sysctlbyname(name.as_ptr() as *const c_char,
+                     buf.as_mut_ptr() as *mut c_void,
+                     &mut len,
+                     ptr::null_mut(),
+                     0)
+    };
+    if ret != 0 {
+        return None;
+    }
+    let value = &buf[..len];
+    let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+    Some(String::from_utf8_lossy(&value[..end]).into_owned())
+}
+
+/// What this crate can do on the current system, as the current process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Capabilities {
+    /// The running macOS version, if it could be determined.
+    pub os_version: Option<OsVersion>,
+    /// Whether `bootstrap_register2` exists and this process is allowed to
+    /// register services with it. Spawning with a task port needs this.
+    pub bootstrap_register2: bool,
+    /// Whether this process may check in a new service with
+    /// `bootstrap_check_in`, which `RegistrationMethod::CheckIn` needs.
+    pub bootstrap_check_in: bool,
+    /// Whether the kernel hands out audit trailers, which identify the
+    /// sender of a message.
+    pub audit_trailers: bool,
+    /// Whether the `mach_msg2` interface, added in macOS 13, is available.
+    pub mach_msg2: bool,
+    /// Whether task read ports, added in macOS 11, are available.
+    pub task_read_port: bool,
+    /// Whether task inspect ports, added in macOS 11, are available.
+    pub task_inspect_port: bool,
+    /// Whether task identity tokens, added in macOS 11, are available.
+    pub identity_tokens: bool,
+    /// Whether this process may install host-level exception handlers
+    /// with `HostExceptionMonitor`, which needs root.
+    pub host_exception_ports: bool,
+    /// Whether this process may list every task with
+    /// `processor_set_tasks`, which `task_port_for_pid` falls back to. It
+    /// needs root.
+    pub processor_set_tasks: bool,
+    /// Whether this process runs with the hardened runtime.
+    pub hardened_runtime: bool,
+    /// Whether this process is signed with `get-task-allow`, letting other
+    /// processes get its task port.
+    pub get_task_allow: bool,
+}
+
+impl Capabilities {
+    /// Probe the current system.
+    ///
+    /// This registers a throwaway bootstrap service and sends a message to
+    /// itself, so it isn't free; callers that need the result repeatedly
+    /// should keep it around.
+    pub fn detect() -> Capabilities {
+        let cs_flags = code_signing_flags();
+        Capabilities {
+            os_version: running_version(),
+            bootstrap_register2: can_register_service(),
+            bootstrap_check_in: can_check_in_service(),
+            audit_trailers: receives_audit_trailers(),
+            mach_msg2: has_symbol(b"mach_msg2_internal\0"),
+            task_read_port: has_task_special_port(TASK_READ_PORT),
+            task_inspect_port: has_task_special_port(TASK_INSPECT_PORT),
+            identity_tokens: identity::available(),
+            host_exception_ports: HostExceptionMonitor::available(),
+            processor_set_tasks: privileged::processor_set_tasks_available(),
+            hardened_runtime: cs_flags & CS_RUNTIME != 0,
+            get_task_allow: cs_flags & CS_GET_TASK_ALLOW != 0,
+        }
+    }
+}
+
+/// Whether `symbol`, which must be NUL-terminated, is exported by any
+/// loaded image.
+fn has_symbol(symbol: &[u8]) -> bool {
+    unsafe { !libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr() as *const c_char).is_null() }
+}
+
+/// Destroy the receive right `port`, which also unregisters any bootstrap
+/// service it was registered under.
+fn destroy_receive_right(port: MachPort) {
+    unsafe {
+        mach_port_mod_refs(mach_task_self(), port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+    }
+}
+
+fn can_register_service() -> bool {
+    if !has_symbol(b"bootstrap_register2\0") {
+        return false;
+    }
+    let port = match allocate_server_port() {
+        Ok(port) => port,
+        Err(_) => return false,
+    };
+    let registered = ServiceName::random()
+        .and_then(|name| register_service(name.as_c_str(), port.0))
+        .is_ok();
+    destroy_receive_right(port);
+    registered
+}
+
+fn can_check_in_service() -> bool {
+    match ServiceName::random().and_then(|name| check_in_service(name.as_c_str())) {
+        Ok(port) => {
+            destroy_receive_right(port);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Send an empty message to a fresh port of our own and check that it
+/// comes back with a full audit trailer.
+fn receives_audit_trailers() -> bool {
+    #[repr(C)]
+    struct Message {
+        header: mach_msg_header_t,
+        trailer: RawTrailer,
+    }
+
+    let port = match allocate_server_port() {
+        Ok(port) => port,
+        Err(_) => return false,
+    };
+    let received = unsafe {
+        let mut msg: Message = mem::zeroed();
+        msg.header.msgh_bits = MACH_MSGH_BITS(MACH_MSG_TYPE_MAKE_SEND, 0);
+        msg.header.msgh_size = mem::size_of::<mach_msg_header_t>() as u32;
+        msg.header.msgh_remote_port = port.0;
+        msg.header.msgh_local_port = MACH_PORT_NULL;
+        let kr = mach_msg(&mut msg.header,
+                          MACH_SEND_MSG | MACH_RCV_MSG | TrailerType::Audit.to_option(),
+                          mem::size_of::<mach_msg_header_t>() as u32,
+                          mem::size_of::<Message>() as u32,
+                          port.0,
+                          MACH_MSG_TIMEOUT_NONE,
+                          MACH_PORT_NULL);
+        kr == KERN_SUCCESS && msg.trailer.parse().audit_token().is_some()
+    };
+    destroy_receive_right(port);
+    received
+}
+
+fn has_task_special_port(which: c_int) -> bool {
+    unsafe {
+        let mut port: mach_port_t = MACH_PORT_NULL;
+        let kr = task_get_special_port(mach_task_self(), which, &mut port);
+        if kr == KERN_SUCCESS && port != MACH_PORT_NULL {
+            drop(MachPort(port));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The current process' code signing status flags, or 0 if they can't be
+/// read.
+pub(crate) fn code_signing_flags() -> u32 {
+    let mut flags: u32 = 0;
+    let ret = unsafe {
+        csops(libc::getpid(),
+              CS_OPS_STATUS,
+              &mut flags as *mut u32 as *mut c_void,
+              mem::size_of::<u32>())
+    };
+    if ret == 0 { flags } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_os_versions() {
+        assert_eq!(OsVersion::parse("14.2.1"), Some(OsVersion::new(14, 2, 1)));
+        assert_eq!(OsVersion::parse("10.15\n"), Some(OsVersion::new(10, 15, 0)));
+        assert_eq!(OsVersion::parse("11"), Some(OsVersion::new(11, 0, 0)));
+        assert_eq!(OsVersion::parse("14.x"), None);
+        assert_eq!(OsVersion::parse(""), None);
+        assert!(OsVersion::new(10, 15, 7) < OsVersion::new(11, 0, 0));
+    }
+
+    #[test]
+    fn map_darwin_releases() {
+        assert_eq!(OsVersion::from_darwin("13.4.0"), Some(OsVersion::new(10, 9, 0)));
+        assert_eq!(OsVersion::from_darwin("19.6.0"), Some(OsVersion::new(10, 15, 0)));
+        assert_eq!(OsVersion::from_darwin("20.1.0"), Some(OsVersion::new(11, 0, 0)));
+        assert_eq!(OsVersion::from_darwin("23.2.0"), Some(OsVersion::new(14, 0, 0)));
+        assert_eq!(OsVersion::from_darwin("4.0"), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Checking in from a child that wasn't spawned through this crate.
+//!
+//! `CommandSpawnWithTask` checks in from the child's `pre_exec` hook, so
+//! the program it runs needn't know about any of this. Programs that are
+//! started some other way, by a shell, a process manager or another
+//! language's spawn API, can still hand their task port to a waiting
+//! parent by calling `send_task_port` themselves, at any point while they
+//! run, with the name of a service the parent registered, for example with
+//! `LaunchdHelperReceiver::register`. How the name gets to the child is up
+//! to the parent: an argument and an environment variable both do.
+//!
+//! Children of a `MachPortBroker` find its service name in the environment
+//! instead, and `check_in` sends their task port to whichever broker left
+//! one there. The `child_entry!` macro wraps a Rust program's `main` to
+//! call it before anything else runs.
+
+use libc;
+use std::ffi::{CStr, CString};
+use std::io::{Error, ErrorKind, Result};
+use std::os::raw::c_int;
+
+use mach::bootstrap::bootstrap_look_up;
+use mach::kern_return::KERN_SUCCESS;
+use mach::message::{MACH_MSG_TIMEOUT_NONE, MACH_MSG_TYPE_COPY_SEND};
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+use mach::traps::mach_task_self;
+
+use {MachPort, TASK_PORT_MSG_ID, descendant, exec_check_in, send_check_in_timeout};
+
+/// Send this process' task port to the parent that registered
+/// `service_name` with the bootstrap server.
+///
+/// This blocks until there is room in the parent's queue.
+pub fn send_task_port(service_name: &str) -> Result<()> {
+    let name = CString::new(service_name)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "service name contains a NUL"))?;
+    send_to(&name, TASK_PORT_MSG_ID, 0)
+}
+
+/// Send this process' task port to every broker whose environment
+/// variables it inherited, as `descendant::check_in` and
+/// `exec_check_in::check_in` would, returning whether there were any.
+///
+/// Daemons are left out: `daemon::check_in` has to wait until the daemon
+/// has finished forking, so call that yourself.
+pub fn check_in() -> Result<bool> {
+    let after_exec = exec_check_in::check_in()?;
+    let as_descendant = descendant::check_in()?;
+    Ok(after_exec || as_descendant)
+}
+
+/// Wrap a program's `main` to call `child::check_in` first, exiting with
+/// an error if that fails, so that a Rust child spawned by a broker checks
+/// in without any code of its own.
+///
+/// ```rust,no_run
+/// #[macro_use]
+/// extern crate spawn_task_port;
+///
+/// child_entry! {
+///     fn main() {
+///         println!("the broker already has my task port");
+///     }
+/// }
+/// ```
+///
+/// `main` may return a `Result` as usual.
+#[macro_export]
+macro_rules! child_entry {
+    ($(#[$attr:meta])* fn main() $(-> $ret:ty)? $body:block) => {
+        $(#[$attr])*
+        fn main() $(-> $ret)? {
+            if let Err(err) = $crate::child::check_in() {
+                eprintln!("failed to send task port: {}", err);
+                ::std::process::exit(1);
+            }
+            $body
+        }
+    }
+}
+
+/// Look up the service `name` and send it this process' task port, in a
+/// check-in with `id` and `token`.
+pub(crate) fn send_to(name: &CStr, id: c_int, token: u32) -> Result<()> {
+    unsafe {
+        let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+        ktry!(task_get_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, &mut bootstrap_port));
+        let bootstrap_port = MachPort(bootstrap_port);
+        let mut parent_port: mach_port_t = MACH_PORT_NULL;
+        ktry!(bootstrap_look_up(bootstrap_port.0, name.as_ptr(), &mut parent_port));
+        let parent_port = MachPort(parent_port);
+        ktry!(send_check_in_timeout(parent_port.0,
+                                    mach_task_self(),
+                                    MACH_MSG_TYPE_COPY_SEND,
+                                    id,
+                                    libc::getpid(),
+                                    token,
+                                    MACH_MSG_TIMEOUT_NONE));
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! Coalition membership and resource usage.
+//!
+//! macOS groups related processes, such as an app and its helpers, into
+//! coalitions, and accounts CPU time, energy and I/O to the coalition as a
+//! whole. A child normally joins its parent's coalitions.
+
+use std::io::{Error, Result};
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::time::Duration;
+
+use TaskPort;
+
+/// `PROC_PIDCOALITIONINFO` from `<sys/proc_info.h>`.
+const PROC_PIDCOALITIONINFO: c_int = 20;
+
+/// `struct proc_pidcoalitioninfo`
+#[repr(C)]
+struct proc_pidcoalitioninfo {
+    coalition_id: [u64; 2],
+    reserved1: u64,
+    reserved2: u64,
+    reserved3: u64,
+}
+
+/// `struct coalition_resource_usage`, up to the fields this crate reports.
+/// The kernel copies out no more than the size it is given.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct coalition_resource_usage {
+    tasks_started: u64,
+    tasks_exited: u64,
+    time_nonempty: u64,
+    cpu_time: u64,
+    interrupt_wakeups: u64,
+    platform_idle_wakeups: u64,
+    bytesread: u64,
+    byteswritten: u64,
+    gpu_time: u64,
+    cpu_time_billed_to_me: u64,
+    cpu_time_billed_to_others: u64,
+    energy: u64,
+}
+
+#[repr(C)]
+struct mach_timebase_info {
+    numer: u32,
+    denom: u32,
+}
+
+extern "C" {
+    fn proc_pidinfo(pid: c_int,
+                    flavor: c_int,
+                    arg: u64,
+                    buffer: *mut c_void,
+                    buffersize: c_int)
+                    -> c_int;
+    fn coalition_info_resource_usage(cid: u64,
+                                     cru: *mut coalition_resource_usage,
+                                     sz: usize)
+                                     -> c_int;
+    fn mach_timebase_info(info: *mut mach_timebase_info) -> c_int;
+}
+
+/// The coalitions a process belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CoalitionIds {
+    /// The resource coalition, which usage is accounted to.
+    pub resource: u64,
+    /// The jetsam coalition, which memory pressure decisions are made for.
+    pub jetsam: u64,
+}
+
+/// Resource usage accounted to a coalition, from
+/// `coalition_info_resource_usage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CoalitionResourceUsage {
+    /// The number of tasks that have ever joined the coalition.
+    pub tasks_started: u64,
+    /// The number of those tasks that have exited.
+    pub tasks_exited: u64,
+    /// CPU time used by the coalition's tasks.
+    pub cpu_time: Duration,
+    /// GPU time used by the coalition's tasks.
+    pub gpu_time: Duration,
+    /// Interrupt wakeups caused by the coalition's tasks.
+    pub interrupt_wakeups: u64,
+    /// Wakeups from platform idle caused by the coalition's tasks.
+    pub platform_idle_wakeups: u64,
+    /// Bytes read from storage.
+    pub bytes_read: u64,
+    /// Bytes written to storage.
+    pub bytes_written: u64,
+    /// Energy used, in nanojoules.
+    pub energy: u64,
+}
+
+/// Convert Mach absolute time units to a `Duration`.
+pub(crate) fn mach_time_to_duration(t: u64) -> Duration {
+    let mut timebase = mach_timebase_info { numer: 0, denom: 0 };
+    unsafe {
+        mach_timebase_info(&mut timebase);
+    }
+    if timebase.denom == 0 {
+        return Duration::from_nanos(t);
+    }
+    Duration::from_nanos((t as u128 * timebase.numer as u128 / timebase.denom as u128) as u64)
+}
+
+/// Get the coalitions the process `pid` belongs to.
+pub fn coalition_ids(pid: u32) -> Result<CoalitionIds> {
+    unsafe {
+        let mut info: proc_pidcoalitioninfo = mem::zeroed();
+        let size = mem::size_of::<proc_pidcoalitioninfo>() as c_int;
+        let ret = proc_pidinfo(pid as c_int,
+                               PROC_PIDCOALITIONINFO,
+                               0,
+                               &mut info as *mut _ as *mut c_void,
+                               size);
+        if ret != size {
+            return Err(Error::last_os_error());
+        }
+        Ok(CoalitionIds {
+            resource: info.coalition_id[0],
+            jetsam: info.coalition_id[1],
+        })
+    }
+}
+
+/// Get the resource usage accounted to the resource coalition `id`.
+pub fn coalition_resource_usage(id: u64) -> Result<CoalitionResourceUsage> {
+    unsafe {
+        let mut usage: coalition_resource_usage = mem::zeroed();
+        if coalition_info_resource_usage(id, &mut usage, mem::size_of_val(&usage)) != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(CoalitionResourceUsage {
+            tasks_started: usage.tasks_started,
+            tasks_exited: usage.tasks_exited,
+            cpu_time: mach_time_to_duration(usage.cpu_time),
+            gpu_time: Duration::from_nanos(usage.gpu_time),
+            interrupt_wakeups: usage.interrupt_wakeups,
+            platform_idle_wakeups: usage.platform_idle_wakeups,
+            bytes_read: usage.bytesread,
+            bytes_written: usage.byteswritten,
+            energy: usage.energy,
+        })
+    }
+}
+
+impl TaskPort {
+    /// Get the coalitions the task belongs to.
+    pub fn coalition_ids(&self) -> Result<CoalitionIds> {
+        coalition_ids(self.pid()?)
+    }
+
+    /// Get the resource usage of the task's resource coalition, which
+    /// includes every other process in it.
+    pub fn coalition_resource_usage(&self) -> Result<CoalitionResourceUsage> {
+        coalition_resource_usage(self.coalition_ids()?.resource)
+    }
+}
@@ -0,0 +1,46 @@
+//! The kernel's per-process CPU usage monitor.
+//!
+//! Once a monitor is set, a process that uses more than its share of CPU
+//! over the interval raises `EXC_RESOURCE`. An `ExceptionServer` attached
+//! for `ExceptionMask::RESOURCE` receives it, and
+//! `Exception::resource` decodes it into `ResourceException::Cpu`, which
+//! is the parent's cue to throttle or kill the child. If nothing handles
+//! it, the crash reporter writes a report and the child carries on.
+
+use std::io::{Error, Result};
+use std::os::raw::c_int;
+use std::time::Duration;
+
+use TaskPort;
+
+extern "C" {
+    fn proc_set_cpumon_params(pid: c_int, percentage: c_int, interval: c_int) -> c_int;
+    fn proc_disable_cpumon(pid: c_int) -> c_int;
+}
+
+impl TaskPort {
+    /// Monitor the process' CPU usage, raising `EXC_RESOURCE` when it uses
+    /// more than `percent` of one CPU averaged over `interval`, which is
+    /// rounded down to whole seconds.
+    ///
+    /// The kernel only allows this for processes of the same user, and
+    /// for other users' processes only to root.
+    pub fn set_cpu_monitor(&self, percent: u8, interval: Duration) -> Result<()> {
+        let pid = self.pid()? as c_int;
+        let interval = interval.as_secs().min(c_int::max_value() as u64) as c_int;
+        if unsafe { proc_set_cpumon_params(pid, percent as c_int, interval) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Turn off the process' CPU usage monitor, including the default one
+    /// some processes get from launchd.
+    pub fn disable_cpu_monitor(&self) -> Result<()> {
+        let pid = self.pid()? as c_int;
+        if unsafe { proc_disable_cpumon(pid) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! Getting the task port of a daemon that double-forks.
+//!
+//! A program that daemonizes forks, lets the intermediate process exit,
+//! and carries on in a grandchild that launchd has re-parented. Checking
+//! in from a `pre_exec` hook would only deliver the task port of the
+//! intermediate process, which is about to exit. Instead,
+//! `MachPortBroker::spawn_daemon` passes its service name and a token for
+//! the spawn to the child in environment variables, the daemon calls
+//! `check_in` once it has finished daemonizing, and the broker matches its
+//! check-in to the spawn by the token rather than by pid.
+
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+
+use child;
+use {DAEMON_MSG_ID, ServiceName};
+
+/// The environment variable holding the broker's service name.
+pub(crate) const SERVICE_NAME_VAR: &'static str = "SPAWN_TASK_PORT_DAEMON_SERVICE";
+/// The environment variable holding the token for the spawn.
+pub(crate) const TOKEN_VAR: &'static str = "SPAWN_TASK_PORT_DAEMON_TOKEN";
+
+/// Send this process' task port to the broker that spawned it, if it was
+/// spawned by `MachPortBroker::spawn_daemon`, returning whether it was.
+///
+/// Call this in the daemon once it has finished forking: a process that
+/// checks in before forking delivers its own task port, not the daemon's.
+/// The environment variables are removed, so that the daemon's own children
+/// don't check in as well.
+pub fn check_in() -> Result<bool> {
+    let (name, token) = match (env::var(SERVICE_NAME_VAR), env::var(TOKEN_VAR)) {
+        (Ok(name), Ok(token)) => (name, token),
+        _ => return Ok(false),
+    };
+    env::remove_var(SERVICE_NAME_VAR);
+    env::remove_var(TOKEN_VAR);
+
+    let invalid = || Error::new(ErrorKind::InvalidInput, "invalid daemon environment");
+    let name = ServiceName::from_str(&name).ok_or_else(invalid)?;
+    let token = token.parse::<u32>().map_err(|_| invalid())?;
+    child::send_to(name.as_c_str(), DAEMON_MSG_ID, token)?;
+    Ok(true)
+}
@@ -0,0 +1,195 @@
+//! A thread's debug registers, and single-stepping it.
+//!
+//! The debug registers are architecture specific, so `DebugState` is a
+//! different struct on ARM64 and x86-64, laid out like the kernel's
+//! `arm_debug_state64_t` and `x86_debug_state64_t`. Single-stepping is
+//! controlled by the `MDSCR_EL1.SS` bit on ARM64 and the `EFLAGS.TF` bit
+//! on x86-64; `ThreadPort::single_step` hides the difference.
+//!
+//! Each step raises `EXC_BREAKPOINT`, so a stepping loop is an
+//! `ExceptionServer` for `ExceptionMask::BREAKPOINT` that resumes every
+//! event for which `ExceptionEvent::is_single_step` is true.
+
+use std::io::Result;
+use std::mem;
+use std::os::raw::c_int;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::message::mach_msg_type_number_t;
+use mach::port::mach_port_t;
+
+use exception::ExceptionKind;
+use exception_server::ExceptionEvent;
+use ThreadPort;
+
+/// `ARM_THREAD_STATE64` from `<mach/arm/thread_status.h>`.
+#[cfg(target_arch = "aarch64")]
+const THREAD_STATE_FLAVOR: c_int = 6;
+/// `ARM_DEBUG_STATE64`.
+#[cfg(target_arch = "aarch64")]
+const DEBUG_STATE_FLAVOR: c_int = 15;
+/// `x86_THREAD_STATE64` from `<mach/i386/thread_status.h>`.
+#[cfg(target_arch = "x86_64")]
+const THREAD_STATE_FLAVOR: c_int = 4;
+/// `x86_DEBUG_STATE64`.
+#[cfg(target_arch = "x86_64")]
+const DEBUG_STATE_FLAVOR: c_int = 11;
+
+/// The single-step bit of `MDSCR_EL1`.
+#[cfg(target_arch = "aarch64")]
+const MDSCR_SS: u64 = 1;
+/// The trap flag of `EFLAGS`.
+#[cfg(target_arch = "x86_64")]
+const EFLAGS_TF: u64 = 0x100;
+
+/// `arm_thread_state64_t`: `x0`–`x28`, `fp`, `lr`, `sp`, `pc`, then
+/// `cpsr` and padding packed into the last word.
+#[cfg(target_arch = "aarch64")]
+type ThreadState = [u64; 34];
+#[cfg(target_arch = "aarch64")]
+const PC_INDEX: usize = 32;
+/// `x86_thread_state64_t`: the general purpose registers, `rip`,
+/// `rflags` and the segment registers.
+#[cfg(target_arch = "x86_64")]
+type ThreadState = [u64; 21];
+#[cfg(target_arch = "x86_64")]
+const PC_INDEX: usize = 16;
+#[cfg(target_arch = "x86_64")]
+const RFLAGS_INDEX: usize = 17;
+
+/// `EXC_ARM_BREAKPOINT` and `EXC_I386_SGL`, the first code of the
+/// `EXC_BREAKPOINT` raised by a single step.
+const EXC_SINGLE_STEP: i64 = 1;
+
+extern "C" {
+    fn thread_get_state(thread: mach_port_t,
+                        flavor: c_int,
+                        state: *mut u32,
+                        count: *mut mach_msg_type_number_t)
+                        -> kern_return_t;
+    fn thread_set_state(thread: mach_port_t,
+                        flavor: c_int,
+                        state: *const u32,
+                        count: mach_msg_type_number_t)
+                        -> kern_return_t;
+}
+
+/// A thread's debug registers, as `arm_debug_state64_t`.
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DebugState {
+    /// The breakpoint value registers, `DBGBVR<n>_EL1`.
+    pub bvr: [u64; 16],
+    /// The breakpoint control registers, `DBGBCR<n>_EL1`.
+    pub bcr: [u64; 16],
+    /// The watchpoint value registers, `DBGWVR<n>_EL1`.
+    pub wvr: [u64; 16],
+    /// The watchpoint control registers, `DBGWCR<n>_EL1`.
+    pub wcr: [u64; 16],
+    /// The debug system control register. Only its `SS` bit can be
+    /// changed.
+    pub mdscr_el1: u64,
+}
+
+/// A thread's debug registers, as `x86_debug_state64_t`.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DebugState {
+    /// `DR0` to `DR7`. `DR4` and `DR5` are reserved.
+    pub dr: [u64; 8],
+}
+
+/// Get thread state of flavor `flavor` into a `T`.
+fn get_state<T: Copy>(thread: &ThreadPort, flavor: c_int) -> Result<T> {
+    unsafe {
+        let mut state: T = mem::zeroed();
+        let mut count = (mem::size_of::<T>() / 4) as mach_msg_type_number_t;
+        ktry!(thread_get_state(thread.as_raw(),
+                               flavor,
+                               &mut state as *mut T as *mut u32,
+                               &mut count));
+        Ok(state)
+    }
+}
+
+/// Set thread state of flavor `flavor` from a `T`.
+fn set_state<T: Copy>(thread: &ThreadPort, flavor: c_int, state: &T) -> Result<()> {
+    unsafe {
+        ktry!(thread_set_state(thread.as_raw(),
+                               flavor,
+                               state as *const T as *const u32,
+                               (mem::size_of::<T>() / 4) as mach_msg_type_number_t));
+    }
+    Ok(())
+}
+
+impl ThreadPort {
+    /// Read the thread's debug registers.
+    pub fn debug_state(&self) -> Result<DebugState> {
+        get_state(self, DEBUG_STATE_FLAVOR)
+    }
+
+    /// Write the thread's debug registers. The kernel validates them, and
+    /// fails with `KERN_PROTECTION_FAILURE` for breakpoints or watchpoints
+    /// on kernel addresses.
+    pub fn set_debug_state(&self, state: &DebugState) -> Result<()> {
+        set_state(self, DEBUG_STATE_FLAVOR, state)
+    }
+
+    /// The thread's program counter. The thread should be suspended, or
+    /// stopped in an exception, for this to mean much.
+    pub fn program_counter(&self) -> Result<u64> {
+        let state: ThreadState = get_state(self, THREAD_STATE_FLAVOR)?;
+        Ok(state[PC_INDEX])
+    }
+
+    /// The thread's general purpose registers, as the words of
+    /// `arm_thread_state64_t` or `x86_thread_state64_t`.
+    pub fn registers(&self) -> Result<Vec<u64>> {
+        let state: ThreadState = get_state(self, THREAD_STATE_FLAVOR)?;
+        Ok(state.to_vec())
+    }
+
+    /// Turn single-stepping on or off. While it's on, the thread raises
+    /// `EXC_BREAKPOINT` after every instruction it executes.
+    #[cfg(target_arch = "aarch64")]
+    pub fn single_step(&self, enable: bool) -> Result<()> {
+        let mut state = self.debug_state()?;
+        if enable {
+            state.mdscr_el1 |= MDSCR_SS;
+        } else {
+            state.mdscr_el1 &= !MDSCR_SS;
+        }
+        self.set_debug_state(&state)
+    }
+
+    /// Turn single-stepping on or off. While it's on, the thread raises
+    /// `EXC_BREAKPOINT` after every instruction it executes.
+    #[cfg(target_arch = "x86_64")]
+    pub fn single_step(&self, enable: bool) -> Result<()> {
+        let mut state: ThreadState = get_state(self, THREAD_STATE_FLAVOR)?;
+        if enable {
+            state[RFLAGS_INDEX] |= EFLAGS_TF;
+        } else {
+            state[RFLAGS_INDEX] &= !EFLAGS_TF;
+        }
+        set_state(self, THREAD_STATE_FLAVOR, &state)
+    }
+}
+
+impl ExceptionEvent {
+    /// Whether this is the exception raised by a single step.
+    ///
+    /// On ARM64, a hardware breakpoint at address zero reports the same
+    /// codes, which can't be told apart from a step.
+    pub fn is_single_step(&self) -> bool {
+        let exception = self.exception();
+        exception.kind == ExceptionKind::Breakpoint &&
+        exception.codes.first() == Some(&EXC_SINGLE_STEP) &&
+        (cfg!(target_arch = "x86_64") || matches!(exception.codes.get(1), None | Some(&0)))
+    }
+}
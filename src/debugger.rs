@@ -0,0 +1,128 @@
+//! Spawning a child for a debugger to attach to.
+//!
+//! Helpers spawned through this crate are awkward to debug: by the time
+//! someone has found the pid and typed `lldb -p`, the interesting part is
+//! over. `CommandSpawnWithTask::spawn_for_debugger` suspends the child as
+//! soon as it has checked in, which is usually before `main`, hands a
+//! `DebuggerAttach` to a callback, and only resumes the child once the
+//! callback returns. The callback can print `DebuggerAttach`'s instructions
+//! and then call `wait_for_debugger` to block until one attaches.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `PROC_PIDTBSDINFO` from `<sys/proc_info.h>`.
+const PROC_PIDTBSDINFO: c_int = 3;
+/// `PROC_FLAG_TRACED`: a debugger is attached.
+const PROC_FLAG_TRACED: u32 = 2;
+
+/// How often `wait_for_debugger` checks.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `struct proc_bsdinfo`
+#[repr(C)]
+pub(crate) struct proc_bsdinfo {
+    pub pbi_flags: u32,
+    pbi_status: u32,
+    pbi_xstatus: u32,
+    pbi_pid: u32,
+    pub pbi_ppid: u32,
+    pbi_uid: u32,
+    pbi_gid: u32,
+    pbi_ruid: u32,
+    pbi_rgid: u32,
+    pbi_svuid: u32,
+    pbi_svgid: u32,
+    rfu_1: u32,
+    pub pbi_comm: [u8; 16],
+    pub pbi_name: [u8; 32],
+    pbi_nfiles: u32,
+    pbi_pgid: u32,
+    pbi_pjobc: u32,
+    e_tdev: u32,
+    e_tpgid: u32,
+    pbi_nice: i32,
+    pbi_start_tvsec: u64,
+    pbi_start_tvusec: u64,
+}
+
+extern "C" {
+    fn proc_pidinfo(pid: c_int,
+                    flavor: c_int,
+                    arg: u64,
+                    buffer: *mut c_void,
+                    buffersize: c_int)
+                    -> c_int;
+}
+
+/// Get the BSD information about the process `pid`.
+pub(crate) fn bsd_info(pid: u32) -> Result<proc_bsdinfo> {
+    unsafe {
+        let mut info: proc_bsdinfo = mem::zeroed();
+        let size = mem::size_of::<proc_bsdinfo>() as c_int;
+        let ret = proc_pidinfo(pid as c_int,
+                               PROC_PIDTBSDINFO,
+                               0,
+                               &mut info as *mut _ as *mut c_void,
+                               size);
+        if ret != size {
+            return Err(Error::last_os_error());
+        }
+        Ok(info)
+    }
+}
+
+/// What a debugger needs to attach to a child spawned by
+/// `spawn_for_debugger`, which stays suspended until the callback it is
+/// passed to returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DebuggerAttach {
+    pid: u32,
+}
+
+impl DebuggerAttach {
+    pub(crate) fn new(pid: u32) -> DebuggerAttach {
+        DebuggerAttach { pid: pid }
+    }
+
+    /// The child's pid.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// The command to attach LLDB to the child.
+    pub fn lldb_command(&self) -> String {
+        format!("lldb -p {}", self.pid)
+    }
+
+    /// Whether a debugger is attached to the child.
+    pub fn is_debugger_attached(&self) -> Result<bool> {
+        Ok(bsd_info(self.pid)?.pbi_flags & PROC_FLAG_TRACED != 0)
+    }
+
+    /// Block until a debugger attaches to the child, or `timeout` passes.
+    pub fn wait_for_debugger(&self, timeout: Option<Duration>) -> Result<()> {
+        let start = Instant::now();
+        while !self.is_debugger_attached()? {
+            if matches!(timeout, Some(timeout) if start.elapsed() >= timeout) {
+                return Err(Error::new(ErrorKind::TimedOut,
+                                      format!("no debugger attached to {}", self.pid)));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DebuggerAttach {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "process {} is suspended waiting for a debugger; attach with `{}`",
+               self.pid,
+               self.lldb_command())
+    }
+}
@@ -0,0 +1,36 @@
+//! Getting the task ports of a child's own children.
+//!
+//! A child spawned with `MachPortBroker::spawn_with_descendants` inherits
+//! the broker's service name in an environment variable, and passes it on
+//! to whatever it spawns in turn. Any process in that tree can then call
+//! `check_in` to send the broker its task port, along with its parent's
+//! pid, which is how the broker places it in the tree.
+//!
+//! Unlike `daemon::check_in`, the environment variable is left in place,
+//! so that grandchildren's children can check in as well.
+
+use libc;
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+
+use child;
+use {DESCENDANT_MSG_ID, ServiceName};
+
+/// The environment variable holding the broker's service name.
+pub(crate) const SERVICE_NAME_VAR: &'static str = "SPAWN_TASK_PORT_BROKER_SERVICE";
+
+/// Send this process' task port to the broker that spawned one of its
+/// ancestors, if it was spawned by `MachPortBroker::spawn_with_descendants`,
+/// returning whether it was.
+///
+/// Each process should only check in once.
+pub fn check_in() -> Result<bool> {
+    let name = match env::var(SERVICE_NAME_VAR) {
+        Ok(name) => name,
+        Err(_) => return Ok(false),
+    };
+    let name = ServiceName::from_str(&name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid broker service name"))?;
+    child::send_to(name.as_c_str(), DESCENDANT_MSG_ID, unsafe { libc::getppid() } as u32)?;
+    Ok(true)
+}
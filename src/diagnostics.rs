@@ -0,0 +1,185 @@
+//! Optional recording of the Mach calls made during a handshake.
+//!
+//! When diagnostics are enabled with `set_enabled(true)`, every Mach and
+//! bootstrap call the parent makes during a handshake is recorded along
+//! with the values of its arguments and its result. If the handshake
+//! fails, the returned `io::Error` wraps a `HandshakeError` carrying the
+//! transcript; either way, the transcript of the most recent handshake on
+//! the current thread is available from `last_handshake_log`.
+//!
+//! Calls made by the child between `fork` and `exec` aren't recorded,
+//! because the child must not allocate there. A failure in the child still
+//! shows up as the error from spawning it.
+
+use std::cell::RefCell;
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::io::{Error, Result};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The transcript of the handshake in progress on this thread, if any.
+    static CURRENT: RefCell<Option<HandshakeLog>> = RefCell::new(None);
+    /// The transcript of the last finished handshake on this thread.
+    static LAST: RefCell<Option<HandshakeLog>> = RefCell::new(None);
+}
+
+/// Turn diagnostics on or off for all threads. They are off by default.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Whether diagnostics are turned on.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// The transcript of the most recent handshake on the current thread that
+/// ran with diagnostics enabled.
+pub fn last_handshake_log() -> Option<HandshakeLog> {
+    LAST.with(|last| last.borrow().clone())
+}
+
+/// A single recorded call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CallRecord {
+    /// The call as written in the source, including its arguments.
+    pub call: &'static str,
+    /// What each argument evaluated to, in order. C strings are shown as
+    /// strings, and out-parameters as the value they held before the call.
+    pub arguments: Vec<String>,
+    /// What the call returned.
+    pub result: kern_return_t,
+}
+
+/// An argument to a recorded call, as the transcript shows it.
+pub(crate) trait Argument {
+    fn describe(&self) -> String;
+}
+
+impl Argument for u32 {
+    fn describe(&self) -> String {
+        format!("{:#x}", self)
+    }
+}
+
+impl Argument for i32 {
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Argument for u64 {
+    fn describe(&self) -> String {
+        format!("{:#x}", self)
+    }
+}
+
+impl Argument for &mut u32 {
+    fn describe(&self) -> String {
+        format!("&mut {:#x}", **self)
+    }
+}
+
+impl Argument for *const c_char {
+    fn describe(&self) -> String {
+        if self.is_null() {
+            return "NULL".to_owned();
+        }
+        // Every C string passed to a recorded call is a `CStr` the caller
+        // still holds.
+        format!("{:?}", unsafe { CStr::from_ptr(*self) })
+    }
+}
+
+/// The calls made during one handshake, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HandshakeLog {
+    pub calls: Vec<CallRecord>,
+}
+
+impl fmt::Display for HandshakeLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for record in &self.calls {
+            let status = if record.result == KERN_SUCCESS { "ok" } else { "FAILED" };
+            writeln!(f,
+                     "  {} [{}] -> {:#x} ({})",
+                     record.call,
+                     record.arguments.join(", "),
+                     record.result,
+                     status)?;
+        }
+        Ok(())
+    }
+}
+
+/// A handshake failure, with the transcript of the calls leading up to it.
+///
+/// This is the inner error of the `io::Error` returned by a failed
+/// handshake when diagnostics are enabled.
+#[derive(Debug)]
+pub struct HandshakeError {
+    /// The error the handshake failed with.
+    pub error: Error,
+    /// The calls made before it failed.
+    pub log: HandshakeLog,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\nhandshake transcript:\n{}", self.error, self.log)
+    }
+}
+
+impl error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Run a handshake in `f`, recording its calls if diagnostics are enabled.
+pub(crate) fn record_handshake<T, F>(f: F) -> Result<T>
+    where F: FnOnce() -> Result<T>
+{
+    if !is_enabled() {
+        return f();
+    }
+    CURRENT.with(|current| *current.borrow_mut() = Some(HandshakeLog::default()));
+    let result = f();
+    let log = CURRENT.with(|current| current.borrow_mut().take()).unwrap_or_default();
+    LAST.with(|last| *last.borrow_mut() = Some(log.clone()));
+    result.map_err(|e| {
+        Error::new(e.kind(),
+                   HandshakeError {
+                       error: e,
+                       log: log,
+                   })
+    })
+}
+
+/// Add `arg` to the `arguments` of a call about to be recorded, if there is
+/// a handshake to record it in, so that nothing is formatted otherwise.
+pub(crate) fn describe<A: Argument>(arg: &A, arguments: &mut Vec<String>) {
+    if CURRENT.with(|current| current.borrow().is_some()) {
+        arguments.push(arg.describe());
+    }
+}
+
+/// Record a call in the current handshake's transcript, if there is one.
+pub(crate) fn record(call: &'static str, arguments: Vec<String>, result: kern_return_t) {
+    CURRENT.with(|current| if let Some(ref mut log) = *current.borrow_mut() {
+        log.calls.push(CallRecord {
+            call: call,
+            arguments: arguments,
+            result: result,
+        });
+    });
+}
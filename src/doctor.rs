@@ -0,0 +1,291 @@
+//! Explain why spawning with a task port is likely to fail.
+//!
+//! Most handshake failures on newer macOS releases aren't bugs in the
+//! handshake itself, but policy: System Integrity Protection, the hardened
+//! runtime, and the sandbox all restrict who may hold or use another
+//! process' task port. `doctor` gathers the relevant facts about this
+//! process and the program it is about to spawn, and turns them into a list
+//! of likely problems.
+
+use std::fmt;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+use capabilities::{CS_OPS_ENTITLEMENTS_BLOB, CS_RUNTIME, code_signing_flags, csops};
+use system;
+use registration::{registration_method, RegistrationMethod};
+use {Capabilities, OsVersion};
+
+/// The entitlement that lets a process use other processes' task ports.
+const DEBUGGER_ENTITLEMENT: &'static str = "com.apple.security.cs.debugger";
+/// The entitlement that lets other processes use this process' task port.
+const GET_TASK_ALLOW_ENTITLEMENT: &'static str = "com.apple.security.get-task-allow";
+
+extern "C" {
+    fn sandbox_check(pid: c_int, operation: *const c_char, filter_type: c_int, ...) -> c_int;
+}
+
+/// How the program to be spawned is signed, as reported by `codesign`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TargetSignature {
+    /// Whether the program is signed at all.
+    pub signed: bool,
+    /// Whether it is an Apple platform binary.
+    pub platform_binary: bool,
+    /// Whether it opts into the hardened runtime.
+    pub hardened_runtime: bool,
+    /// Whether it has the `get-task-allow` entitlement.
+    pub get_task_allow: bool,
+}
+
+/// Something that is likely to make `spawn_with_task_port` fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Problem {
+    /// The program to be spawned doesn't exist.
+    TargetNotFound(PathBuf),
+    /// This process can't register a bootstrap service, so the child has
+    /// nowhere to send its task port.
+    CannotRegisterService {
+        sandboxed: bool,
+    },
+    /// The target is an Apple platform binary, whose task port SIP doesn't
+    /// let other processes use.
+    PlatformBinary,
+    /// The target runs with the hardened runtime but without
+    /// `get-task-allow`, and this process lacks the debugger entitlement.
+    HardenedRuntime,
+    /// The target's signature couldn't be inspected.
+    UnknownSignature(String),
+}
+
+impl Problem {
+    /// Whether this problem will almost certainly make the handshake fail,
+    /// rather than just possibly.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(*self, Problem::UnknownSignature(_))
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::TargetNotFound(ref path) => write!(f, "{} does not exist", path.display()),
+            Problem::CannotRegisterService { sandboxed: true } => {
+                write!(f,
+                       "this process is sandboxed and may not register bootstrap services; \
+                        allow `mach-register` in its sandbox profile")
+            }
+            Problem::CannotRegisterService { sandboxed: false } => {
+                write!(f, "this process may not register bootstrap services")
+            }
+            Problem::PlatformBinary => {
+                write!(f,
+                       "the target is an Apple platform binary, and System Integrity \
+                        Protection stops other processes from using its task port; spawn a \
+                        copy that you have re-signed instead")
+            }
+            Problem::HardenedRuntime => {
+                write!(f,
+                       "the target uses the hardened runtime without the `{}` entitlement, \
+                        and this process lacks `{}`",
+                       GET_TASK_ALLOW_ENTITLEMENT,
+                       DEBUGGER_ENTITLEMENT)
+            }
+            Problem::UnknownSignature(ref reason) => {
+                write!(f, "couldn't inspect the target's code signature: {}", reason)
+            }
+        }
+    }
+}
+
+/// Everything `doctor` found out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DoctorReport {
+    /// The running macOS version, if it could be determined.
+    pub os_version: Option<OsVersion>,
+    /// Whether System Integrity Protection is enabled.
+    pub sip_enabled: bool,
+    /// Whether SIP restricts `task_for_pid`.
+    pub task_for_pid_restricted: bool,
+    /// Whether Developer Mode is enabled, or `None` before macOS 13.
+    pub developer_mode: Option<bool>,
+    /// Whether this process is sandboxed.
+    pub sandboxed: bool,
+    /// Whether this process runs with the hardened runtime.
+    pub hardened_runtime: bool,
+    /// Whether this process has the debugger entitlement.
+    pub debugger_entitlement: bool,
+    /// The target's signature, if it could be inspected.
+    pub target: Option<TargetSignature>,
+    /// The likely problems, most serious first.
+    pub problems: Vec<Problem>,
+}
+
+impl DoctorReport {
+    /// Whether spawning the target with its task port is likely to fail.
+    pub fn likely_to_fail(&self) -> bool {
+        self.problems.iter().any(Problem::is_fatal)
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.os_version {
+            Some(version) => writeln!(f, "macOS {}", version)?,
+            None => writeln!(f, "macOS version unknown")?,
+        }
+        writeln!(f, "SIP enabled: {}", self.sip_enabled)?;
+        if let Some(developer_mode) = self.developer_mode {
+            writeln!(f, "Developer Mode enabled: {}", developer_mode)?;
+        }
+        writeln!(f, "sandboxed: {}", self.sandboxed)?;
+        writeln!(f, "debugger entitlement: {}", self.debugger_entitlement)?;
+        if let Some(ref target) = self.target {
+            writeln!(f, "target: {:?}", target)?;
+        }
+        if self.problems.is_empty() {
+            writeln!(f, "no problems found")?;
+        }
+        for problem in &self.problems {
+            let label = if problem.is_fatal() { "error" } else { "warning" };
+            writeln!(f, "{}: {}", label, problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check whether spawning `target` with its task port is likely to work
+/// from this process, and explain why not.
+pub fn doctor<P: AsRef<Path>>(target: P) -> DoctorReport {
+    let target = target.as_ref();
+    let caps = Capabilities::detect();
+    let sandboxed = unsafe { sandbox_check(libc::getpid(), ptr::null(), 0) } != 0;
+    let debugger_entitlement = matches!(own_entitlements(),
+                                        Some(ref e) if e.contains(DEBUGGER_ENTITLEMENT));
+    let sip = system::sip_status();
+    let mut report = DoctorReport {
+        os_version: caps.os_version,
+        sip_enabled: sip.enabled,
+        task_for_pid_restricted: sip.task_for_pid_restricted,
+        developer_mode: system::developer_mode_enabled(),
+        sandboxed: sandboxed,
+        hardened_runtime: code_signing_flags() & CS_RUNTIME != 0,
+        debugger_entitlement: debugger_entitlement,
+        target: None,
+        problems: vec![],
+    };
+    let can_register = match registration_method() {
+        RegistrationMethod::Register2 => caps.bootstrap_register2,
+        RegistrationMethod::CheckIn => caps.bootstrap_check_in,
+    };
+    if !can_register {
+        report.problems.push(Problem::CannotRegisterService { sandboxed: sandboxed });
+    }
+    if !target.exists() {
+        report.problems.push(Problem::TargetNotFound(target.to_owned()));
+        return report;
+    }
+    match target_signature(target) {
+        Ok(sig) => {
+            if sig.platform_binary && report.sip_enabled {
+                report.problems.push(Problem::PlatformBinary);
+            } else if sig.hardened_runtime && !sig.get_task_allow && !debugger_entitlement {
+                report.problems.push(Problem::HardenedRuntime);
+            }
+            report.target = Some(sig);
+        }
+        Err(reason) => report.problems.push(Problem::UnknownSignature(reason)),
+    }
+    report
+}
+
+/// Run `codesign` to find out how `target` is signed.
+fn target_signature(target: &Path) -> Result<TargetSignature, String> {
+    let output = Command::new("codesign")
+        .args(["--display", "--verbose=2"])
+        .arg(target)
+        .output()
+        .map_err(|e| format!("failed to run codesign: {}", e))?;
+    // `codesign --display` writes its report to stderr.
+    let info = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        if info.contains("not signed at all") {
+            return Ok(TargetSignature::default());
+        }
+        return Err(info.trim().to_owned());
+    }
+    let entitlements = Command::new("codesign")
+        .args(["--display", "--entitlements", "-"])
+        .arg(target)
+        .output()
+        .map_err(|e| format!("failed to run codesign: {}", e))?;
+    Ok(parse_signature(&info, &String::from_utf8_lossy(&entitlements.stdout)))
+}
+
+/// Interpret the output of `codesign --display --verbose=2` and
+/// `codesign --display --entitlements -`.
+fn parse_signature(info: &str, entitlements: &str) -> TargetSignature {
+    let flags = info.lines()
+        .find(|l| l.starts_with("CodeDirectory "))
+        .and_then(|l| l.split_whitespace().find(|w| w.starts_with("flags=")))
+        .unwrap_or("");
+    TargetSignature {
+        signed: true,
+        platform_binary: info.lines().any(|l| l.starts_with("Platform identifier=")),
+        hardened_runtime: flags.contains("runtime"),
+        get_task_allow: entitlements.contains(GET_TASK_ALLOW_ENTITLEMENT),
+    }
+}
+
+/// This process' entitlements blob, as XML.
+fn own_entitlements() -> Option<String> {
+    // The blob starts with a big-endian magic number and total length.
+    let mut header = [0u32; 2];
+    unsafe {
+        csops(libc::getpid(),
+              CS_OPS_ENTITLEMENTS_BLOB,
+              header.as_mut_ptr() as *mut c_void,
+              mem::size_of_val(&header));
+    }
+    let len = u32::from_be(header[1]) as usize;
+    if len <= mem::size_of_val(&header) {
+        return None;
+    }
+    let mut blob = vec![0u8; len];
+    let ret = unsafe {
+        csops(libc::getpid(),
+              CS_OPS_ENTITLEMENTS_BLOB,
+              blob.as_mut_ptr() as *mut c_void,
+              blob.len())
+    };
+    if ret != 0 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&blob[mem::size_of_val(&header)..]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_codesign_output() {
+        let info = "Executable=/bin/ls\n\
+                    Identifier=com.apple.ls\n\
+                    CodeDirectory v=20400 size=1234 flags=0x0(none) hashes=33+2 location=embedded\n\
+                    Platform identifier=15\n";
+        let sig = parse_signature(info, "");
+        assert!(sig.signed && sig.platform_binary && !sig.hardened_runtime);
+
+        let info = "CodeDirectory v=20500 size=1234 flags=0x10000(runtime) hashes=33+7\n";
+        let entitlements = "[Key] com.apple.security.get-task-allow\n[Value]\n[Bool] true\n";
+        let sig = parse_signature(info, entitlements);
+        assert!(!sig.platform_binary && sig.hardened_runtime && sig.get_task_allow);
+    }
+}
@@ -0,0 +1,50 @@
+//! Support for `duct` expressions, enabled by the `duct` feature.
+
+use std::io::{Error, ErrorKind, Result};
+use std::os::raw::c_int;
+use std::os::unix::process::CommandExt;
+
+use duct::{Expression, Handle};
+
+use {MachPortBroker, TaskPort, pre_exec_hook};
+
+/// An extension to `duct::Expression` to start it and get back access to
+/// the Mach task ports of the processes it spawns.
+pub trait ExpressionSpawnWithTask {
+    /// Start the expression, as `Expression::start` does, returning both
+    /// the `Handle` as well as the Mach task ports of all of the child
+    /// processes, in the same order as `Handle::pids`.
+    fn spawn_with_task_ports(&self) -> Result<(Handle, Vec<TaskPort>)>;
+}
+
+impl ExpressionSpawnWithTask for Expression {
+    fn spawn_with_task_ports(&self) -> Result<(Handle, Vec<TaskPort>)> {
+        // An expression can spawn any number of children, so have them all
+        // check in with one broker.
+        let broker = MachPortBroker::new()?;
+        let check_in = broker.check_in();
+        let handle = self.before_spawn(move |command| {
+                unsafe {
+                    command.pre_exec(pre_exec_hook(check_in));
+                }
+                Ok(())
+            })
+            .start()?;
+        // Each child checked in before it was executed, so its check-in is
+        // queued by the time `start` returns, or never will be.
+        let not_checked_in = || Err(Error::new(ErrorKind::Other, "a child didn't check in"));
+        let task_ports = handle.pids()
+            .into_iter()
+            .map(|pid| broker.receive_for_pid(pid as c_int, not_checked_in))
+            .collect::<Result<Vec<_>>>();
+        match task_ports {
+            Ok(task_ports) => Ok((handle, task_ports)),
+            Err(e) => {
+                // Don't leave the children that did start running, or
+                // unreaped. This waits for all of them.
+                let _ = handle.kill();
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Scrubbing variables that change how a child runs from its environment.
+//!
+//! A parent holding its children's task ports is usually worth attacking,
+//! and the environment it passes on is an easy way in: `DYLD_*` variables
+//! load code into the child or swap its libraries, and the `Malloc*` ones
+//! make it log to files. `EnvScrub` removes them from a `Command`, except
+//! for any that are explicitly allowed.
+
+use std::collections::HashSet;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+
+/// Prefixes of the variables that are scrubbed. launchd passes on
+/// `__XPC_DYLD_*` as `DYLD_*` to XPC services.
+const SCRUBBED_PREFIXES: &'static [&'static [u8]] = &[b"DYLD_", b"__XPC_DYLD_", b"Malloc"];
+
+/// Which variables to remove from a child's environment.
+#[derive(Clone, Debug, Default)]
+pub struct EnvScrub {
+    allowed: HashSet<OsString>,
+}
+
+impl EnvScrub {
+    /// Scrub every variable that would change how the child runs.
+    pub fn new() -> EnvScrub {
+        EnvScrub::default()
+    }
+
+    /// Keep the variable `name`, even though it would otherwise be scrubbed.
+    pub fn allow<K: AsRef<OsStr>>(&mut self, name: K) -> &mut EnvScrub {
+        self.allowed.insert(name.as_ref().to_owned());
+        self
+    }
+
+    /// Whether `name` would be scrubbed.
+    pub fn scrubs<K: AsRef<OsStr>>(&self, name: K) -> bool {
+        let name = name.as_ref();
+        !self.allowed.contains(name) &&
+        SCRUBBED_PREFIXES.iter().any(|prefix| name.as_bytes().starts_with(prefix))
+    }
+
+    /// Remove the scrubbed variables from `command`'s environment, both the
+    /// ones it would inherit and the ones already set on it.
+    ///
+    /// Variables set on `command` afterwards are kept.
+    pub fn apply<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        let mut names = env::vars_os().map(|(name, _)| name).collect::<Vec<_>>();
+        names.extend(command.get_envs().map(|(name, _)| name.to_owned()));
+        for name in names {
+            if self.scrubs(&name) {
+                command.env_remove(name);
+            }
+        }
+        command
+    }
+}
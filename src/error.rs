@@ -0,0 +1,232 @@
+//! Typed causes for the crate's errors.
+//!
+//! Everything still returns `io::Result`, so existing callers keep working,
+//! but the failures that are specific to this crate carry a
+//! `SpawnTaskPortError` as the `io::Error`'s inner error. Callers that want
+//! to tell them apart get it back with `SpawnTaskPortError::from_io`.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use mach::kern_return::kern_return_t;
+
+use diagnostics::HandshakeError;
+
+/// The Mach calls a child makes between `fork` and `exec`.
+///
+/// The child can't allocate a `SpawnTaskPortError` there, and only an
+/// errno makes it back to `spawn` in the parent, so `child_call_error`
+/// packs the index of the call that failed in this list and its result
+/// into a negative errno, and `SpawnTaskPortError::from_spawn` unpacks it.
+const CHILD_CALLS: [&'static str; 8] = ["bootstrap_look_up",
+                                        "create_identity_token",
+                                        "mach_msg",
+                                        "mach_msg_send",
+                                        "mach_port_allocate",
+                                        "task_get_special_port",
+                                        "task_set_exception_ports",
+                                        "task_set_special_port"];
+
+/// The index of `call` in `CHILD_CALLS`. Evaluated in a constant, this
+/// fails to compile for a call that isn't listed.
+pub(crate) const fn child_call(call: &str) -> usize {
+    let call = call.as_bytes();
+    let mut i = 0;
+    while i < CHILD_CALLS.len() {
+        let listed = CHILD_CALLS[i].as_bytes();
+        if listed.len() == call.len() {
+            let mut j = 0;
+            while j < call.len() && listed[j] == call[j] {
+                j += 1;
+            }
+            if j == call.len() {
+                return i;
+            }
+        }
+        i += 1;
+    }
+    panic!("not a call the child makes before exec");
+}
+
+/// The error for the child's call `CHILD_CALLS[call]` failing with `code`.
+///
+/// This doesn't allocate. Bit 31 marks the errno as a packed call, bits
+/// 28 to 30 hold `call`, and the rest hold `code`, with its system folded
+/// into two bits: the kernel's, which includes the bootstrap server's
+/// codes, IPC's, or the negative codes of MIG. The calls don't fail with
+/// codes of other systems, which would lose their system.
+pub(crate) fn child_call_error(call: usize, code: kern_return_t) -> io::Error {
+    let code = code as u32;
+    let system = match code >> 26 {
+        0x04 => 1,
+        0x3f => 2,
+        _ => 0,
+    };
+    let packed = 1 << 31 | (call as u32) << 28 | system << 26 | code & 0x03ff_ffff;
+    io::Error::from_raw_os_error(packed as i32)
+}
+
+/// The call and the result that `child_call_error` packed into `errno`,
+/// if it is one of its errnos.
+fn unpack_child_call(errno: i32) -> Option<(&'static str, kern_return_t)> {
+    if errno >= 0 {
+        return None;
+    }
+    let packed = errno as u32;
+    let system: u32 = match packed >> 26 & 0x3 {
+        1 => 0x04,
+        2 => 0x3f,
+        _ => 0,
+    };
+    Some((CHILD_CALLS[(packed >> 28 & 0x7) as usize],
+          (system << 26 | packed & 0x03ff_ffff) as kern_return_t))
+}
+
+/// Why a Mach call, a bootstrap call or a handshake failed.
+#[derive(Debug)]
+pub enum SpawnTaskPortError {
+    /// A Mach call failed with `code`.
+    KernError {
+        /// The call as written in the source, including its arguments, or
+        /// just the function's name if the child made it before `exec`.
+        call: &'static str,
+        code: kern_return_t,
+    },
+    /// A call to the bootstrap server, such as `bootstrap_look_up`, failed
+    /// with `code`.
+    BootstrapError {
+        /// The call as written in the source, including its arguments, or
+        /// just the function's name if the child made it before `exec`.
+        call: &'static str,
+        code: kern_return_t,
+    },
+    /// No check-in arrived in time.
+    ReceiveTimeout,
+    /// A check-in or task port came from a different process than the one
+    /// it should have.
+    AuditMismatch {
+        /// The pid it should have come from.
+        expected: u32,
+        /// The pid it actually came from.
+        actual: u32,
+    },
+    /// Spawning the child failed, before any handshake.
+    Spawn(io::Error),
+}
+
+impl SpawnTaskPortError {
+    /// The error for `call` failing with `code`, told apart by the call's
+    /// name.
+    pub(crate) fn from_call(call: &'static str, code: kern_return_t) -> SpawnTaskPortError {
+        if call.starts_with("bootstrap_") {
+            SpawnTaskPortError::BootstrapError {
+                call: call,
+                code: code,
+            }
+        } else {
+            SpawnTaskPortError::KernError {
+                call: call,
+                code: code,
+            }
+        }
+    }
+
+    /// The error for spawning a child failing with `error`: the Mach or
+    /// bootstrap call the child made before `exec` that failed, if it
+    /// packed one into `error`, or `Spawn(error)` otherwise.
+    pub(crate) fn from_spawn(error: io::Error) -> SpawnTaskPortError {
+        match error.raw_os_error().and_then(unpack_child_call) {
+            Some((call, code)) => SpawnTaskPortError::from_call(call, code),
+            None => SpawnTaskPortError::Spawn(error),
+        }
+    }
+
+    /// The `SpawnTaskPortError` inside `error`, if it has one, looking
+    /// through the transcript that diagnostics wrap errors in.
+    pub fn from_io(error: &io::Error) -> Option<&SpawnTaskPortError> {
+        let inner = error.get_ref()?;
+        if let Some(handshake) = inner.downcast_ref::<HandshakeError>() {
+            return SpawnTaskPortError::from_io(&handshake.error);
+        }
+        inner.downcast_ref()
+    }
+
+    /// The `kern_return_t` a Mach or bootstrap call failed with.
+    pub fn kern_return(&self) -> Option<kern_return_t> {
+        match *self {
+            SpawnTaskPortError::KernError { code, .. } |
+            SpawnTaskPortError::BootstrapError { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The `io::ErrorKind` that an `io::Error` wrapping this gets.
+    fn kind(&self) -> io::ErrorKind {
+        match *self {
+            SpawnTaskPortError::KernError { .. } |
+            SpawnTaskPortError::BootstrapError { .. } => io::ErrorKind::Other,
+            SpawnTaskPortError::ReceiveTimeout => io::ErrorKind::TimedOut,
+            SpawnTaskPortError::AuditMismatch { .. } => io::ErrorKind::InvalidData,
+            SpawnTaskPortError::Spawn(ref e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for SpawnTaskPortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpawnTaskPortError::KernError { call, code } |
+            SpawnTaskPortError::BootstrapError { call, code } => {
+                write!(f, "`{}` failed with return code {:x}", call, code)
+            }
+            SpawnTaskPortError::ReceiveTimeout => write!(f, "timed out waiting for a check-in"),
+            SpawnTaskPortError::AuditMismatch { expected, actual } => {
+                write!(f, "expected a check-in from pid {}, but got one from {}", expected, actual)
+            }
+            SpawnTaskPortError::Spawn(ref e) => write!(f, "failed to spawn the child: {}", e),
+        }
+    }
+}
+
+impl error::Error for SpawnTaskPortError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SpawnTaskPortError::Spawn(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SpawnTaskPortError> for io::Error {
+    fn from(error: SpawnTaskPortError) -> io::Error {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpacks_calls_the_child_packed() {
+        let codes = [1, 1102, 0x1000_0003, 0x1000_0004, -308];
+        for (call, &name) in CHILD_CALLS.iter().enumerate() {
+            assert_eq!(child_call(name), call);
+            for &code in &codes {
+                let error = child_call_error(call, code);
+                match SpawnTaskPortError::from_spawn(error) {
+                    SpawnTaskPortError::KernError { call, code: unpacked } |
+                    SpawnTaskPortError::BootstrapError { call, code: unpacked } => {
+                        assert_eq!((call, unpacked), (name, code));
+                    }
+                    e => panic!("unexpected error {:?}", e),
+                }
+            }
+        }
+        match SpawnTaskPortError::from_spawn(io::Error::from_raw_os_error(2)) {
+            SpawnTaskPortError::Spawn(e) => assert_eq!(e.raw_os_error(), Some(2)),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
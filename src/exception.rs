@@ -0,0 +1,363 @@
+//! Mach exception messages.
+//!
+//! Exception handlers are ports that the kernel sends a message to when a
+//! thread faults, before the fault turns into a signal. This module has
+//! the message formats and types shared by everything in the crate that
+//! installs a handler; the handlers themselves live elsewhere.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::ops::BitOr;
+use std::os::raw::c_int;
+use std::time::Duration;
+
+use mach::kern_return::{kern_return_t, KERN_FAILURE, KERN_SUCCESS};
+use mach::message::{MACH_MSG_TIMEOUT_NONE, MACH_MSGH_BITS, MACH_RCV_MSG, MACH_RCV_TIMED_OUT,
+                    MACH_RCV_TIMEOUT, mach_msg,
+                    mach_msg_body_t, mach_msg_header_t, mach_msg_port_descriptor_t, mach_msg_send,
+                    mach_msg_trailer_t, mach_msg_type_number_t};
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+
+use {MachPort, TaskPort, ThreadPort};
+
+/// `EXCEPTION_DEFAULT` from `<mach/exception_types.h>`.
+pub(crate) const EXCEPTION_DEFAULT: c_int = 1;
+/// `MACH_EXCEPTION_CODES`: send 64-bit codes.
+pub(crate) const MACH_EXCEPTION_CODES: c_int = 0x8000_0000u32 as c_int;
+
+/// `THREAD_STATE_NONE` for the current architecture, the flavor to pass
+/// along with `EXCEPTION_DEFAULT`.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const THREAD_STATE_NONE: c_int = 5;
+#[cfg(not(target_arch = "aarch64"))]
+pub(crate) const THREAD_STATE_NONE: c_int = 13;
+
+/// `EXC_TYPES_COUNT`: one more than the largest exception type.
+pub(crate) const EXC_TYPES_COUNT: usize = 14;
+
+/// The `msgh_id` of `mach_exception_raise`.
+const MACH_EXCEPTION_RAISE_ID: c_int = 2405;
+
+/// The NDR record MIG puts in every message, for little-endian machines.
+const NDR_RECORD: [u8; 8] = [0, 0, 0, 0, 1, 0, 0, 0];
+
+/// The kinds of Mach exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ExceptionKind {
+    BadAccess,
+    BadInstruction,
+    Arithmetic,
+    Emulation,
+    Software,
+    Breakpoint,
+    Syscall,
+    MachSyscall,
+    RpcAlert,
+    Crash,
+    Resource,
+    Guard,
+    CorpseNotify,
+    /// An exception type this crate doesn't know about.
+    Other(i32),
+}
+
+impl ExceptionKind {
+    /// Convert from an `EXC_*` value.
+    pub fn from_raw(raw: i32) -> ExceptionKind {
+        match raw {
+            1 => ExceptionKind::BadAccess,
+            2 => ExceptionKind::BadInstruction,
+            3 => ExceptionKind::Arithmetic,
+            4 => ExceptionKind::Emulation,
+            5 => ExceptionKind::Software,
+            6 => ExceptionKind::Breakpoint,
+            7 => ExceptionKind::Syscall,
+            8 => ExceptionKind::MachSyscall,
+            9 => ExceptionKind::RpcAlert,
+            10 => ExceptionKind::Crash,
+            11 => ExceptionKind::Resource,
+            12 => ExceptionKind::Guard,
+            13 => ExceptionKind::CorpseNotify,
+            other => ExceptionKind::Other(other),
+        }
+    }
+
+    /// The `EXC_*` value.
+    pub fn to_raw(self) -> i32 {
+        match self {
+            ExceptionKind::BadAccess => 1,
+            ExceptionKind::BadInstruction => 2,
+            ExceptionKind::Arithmetic => 3,
+            ExceptionKind::Emulation => 4,
+            ExceptionKind::Software => 5,
+            ExceptionKind::Breakpoint => 6,
+            ExceptionKind::Syscall => 7,
+            ExceptionKind::MachSyscall => 8,
+            ExceptionKind::RpcAlert => 9,
+            ExceptionKind::Crash => 10,
+            ExceptionKind::Resource => 11,
+            ExceptionKind::Guard => 12,
+            ExceptionKind::CorpseNotify => 13,
+            ExceptionKind::Other(other) => other,
+        }
+    }
+}
+
+/// A set of exception kinds to catch, like `exception_mask_t`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ExceptionMask(u32);
+
+impl ExceptionMask {
+    pub const BAD_ACCESS: ExceptionMask = ExceptionMask(1 << 1);
+    pub const BAD_INSTRUCTION: ExceptionMask = ExceptionMask(1 << 2);
+    pub const ARITHMETIC: ExceptionMask = ExceptionMask(1 << 3);
+    pub const EMULATION: ExceptionMask = ExceptionMask(1 << 4);
+    pub const SOFTWARE: ExceptionMask = ExceptionMask(1 << 5);
+    pub const BREAKPOINT: ExceptionMask = ExceptionMask(1 << 6);
+    pub const SYSCALL: ExceptionMask = ExceptionMask(1 << 7);
+    pub const MACH_SYSCALL: ExceptionMask = ExceptionMask(1 << 8);
+    pub const RPC_ALERT: ExceptionMask = ExceptionMask(1 << 9);
+    pub const CRASH: ExceptionMask = ExceptionMask(1 << 10);
+    pub const RESOURCE: ExceptionMask = ExceptionMask(1 << 11);
+    pub const GUARD: ExceptionMask = ExceptionMask(1 << 12);
+    pub const CORPSE_NOTIFY: ExceptionMask = ExceptionMask(1 << 13);
+
+    /// Every exception a task can have a handler for, like
+    /// `EXC_MASK_VALID`.
+    pub const ALL: ExceptionMask = ExceptionMask(0x3ffe);
+
+    /// The exceptions that end a process if nobody handles them.
+    pub const CRASHES: ExceptionMask = ExceptionMask(ExceptionMask::BAD_ACCESS.0 |
+                                                     ExceptionMask::BAD_INSTRUCTION.0 |
+                                                     ExceptionMask::ARITHMETIC.0 |
+                                                     ExceptionMask::SOFTWARE.0 |
+                                                     ExceptionMask::BREAKPOINT.0 |
+                                                     ExceptionMask::CRASH.0);
+
+    /// The raw `exception_mask_t`.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Wrap a raw `exception_mask_t`.
+    pub fn from_bits(bits: u32) -> ExceptionMask {
+        ExceptionMask(bits)
+    }
+
+    /// Whether `kind` is in the set.
+    pub fn contains(self, kind: ExceptionKind) -> bool {
+        let raw = kind.to_raw();
+        raw > 0 && raw < 32 && self.0 & (1 << raw) != 0
+    }
+}
+
+impl BitOr for ExceptionMask {
+    type Output = ExceptionMask;
+
+    fn bitor(self, other: ExceptionMask) -> ExceptionMask {
+        ExceptionMask(self.0 | other.0)
+    }
+}
+
+/// An exception raised by a thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Exception {
+    /// The pid of the process the thread belongs to.
+    pub pid: u32,
+    /// What kind of exception it was.
+    pub kind: ExceptionKind,
+    /// The exception codes, whose meaning depends on `kind`. There are
+    /// usually two.
+    pub codes: Vec<i64>,
+}
+
+/// The `mach_exception_raise` request, as MIG lays it out.
+#[repr(C, packed(4))]
+struct ExceptionRequest {
+    header: mach_msg_header_t,
+    body: mach_msg_body_t,
+    thread: mach_msg_port_descriptor_t,
+    task: mach_msg_port_descriptor_t,
+    ndr: [u8; 8],
+    exception: c_int,
+    code_count: u32,
+    code: [i64; 2],
+    trailer: mach_msg_trailer_t,
+}
+
+/// The reply to any exception request.
+#[repr(C, packed(4))]
+struct ExceptionReply {
+    header: mach_msg_header_t,
+    ndr: [u8; 8],
+    ret_code: kern_return_t,
+}
+
+/// An exception handler that was installed before ours, to put back when
+/// ours goes away.
+pub(crate) struct SavedHandler {
+    pub(crate) mask: u32,
+    pub(crate) port: MachPort,
+    pub(crate) behavior: c_int,
+    pub(crate) flavor: c_int,
+}
+
+/// Collect the handlers reported by one of the `*_get_exception_ports`
+/// calls, which `get` makes with the arrays it is given.
+pub(crate) fn saved_handlers<F>(get: F) -> Result<Vec<SavedHandler>>
+    where F: FnOnce(*mut u32,
+                    *mut mach_msg_type_number_t,
+                    *mut mach_port_t,
+                    *mut c_int,
+                    *mut c_int)
+                    -> kern_return_t
+{
+    let mut masks = [0u32; EXC_TYPES_COUNT];
+    let mut ports = [MACH_PORT_NULL; EXC_TYPES_COUNT];
+    let mut behaviors = [0; EXC_TYPES_COUNT];
+    let mut flavors = [0; EXC_TYPES_COUNT];
+    let mut count = EXC_TYPES_COUNT as mach_msg_type_number_t;
+    let kr = get(masks.as_mut_ptr(),
+                 &mut count,
+                 ports.as_mut_ptr(),
+                 behaviors.as_mut_ptr(),
+                 flavors.as_mut_ptr());
+    if kr != KERN_SUCCESS {
+        return Err(Error::new(ErrorKind::Other,
+                              format!("`get_exception_ports` failed with return code {:x}", kr)));
+    }
+    Ok((0..count as usize)
+        .map(|i| {
+            SavedHandler {
+                mask: masks[i],
+                port: MachPort(ports[i]),
+                behavior: behaviors[i],
+                flavor: flavors[i],
+            }
+        })
+        .collect())
+}
+
+/// An exception that has been received but not replied to yet. The
+/// faulting thread stays suspended until the reply is sent; dropping this
+/// replies with `KERN_FAILURE`, which lets the exception continue to the
+/// next handler.
+pub(crate) struct PendingException {
+    pub(crate) exception: Exception,
+    pub(crate) thread: ThreadPort,
+    pub(crate) task: TaskPort,
+    reply_port: mach_port_t,
+    reply_bits: u32,
+    reply_id: c_int,
+}
+
+impl PendingException {
+    /// Send the reply. `KERN_SUCCESS` resumes the thread as though the
+    /// exception was handled; anything else passes it on.
+    pub(crate) fn reply(mut self, result: kern_return_t) -> Result<()> {
+        let reply_port = mem::replace(&mut self.reply_port, MACH_PORT_NULL);
+        ktry!(send_reply(reply_port, self.reply_bits, self.reply_id, result));
+        Ok(())
+    }
+}
+
+impl Drop for PendingException {
+    fn drop(&mut self) {
+        if self.reply_port != MACH_PORT_NULL {
+            // Ignore failures, there's not much that can be done here.
+            send_reply(self.reply_port, self.reply_bits, self.reply_id, KERN_FAILURE);
+        }
+    }
+}
+
+fn send_reply(port: mach_port_t, bits: u32, id: c_int, result: kern_return_t) -> kern_return_t {
+    let mut reply = ExceptionReply {
+        header: mach_msg_header_t {
+            msgh_bits: MACH_MSGH_BITS(bits, 0),
+            msgh_size: mem::size_of::<ExceptionReply>() as u32,
+            msgh_remote_port: port,
+            msgh_local_port: MACH_PORT_NULL,
+            msgh_voucher_port: MACH_PORT_NULL,
+            msgh_id: id,
+        },
+        ndr: NDR_RECORD,
+        ret_code: result,
+    };
+    unsafe { mach_msg_send(&mut reply.header) }
+}
+
+/// Block until an exception message arrives on `port`.
+///
+/// The handler must have been installed with
+/// `EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES`.
+pub(crate) fn receive_exception(port: mach_port_t) -> Result<PendingException> {
+    receive_exception_timeout(port, None).map(|pending| pending.expect("receive timed out"))
+}
+
+/// Like `receive_exception`, but give up and return `None` if nothing
+/// arrives within `timeout`.
+pub(crate) fn receive_exception_timeout(port: mach_port_t,
+                                        timeout: Option<Duration>)
+                                        -> Result<Option<PendingException>> {
+    let (option, timeout_ms) = match timeout {
+        Some(timeout) => {
+            (MACH_RCV_TIMEOUT, timeout.as_millis().min(u32::max_value() as u128) as u32)
+        }
+        None => (0, MACH_MSG_TIMEOUT_NONE),
+    };
+    unsafe {
+        let mut msg: ExceptionRequest = mem::zeroed();
+        let kr = mach_msg(&mut msg.header,
+                          MACH_RCV_MSG | option,
+                          0,
+                          mem::size_of::<ExceptionRequest>() as u32,
+                          port,
+                          timeout_ms,
+                          MACH_PORT_NULL);
+        if kr == MACH_RCV_TIMED_OUT {
+            return Ok(None);
+        }
+        ktry!(kr);
+        let header = msg.header;
+        if header.msgh_id != MACH_EXCEPTION_RAISE_ID {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unexpected exception message id {}", header.msgh_id)));
+        }
+        let code = msg.code;
+        let count = (msg.code_count as usize).min(code.len());
+        // From here on, dropping `pending` on error still replies, so the
+        // faulting thread doesn't hang.
+        let mut pending = PendingException {
+            exception: Exception {
+                pid: 0,
+                kind: ExceptionKind::from_raw(msg.exception),
+                codes: code[..count].to_vec(),
+            },
+            thread: ThreadPort::from_raw(msg.thread.name),
+            task: TaskPort::from_raw(msg.task.name),
+            reply_port: header.msgh_remote_port,
+            reply_bits: header.msgh_bits & 0x1f,
+            reply_id: header.msgh_id + 100,
+        };
+        pending.exception.pid = pending.task.pid()?;
+        Ok(Some(pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exception_kinds_round_trip() {
+        for raw in 0..16 {
+            assert_eq!(ExceptionKind::from_raw(raw).to_raw(), raw);
+        }
+        assert!(ExceptionMask::CRASHES.contains(ExceptionKind::BadAccess));
+        assert!(!ExceptionMask::CRASHES.contains(ExceptionKind::Resource));
+        assert_eq!((ExceptionMask::GUARD | ExceptionMask::RESOURCE).bits(), 0x1800);
+    }
+}
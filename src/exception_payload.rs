@@ -0,0 +1,400 @@
+//! Decoding the codes of `EXC_RESOURCE` and `EXC_GUARD` exceptions.
+//!
+//! Both pack a type, a flavor and a few numbers into their two 64-bit
+//! codes, as described in `<kern/exc_resource.h>` and
+//! `<kern/exc_guard.h>`. They are the most common exceptions that aren't
+//! crashes: a process over a resource limit, or one that closed a guarded
+//! file descriptor or misused a guarded Mach port.
+
+use std::time::Duration;
+
+use exception::{Exception, ExceptionKind};
+
+/// `RESOURCE_TYPE_*`
+const RESOURCE_TYPE_CPU: u8 = 1;
+const RESOURCE_TYPE_WAKEUPS: u8 = 2;
+const RESOURCE_TYPE_MEMORY: u8 = 3;
+const RESOURCE_TYPE_IO: u8 = 4;
+const RESOURCE_TYPE_THREADS: u8 = 5;
+const RESOURCE_TYPE_PORTS: u8 = 6;
+/// `FLAVOR_CPU_MONITOR_FATAL`: the process is killed after reporting.
+const FLAVOR_CPU_MONITOR_FATAL: u8 = 2;
+/// `FLAVOR_IO_LOGICAL_WRITES`, as opposed to physical ones.
+const FLAVOR_IO_LOGICAL_WRITES: u8 = 2;
+
+/// `GUARD_TYPE_*`
+const GUARD_TYPE_MACH_PORT: u8 = 1;
+const GUARD_TYPE_FD: u8 = 2;
+
+/// A decoded `EXC_RESOURCE` exception: a process went over one of the
+/// limits the kernel monitors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ResourceException {
+    /// The CPU usage monitor tripped; see `TaskPort::set_cpu_monitor`.
+    Cpu {
+        /// The limit, in percent of one CPU.
+        limit_percent: u8,
+        /// The interval the limit applies over.
+        interval: Duration,
+        /// The usage that tripped it, in percent of one CPU.
+        observed_percent: u8,
+        /// Whether the process is killed once the exception is handled.
+        fatal: bool,
+    },
+    /// The process woke the CPU up too often.
+    Wakeups {
+        /// The limit, in wakeups per second.
+        permitted_per_sec: u32,
+        /// The interval the limit applies over.
+        interval: Duration,
+        /// The rate that tripped it.
+        observed_per_sec: u32,
+    },
+    /// The process' footprint went over its high watermark.
+    Memory {
+        /// The watermark, in megabytes.
+        limit_mb: u32,
+    },
+    /// The process wrote too much to storage.
+    Io {
+        /// Whether the limit is on logical writes, which include ones that
+        /// never reach the disk, rather than physical ones.
+        logical: bool,
+        /// The limit, in megabytes.
+        limit_mb: u32,
+        /// The interval the limit applies over.
+        interval: Duration,
+        /// The amount that tripped it, in megabytes.
+        observed_mb: u32,
+    },
+    /// The process created too many threads.
+    Threads {
+        /// The limit.
+        limit: u32,
+    },
+    /// The process' IPC space filled up.
+    Ports {
+        /// The limit on port names.
+        limit: u32,
+    },
+    /// A resource this crate doesn't decode.
+    Other {
+        /// The `RESOURCE_TYPE_*`.
+        resource: u8,
+        /// The `FLAVOR_*`, whose meaning depends on the resource.
+        flavor: u8,
+    },
+}
+
+impl ResourceException {
+    /// Decode the codes of an `EXC_RESOURCE` exception.
+    fn decode(code: u64, subcode: u64) -> ResourceException {
+        let resource = ((code >> 61) & 0x7) as u8;
+        let flavor = ((code >> 58) & 0x7) as u8;
+        match resource {
+            RESOURCE_TYPE_CPU => {
+                ResourceException::Cpu {
+                    limit_percent: (code & 0x7f) as u8,
+                    interval: Duration::from_secs((code >> 7) & 0x1ff_ffff),
+                    observed_percent: (subcode & 0x7f) as u8,
+                    fatal: flavor == FLAVOR_CPU_MONITOR_FATAL,
+                }
+            }
+            RESOURCE_TYPE_WAKEUPS => {
+                ResourceException::Wakeups {
+                    permitted_per_sec: (code & 0xf_ffff) as u32,
+                    interval: Duration::from_secs((code >> 20) & 0xfff),
+                    observed_per_sec: (subcode & 0xf_ffff) as u32,
+                }
+            }
+            RESOURCE_TYPE_MEMORY => ResourceException::Memory { limit_mb: (code & 0x1fff) as u32 },
+            RESOURCE_TYPE_IO => {
+                ResourceException::Io {
+                    logical: flavor == FLAVOR_IO_LOGICAL_WRITES,
+                    limit_mb: (code & 0x7fff) as u32,
+                    interval: Duration::from_secs((code >> 15) & 0x1_ffff),
+                    observed_mb: (subcode & 0x7fff) as u32,
+                }
+            }
+            RESOURCE_TYPE_THREADS => ResourceException::Threads { limit: (code & 0x7fff) as u32 },
+            RESOURCE_TYPE_PORTS => ResourceException::Ports { limit: (code & 0xff_ffff) as u32 },
+            _ => {
+                ResourceException::Other {
+                    resource: resource,
+                    flavor: flavor,
+                }
+            }
+        }
+    }
+}
+
+/// What a process did to a guarded Mach port, from the `kGUARD_EXC_*`
+/// flavors of `<mach/port.h>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum PortGuardViolation {
+    /// Destroyed a guarded receive right.
+    Destroy,
+    /// Dropped the last reference to a guarded right.
+    ModRefs,
+    /// Changed the context of a guarded port.
+    SetContext,
+    /// Unguarded a port that wasn't guarded.
+    Unguarded,
+    /// Used the wrong guard value.
+    IncorrectGuard,
+    /// Tried to move an immovable right.
+    Immovable,
+    /// Broke the rules for a strict reply port.
+    StrictReply,
+    /// Used a right it doesn't have.
+    InvalidRight,
+    /// Used a name that isn't in its IPC space.
+    InvalidName,
+    /// Passed an invalid value.
+    InvalidValue,
+    /// Passed an invalid argument.
+    InvalidArgument,
+    /// Created a right that already exists.
+    RightExists,
+    /// Ran out of room in its IPC space.
+    KernNoSpace,
+    /// Made a call that failed.
+    KernFailure,
+    /// Made a call that ran out of a resource.
+    KernResource,
+    /// A flavor this crate doesn't know about.
+    Other(u32),
+}
+
+impl PortGuardViolation {
+    fn from_flavor(flavor: u32) -> PortGuardViolation {
+        match flavor {
+            1 => PortGuardViolation::Destroy,
+            2 => PortGuardViolation::ModRefs,
+            4 => PortGuardViolation::SetContext,
+            0x8 => PortGuardViolation::Unguarded,
+            0x10 => PortGuardViolation::IncorrectGuard,
+            0x20 => PortGuardViolation::Immovable,
+            0x40 => PortGuardViolation::StrictReply,
+            0x100 => PortGuardViolation::InvalidRight,
+            0x200 => PortGuardViolation::InvalidName,
+            0x400 => PortGuardViolation::InvalidValue,
+            0x800 => PortGuardViolation::InvalidArgument,
+            0x1000 => PortGuardViolation::RightExists,
+            0x2000 => PortGuardViolation::KernNoSpace,
+            0x4000 => PortGuardViolation::KernFailure,
+            0x8000 => PortGuardViolation::KernResource,
+            other => PortGuardViolation::Other(other),
+        }
+    }
+}
+
+/// What a process did to a guarded file descriptor, from the
+/// `kGUARD_EXC_*` flavors of `<sys/guarded.h>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum FdGuardViolation {
+    /// Closed it.
+    Close,
+    /// Duplicated it.
+    Dup,
+    /// Cleared its close-on-exec flag.
+    NoCloexec,
+    /// Sent it over a socket.
+    SocketIpc,
+    /// Made a fileport for it.
+    Fileport,
+    /// Used the wrong guard value.
+    Mismatch,
+    /// Wrote to it.
+    Write,
+    /// A flavor this crate doesn't know about.
+    Other(u32),
+}
+
+impl FdGuardViolation {
+    fn from_flavor(flavor: u32) -> FdGuardViolation {
+        match flavor {
+            1 => FdGuardViolation::Close,
+            2 => FdGuardViolation::Dup,
+            4 => FdGuardViolation::NoCloexec,
+            8 => FdGuardViolation::SocketIpc,
+            16 => FdGuardViolation::Fileport,
+            32 => FdGuardViolation::Mismatch,
+            64 => FdGuardViolation::Write,
+            other => FdGuardViolation::Other(other),
+        }
+    }
+}
+
+/// A decoded `EXC_GUARD` exception: a process broke the rules for a
+/// guarded resource.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum GuardException {
+    /// A guarded Mach port was misused.
+    MachPort {
+        /// The port's name in the process' IPC space.
+        name: u32,
+        /// What was done to it.
+        violation: PortGuardViolation,
+        /// Extra information, whose meaning depends on the violation.
+        payload: u64,
+    },
+    /// A guarded file descriptor was misused.
+    Fd {
+        /// The file descriptor.
+        fd: i32,
+        /// What was done to it.
+        violation: FdGuardViolation,
+        /// The descriptor's guard value.
+        guard: u64,
+    },
+    /// A guard type this crate doesn't decode, such as a vnode or virtual
+    /// memory guard.
+    Other {
+        /// The `GUARD_TYPE_*`.
+        guard_type: u8,
+        /// The flavor, whose meaning depends on the guard type.
+        flavor: u32,
+        /// What was guarded, such as a port name or a file descriptor.
+        target: u32,
+        /// Extra information, whose meaning depends on the guard type.
+        payload: u64,
+    },
+}
+
+impl GuardException {
+    /// Decode the codes of an `EXC_GUARD` exception.
+    fn decode(code: u64, subcode: u64) -> GuardException {
+        let guard_type = ((code >> 61) & 0x7) as u8;
+        let flavor = ((code >> 32) & 0x1fff_ffff) as u32;
+        let target = code as u32;
+        match guard_type {
+            GUARD_TYPE_MACH_PORT => {
+                GuardException::MachPort {
+                    name: target,
+                    violation: PortGuardViolation::from_flavor(flavor),
+                    payload: subcode,
+                }
+            }
+            GUARD_TYPE_FD => {
+                GuardException::Fd {
+                    fd: target as i32,
+                    violation: FdGuardViolation::from_flavor(flavor),
+                    guard: subcode,
+                }
+            }
+            _ => {
+                GuardException::Other {
+                    guard_type: guard_type,
+                    flavor: flavor,
+                    target: target,
+                    payload: subcode,
+                }
+            }
+        }
+    }
+}
+
+impl Exception {
+    /// The first two codes, as unsigned values.
+    fn code_pair(&self) -> (u64, u64) {
+        (self.codes.first().cloned().unwrap_or(0) as u64,
+         self.codes.get(1).cloned().unwrap_or(0) as u64)
+    }
+
+    /// Decode an `EXC_RESOURCE` exception, or `None` for any other kind.
+    pub fn resource(&self) -> Option<ResourceException> {
+        if self.kind != ExceptionKind::Resource {
+            return None;
+        }
+        let (code, subcode) = self.code_pair();
+        Some(ResourceException::decode(code, subcode))
+    }
+
+    /// Decode an `EXC_GUARD` exception, or `None` for any other kind.
+    pub fn guard(&self) -> Option<GuardException> {
+        if self.kind != ExceptionKind::Guard {
+            return None;
+        }
+        let (code, subcode) = self.code_pair();
+        Some(GuardException::decode(code, subcode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exception(kind: ExceptionKind, code: u64, subcode: u64) -> Exception {
+        Exception {
+            pid: 1,
+            kind: kind,
+            codes: vec![code as i64, subcode as i64],
+        }
+    }
+
+    #[test]
+    fn decode_resource_exceptions() {
+        // 50% over 180 seconds, exceeded at 73%.
+        let cpu = exception(ExceptionKind::Resource, 1 << 61 | 1 << 58 | 180 << 7 | 50, 73);
+        assert_eq!(cpu.resource(),
+                   Some(ResourceException::Cpu {
+                       limit_percent: 50,
+                       interval: Duration::from_secs(180),
+                       observed_percent: 73,
+                       fatal: false,
+                   }));
+        // 150 wakeups a second over 300 seconds, exceeded at 400.
+        let wakeups = exception(ExceptionKind::Resource, 2 << 61 | 1 << 58 | 300 << 20 | 150, 400);
+        assert_eq!(wakeups.resource(),
+                   Some(ResourceException::Wakeups {
+                       permitted_per_sec: 150,
+                       interval: Duration::from_secs(300),
+                       observed_per_sec: 400,
+                   }));
+        let memory = exception(ExceptionKind::Resource, 3 << 61 | 1 << 58 | 2048, 0);
+        assert_eq!(memory.resource(),
+                   Some(ResourceException::Memory { limit_mb: 2048 }));
+        // 2 GB of logical writes a day, exceeded at 3 GB.
+        let io = exception(ExceptionKind::Resource, 4 << 61 | 2 << 58 | 86400 << 15 | 2048, 3072);
+        assert_eq!(io.resource(),
+                   Some(ResourceException::Io {
+                       logical: true,
+                       limit_mb: 2048,
+                       interval: Duration::from_secs(86400),
+                       observed_mb: 3072,
+                   }));
+        assert_eq!(exception(ExceptionKind::Crash, 0, 0).resource(), None);
+    }
+
+    #[test]
+    fn decode_guard_exceptions() {
+        let port = exception(ExceptionKind::Guard, 1 << 61 | 1 << 32 | 0x1503, 0xdead);
+        assert_eq!(port.guard(),
+                   Some(GuardException::MachPort {
+                       name: 0x1503,
+                       violation: PortGuardViolation::Destroy,
+                       payload: 0xdead,
+                   }));
+        let fd = exception(ExceptionKind::Guard, 2 << 61 | 1 << 32 | 7, 0x1234);
+        assert_eq!(fd.guard(),
+                   Some(GuardException::Fd {
+                       fd: 7,
+                       violation: FdGuardViolation::Close,
+                       guard: 0x1234,
+                   }));
+        let vnode = exception(ExceptionKind::Guard, 4 << 61 | 2 << 32 | 3, 0);
+        assert_eq!(vnode.guard(),
+                   Some(GuardException::Other {
+                       guard_type: 4,
+                       flavor: 2,
+                       target: 3,
+                       payload: 0,
+                   }));
+        assert_eq!(port.resource(), None);
+    }
+}
@@ -0,0 +1,289 @@
+//! Handling a spawned child's exceptions from the parent.
+//!
+//! An `ExceptionServer` is installed on one task's exception ports, so it
+//! sees the exceptions that task's threads raise before they turn into
+//! signals. Each one arrives as an `ExceptionEvent` with the faulting
+//! thread suspended; the handler decides whether to resume it or pass the
+//! exception on to the next handler, which is what happens if the event is
+//! dropped.
+//!
+//! A server can also be installed on just some of a task's threads, such as
+//! worker threads a tool injected, with `attach_threads`. Each thread's own
+//! handlers take precedence over the task's, so the server sees those
+//! threads' exceptions while the rest of the task's still go wherever they
+//! went before.
+
+use std::io::Result;
+use std::os::raw::c_int;
+use std::time::Duration;
+
+use mach::kern_return::{kern_return_t, KERN_FAILURE, KERN_SUCCESS};
+use mach::message::mach_msg_type_number_t;
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::traps::mach_task_self;
+
+use exception::{EXCEPTION_DEFAULT, Exception, ExceptionMask, MACH_EXCEPTION_CODES,
+                PendingException, SavedHandler, THREAD_STATE_NONE, receive_exception,
+                receive_exception_timeout, saved_handlers};
+use {MachPort, TaskPort, ThreadPort, allocate_server_port, mach_port_mod_refs};
+
+extern "C" {
+    pub(crate) fn task_get_exception_ports(task: mach_port_t,
+                                exception_mask: u32,
+                                masks: *mut u32,
+                                masks_count: *mut mach_msg_type_number_t,
+                                old_handlers: *mut mach_port_t,
+                                old_behaviors: *mut c_int,
+                                old_flavors: *mut c_int)
+                                -> kern_return_t;
+    pub(crate) fn task_set_exception_ports(task: mach_port_t,
+                                exception_mask: u32,
+                                new_port: mach_port_t,
+                                behavior: c_int,
+                                new_flavor: c_int)
+                                -> kern_return_t;
+    fn thread_get_exception_ports(thread: mach_port_t,
+                                  exception_mask: u32,
+                                  masks: *mut u32,
+                                  masks_count: *mut mach_msg_type_number_t,
+                                  old_handlers: *mut mach_port_t,
+                                  old_behaviors: *mut c_int,
+                                  old_flavors: *mut c_int)
+                                  -> kern_return_t;
+    fn thread_set_exception_ports(thread: mach_port_t,
+                                  exception_mask: u32,
+                                  new_port: mach_port_t,
+                                  behavior: c_int,
+                                  new_flavor: c_int)
+                                  -> kern_return_t;
+}
+
+/// The handlers `thread` has installed for the exceptions in `mask`.
+fn thread_handlers(thread: &ThreadPort, mask: ExceptionMask) -> Result<Vec<SavedHandler>> {
+    saved_handlers(|masks, count, ports, behaviors, flavors| unsafe {
+        thread_get_exception_ports(thread.as_raw(),
+                                   mask.bits(),
+                                   masks,
+                                   count,
+                                   ports,
+                                   behaviors,
+                                   flavors)
+    })
+}
+
+/// An exception raised by a thread of the task an `ExceptionServer` is
+/// installed on. The thread stays suspended until the event is resumed or
+/// passed on; dropping it passes it on.
+pub struct ExceptionEvent {
+    pending: PendingException,
+}
+
+impl ExceptionEvent {
+    /// The exception.
+    pub fn exception(&self) -> &Exception {
+        &self.pending.exception
+    }
+
+    /// The thread that raised it.
+    pub fn thread(&self) -> &ThreadPort {
+        &self.pending.thread
+    }
+
+    /// The task the thread belongs to.
+    pub fn task(&self) -> &TaskPort {
+        &self.pending.task
+    }
+
+    /// Resume the thread as though the exception had been handled. Unless
+    /// the handler has changed the thread's state, it retries the
+    /// instruction that raised it.
+    pub fn resume(self) -> Result<()> {
+        self.pending.reply(KERN_SUCCESS)
+    }
+
+    /// Pass the exception on to the next handler, as though this server
+    /// weren't installed.
+    pub fn pass_on(self) -> Result<()> {
+        self.pending.reply(KERN_FAILURE)
+    }
+}
+
+/// A handler for the exceptions raised by one task, or by some of its
+/// threads.
+pub struct ExceptionServer {
+    port: MachPort,
+    task: TaskPort,
+    mask: ExceptionMask,
+    /// The task's previous handlers, if the server is installed on it.
+    saved: Vec<SavedHandler>,
+    /// The threads the server is installed on instead, each with its
+    /// previous handlers.
+    threads: Vec<(ThreadPort, Vec<SavedHandler>)>,
+}
+
+impl ExceptionServer {
+    /// Install a handler for the exceptions in `mask` on `task`, saving its
+    /// current handlers to restore when the server is dropped.
+    ///
+    /// This replaces any handler the task installed for itself, and child
+    /// processes it spawns from then on inherit it.
+    pub fn attach(task: &TaskPort, mask: ExceptionMask) -> Result<ExceptionServer> {
+        let task = task.try_clone()?;
+        let saved = saved_handlers(|masks, count, ports, behaviors, flavors| unsafe {
+            task_get_exception_ports(task.as_raw(),
+                                     mask.bits(),
+                                     masks,
+                                     count,
+                                     ports,
+                                     behaviors,
+                                     flavors)
+        })?;
+        let port = allocate_server_port()?;
+        let server = ExceptionServer {
+            port: port,
+            task: task,
+            mask: mask,
+            saved: saved,
+            threads: Vec::new(),
+        };
+        unsafe {
+            ktry!(task_set_exception_ports(server.task.as_raw(),
+                                           mask.bits(),
+                                           server.port.0,
+                                           EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES,
+                                           THREAD_STATE_NONE));
+        }
+        Ok(server)
+    }
+
+    /// Install a handler for the exceptions in `mask` on each of `threads`,
+    /// which must belong to `task`, saving their current handlers to
+    /// restore when the server is dropped.
+    ///
+    /// The task's other threads, and threads it creates later, keep the
+    /// task's handlers. Threads that exit simply stop raising exceptions.
+    pub fn attach_threads(task: &TaskPort,
+                          threads: &[ThreadPort],
+                          mask: ExceptionMask)
+                          -> Result<ExceptionServer> {
+        let mut server = ExceptionServer {
+            port: allocate_server_port()?,
+            task: task.try_clone()?,
+            mask: mask,
+            saved: Vec::new(),
+            threads: Vec::new(),
+        };
+        for thread in threads {
+            let saved = thread_handlers(thread, mask)?;
+            server.threads.push((thread.try_clone()?, saved));
+            unsafe {
+                ktry!(thread_set_exception_ports(thread.as_raw(),
+                                                 mask.bits(),
+                                                 server.port.0,
+                                                 EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES,
+                                                 THREAD_STATE_NONE));
+            }
+        }
+        Ok(server)
+    }
+
+    /// The exceptions this server handles.
+    pub fn mask(&self) -> ExceptionMask {
+        self.mask
+    }
+
+    /// The task this server is installed on.
+    pub fn task(&self) -> &TaskPort {
+        &self.task
+    }
+
+    /// Block until one of the task's threads raises an exception.
+    pub fn receive(&self) -> Result<ExceptionEvent> {
+        Ok(ExceptionEvent { pending: receive_exception(self.port.0)? })
+    }
+
+    /// Like `receive`, but give up and return `None` if no exception is
+    /// raised within `timeout`.
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<Option<ExceptionEvent>> {
+        let pending = receive_exception_timeout(self.port.0, Some(timeout))?;
+        Ok(pending.map(|pending| ExceptionEvent { pending: pending }))
+    }
+}
+
+impl TaskPort {
+    /// The exceptions the task has a handler installed for, whether its
+    /// own, one it inherited, or an `ExceptionServer`.
+    pub fn handled_exceptions(&self) -> Result<ExceptionMask> {
+        let handlers = saved_handlers(|masks, count, ports, behaviors, flavors| unsafe {
+            task_get_exception_ports(self.as_raw(),
+                                     ExceptionMask::ALL.bits(),
+                                     masks,
+                                     count,
+                                     ports,
+                                     behaviors,
+                                     flavors)
+        })?;
+        let bits = handlers.iter()
+            .filter(|handler| handler.port.0 != MACH_PORT_NULL)
+            .fold(0, |bits, handler| bits | handler.mask);
+        Ok(ExceptionMask::from_bits(bits))
+    }
+}
+
+impl ThreadPort {
+    /// The exceptions the thread has a handler of its own installed for.
+    /// Exceptions it has none for go to its task's handlers.
+    pub fn handled_exceptions(&self) -> Result<ExceptionMask> {
+        let bits = thread_handlers(self, ExceptionMask::ALL)?
+            .iter()
+            .filter(|handler| handler.port.0 != MACH_PORT_NULL)
+            .fold(0, |bits, handler| bits | handler.mask);
+        Ok(ExceptionMask::from_bits(bits))
+    }
+
+    /// Remove the thread's own handlers for the exceptions in `mask`, so
+    /// that they go to its task's handlers instead.
+    pub fn clear_exception_ports(&self, mask: ExceptionMask) -> Result<()> {
+        unsafe {
+            ktry!(thread_set_exception_ports(self.as_raw(),
+                                             mask.bits(),
+                                             MACH_PORT_NULL,
+                                             EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES,
+                                             THREAD_STATE_NONE));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExceptionServer {
+    fn drop(&mut self) {
+        // Put the previous handlers back, then destroy our receive right.
+        // Ignore failures: if the task is gone, there is nothing to restore.
+        unsafe {
+            for handler in &self.saved {
+                task_set_exception_ports(self.task.as_raw(),
+                                         handler.mask,
+                                         handler.port.0,
+                                         handler.behavior,
+                                         handler.flavor);
+            }
+            for (thread, saved) in &self.threads {
+                // Threads usually had no handlers of their own, and then
+                // there is nothing to restore, so remove ours first.
+                thread_set_exception_ports(thread.as_raw(),
+                                           self.mask.bits(),
+                                           MACH_PORT_NULL,
+                                           EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES,
+                                           THREAD_STATE_NONE);
+                for handler in saved {
+                    thread_set_exception_ports(thread.as_raw(),
+                                               handler.mask,
+                                               handler.port.0,
+                                               handler.behavior,
+                                               handler.flavor);
+                }
+            }
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Getting a child's task port again after it executes another image.
+//!
+//! A child spawned through a wrapper (a shell script, `env`, `nice`) checks
+//! in from its `pre_exec` hook with the task port of the wrapper, and that
+//! port stops working once the wrapper executes the program it wraps: the
+//! kernel gives the new image a task port of its own. A child spawned with
+//! `MachPortBroker::spawn_following_execs` inherits the broker's service
+//! name in an environment variable, and whenever `check_in` runs in it
+//! after an `exec`, the broker replaces the task port it remembers for the
+//! child's pid with the new one, so `task_port_for_pid` always returns the
+//! port of the image the child is running now.
+//!
+//! Programs that can't be changed to call `check_in` themselves can have it
+//! called for them: built with
+//! `cargo rustc --release --features exec-check-in-dylib --crate-type cdylib`,
+//! the crate is a library that calls it from a constructor, which
+//! `insert_dylib` has `dyld` load into every image the child executes.
+//! `dyld` ignores `DYLD_INSERT_LIBRARIES` for programs with the hardened
+//! runtime and for those protected by System Integrity Protection, which
+//! include `/bin/sh` and `/usr/bin/env`, and the kernel drops it from the
+//! environment of the latter, so anything they execute in turn doesn't
+//! check in either.
+
+use std::env;
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::process::Command;
+
+use child;
+use {EXEC_MSG_ID, ServiceName};
+
+/// The environment variable holding the broker's service name.
+pub(crate) const SERVICE_NAME_VAR: &'static str = "SPAWN_TASK_PORT_EXEC_SERVICE";
+
+/// The environment variable `dyld` loads extra libraries from.
+const INSERT_LIBRARIES_VAR: &'static str = "DYLD_INSERT_LIBRARIES";
+
+/// Send this process' task port to the broker that spawned it, or the
+/// process that executed it, if that was spawned by
+/// `MachPortBroker::spawn_following_execs`, returning whether it was.
+///
+/// The environment variable is left in place, so that the images this one
+/// executes can check in too.
+pub fn check_in() -> Result<bool> {
+    let name = match env::var(SERVICE_NAME_VAR) {
+        Ok(name) => name,
+        Err(_) => return Ok(false),
+    };
+    let name = ServiceName::from_str(&name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid broker service name"))?;
+    child::send_to(name.as_c_str(), EXEC_MSG_ID, 0)?;
+    Ok(true)
+}
+
+/// Have `dyld` load the library at `dylib` into every image `command`
+/// executes, after any libraries it would already load.
+///
+/// `dylib` should be the crate built as a library with the
+/// `exec-check-in-dylib` feature, or another that calls `check_in` when it
+/// is loaded.
+pub fn insert_dylib<'a>(command: &'a mut Command, dylib: &Path) -> &'a mut Command {
+    // A variable the command sets or removes overrides the inherited one.
+    let mut libraries = match command.get_envs().find(|&(key, _)| key == INSERT_LIBRARIES_VAR) {
+            Some((_, value)) => value.map(|value| value.to_owned()),
+            None => env::var_os(INSERT_LIBRARIES_VAR),
+        }
+        .unwrap_or_else(OsString::new);
+    if !libraries.is_empty() {
+        libraries.push(":");
+    }
+    libraries.push(dylib);
+    command.env(INSERT_LIBRARIES_VAR, libraries)
+}
+
+/// Run `check_in` when the library is loaded, before the program's `main`.
+#[cfg(feature = "exec-check-in-dylib")]
+#[used]
+#[link_section = "__DATA,__mod_init_func"]
+static CHECK_IN_ON_LOAD: extern "C" fn() = check_in_on_load;
+
+#[cfg(feature = "exec-check-in-dylib")]
+extern "C" fn check_in_on_load() {
+    // There's no one to report a failure to, and the program should run
+    // whether or not its parent gets its task port.
+    let _ = check_in();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_dylib_appends() {
+        let mut command = Command::new("true");
+        command.env(INSERT_LIBRARIES_VAR, "/tmp/first.dylib");
+        insert_dylib(&mut command, Path::new("/tmp/second.dylib"));
+        let value = command.get_envs()
+            .find(|&(key, _)| key == INSERT_LIBRARIES_VAR)
+            .and_then(|(_, value)| value);
+        assert_eq!(value.and_then(|value| value.to_str()),
+                   Some("/tmp/first.dylib:/tmp/second.dylib"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Why a child exited, in more detail than `ExitStatus` gives.
+//!
+//! When a process dies of a signal that dumps core, the kernel raises
+//! `EXC_CRASH` on its way out, with the exception that caused the signal,
+//! if any, packed into the first code along with the signal number. That's
+//! where crash reports get their "EXC_BAD_ACCESS (SIGSEGV)" line from.
+//! `ChildWithTask::watch_for_crash` installs a handler that records it
+//! before passing it on to the crash reporter, and
+//! `ChildWithTask::exit_details` combines it with the exit status.
+
+use std::io::Result;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use exception::{Exception, ExceptionKind, ExceptionMask};
+use exception_server::ExceptionServer;
+use TaskPort;
+
+/// How long the watcher waits for an exception before checking whether
+/// it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A decoded `EXC_CRASH` exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CrashDetails {
+    /// The exception that led to the crash, or `None` if the process was
+    /// just sent a signal, as `abort` does.
+    pub exception: Option<ExceptionKind>,
+    /// The low 20 bits of the original exception's first code, such as
+    /// `KERN_INVALID_ADDRESS` for a bad access.
+    pub code: u32,
+    /// The original exception's second code, such as the faulting address.
+    pub subcode: i64,
+    /// The signal the process died of.
+    pub signal: i32,
+    /// The ID of the thread that crashed, as in `ThreadPort::id`.
+    pub thread_id: Option<u64>,
+}
+
+impl CrashDetails {
+    /// Decode an `EXC_CRASH` exception, or return `None` for any other kind.
+    pub(crate) fn from_exception(exception: &Exception,
+                                 thread_id: Option<u64>)
+                                 -> Option<CrashDetails> {
+        if exception.kind != ExceptionKind::Crash {
+            return None;
+        }
+        let code = exception.codes.first().cloned().unwrap_or(0) as u64;
+        let original = ((code >> 20) & 0xf) as i32;
+        Some(CrashDetails {
+            exception: if original == 0 {
+                None
+            } else {
+                Some(ExceptionKind::from_raw(original))
+            },
+            code: (code & 0xfffff) as u32,
+            subcode: exception.codes.get(1).cloned().unwrap_or(0),
+            signal: ((code >> 24) & 0xff) as i32,
+            thread_id: thread_id,
+        })
+    }
+}
+
+/// How a child exited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ExitDetails {
+    /// The status `wait` returned, which serializes as the raw wait status.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_status"))]
+    pub status: ExitStatus,
+    /// The signal that killed the child, if one did.
+    pub signal: Option<i32>,
+    /// What the child crashed with, if it crashed while
+    /// `watch_for_crash` was watching.
+    pub crash: Option<CrashDetails>,
+}
+
+impl ExitDetails {
+    pub(crate) fn new(status: ExitStatus, crash: Option<CrashDetails>) -> ExitDetails {
+        ExitDetails {
+            status: status,
+            signal: status.signal(),
+            crash: crash,
+        }
+    }
+}
+
+/// `ExitStatus` isn't `Serialize`, so serialize the raw wait status.
+#[cfg(feature = "serde")]
+fn serialize_status<S>(status: &ExitStatus, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    where S: ::serde::Serializer
+{
+    serializer.serialize_i32(status.into_raw())
+}
+
+/// A thread that records a task's `EXC_CRASH`, which stops when dropped.
+#[derive(Debug)]
+pub(crate) struct CrashWatch {
+    crash: Arc<Mutex<Option<CrashDetails>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CrashWatch {
+    pub(crate) fn new(task: &TaskPort) -> Result<CrashWatch> {
+        // The kernel delivers EXC_CRASH synchronously while the process
+        // exits, so something has to be receiving by then or the child
+        // never finishes exiting.
+        let server = ExceptionServer::attach(task, ExceptionMask::CRASH)?;
+        let crash = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let crash = crash.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    let event = match server.receive_timeout(POLL_INTERVAL) {
+                        Ok(Some(event)) => event,
+                        Ok(None) => continue,
+                        Err(_) => return,
+                    };
+                    let thread_id = event.thread().id().ok();
+                    let details = CrashDetails::from_exception(event.exception(), thread_id);
+                    *crash.lock().unwrap() = details;
+                    // Let the crash reporter see it too.
+                    let _ = event.pass_on();
+                }
+            })
+        };
+        Ok(CrashWatch {
+            crash: crash,
+            stop: stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The crash, if there has been one.
+    pub(crate) fn crash(&self) -> Option<CrashDetails> {
+        *self.crash.lock().unwrap()
+    }
+}
+
+impl Drop for CrashWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_crash_codes() {
+        // EXC_BAD_ACCESS (SIGSEGV), KERN_INVALID_ADDRESS at 0x10.
+        let exception = Exception {
+            pid: 1,
+            kind: ExceptionKind::Crash,
+            codes: vec![(11 << 24 | 1 << 20 | 1) as i64, 0x10],
+        };
+        assert_eq!(CrashDetails::from_exception(&exception, Some(7)),
+                   Some(CrashDetails {
+                       exception: Some(ExceptionKind::BadAccess),
+                       code: 1,
+                       subcode: 0x10,
+                       signal: 11,
+                       thread_id: Some(7),
+                   }));
+        let exception = Exception {
+            pid: 1,
+            kind: ExceptionKind::Crash,
+            codes: vec![(6 << 24) as i64, 0],
+        };
+        let crash = CrashDetails::from_exception(&exception, None).unwrap();
+        assert_eq!(crash.exception, None);
+        assert_eq!(crash.signal, 6);
+        let exception = Exception {
+            pid: 1,
+            kind: ExceptionKind::Guard,
+            codes: vec![0, 0],
+        };
+        assert_eq!(CrashDetails::from_exception(&exception, None), None);
+    }
+}
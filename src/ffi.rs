@@ -0,0 +1,147 @@
+//! A C API, enabled by the `ffi` feature.
+//!
+//! Build a static library to link into C, Objective-C or Swift code with:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type staticlib
+//! ```
+//!
+//! The matching header is `swift/Sources/CSpawnTaskPort/spawn_task_port.h`.
+//! Functions that can fail return 0 on success, or an `errno` value on
+//! failure (`EIO` for Mach errors, which have no `errno` equivalent).
+
+use libc::{self, c_char, c_int, pid_t, size_t};
+use std::ffi::{CStr, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::ptr;
+use std::slice;
+
+use mach::port::mach_port_t;
+
+use {ChildWithTask, CommandSpawnWithTask};
+
+/// A spawned child process and its task port. Opaque to C.
+#[allow(non_camel_case_types)]
+pub struct stp_child(ChildWithTask);
+
+fn error_code(e: &io::Error) -> c_int {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+unsafe fn c_str<'a>(s: *const c_char) -> &'a OsStr {
+    OsStr::from_bytes(CStr::from_ptr(s).to_bytes())
+}
+
+/// Spawn `path` with the `nargs` arguments in `args`, storing a new
+/// `stp_child` in `*out_child` on success. The child inherits the caller's
+/// stdio. Free the result with `stp_child_free`.
+///
+/// # Safety
+///
+/// `path` and each of the `nargs` pointers in `args` must point to a
+/// NUL-terminated string, and `out_child` to writable storage for a
+/// pointer. `args` may only be `NULL` if `nargs` is 0. None of them are
+/// used after this returns.
+#[no_mangle]
+pub unsafe extern "C" fn stp_spawn(path: *const c_char,
+                                   args: *const *const c_char,
+                                   nargs: size_t,
+                                   out_child: *mut *mut stp_child)
+                                   -> c_int {
+    if path.is_null() || out_child.is_null() || (args.is_null() && nargs != 0) {
+        return libc::EINVAL;
+    }
+    let mut command = Command::new(c_str(path));
+    if nargs != 0 {
+        command.args(slice::from_raw_parts(args, nargs).iter().map(|&arg| c_str(arg)));
+    }
+    match command.spawn_with_task() {
+        Ok(child) => {
+            *out_child = Box::into_raw(Box::new(stp_child(child)));
+            0
+        }
+        Err(e) => {
+            *out_child = ptr::null_mut();
+            error_code(&e)
+        }
+    }
+}
+
+/// The child's process ID.
+///
+/// # Safety
+///
+/// `child` must be a pointer that `stp_spawn` stored, and that hasn't been
+/// passed to `stp_child_free`.
+#[no_mangle]
+pub unsafe extern "C" fn stp_child_pid(child: *const stp_child) -> pid_t {
+    (*child).0.id() as pid_t
+}
+
+/// The child's task port, which remains owned by `child`. Use
+/// `mach_port_mod_refs` to add a reference if it needs to outlive it.
+///
+/// # Safety
+///
+/// `child` must be a pointer that `stp_spawn` stored, and that hasn't been
+/// passed to `stp_child_free`. The returned port name is only valid until
+/// it is.
+#[no_mangle]
+pub unsafe extern "C" fn stp_child_task_port(child: *const stp_child) -> mach_port_t {
+    (*child).0.task_port().as_raw()
+}
+
+/// Forcibly kill the child.
+///
+/// # Safety
+///
+/// `child` must be a pointer that `stp_spawn` stored, and that hasn't been
+/// passed to `stp_child_free`. No other thread may be using it at the same
+/// time.
+#[no_mangle]
+pub unsafe extern "C" fn stp_child_kill(child: *mut stp_child) -> c_int {
+    match (*child).0.kill() {
+        Ok(()) => 0,
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Wait for the child to exit, storing its raw wait status (as from
+/// `waitpid`) in `*out_status` if that isn't `NULL`.
+///
+/// # Safety
+///
+/// `child` must be a pointer that `stp_spawn` stored, and that hasn't been
+/// passed to `stp_child_free`. No other thread may be using it at the same
+/// time. `out_status` must be `NULL` or point to writable storage for an
+/// `int`.
+#[no_mangle]
+pub unsafe extern "C" fn stp_child_wait(child: *mut stp_child, out_status: *mut c_int) -> c_int {
+    match (*child).0.wait() {
+        Ok(status) => {
+            if !out_status.is_null() {
+                *out_status = status.into_raw();
+            }
+            0
+        }
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Free `child`, deallocating its task port. This doesn't wait for or kill
+/// the child process. Passing `NULL` is allowed.
+///
+/// # Safety
+///
+/// `child` must be `NULL` or a pointer that `stp_spawn` stored, and that
+/// hasn't been passed to `stp_child_free` already. It can't be used after
+/// this returns.
+#[no_mangle]
+pub unsafe extern "C" fn stp_child_free(child: *mut stp_child) {
+    if !child.is_null() {
+        drop(Box::from_raw(child));
+    }
+}
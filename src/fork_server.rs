@@ -0,0 +1,256 @@
+//! An AFL-style fork server that delivers each forked worker's task port.
+//!
+//! Spawning a fresh process for every iteration means paying for `exec`,
+//! dynamic linking and program start-up each time. With a fork server, the
+//! parent spawns one cooperative child, which initializes itself once and
+//! then calls `serve`. After that, every `ForkServer::fork` call asks the
+//! server to `fork` a worker, which sends its task port to the parent
+//! before `serve` returns in it and it runs the iteration.
+//!
+//! The parent and the server talk over a pair of pipes, and workers check
+//! in with a `MachPortBroker` that the parent owns. Both are passed to the
+//! server in environment variables.
+
+use libc;
+use std::env;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{self, Child, Command, ExitStatus};
+
+use {ChildCheckIn, MachPortBroker, ServiceName, TaskPort};
+
+/// The environment variable holding the broker's service name.
+const SERVICE_NAME_VAR: &'static str = "SPAWN_TASK_PORT_FORK_SERVER_SERVICE";
+/// The environment variable holding the server's control and status fds.
+const FDS_VAR: &'static str = "SPAWN_TASK_PORT_FORK_SERVER_FDS";
+
+/// The message the server sends once it is ready to fork workers.
+const HELLO: [u8; 4] = *b"STPF";
+/// The command the parent sends to ask for a new worker.
+const FORK: [u8; 4] = *b"FORK";
+
+/// The parent's handle to a running fork server.
+pub struct ForkServer {
+    broker: MachPortBroker,
+    server: Child,
+    server_task_port: TaskPort,
+    /// The write end of the control pipe.
+    control: File,
+    /// The read end of the status pipe.
+    status: File,
+    /// Whether a worker has been forked whose status hasn't been read yet.
+    worker_running: bool,
+}
+
+/// Create a pipe, returning `(read, write)` ends. Neither end is
+/// close-on-exec.
+fn pipe() -> Result<(RawFd, RawFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok((fds[0], fds[1]))
+}
+
+fn set_cloexec(fd: RawFd) -> Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `f` has data to read, or is at EOF, without blocking.
+fn readable(f: &File) -> Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: f.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut pollfd, 1, 0) } {
+        -1 => Err(Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+fn read_u32(f: &mut File) -> Result<u32> {
+    let mut buf = [0; 4];
+    f.read_exact(&mut buf)?;
+    Ok(u32::from_ne_bytes(buf))
+}
+
+impl ForkServer {
+    /// Spawn `command` as a fork server, and wait until it is ready to fork
+    /// workers.
+    ///
+    /// The command must run a program that calls `serve`.
+    pub fn spawn(command: &mut Command) -> Result<ForkServer> {
+        let broker = MachPortBroker::new()?;
+        let (control_read, control_write) = pipe()?;
+        let (status_read, status_write) = pipe()?;
+        // Wrap everything in `File`s right away so they get closed on error.
+        let control = unsafe { File::from_raw_fd(control_write) };
+        let mut status = unsafe { File::from_raw_fd(status_read) };
+        let server_control = unsafe { File::from_raw_fd(control_read) };
+        let server_status = unsafe { File::from_raw_fd(status_write) };
+        // Only the server's ends of the pipes should be inherited.
+        set_cloexec(control_write)?;
+        set_cloexec(status_read)?;
+
+        let (server, server_task_port) = {
+            let command = command.env(SERVICE_NAME_VAR, broker.service_name().as_str())
+                .env(FDS_VAR, format!("{},{}", control_read, status_write));
+            broker.spawn(command)?
+        };
+        // Close our copies of the server's ends, so that we see EOF if the
+        // server exits.
+        drop(server_control);
+        drop(server_status);
+
+        let mut hello = [0; 4];
+        status.read_exact(&mut hello)?;
+        if hello != HELLO {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  "fork server sent an unexpected greeting"));
+        }
+        Ok(ForkServer {
+            broker: broker,
+            server: server,
+            server_task_port: server_task_port,
+            control: control,
+            status: status,
+            worker_running: false,
+        })
+    }
+
+    /// The fork server process itself.
+    pub fn server(&mut self) -> &mut Child {
+        &mut self.server
+    }
+
+    /// The fork server's Mach task port, which remains owned by the
+    /// `ForkServer`.
+    pub fn server_task_port(&self) -> &TaskPort {
+        &self.server_task_port
+    }
+
+    /// Ask the server to fork a new worker, returning both the worker's pid
+    /// as well as a `TaskPort` that owns its Mach task port.
+    ///
+    /// Only one worker runs at a time: `wait` for the previous worker
+    /// before forking the next one. Fails if the worker or the server exits
+    /// before the worker has checked in, in which case there is nothing to
+    /// `wait` for.
+    pub fn fork(&mut self) -> Result<(u32, TaskPort)> {
+        if self.worker_running {
+            return Err(Error::new(ErrorKind::Other,
+                                  "the previous worker must be waited for first"));
+        }
+        self.control.write_all(&FORK)?;
+        let pid = read_u32(&mut self.status)?;
+        self.worker_running = true;
+        let (server, status, worker_running) =
+            (&mut self.server, &mut self.status, &mut self.worker_running);
+        let task_port = self.broker.receive_for_pid(pid as libc::c_int, || {
+            // The server sends the worker's status once it has exited, and
+            // closes the pipe if it exits itself.
+            if readable(status)? {
+                read_u32(status)?;
+                *worker_running = false;
+                return Err(Error::new(ErrorKind::Other, "the worker exited before checking in"));
+            }
+            if server.try_wait()?.is_some() {
+                return Err(Error::new(ErrorKind::Other, "the fork server exited"));
+            }
+            Ok(())
+        })?;
+        Ok((pid, task_port))
+    }
+
+    /// Wait for the most recently forked worker to exit, returning its exit
+    /// status.
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        if !self.worker_running {
+            return Err(Error::new(ErrorKind::Other, "no worker is running"));
+        }
+        let status = read_u32(&mut self.status)?;
+        self.worker_running = false;
+        Ok(ExitStatus::from_raw(status as i32))
+    }
+}
+
+impl Drop for ForkServer {
+    fn drop(&mut self) {
+        // Closing the control pipe would be enough for a server that's
+        // waiting for a command, but not for one stuck in a worker.
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+    }
+}
+
+/// Run the fork server loop, if this process was spawned by
+/// `ForkServer::spawn`.
+///
+/// If it was not, this returns immediately. Otherwise this only returns in
+/// forked workers, after the worker has sent its task port to the parent;
+/// the server itself exits once the parent drops its `ForkServer`.
+///
+/// Call this from the child's `main` once any expensive initialization has
+/// been done. Since it forks, the process should be single-threaded at
+/// that point.
+pub fn serve() -> Result<()> {
+    let (name, fds) = match (env::var(SERVICE_NAME_VAR), env::var(FDS_VAR)) {
+        (Ok(name), Ok(fds)) => (name, fds),
+        _ => return Ok(()),
+    };
+    // Don't let workers' own children think they're fork servers.
+    env::remove_var(SERVICE_NAME_VAR);
+    env::remove_var(FDS_VAR);
+
+    let invalid = || Error::new(ErrorKind::InvalidInput, "invalid fork server environment");
+    let check_in = ChildCheckIn::new(ServiceName::from_str(&name).ok_or_else(invalid)?);
+    let mut fds = fds.split(',').map(|fd| fd.parse::<RawFd>());
+    let (control, status) = match (fds.next(), fds.next(), fds.next()) {
+        (Some(Ok(control)), Some(Ok(status)), None) => (control, status),
+        _ => return Err(invalid()),
+    };
+    set_cloexec(control)?;
+    set_cloexec(status)?;
+    let mut control = unsafe { File::from_raw_fd(control) };
+    let mut status = unsafe { File::from_raw_fd(status) };
+
+    status.write_all(&HELLO)?;
+    loop {
+        let mut command = [0; 4];
+        match control.read_exact(&mut command) {
+            Ok(()) => {}
+            // The parent went away.
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => process::exit(0),
+            Err(e) => return Err(e),
+        }
+        if command != FORK {
+            return Err(Error::new(ErrorKind::InvalidData, "unknown fork server command"));
+        }
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(Error::last_os_error());
+        }
+        if pid == 0 {
+            // In the worker.
+            drop(control);
+            drop(status);
+            unsafe {
+                check_in.send_task_port()?;
+            }
+            return Ok(());
+        }
+        status.write_all(&(pid as u32).to_ne_bytes())?;
+        let mut wait_status = 0;
+        if unsafe { libc::waitpid(pid, &mut wait_status, 0) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        status.write_all(&(wait_status as u32).to_ne_bytes())?;
+    }
+}
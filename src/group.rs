@@ -0,0 +1,67 @@
+//! Support for `command_group`'s process groups, enabled by the
+//! `command-group` feature.
+
+use std::io::Result;
+use std::process::Command;
+
+use command_group::{CommandGroup, GroupChild};
+
+use descendant;
+use {MachPortBroker, SpawnedProcess, TaskPort, spawn_with_check_in};
+
+impl SpawnedProcess for GroupChild {
+    fn pid(&self) -> u32 {
+        self.id()
+    }
+
+    fn has_exited(&mut self) -> Result<bool> {
+        Ok(self.try_wait()?.is_some())
+    }
+
+    fn abandon(&mut self) {
+        let _ = self.kill();
+        let _ = self.wait();
+    }
+}
+
+/// An extension to `std::process::Command` to spawn a process in a new
+/// process group, as `command_group::CommandGroup::group_spawn` does, and get
+/// back access to its Mach task port.
+///
+/// The task port is for the group leader, which is the process this
+/// spawns. Other processes that the leader starts join its group, but
+/// don't send their task ports anywhere. To get those of members that
+/// cooperate, spawn the leader with
+/// `MachPortBroker::group_spawn_with_members` instead.
+pub trait GroupSpawnWithTask {
+    /// Executes the command as the leader of a new process group, returning
+    /// both the `GroupChild` as well as the leader's Mach task port.
+    fn group_spawn_get_task_port(&mut self) -> Result<(GroupChild, TaskPort)>;
+}
+
+impl GroupSpawnWithTask for Command {
+    fn group_spawn_get_task_port(&mut self) -> Result<(GroupChild, TaskPort)> {
+        // `group_spawn` uses `CommandExt::process_group` rather than a
+        // `pre_exec` hook of its own, so there's nothing for ours to
+        // conflict with.
+        let (child, task_port) = spawn_with_check_in(self, |command| command.group_spawn())?;
+        Ok((child, unsafe { TaskPort::from_raw(task_port) }))
+    }
+}
+
+impl MachPortBroker {
+    /// Like `GroupSpawnWithTask::group_spawn_get_task_port`, but check the
+    /// leader in with the broker, and let the members of its group check
+    /// in too, by calling `descendant::check_in`.
+    ///
+    /// Members that do are listed by `descendants` under the leader's pid.
+    /// As with `spawn_with_descendants`, they are placed by their parent
+    /// pids rather than their process group, so only the leader's own
+    /// descendants can check in, whether or not they're still in its group.
+    pub fn group_spawn_with_members(&self,
+                                    command: &mut Command)
+                                    -> Result<(GroupChild, TaskPort)> {
+        let command = command.env(descendant::SERVICE_NAME_VAR, self.service_name().as_str());
+        self.spawn_using(command, |command| command.group_spawn())
+    }
+}
@@ -0,0 +1,224 @@
+//! A spawned child process together with its task port.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ExitStatus};
+use std::time::Duration;
+
+use libc;
+
+use exit_details::{CrashDetails, CrashWatch, ExitDetails};
+use kqueue::{EVFILT_PROC, Kqueue, NOTE_EXIT};
+use TaskPort;
+
+/// Where a child is at, going by both `waitpid` and its task port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChildStatus {
+    /// The child is running, and its task port still names its task.
+    Running,
+    /// The child has exited, without a recorded crash.
+    Exited(ExitStatus),
+    /// The child crashed while `watch_for_crash` was watching.
+    Crashed(ExitStatus, CrashDetails),
+    /// The child hasn't exited, but its task port no longer names its task,
+    /// as happens when it execs a setuid program.
+    PortStale,
+}
+
+/// The resources a child used, from `wait4`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ResourceUsage {
+    /// The peak resident set size, in bytes.
+    pub max_rss: u64,
+    /// Time spent in user mode.
+    pub user_time: Duration,
+    /// Time spent in the kernel.
+    pub system_time: Duration,
+}
+
+impl ResourceUsage {
+    fn from_rusage(usage: &libc::rusage) -> ResourceUsage {
+        let duration = |time: libc::timeval| {
+            Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+        };
+        ResourceUsage {
+            // Darwin reports this in bytes, not kilobytes.
+            max_rss: usage.ru_maxrss as u64,
+            user_time: duration(usage.ru_utime),
+            system_time: duration(usage.ru_stime),
+        }
+    }
+}
+
+/// A child process spawned by this crate, which owns both the `Child` and
+/// the child's task port.
+#[derive(Debug)]
+pub struct ChildWithTask {
+    child: Child,
+    task_port: TaskPort,
+    crash: Option<CrashWatch>,
+    /// The exit status, if the child was reaped by `wait_with_rusage`
+    /// rather than by `child`.
+    status: Option<ExitStatus>,
+}
+
+impl ChildWithTask {
+    /// Wrap a `Child` and its task port.
+    pub fn new(child: Child, task_port: TaskPort) -> ChildWithTask {
+        ChildWithTask {
+            child: child,
+            task_port: task_port,
+            crash: None,
+            status: None,
+        }
+    }
+
+    /// The child's process ID.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// The child's task port.
+    pub fn task_port(&self) -> &TaskPort {
+        &self.task_port
+    }
+
+    /// The underlying `Child`.
+    pub fn child(&self) -> &Child {
+        &self.child
+    }
+
+    /// The underlying `Child`, mutably, for access to its stdio handles.
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Resume the child, if it was started suspended with
+    /// `PosixSpawnOptions::start_suspended` or suspended since through its
+    /// task port.
+    pub fn resume(&self) -> Result<()> {
+        self.task_port.resume()
+    }
+
+    /// Split this into the `Child` and its task port.
+    ///
+    /// If `wait_with_rusage` has reaped the child, the `Child` can no
+    /// longer wait for it.
+    pub fn into_parts(self) -> (Child, TaskPort) {
+        (self.child, self.task_port)
+    }
+
+    /// Forcibly kill the child. See `Child::kill`.
+    pub fn kill(&mut self) -> Result<()> {
+        if self.status.is_some() {
+            // Its pid may belong to another process by now.
+            return Err(Error::new(ErrorKind::InvalidInput, "the child has already been reaped"));
+        }
+        self.child.kill()
+    }
+
+    /// Wait for the child to exit. See `Child::wait`.
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        match self.status {
+            Some(status) => Ok(status),
+            None => self.child.wait(),
+        }
+    }
+
+    /// Wait at most `timeout` for the child to exit, returning its status,
+    /// or `None` if it is still running.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>> {
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+        if let Some(status) = self.child.try_wait()? {
+            return Ok(Some(status));
+        }
+        let kqueue = Kqueue::new()?;
+        match kqueue.add(self.child.id() as usize, EVFILT_PROC, NOTE_EXIT) {
+            Ok(()) => {
+                kqueue.wait(Some(timeout))?;
+            }
+            // It exited after `try_wait`.
+            Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            Err(e) => return Err(e),
+        }
+        self.child.try_wait()
+    }
+
+    /// The child's status, without waiting.
+    ///
+    /// Crashes are only told apart from other deaths by signal if
+    /// `watch_for_crash` was called before they happened.
+    pub fn status(&mut self) -> Result<ChildStatus> {
+        if let Some(details) = self.exit_details()? {
+            return Ok(match details.crash {
+                Some(crash) => ChildStatus::Crashed(details.status, crash),
+                None => ChildStatus::Exited(details.status),
+            });
+        }
+        match self.task_port.pid() {
+            Ok(pid) if pid == self.child.id() => Ok(ChildStatus::Running),
+            _ => Ok(ChildStatus::PortStale),
+        }
+    }
+
+    /// Wait for the child to exit and reap it with `wait4`, returning the
+    /// resources it used along with its exit status.
+    ///
+    /// Like `wait`, this closes the child's stdin first. It fails if the
+    /// child has already been reaped, since its resource usage went with it.
+    pub fn wait_with_rusage(&mut self) -> Result<(ExitStatus, ResourceUsage)> {
+        if self.status.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "the child has already been reaped"));
+        }
+        drop(self.child.stdin.take());
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        let pid = self.child.id() as libc::pid_t;
+        loop {
+            if unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } >= 0 {
+                break;
+            }
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        let status = ExitStatus::from_raw(status);
+        self.status = Some(status);
+        Ok((status, ResourceUsage::from_rusage(&usage)))
+    }
+
+    /// Start recording how the child crashes, if it does, for
+    /// `exit_details`.
+    ///
+    /// This takes over the child's `EXC_CRASH` handler, passing each crash
+    /// on to the crash reporter once it has been recorded.
+    pub fn watch_for_crash(&mut self) -> Result<()> {
+        if self.crash.is_none() {
+            self.crash = Some(CrashWatch::new(&self.task_port)?);
+        }
+        Ok(())
+    }
+
+    /// How the child exited, or `None` if it hasn't yet.
+    ///
+    /// The details only include a crash if `watch_for_crash` was called
+    /// before it happened.
+    pub fn exit_details(&mut self) -> Result<Option<ExitDetails>> {
+        let status = match self.status {
+            Some(status) => status,
+            None => {
+                match self.child.try_wait()? {
+                    Some(status) => status,
+                    None => return Ok(None),
+                }
+            }
+        };
+        let crash = self.crash.as_ref().and_then(|watch| watch.crash());
+        Ok(Some(ExitDetails::new(status, crash)))
+    }
+}
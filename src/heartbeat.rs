@@ -0,0 +1,255 @@
+//! Liveness heartbeats from cooperative children.
+//!
+//! Watching for a child to exit says nothing about a child that is stuck.
+//! A child that calls `start_heartbeat` pings its parent's `Heartbeat` on
+//! an interval from a thread of its own, so a parent that stops hearing
+//! from it knows it has hung, or at least that it has stopped scheduling
+//! threads. The `Heartbeat` registers its own bootstrap service and passes
+//! its name to the child in an environment variable.
+//!
+//! Pings carry nothing but their `msgh_id`, and anything in the bootstrap
+//! namespace that learns the name can send them, so they are a liveness
+//! signal, not an authenticated one.
+
+use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use mach::bootstrap::bootstrap_look_up;
+use mach::kern_return::KERN_SUCCESS;
+use mach::message::{MACH_MSGH_BITS, MACH_MSGH_BITS_COMPLEX, MACH_MSG_TYPE_COPY_SEND,
+                    MACH_RCV_INVALID_NAME, MACH_RCV_MSG, MACH_RCV_PORT_DIED, MACH_RCV_TIMEOUT,
+                    MACH_SEND_MSG, MACH_SEND_TIMED_OUT, MACH_SEND_TIMEOUT, mach_msg,
+                    mach_msg_destroy, mach_msg_header_t, mach_msg_trailer_t};
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+use mach::traps::mach_task_self;
+
+use registration::create_service;
+use {MachPort, ServiceName, mach_port_mod_refs};
+
+/// The environment variable holding the `Heartbeat`'s service name.
+const SERVICE_NAME_VAR: &'static str = "SPAWN_TASK_PORT_HEARTBEAT_SERVICE";
+
+/// The `msgh_id` of a ping.
+const HEARTBEAT_MSG_ID: i32 = 0x5354_4842;
+
+/// How often the receiving thread checks whether it should stop, and
+/// whether the child has gone stale.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[repr(C)]
+struct PingMessage {
+    header: mach_msg_header_t,
+    trailer: mach_msg_trailer_t,
+}
+
+type StaleCallback = Box<dyn FnMut() + Send>;
+
+/// What the parent's receiving thread shares with the `Heartbeat`.
+struct State {
+    started: Instant,
+    last: Option<Instant>,
+    /// The staleness threshold and callback, and whether the callback has
+    /// fired since the last ping.
+    on_stale: Option<(Duration, StaleCallback, bool)>,
+}
+
+/// The parent's end of a heartbeat: a registered service that a child
+/// pings, and a thread that records the pings, which stops when dropped.
+pub struct Heartbeat {
+    port: Arc<MachPort>,
+    name: ServiceName,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Register a new heartbeat service and start listening for pings.
+    pub fn new() -> Result<Heartbeat> {
+        let name = ServiceName::random()?;
+        let port = Arc::new(create_service(name.as_c_str())?);
+        let state = Arc::new(Mutex::new(State {
+            started: Instant::now(),
+            last: None,
+            on_stale: None,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let port = port.clone();
+            let state = state.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    // Only stop once the port is gone.
+                    let pinged = match receive_ping(port.0) {
+                        Ok(pinged) => pinged,
+                        Err(_) => return,
+                    };
+                    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                    let now = Instant::now();
+                    if pinged {
+                        state.last = Some(now);
+                    }
+                    let since = now - state.last.unwrap_or(state.started);
+                    if let Some((max_age, ref mut callback, ref mut fired)) = state.on_stale {
+                        if pinged {
+                            *fired = false;
+                        } else if since > max_age && !*fired {
+                            *fired = true;
+                            callback();
+                        }
+                    }
+                }
+            })
+        };
+        Ok(Heartbeat {
+            port: port,
+            name: name,
+            state: state,
+            stop: stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Pass the service name to the child that `command` spawns, for
+    /// `start_heartbeat`.
+    pub fn configure<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        command.env(SERVICE_NAME_VAR, self.name.as_str())
+    }
+
+    /// When the last ping arrived, or `None` if none has yet.
+    pub fn last_heartbeat(&self) -> Option<Instant> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).last
+    }
+
+    /// Whether the child hasn't pinged within `max_age`. Before the first
+    /// ping, this counts from when the `Heartbeat` was created.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.last.unwrap_or(state.started).elapsed() > max_age
+    }
+
+    /// Call `callback` on the receiving thread whenever the child goes
+    /// `max_age` without pinging. It is called once each time the child
+    /// goes stale, not again until after it has pinged, and replaces any
+    /// earlier callback.
+    pub fn on_stale<F>(&self, max_age: Duration, callback: F)
+        where F: FnMut() + Send + 'static
+    {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.on_stale = Some((max_age, Box::new(callback), false));
+    }
+}
+
+impl fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Heartbeat")
+            .field("service_name", &self.name.as_str())
+            .field("last_heartbeat", &self.last_heartbeat())
+            .finish()
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // Destroying the receive right unregisters the service.
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
+
+/// Wait up to `POLL_INTERVAL` for a ping, returning whether one arrived.
+///
+/// Anyone can send to the port, so anything other than a ping is dropped,
+/// and only a port that is gone fails this.
+fn receive_ping(port: mach_port_t) -> Result<bool> {
+    unsafe {
+        let mut msg: PingMessage = mem::zeroed();
+        let kr = mach_msg(&mut msg.header,
+                          MACH_RCV_MSG | MACH_RCV_TIMEOUT,
+                          0,
+                          mem::size_of::<PingMessage>() as u32,
+                          port,
+                          POLL_INTERVAL.as_millis() as u32,
+                          MACH_PORT_NULL);
+        match kr {
+            KERN_SUCCESS => {}
+            MACH_RCV_PORT_DIED | MACH_RCV_INVALID_NAME => ktry!(kr),
+            // Without `MACH_RCV_LARGE`, the kernel has already destroyed a
+            // message too large for a ping.
+            _ => return Ok(false),
+        }
+        if msg.header.msgh_id != HEARTBEAT_MSG_ID ||
+           msg.header.msgh_bits & MACH_MSGH_BITS_COMPLEX != 0 {
+            mach_msg_destroy(&mut msg.header);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// Start pinging the parent's `Heartbeat` every `interval` from a new
+/// thread, if this process was spawned with `Heartbeat::configure`.
+///
+/// Returns whether it was. The thread stops once the parent's `Heartbeat`
+/// is gone.
+pub fn start_heartbeat(interval: Duration) -> Result<bool> {
+    let name = match env::var(SERVICE_NAME_VAR) {
+        Ok(name) => name,
+        Err(_) => return Ok(false),
+    };
+    // Don't let our own children ping in our place.
+    env::remove_var(SERVICE_NAME_VAR);
+    let name = CString::new(name)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid heartbeat environment"))?;
+    let port = unsafe {
+        let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+        ktry!(task_get_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, &mut bootstrap_port));
+        let bootstrap_port = MachPort(bootstrap_port);
+        let mut port: mach_port_t = MACH_PORT_NULL;
+        ktry!(bootstrap_look_up(bootstrap_port.0, name.as_ptr(), &mut port));
+        MachPort(port)
+    };
+    thread::spawn(move || {
+        loop {
+            let mut header = mach_msg_header_t {
+                msgh_bits: MACH_MSGH_BITS(MACH_MSG_TYPE_COPY_SEND, 0),
+                msgh_size: mem::size_of::<mach_msg_header_t>() as u32,
+                msgh_remote_port: port.0,
+                msgh_local_port: MACH_PORT_NULL,
+                msgh_voucher_port: MACH_PORT_NULL,
+                msgh_id: HEARTBEAT_MSG_ID,
+            };
+            // Don't block if the parent has fallen behind: a full queue
+            // already shows we're alive.
+            let kr = unsafe {
+                mach_msg(&mut header,
+                         MACH_SEND_MSG | MACH_SEND_TIMEOUT,
+                         header.msgh_size,
+                         0,
+                         MACH_PORT_NULL,
+                         0,
+                         MACH_PORT_NULL)
+            };
+            if kr != KERN_SUCCESS && kr != MACH_SEND_TIMED_OUT {
+                return;
+            }
+            thread::sleep(interval);
+        }
+    });
+    Ok(true)
+}
@@ -0,0 +1,79 @@
+//! A tiny program for examples and tests to spawn.
+//!
+//! Tests that need a child to hold a task port for often re-run their own
+//! executable with a special argument, which doesn't work once a test
+//! harness owns `main`. With the `helper` feature, the crate builds
+//! `spawn-task-port-helper` instead, a program that blocks reading its
+//! stdin, and exits once that is closed, so the child lives exactly as
+//! long as the parent holds its stdin open.
+//!
+//! `helper_path` finds the program, either where the
+//! `SPAWN_TASK_PORT_HELPER` environment variable says, or next to the
+//! current executable or in the directory above it, which covers binaries
+//! and the test executables Cargo builds in `deps`.
+
+use std::env;
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The name of the helper program.
+pub const HELPER_NAME: &'static str = "spawn-task-port-helper";
+
+/// The environment variable that overrides where the helper is.
+pub const HELPER_PATH_VAR: &'static str = "SPAWN_TASK_PORT_HELPER";
+
+/// Where to look for the helper, given the current executable.
+fn candidates(current_exe: &Path) -> Vec<PathBuf> {
+    let mut file_name = OsString::from(HELPER_NAME);
+    if !env::consts::EXE_EXTENSION.is_empty() {
+        file_name.push(".");
+        file_name.push(env::consts::EXE_EXTENSION);
+    }
+    current_exe.ancestors()
+        .skip(1)
+        .take(2)
+        .map(|dir| dir.join(&file_name))
+        .collect()
+}
+
+/// The path of the helper program.
+///
+/// This fails with `ErrorKind::NotFound` if it hasn't been built, with
+/// `cargo build --features helper`.
+pub fn helper_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os(HELPER_PATH_VAR) {
+        return Ok(PathBuf::from(path));
+    }
+    candidates(&env::current_exe()?)
+        .into_iter()
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            Error::new(ErrorKind::NotFound,
+                       format!("{} not found; build it with the `helper` feature, or set {}",
+                               HELPER_NAME,
+                               HELPER_PATH_VAR))
+        })
+}
+
+/// A `Command` for the helper, with its stdin piped, so that it runs until
+/// the child's stdin is dropped.
+pub fn helper_command() -> Result<Command> {
+    let mut command = Command::new(helper_path()?);
+    command.stdin(Stdio::piped());
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_beside_and_above_the_executable() {
+        let found = candidates(Path::new("/build/target/debug/deps/tests-0123"));
+        assert_eq!(found,
+                   vec![PathBuf::from("/build/target/debug/deps/spawn-task-port-helper"),
+                        PathBuf::from("/build/target/debug/spawn-task-port-helper")]);
+    }
+}
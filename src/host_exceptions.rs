@@ -0,0 +1,144 @@
+//! Catching children's crashes with host-level exception ports.
+//!
+//! Installing an exception port on a child's task races its early exit:
+//! a child that crashes before the parent gets around to it goes
+//! unnoticed. The host-level exception ports see every task's exceptions
+//! that weren't handled at the thread or task level, so a privileged parent
+//! can install one before spawning anything and never miss a crash.
+//!
+//! This affects the whole machine. While a `HostExceptionMonitor` exists,
+//! every unhandled exception on the host comes to it instead of to the
+//! previous handler, which is normally the crash reporter; exceptions it
+//! receives are passed on as unhandled, so the faulting process still dies,
+//! but no crash report is written for it. The previous handlers are
+//! restored when the monitor is dropped. It needs root, and `available`
+//! should be checked first.
+
+use std::io::Result;
+use std::os::raw::c_int;
+
+use mach::kern_return::{kern_return_t, KERN_FAILURE, KERN_SUCCESS};
+use mach::message::mach_msg_type_number_t;
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::traps::mach_task_self;
+
+use exception::{EXCEPTION_DEFAULT, Exception, ExceptionMask, MACH_EXCEPTION_CODES, SavedHandler,
+                THREAD_STATE_NONE, receive_exception, saved_handlers};
+use {MachPort, allocate_server_port, mach_port_mod_refs};
+
+extern "C" {
+    pub(crate) fn mach_host_self() -> mach_port_t;
+    fn host_get_host_priv_port(host: mach_port_t, host_priv: *mut mach_port_t) -> kern_return_t;
+    fn host_get_exception_ports(host_priv: mach_port_t,
+                                exception_mask: u32,
+                                masks: *mut u32,
+                                masks_count: *mut mach_msg_type_number_t,
+                                old_handlers: *mut mach_port_t,
+                                old_behaviors: *mut c_int,
+                                old_flavors: *mut c_int)
+                                -> kern_return_t;
+    fn host_set_exception_ports(host_priv: mach_port_t,
+                                exception_mask: u32,
+                                new_port: mach_port_t,
+                                behavior: c_int,
+                                new_flavor: c_int)
+                                -> kern_return_t;
+}
+
+/// Get the host privileged port, which only root can.
+pub(crate) fn host_priv_port() -> Result<MachPort> {
+    unsafe {
+        let host = MachPort(mach_host_self());
+        let mut host_priv: mach_port_t = MACH_PORT_NULL;
+        ktry!(host_get_host_priv_port(host.0, &mut host_priv));
+        Ok(MachPort(host_priv))
+    }
+}
+
+/// Get the handlers currently installed for `mask`.
+fn host_saved_handlers(host_priv: mach_port_t, mask: ExceptionMask) -> Result<Vec<SavedHandler>> {
+    saved_handlers(|masks, count, ports, behaviors, flavors| unsafe {
+        host_get_exception_ports(host_priv, mask.bits(), masks, count, ports, behaviors, flavors)
+    })
+}
+
+/// A host-level exception handler, which receives the unhandled exceptions
+/// of every process on the host.
+pub struct HostExceptionMonitor {
+    port: MachPort,
+    host_priv: MachPort,
+    saved: Vec<SavedHandler>,
+}
+
+impl HostExceptionMonitor {
+    /// Whether this process may install a host-level exception handler.
+    ///
+    /// This checks that it can get the host privileged port and read the
+    /// current handlers. On some systems, replacing them is refused even
+    /// so, in which case `install` fails.
+    pub fn available() -> bool {
+        host_priv_port()
+            .and_then(|host_priv| host_saved_handlers(host_priv.0, ExceptionMask::CRASHES))
+            .is_ok()
+    }
+
+    /// Install a handler for the exceptions in `mask`, saving the current
+    /// handlers to restore when the monitor is dropped.
+    pub fn install(mask: ExceptionMask) -> Result<HostExceptionMonitor> {
+        let host_priv = host_priv_port()?;
+        let saved = host_saved_handlers(host_priv.0, mask)?;
+        let port = allocate_server_port()?;
+        let monitor = HostExceptionMonitor {
+            port: port,
+            host_priv: host_priv,
+            saved: saved,
+        };
+        unsafe {
+            ktry!(host_set_exception_ports(monitor.host_priv.0,
+                                           mask.bits(),
+                                           monitor.port.0,
+                                           EXCEPTION_DEFAULT | MACH_EXCEPTION_CODES,
+                                           THREAD_STATE_NONE));
+        }
+        Ok(monitor)
+    }
+
+    /// Block until any process on the host raises an unhandled exception.
+    ///
+    /// The exception is passed on as unhandled before this returns, so the
+    /// process dies as it would have otherwise.
+    pub fn receive(&self) -> Result<Exception> {
+        let pending = receive_exception(self.port.0)?;
+        let exception = pending.exception.clone();
+        pending.reply(KERN_FAILURE)?;
+        Ok(exception)
+    }
+
+    /// Block until the process `pid` raises an unhandled exception, passing
+    /// on other processes' exceptions untouched.
+    pub fn receive_for_pid(&self, pid: u32) -> Result<Exception> {
+        loop {
+            let exception = self.receive()?;
+            if exception.pid == pid {
+                return Ok(exception);
+            }
+        }
+    }
+}
+
+impl Drop for HostExceptionMonitor {
+    fn drop(&mut self) {
+        // Put the previous handlers back, then destroy our receive right.
+        // Ignore failures, there's not much that can be done here.
+        unsafe {
+            for handler in &self.saved {
+                host_set_exception_ports(self.host_priv.0,
+                                         handler.mask,
+                                         handler.port.0,
+                                         handler.behavior,
+                                         handler.flavor);
+            }
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
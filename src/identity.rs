@@ -0,0 +1,247 @@
+//! Task identity tokens, available on macOS 11 and later.
+//!
+//! Instead of sending its task control port, a child can send an identity
+//! token: a port that names the task without granting any access to it.
+//! The parent then converts the token to a task port of whichever flavor it
+//! actually needs, so a full control right never has to travel in a
+//! message unless it's asked for. `spawn_with_identity_token` returns the
+//! token itself, while `SpawnOptions::task_flavor` has any spawn that takes
+//! options convert it on receipt and return the task port.
+//!
+//! Tokens can also be passed on: `IdentityToken::forward_to` sends a copy
+//! to a third process, such as a monitoring daemon, which receives it with
+//! an `IdentityTokenReceiver` and converts it to an inspect or read port
+//! itself. Neither hop carries a control right.
+//!
+//! The token calls are looked up at runtime, so this module builds and
+//! loads on older releases and just reports that tokens are unavailable.
+
+use std::ffi::CString;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::sync::Mutex;
+
+use mach::bootstrap::bootstrap_look_up;
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::message::MACH_MSG_TYPE_COPY_SEND;
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+use mach::traps::mach_task_self;
+
+use registration::create_service;
+use {IDENTITY_TOKEN_MSG_ID, MachPort, RecvMessage, ServiceName, TaskPort, mach_port_mod_refs,
+     receive_task_port, send_check_in};
+
+/// `task_create_identity_token`, as called from the child.
+pub(crate) type CreateIdentityToken = unsafe extern "C" fn(task: mach_port_t,
+                                                           token: *mut mach_port_t)
+                                                           -> kern_return_t;
+
+/// `task_identity_token_get_task_port`.
+type GetTaskPort = unsafe extern "C" fn(token: mach_port_t,
+                                        flavor: u32,
+                                        task_port: *mut mach_port_t)
+                                        -> kern_return_t;
+
+/// Look up a function by its NUL-terminated name. `F` must be a function
+/// pointer type.
+pub(crate) unsafe fn lookup<F: Copy>(symbol: &[u8]) -> Option<F> {
+    let f = libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr() as *const c_char);
+    if f.is_null() {
+        None
+    } else {
+        Some(mem::transmute_copy(&f))
+    }
+}
+
+/// `task_create_identity_token`, if the running OS has it.
+pub(crate) fn create_identity_token_fn() -> Option<CreateIdentityToken> {
+    unsafe { lookup(b"task_create_identity_token\0") }
+}
+
+fn get_task_port_fn() -> Option<GetTaskPort> {
+    unsafe { lookup(b"task_identity_token_get_task_port\0") }
+}
+
+/// Whether the running OS supports identity tokens.
+pub(crate) fn available() -> bool {
+    create_identity_token_fn().is_some() && get_task_port_fn().is_some()
+}
+
+/// The error returned when identity tokens aren't available.
+pub(crate) fn unavailable() -> Error {
+    Error::new(ErrorKind::Other, "task identity tokens need macOS 11 or later")
+}
+
+/// Convert the identity token `token`, whose send right is consumed, to a
+/// task port of `flavor`.
+pub(crate) fn token_to_task_port(token: mach_port_t, flavor: TaskFlavor) -> Result<TaskPort> {
+    unsafe { IdentityToken::from_raw(token) }.task_port(flavor)
+}
+
+/// The kinds of task port an identity token can be converted to, from the
+/// most to the least privileged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum TaskFlavor {
+    /// A full task control port, like `mach_task_self`.
+    Control,
+    /// A port that can read the task's memory and state, but not change it.
+    Read,
+    /// A port that can only inspect the task, for `task_info` and the like.
+    Inspect,
+    /// A task name port, which only identifies the task.
+    Name,
+}
+
+impl TaskFlavor {
+    /// The `TASK_FLAVOR_*` value from `<mach/task_special_ports.h>`.
+    fn to_raw(self) -> u32 {
+        match self {
+            TaskFlavor::Control => 0,
+            TaskFlavor::Read => 1,
+            TaskFlavor::Inspect => 2,
+            TaskFlavor::Name => 3,
+        }
+    }
+}
+
+/// A send right to a task identity token, which is deallocated when the
+/// `IdentityToken` is dropped.
+pub struct IdentityToken(MachPort);
+
+impl IdentityToken {
+    /// Take ownership of a send right to an identity token.
+    ///
+    /// # Safety
+    ///
+    /// The right will be deallocated when the `IdentityToken` is dropped,
+    /// so the caller must actually own it.
+    pub unsafe fn from_raw(port: mach_port_t) -> IdentityToken {
+        IdentityToken(MachPort(port))
+    }
+
+    /// The raw port, which remains owned by this `IdentityToken`.
+    pub fn as_raw(&self) -> mach_port_t {
+        (self.0).0
+    }
+
+    /// Give up ownership of the send right, returning the raw port.
+    pub fn into_raw(self) -> mach_port_t {
+        self.0.into_raw()
+    }
+
+    /// Get a task port of the given flavor for the task this token
+    /// identifies.
+    ///
+    /// Whether this is allowed is decided here, by the calling process'
+    /// own privileges, rather than by whoever created the token.
+    pub fn task_port(&self, flavor: TaskFlavor) -> Result<TaskPort> {
+        let get_task_port = get_task_port_fn().ok_or_else(unavailable)?;
+        unsafe {
+            let mut port: mach_port_t = MACH_PORT_NULL;
+            ktry!(get_task_port(self.as_raw(), flavor.to_raw(), &mut port));
+            Ok(TaskPort::from_raw(port))
+        }
+    }
+
+    /// Send a copy of this token to the process that registered the
+    /// bootstrap service `service_name`, normally with an
+    /// `IdentityTokenReceiver`.
+    pub fn forward_to(&self, service_name: &str) -> Result<()> {
+        let name = CString::new(service_name)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "service name contains a NUL"))?;
+        // Tell the receiver whose token this is. Converting to a name port
+        // needs no privileges.
+        let pid = self.task_port(TaskFlavor::Name)?.pid()?;
+        unsafe {
+            let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+            ktry!(task_get_special_port(mach_task_self(),
+                                        TASK_BOOTSTRAP_PORT,
+                                        &mut bootstrap_port));
+            let mut remote: mach_port_t = MACH_PORT_NULL;
+            ktry!(bootstrap_look_up(bootstrap_port, name.as_ptr(), &mut remote));
+            let remote = MachPort(remote);
+            ktry!(send_check_in(remote.0,
+                                self.as_raw(),
+                                MACH_MSG_TYPE_COPY_SEND,
+                                IDENTITY_TOKEN_MSG_ID,
+                                pid as c_int));
+        }
+        Ok(())
+    }
+}
+
+impl TaskPort {
+    /// Create an identity token for this task, to hand to other processes
+    /// in place of the task port itself.
+    pub fn identity_token(&self) -> Result<IdentityToken> {
+        let create_identity_token = create_identity_token_fn().ok_or_else(unavailable)?;
+        unsafe {
+            let mut token: mach_port_t = MACH_PORT_NULL;
+            ktry!(create_identity_token(self.as_raw(), &mut token));
+            Ok(IdentityToken::from_raw(token))
+        }
+    }
+}
+
+/// A registered bootstrap service that receives identity tokens sent with
+/// `IdentityToken::forward_to`.
+pub struct IdentityTokenReceiver {
+    port: MachPort,
+    name: String,
+    msg: Mutex<RecvMessage>,
+}
+
+impl IdentityTokenReceiver {
+    /// Register a receiver under a new random service name.
+    pub fn new() -> Result<IdentityTokenReceiver> {
+        IdentityTokenReceiver::with_name(ServiceName::random()?.as_str())
+    }
+
+    /// Register a receiver under the service name `name`.
+    pub fn with_name(name: &str) -> Result<IdentityTokenReceiver> {
+        let c_name = CString::new(name)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "service name contains a NUL"))?;
+        let port = create_service(&c_name)?;
+        Ok(IdentityTokenReceiver {
+            port: port,
+            name: name.to_owned(),
+            msg: Mutex::new(unsafe { mem::zeroed() }),
+        })
+    }
+
+    /// The service name to pass to `IdentityToken::forward_to`.
+    pub fn service_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Block until a token arrives, returning the pid of the task it
+    /// identifies along with the token.
+    pub fn receive(&self) -> Result<(u32, IdentityToken)> {
+        let mut msg = self.msg.lock().unwrap_or_else(|e| e.into_inner());
+        let port = unsafe { MachPort(receive_task_port(self.port.0, &mut msg)?) };
+        if msg.header.msgh_id != IDENTITY_TOKEN_MSG_ID {
+            return Err(Error::new(ErrorKind::InvalidData, "received a message without a token"));
+        }
+        Ok((msg.pid as u32, IdentityToken(port)))
+    }
+}
+
+impl Drop for IdentityTokenReceiver {
+    fn drop(&mut self) {
+        // Destroying the receive right unregisters the service; `self.port`
+        // then deallocates the send right.
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
+
+impl fmt::Debug for IdentityToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("IdentityToken").field(&self.as_raw()).finish()
+    }
+}
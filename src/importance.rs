@@ -0,0 +1,134 @@
+//! Lending a child the parent's importance and QoS.
+//!
+//! A freshly spawned helper runs at default priority even when a
+//! foreground app is blocked waiting on it. Mach importance fixes that the
+//! same way XPC does: while a message sent with `MACH_SEND_IMPORTANCE`
+//! sits in a port owned by a task, the task is boosted, and a voucher sent
+//! along with it carries the sender's QoS and resource attribution.
+//!
+//! The handshake only goes from the child to the parent, so it can't carry
+//! a donation. Instead, `TaskPort::donate_importance` uses the task port
+//! to make a receive right in the child's IPC space and queues such a
+//! message on it. The child never has to receive it: the boost lasts until
+//! the `ImportanceDonation` is dropped, which destroys the port. The kernel
+//! only honours donations to tasks that receive importance, such as
+//! adaptive daemons and XPC services; any other child just ignores it.
+
+use std::fmt;
+use std::io::Result;
+use std::mem;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::mach_port::{mach_port_allocate, mach_port_extract_right};
+use mach::message::{MACH_MSG_TIMEOUT_NONE, MACH_MSG_TYPE_COPY_SEND, MACH_MSG_TYPE_MAKE_SEND,
+                    MACH_SEND_MSG, MACH_SEND_TIMEOUT, mach_msg, mach_msg_header_t};
+use mach::port::{mach_port_name_t, mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+
+use {MachPort, TaskPort, mach_port_mod_refs};
+
+/// `MACH_SEND_IMPORTANCE` from `<mach/message.h>`: donate the sender's
+/// importance to the receiver for as long as the message is queued.
+const MACH_SEND_IMPORTANCE: i32 = 0x0008_0000;
+/// The `msgh_id` of a donation, which nothing ever looks at.
+const DONATION_MSG_ID: i32 = 0x696d_7074;
+
+extern "C" {
+    fn mach_thread_self() -> mach_port_t;
+    fn thread_get_mach_voucher(thread: mach_port_t,
+                               which: u32,
+                               voucher: *mut mach_port_t)
+                               -> kern_return_t;
+}
+
+/// The calling thread's voucher, which carries its QoS and importance, or
+/// `None` if it has none.
+fn current_voucher() -> Result<Option<MachPort>> {
+    unsafe {
+        let thread = MachPort(mach_thread_self());
+        let mut voucher = MACH_PORT_NULL;
+        ktry!(thread_get_mach_voucher(thread.0, 0, &mut voucher));
+        Ok(if voucher == MACH_PORT_NULL {
+            None
+        } else {
+            Some(MachPort(voucher))
+        })
+    }
+}
+
+/// A boost lent to a child, which lasts until this is dropped.
+pub struct ImportanceDonation {
+    task: TaskPort,
+    /// The port's name in the child's IPC space.
+    remote_name: mach_port_name_t,
+    /// A send right to the port in ours.
+    port: MachPort,
+}
+
+impl fmt::Debug for ImportanceDonation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImportanceDonation")
+            .field("task", &self.task)
+            .field("remote_name", &self.remote_name)
+            .finish()
+    }
+}
+
+impl TaskPort {
+    /// Lend the task the calling thread's importance and voucher until the
+    /// returned `ImportanceDonation` is dropped.
+    pub fn donate_importance(&self) -> Result<ImportanceDonation> {
+        let task = self.try_clone()?;
+        let mut remote_name = MACH_PORT_NULL;
+        unsafe {
+            ktry!(mach_port_allocate(task.as_raw(), MACH_PORT_RIGHT_RECEIVE, &mut remote_name));
+        }
+        // From here on, dropping `donation` destroys the port again.
+        let mut donation = ImportanceDonation {
+            task: task,
+            remote_name: remote_name,
+            port: MachPort(MACH_PORT_NULL),
+        };
+        unsafe {
+            let mut acquired = 0;
+            ktry!(mach_port_extract_right(donation.task.as_raw(),
+                                          remote_name,
+                                          MACH_MSG_TYPE_MAKE_SEND,
+                                          &mut donation.port.0,
+                                          &mut acquired));
+        }
+        let voucher = current_voucher()?;
+        let (voucher_port, voucher_bits) = match voucher {
+            Some(ref voucher) => (voucher.0, MACH_MSG_TYPE_COPY_SEND << 16),
+            None => (MACH_PORT_NULL, 0),
+        };
+        let mut header = mach_msg_header_t {
+            msgh_bits: MACH_MSG_TYPE_COPY_SEND | voucher_bits,
+            msgh_size: mem::size_of::<mach_msg_header_t>() as u32,
+            msgh_remote_port: donation.port.0,
+            msgh_local_port: MACH_PORT_NULL,
+            msgh_voucher_port: voucher_port,
+            msgh_id: DONATION_MSG_ID,
+        };
+        unsafe {
+            // The port is new and empty, so the send never has to wait.
+            ktry!(mach_msg(&mut header,
+                           MACH_SEND_MSG | MACH_SEND_TIMEOUT | MACH_SEND_IMPORTANCE,
+                           header.msgh_size,
+                           0,
+                           MACH_PORT_NULL,
+                           MACH_MSG_TIMEOUT_NONE,
+                           MACH_PORT_NULL));
+        }
+        Ok(donation)
+    }
+}
+
+impl Drop for ImportanceDonation {
+    fn drop(&mut self) {
+        // Destroying the receive right discards the queued message, which
+        // ends the boost. If the child is gone, so is the port.
+        unsafe {
+            mach_port_mod_refs(self.task.as_raw(), self.remote_name, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Typed wrappers for `task_info`.
+
+use std::io::Result;
+use std::mem;
+use std::time::Duration;
+
+use mach::kern_return::KERN_SUCCESS;
+use mach::message::mach_msg_type_number_t;
+use mach::task::task_info;
+use mach::task_info::{MACH_TASK_BASIC_INFO, TASK_EXTMOD_INFO, TASK_THREAD_TIMES_INFO, TASK_VM_INFO,
+                      task_flavor_t};
+use mach::vm_types::{integer_t, natural_t};
+
+use TaskPort;
+use capabilities;
+
+/// `time_value_t`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct time_value_t {
+    seconds: integer_t,
+    microseconds: integer_t,
+}
+
+impl time_value_t {
+    fn to_duration(self) -> Duration {
+        Duration::new(self.seconds as u64, self.microseconds as u32 * 1000)
+    }
+}
+
+/// `struct mach_task_basic_info`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct mach_task_basic_info {
+    virtual_size: u64,
+    resident_size: u64,
+    resident_size_max: u64,
+    user_time: time_value_t,
+    system_time: time_value_t,
+    policy: integer_t,
+    suspend_count: integer_t,
+}
+
+/// `struct task_thread_times_info`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct task_thread_times_info {
+    user_time: time_value_t,
+    system_time: time_value_t,
+}
+
+/// `struct task_vm_info`, up to the fields added in revision 1.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct task_vm_info {
+    virtual_size: u64,
+    region_count: integer_t,
+    page_size: integer_t,
+    resident_size: u64,
+    resident_size_peak: u64,
+    device: u64,
+    device_peak: u64,
+    internal: u64,
+    internal_peak: u64,
+    external: u64,
+    external_peak: u64,
+    reusable: u64,
+    reusable_peak: u64,
+    purgeable_volatile_pmap: u64,
+    purgeable_volatile_resident: u64,
+    purgeable_volatile_virtual: u64,
+    compressed: u64,
+    compressed_peak: u64,
+    compressed_lifetime: u64,
+    phys_footprint: u64,
+}
+
+/// `struct task_extmod_info`, with its `vm_extmod_statistics` inlined.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct task_extmod_info {
+    task_uuid: [u8; 16],
+    task_for_pid_count: i64,
+    task_for_pid_caller_count: i64,
+    thread_creation_count: i64,
+    thread_creation_caller_count: i64,
+    thread_set_state_count: i64,
+    thread_set_state_caller_count: i64,
+}
+
+/// `TASK_VM_INFO_REV0_COUNT`: `task_vm_info` up to `phys_footprint`.
+const TASK_VM_INFO_REV0_COUNT: mach_msg_type_number_t =
+    ((mem::size_of::<task_vm_info>() - mem::size_of::<u64>()) / mem::size_of::<natural_t>()) as
+    mach_msg_type_number_t;
+
+/// Basic information about a task, from `MACH_TASK_BASIC_INFO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TaskBasicInfo {
+    /// Virtual memory size in bytes.
+    pub virtual_size: u64,
+    /// Resident memory size in bytes.
+    pub resident_size: u64,
+    /// Maximum resident memory size in bytes.
+    pub resident_size_max: u64,
+    /// User time of the task's terminated threads.
+    pub user_time: Duration,
+    /// System time of the task's terminated threads.
+    pub system_time: Duration,
+    /// The number of times the task has been suspended.
+    pub suspend_count: i32,
+}
+
+/// CPU time used by a task's live threads, from `TASK_THREAD_TIMES_INFO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TaskThreadTimes {
+    /// User time of the task's live threads.
+    pub user_time: Duration,
+    /// System time of the task's live threads.
+    pub system_time: Duration,
+}
+
+/// Virtual memory statistics for a task, from `TASK_VM_INFO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TaskVmInfo {
+    /// Virtual memory size in bytes.
+    pub virtual_size: u64,
+    /// The number of VM regions.
+    pub region_count: i32,
+    /// The task's page size in bytes.
+    pub page_size: i32,
+    /// Resident memory size in bytes.
+    pub resident_size: u64,
+    /// Peak resident memory size in bytes.
+    pub resident_size_peak: u64,
+    /// Anonymous memory in bytes.
+    pub internal: u64,
+    /// File-backed memory in bytes.
+    pub external: u64,
+    /// Compressed memory in bytes.
+    pub compressed: u64,
+    /// The task's physical footprint in bytes, which is what Activity
+    /// Monitor reports as "Memory", and what the kernel uses for memory
+    /// limits.
+    pub phys_footprint: u64,
+}
+
+/// How often a task has been modified from outside, and how often it has
+/// modified other tasks, from `TASK_EXTMOD_INFO`.
+///
+/// The kernel counts a task as modified from outside when another task
+/// gets its task port with `task_for_pid`, creates a thread in it, or sets
+/// the state of one of its threads, which is what injecting code takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TaskExtmodInfo {
+    /// The UUID of the task's main executable.
+    pub uuid: [u8; 16],
+    /// How often other tasks got this task's port with `task_for_pid`.
+    pub task_for_pid_count: i64,
+    /// How often this task got other tasks' ports with `task_for_pid`.
+    pub task_for_pid_caller_count: i64,
+    /// How many threads other tasks created in this task.
+    pub thread_creation_count: i64,
+    /// How many threads this task created in other tasks.
+    pub thread_creation_caller_count: i64,
+    /// How often other tasks set the state of this task's threads.
+    pub thread_set_state_count: i64,
+    /// How often this task set the state of other tasks' threads.
+    pub thread_set_state_caller_count: i64,
+}
+
+impl TaskExtmodInfo {
+    /// Whether another task has ever created a thread in this task or set
+    /// the state of one of its threads.
+    pub fn was_modified(&self) -> bool {
+        self.thread_creation_count > 0 || self.thread_set_state_count > 0
+    }
+}
+
+/// The size of `T` in `natural_t`s, which is how `task_info` counts.
+fn info_count<T>() -> mach_msg_type_number_t {
+    (mem::size_of::<T>() / mem::size_of::<natural_t>()) as mach_msg_type_number_t
+}
+
+/// Call `task_info` for `flavor`, filling in a `T`.
+pub(crate) fn get_info<T: Copy>(task: &TaskPort, flavor: task_flavor_t) -> Result<T> {
+    get_info_count(task, flavor, info_count::<T>())
+}
+
+/// Call `task_info` for `flavor`, filling in the first `count` `natural_t`s
+/// of a `T` and leaving the rest zeroed.
+fn get_info_count<T: Copy>(task: &TaskPort,
+                           flavor: task_flavor_t,
+                           count: mach_msg_type_number_t)
+                           -> Result<T> {
+    assert!(count <= info_count::<T>());
+    unsafe {
+        let mut info: T = mem::zeroed();
+        let mut count = count;
+        ktry!(task_info(task.as_raw(),
+                        flavor,
+                        &mut info as *mut T as *mut integer_t,
+                        &mut count));
+        Ok(info)
+    }
+}
+
+impl TaskPort {
+    /// Get basic information about the task.
+    pub fn basic_info(&self) -> Result<TaskBasicInfo> {
+        let info: mach_task_basic_info = get_info(self, MACH_TASK_BASIC_INFO)?;
+        Ok(TaskBasicInfo {
+            virtual_size: info.virtual_size,
+            resident_size: info.resident_size,
+            resident_size_max: info.resident_size_max,
+            user_time: info.user_time.to_duration(),
+            system_time: info.system_time.to_duration(),
+            suspend_count: info.suspend_count,
+        })
+    }
+
+    /// Get the CPU time used by the task's live threads.
+    pub fn thread_times(&self) -> Result<TaskThreadTimes> {
+        let info: task_thread_times_info = get_info(self, TASK_THREAD_TIMES_INFO)?;
+        Ok(TaskThreadTimes {
+            user_time: info.user_time.to_duration(),
+            system_time: info.system_time.to_duration(),
+        })
+    }
+
+    /// Get the task's external modification statistics.
+    pub fn extmod_info(&self) -> Result<TaskExtmodInfo> {
+        let info: task_extmod_info = get_info(self, TASK_EXTMOD_INFO)?;
+        Ok(TaskExtmodInfo {
+            uuid: info.task_uuid,
+            task_for_pid_count: info.task_for_pid_count,
+            task_for_pid_caller_count: info.task_for_pid_caller_count,
+            thread_creation_count: info.thread_creation_count,
+            thread_creation_caller_count: info.thread_creation_caller_count,
+            thread_set_state_count: info.thread_set_state_count,
+            thread_set_state_caller_count: info.thread_set_state_caller_count,
+        })
+    }
+
+    /// Get virtual memory statistics for the task.
+    ///
+    /// Before macOS 10.11, the kernel doesn't report `phys_footprint`, so
+    /// it is estimated as `internal + compressed` instead.
+    pub fn vm_info(&self) -> Result<TaskVmInfo> {
+        let info = if capabilities::running_at_least(capabilities::TASK_VM_INFO_REV1) {
+            get_info::<task_vm_info>(self, TASK_VM_INFO)?
+        } else {
+            // Older kernels only fill in revision 0, so don't ask for more.
+            let mut info: task_vm_info =
+                get_info_count(self, TASK_VM_INFO, TASK_VM_INFO_REV0_COUNT)?;
+            info.phys_footprint = info.internal + info.compressed;
+            info
+        };
+        Ok(TaskVmInfo {
+            virtual_size: info.virtual_size,
+            region_count: info.region_count,
+            page_size: info.page_size,
+            resident_size: info.resident_size,
+            resident_size_peak: info.resident_size_peak,
+            internal: info.internal,
+            external: info.external,
+            compressed: info.compressed,
+            phys_footprint: info.phys_footprint,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extmod_info_count() {
+        // `TASK_EXTMOD_INFO_COUNT` from `<mach/task_info.h>`.
+        assert_eq!(info_count::<task_extmod_info>(), 16);
+    }
+}
@@ -0,0 +1,207 @@
+//! Confining a child to a directory, or a sandbox, as it starts.
+//!
+//! A child that should only see part of the file system, or run under a
+//! sandbox profile, has to be confined between `fork` and `exec`, and only
+//! after it has checked in: once it is confined, it may no longer be
+//! allowed to look up the parent's service. Adding a `pre_exec` hook of
+//! one's own for this is easy to get wrong, since hooks run in the order
+//! they were added, and the check-in's is only added once `spawn` is
+//! called. `IsolatedSpawnWithTask` adds the isolation's hook after the
+//! check-in's, and prepares everything it needs before `fork`.
+//!
+//! In the child, the isolation changes the root directory, then the
+//! working directory, and applies the sandbox profile last, so that the
+//! profile doesn't have to allow either. The program is then executed
+//! from inside the new root, so its path must exist there.
+
+use std::ffi::{CString, OsString};
+use std::io::{Error, ErrorKind, Result};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+
+use libc;
+
+use {ChildWithTask, TaskPort, spawn_with_check_in};
+
+extern "C" {
+    /// Not in `<sandbox.h>`, but what `sandbox-exec -D` uses.
+    fn sandbox_init_with_parameters(profile: *const c_char,
+                                    flags: u64,
+                                    parameters: *const *const c_char,
+                                    errorbuf: *mut *mut c_char)
+                                    -> c_int;
+    fn sandbox_free_error(errorbuf: *mut c_char);
+}
+
+/// How to confine a child, for `IsolatedSpawnWithTask`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Isolation {
+    root: Option<OsString>,
+    dir: Option<OsString>,
+    profile: Option<String>,
+    parameters: Vec<(String, String)>,
+}
+
+impl Isolation {
+    pub fn new() -> Isolation {
+        Isolation::default()
+    }
+
+    /// Change the child's root directory to `path`, which needs root.
+    ///
+    /// Unless `chdir` says otherwise, the child starts in the new root.
+    pub fn chroot<P: AsRef<Path>>(&mut self, path: P) -> &mut Isolation {
+        self.root = Some(path.as_ref().as_os_str().to_owned());
+        self
+    }
+
+    /// Start the child in `path`, inside the new root if there is one.
+    ///
+    /// Unlike `Command::current_dir`, this happens after the check-in and
+    /// any `chroot`, and before the sandbox is applied.
+    pub fn chdir<P: AsRef<Path>>(&mut self, path: P) -> &mut Isolation {
+        self.dir = Some(path.as_ref().as_os_str().to_owned());
+        self
+    }
+
+    /// Apply the sandbox profile `profile`, in the Scheme-like language
+    /// `sandbox-exec` takes, once everything else is done. The profile has
+    /// to allow executing the program.
+    pub fn sandbox_profile(&mut self, profile: &str) -> &mut Isolation {
+        self.profile = Some(profile.to_owned());
+        self
+    }
+
+    /// Define the sandbox profile's parameter `key` as `value`, for the
+    /// profile to read with `(param "key")`.
+    pub fn sandbox_parameter(&mut self, key: &str, value: &str) -> &mut Isolation {
+        self.parameters.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Convert everything to what the child needs, so it doesn't have to.
+    fn prepare(&self) -> Result<PreparedIsolation> {
+        fn c_string(bytes: &[u8], what: &str) -> Result<CString> {
+            CString::new(bytes).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, format!("{} contains a NUL", what))
+            })
+        }
+        let root = match self.root {
+            Some(ref root) => Some(c_string(root.as_bytes(), "root directory")?),
+            None => None,
+        };
+        let dir = match self.dir {
+            Some(ref dir) => Some(c_string(dir.as_bytes(), "working directory")?),
+            None if root.is_some() => Some(c_string(b"/", "working directory")?),
+            None => None,
+        };
+        let profile = match self.profile {
+            Some(ref profile) => Some(c_string(profile.as_bytes(), "sandbox profile")?),
+            None => None,
+        };
+        let mut parameters = Vec::new();
+        for (key, value) in &self.parameters {
+            parameters.push(c_string(key.as_bytes(), "sandbox parameter")?);
+            parameters.push(c_string(value.as_bytes(), "sandbox parameter")?);
+        }
+        let mut parameter_ptrs = parameters.iter().map(|p| p.as_ptr()).collect::<Vec<_>>();
+        parameter_ptrs.push(ptr::null());
+        Ok(PreparedIsolation {
+            root: root,
+            dir: dir,
+            profile: profile,
+            _parameters: parameters,
+            parameter_ptrs: parameter_ptrs,
+        })
+    }
+}
+
+/// An `Isolation` ready for the child to apply.
+struct PreparedIsolation {
+    root: Option<CString>,
+    dir: Option<CString>,
+    profile: Option<CString>,
+    /// Owns what `parameter_ptrs` points to.
+    _parameters: Vec<CString>,
+    /// The sandbox parameters as `sandbox_init_with_parameters` takes them:
+    /// keys and values in turn, then a null.
+    parameter_ptrs: Vec<*const c_char>,
+}
+
+// The pointers only point into `_parameters`, which is never changed.
+unsafe impl Send for PreparedIsolation {}
+unsafe impl Sync for PreparedIsolation {}
+
+impl PreparedIsolation {
+    /// Confine the current process.
+    ///
+    /// This runs in the child process between `fork` and `exec`, so it
+    /// makes nothing but system calls. `pre_exec` hooks can only report
+    /// an errno, so a profile that fails to apply is `EPERM`.
+    unsafe fn enter(&self) -> Result<()> {
+        if let Some(ref root) = self.root {
+            if libc::chroot(root.as_ptr()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        if let Some(ref dir) = self.dir {
+            if libc::chdir(dir.as_ptr()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        if let Some(ref profile) = self.profile {
+            let mut error: *mut c_char = ptr::null_mut();
+            if sandbox_init_with_parameters(profile.as_ptr(),
+                                            0,
+                                            self.parameter_ptrs.as_ptr(),
+                                            &mut error) != 0 {
+                if !error.is_null() {
+                    sandbox_free_error(error);
+                }
+                return Err(Error::from_raw_os_error(libc::EPERM));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An extension to `std::process::Command` to spawn a process confined by
+/// an `Isolation` and get back access to its Mach task port.
+pub trait IsolatedSpawnWithTask {
+    /// Executes the command as a child process confined by `isolation`,
+    /// returning a `ChildWithTask` that owns both the `Child` and the
+    /// process' Mach task port.
+    fn spawn_with_task_isolated(&mut self, isolation: &Isolation) -> Result<ChildWithTask>;
+}
+
+impl IsolatedSpawnWithTask for Command {
+    fn spawn_with_task_isolated(&mut self, isolation: &Isolation) -> Result<ChildWithTask> {
+        let isolation = isolation.prepare()?;
+        // Our hook is added after the check-in's, so it runs after it.
+        let (child, task_port) = spawn_with_check_in(self, |command| {
+            unsafe { command.pre_exec(move || isolation.enter()) }.spawn()
+        })?;
+        Ok(ChildWithTask::new(child, unsafe { TaskPort::from_raw(task_port) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prepares_parameters_for_the_child() {
+        let mut isolation = Isolation::new();
+        isolation.chroot("/var/empty").sandbox_parameter("HOME", "/tmp");
+        let prepared = isolation.prepare().unwrap();
+        assert_eq!(prepared.dir.as_ref().unwrap().as_bytes(), b"/");
+        assert_eq!(prepared.parameter_ptrs.len(), 3);
+        assert!(prepared.parameter_ptrs[2].is_null());
+        isolation.sandbox_parameter("a\0b", "c");
+        assert_eq!(isolation.prepare().err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+}
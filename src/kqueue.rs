@@ -0,0 +1,111 @@
+//! A minimal kqueue, for waiting on events with a timeout.
+
+use std::io::{Error, Result};
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+
+use libc;
+
+/// `EVFILT_PROC`, for process events.
+pub(crate) const EVFILT_PROC: i16 = -5;
+/// `NOTE_EXIT`: the process has exited.
+pub(crate) const NOTE_EXIT: u32 = 0x8000_0000;
+/// `EVFILT_MACHPORT`, for messages arriving on a receive right.
+pub(crate) const EVFILT_MACHPORT: i16 = -8;
+
+const EV_ADD: u16 = 0x0001;
+const EV_ONESHOT: u16 = 0x0010;
+const EV_ERROR: u16 = 0x4000;
+
+/// `struct kevent` from `<sys/event.h>`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct kevent {
+    pub ident: usize,
+    pub filter: i16,
+    pub flags: u16,
+    pub fflags: u32,
+    pub data: isize,
+    pub udata: *mut c_void,
+}
+
+extern "C" {
+    fn kqueue() -> c_int;
+    #[link_name = "kevent"]
+    fn kevent_call(kq: c_int,
+                   changelist: *const kevent,
+                   nchanges: c_int,
+                   eventlist: *mut kevent,
+                   nevents: c_int,
+                   timeout: *const libc::timespec)
+                   -> c_int;
+}
+
+/// A kqueue, which is closed on drop.
+pub(crate) struct Kqueue(RawFd);
+
+impl Kqueue {
+    pub(crate) fn new() -> Result<Kqueue> {
+        let fd = unsafe { kqueue() };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Kqueue(fd))
+    }
+
+    /// Register interest in `filter` events on `ident`, once.
+    pub(crate) fn add(&self, ident: usize, filter: i16, fflags: u32) -> Result<()> {
+        let change = kevent {
+            ident: ident,
+            filter: filter,
+            flags: EV_ADD | EV_ONESHOT,
+            fflags: fflags,
+            data: 0,
+            udata: ptr::null_mut(),
+        };
+        if unsafe { kevent_call(self.0, &change, 1, ptr::null_mut(), 0, ptr::null()) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait for an event, for at most `timeout` if given, returning it or
+    /// `None` on timeout.
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> Result<Option<kevent>> {
+        let timeout = timeout.map(|timeout| {
+            libc::timespec {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as libc::c_long,
+            }
+        });
+        let timeout_ptr = timeout.as_ref().map_or(ptr::null(), |t| t as *const libc::timespec);
+        unsafe {
+            let mut event: kevent = mem::zeroed();
+            match kevent_call(self.0, ptr::null(), 0, &mut event, 1, timeout_ptr) {
+                n if n < 0 => Err(Error::last_os_error()),
+                0 => Ok(None),
+                _ if event.flags & EV_ERROR != 0 => {
+                    Err(Error::from_raw_os_error(event.data as c_int))
+                }
+                _ => Ok(Some(event)),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Kqueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Kqueue {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
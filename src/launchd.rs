@@ -0,0 +1,135 @@
+//! Getting the task port of a helper that launchd starts.
+//!
+//! Helpers installed with `SMAppService` or `launchctl` aren't children of
+//! the process that wants their task port, so there is no `pre_exec` hook
+//! to check in from. Instead, the parent registers a fixed service name
+//! with a `LaunchdHelperReceiver`, the helper's plist passes the same name
+//! to the helper, and the helper calls `check_in_with_service` early in
+//! `main`. The parent gets a `LaunchdHelper` handle and the task port, as
+//! it would from a spawn.
+//!
+//! The service is registered in the parent's bootstrap namespace, so only
+//! helpers in the same login session can look it up: launch agents, not
+//! launch daemons.
+
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::c_int;
+use std::process::Command;
+use std::sync::Mutex;
+
+use libc;
+use mach::port::MACH_PORT_RIGHT_RECEIVE;
+use mach::traps::mach_task_self;
+
+use child;
+use registration::create_service;
+use {MachPort, RecvMessage, SpawnTaskPortError, TaskPort, mach_port_mod_refs, receive_task_port};
+
+fn service_c_name(name: &str) -> Result<CString> {
+    CString::new(name)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "service name contains a NUL"))
+}
+
+/// A helper process that launchd started, which checked in with a
+/// `LaunchdHelperReceiver`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LaunchdHelper {
+    pid: u32,
+}
+
+impl LaunchdHelper {
+    /// The helper's process ID.
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    /// Whether the helper is still running.
+    pub fn is_running(&self) -> bool {
+        unsafe { libc::kill(self.pid as c_int, 0) == 0 }
+    }
+
+    /// Send the helper `SIGKILL`. launchd may well start it again,
+    /// depending on its plist.
+    pub fn kill(&self) -> Result<()> {
+        if unsafe { libc::kill(self.pid as c_int, libc::SIGKILL) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A fixed bootstrap service that launchd-managed helpers check in with.
+pub struct LaunchdHelperReceiver {
+    port: MachPort,
+    name: String,
+    msg: Mutex<RecvMessage>,
+}
+
+impl LaunchdHelperReceiver {
+    /// Register `name`, which the helper's plist must pass to it.
+    pub fn register(name: &str) -> Result<LaunchdHelperReceiver> {
+        let c_name = service_c_name(name)?;
+        let port = create_service(&c_name)?;
+        Ok(LaunchdHelperReceiver {
+            port: port,
+            name: name.to_owned(),
+            msg: Mutex::new(unsafe { mem::zeroed() }),
+        })
+    }
+
+    /// The registered service name.
+    pub fn service_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Ask launchd to start the helper whose job label is `label`, in the
+    /// current user's GUI domain, restarting it if it's already running.
+    /// A helper that launchd starts on demand doesn't need this.
+    pub fn kickstart(&self, label: &str) -> Result<()> {
+        let target = format!("gui/{}/{}", unsafe { libc::getuid() }, label);
+        let status = Command::new("/bin/launchctl").args(["kickstart", "-k", &target]).status()?;
+        if !status.success() {
+            return Err(Error::new(ErrorKind::Other,
+                                  format!("`launchctl kickstart {}` failed: {}", target, status)));
+        }
+        Ok(())
+    }
+
+    /// Block until a helper checks in, returning it along with its task
+    /// port.
+    pub fn receive(&self) -> Result<(LaunchdHelper, TaskPort)> {
+        let mut msg = self.msg.lock().unwrap_or_else(|e| e.into_inner());
+        let task = unsafe { TaskPort::from_raw(receive_task_port(self.port.0, &mut msg)?) };
+        // Anything in the session can look the name up, so make sure the
+        // port really is the sender's.
+        let pid = task.pid()?;
+        if pid != msg.pid as u32 {
+            return Err(SpawnTaskPortError::AuditMismatch {
+                    expected: msg.pid as u32,
+                    actual: pid,
+                }
+                .into());
+        }
+        Ok((LaunchdHelper { pid: pid }, task))
+    }
+}
+
+impl Drop for LaunchdHelperReceiver {
+    fn drop(&mut self) {
+        // Destroying the receive right unregisters the service.
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
+
+/// Send the current process' task port to the `LaunchdHelperReceiver`
+/// registered as `service_name`.
+///
+/// A launchd-managed helper calls this early in `main`. It is the same as
+/// `child::send_task_port`.
+pub fn check_in_with_service(service_name: &str) -> Result<()> {
+    child::send_task_port(service_name)
+}
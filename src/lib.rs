@@ -1,27 +1,60 @@
+extern crate libc;
 extern crate mach;
-extern crate uuid;
+#[cfg(feature = "async-process")]
+extern crate async_io;
+#[cfg(feature = "async-process")]
+extern crate async_process;
+#[cfg(feature = "command-group")]
+extern crate command_group;
+#[cfg(feature = "duct")]
+extern crate duct;
+#[cfg(feature = "mio")]
+extern crate mio;
+#[cfg(feature = "napi")]
+extern crate napi;
+#[cfg(feature = "napi")]
+#[macro_use]
+extern crate napi_derive;
+#[cfg(feature = "nix")]
+extern crate nix;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "sysinfo")]
+extern crate sysinfo;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
 // re-export this for convenience.
 pub use mach::port::mach_port_t;
 
-use std::ffi::CString;
-use std::io::{Error, ErrorKind, Result};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Error, Read, Result};
 use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
 use std::ops::Drop;
 use std::os::unix::process::CommandExt;
 use std::process::{Command, Child};
+use std::str;
+use std::time::Duration;
 
 use mach::bootstrap::bootstrap_look_up;
 use mach::kern_return::{kern_return_t, KERN_SUCCESS};
-use mach::port::{MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::port::{MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE, mach_port_right_t};
 use mach::mach_port::{mach_port_allocate, mach_port_deallocate, mach_port_insert_right};
 use mach::message::{MACH_MSG_TYPE_MAKE_SEND, MACH_MSGH_BITS, MACH_MSG_TYPE_COPY_SEND,
-                    MACH_MSGH_BITS_COMPLEX, MACH_RCV_MSG, MACH_MSG_TIMEOUT_NONE, mach_msg_send,
-                    mach_msg, mach_msg_header_t, mach_msg_body_t, mach_msg_port_descriptor_t,
-                    mach_msg_trailer_t};
-use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+                    MACH_MSG_TYPE_MOVE_SEND, MACH_MSGH_BITS_COMPLEX, MACH_RCV_MSG,
+                    MACH_MSG_TIMEOUT_NONE, MACH_RCV_TIMEOUT, MACH_RCV_TIMED_OUT, MACH_SEND_MSG,
+                    MACH_SEND_TIMEOUT, MACH_SEND_TIMED_OUT, mach_msg_send, mach_msg,
+                    mach_msg_header_t, mach_msg_body_t, mach_msg_port_descriptor_t,
+                    mach_msg_option_t, mach_msg_timeout_t, mach_msg_type_name_t,
+                    MACH_MSG_PORT_DESCRIPTOR};
+use mach::task::{TASK_BOOTSTRAP_PORT, TASK_NAME_PORT, task_get_special_port};
 use mach::traps::mach_task_self;
-use uuid::Uuid;
+
+use audit::ResourceKind;
+use trailer::RawTrailer;
 
 /// A macro to wrap mach APIs that return `kern_return_t` to early-return
 /// a `std::io::Result` when they fail.
@@ -29,16 +62,216 @@ macro_rules! ktry {
     ($e:expr) => {{
         let kr = $e;
         if kr != KERN_SUCCESS {
-            return Err(Error::new(ErrorKind::Other,
-                                  format!("`{}` failed with return code {:x}",
-                                          stringify!($e), kr)));
+            return Err(::error::SpawnTaskPortError::from_call(stringify!($e), kr).into());
+        }
+    }}
+}
+
+/// Like `ktry!`, but for the child between `fork` and `exec`, where it
+/// must not allocate. Only an errno makes it back to the parent, so the
+/// failed call and its result are packed into one, which
+/// `SpawnTaskPortError::from_spawn` unpacks there. The call's name has to
+/// be listed in `error::CHILD_CALLS`.
+macro_rules! ktry_child {
+    ($f:ident($($arg:expr),*)) => {
+        ktry_child!(stringify!($f), $f($($arg),*))
+    };
+    ($call:expr, $e:expr) => {{
+        const CALL: usize = ::error::child_call($call);
+        let kr = $e;
+        if kr != KERN_SUCCESS {
+            return Err(::error::child_call_error(CALL, kr));
+        }
+    }}
+}
+
+/// Like `ktry!`, but also records the call, the values of its arguments
+/// and its result in the current handshake's diagnostic transcript. Only
+/// use this in the parent: recording allocates, which the child must not
+/// do between `fork` and `exec`.
+macro_rules! ktrace {
+    ($f:ident($($arg:expr),*)) => {{
+        let mut arguments = Vec::new();
+        let kr = $f($({
+            let arg = $arg;
+            ::diagnostics::describe(&arg, &mut arguments);
+            arg
+        }),*);
+        ::diagnostics::record(stringify!($f($($arg),*)), arguments, kr);
+        if kr != KERN_SUCCESS {
+            return Err(::error::SpawnTaskPortError::from_call(stringify!($f($($arg),*)), kr)
+                .into());
         }
     }}
 }
 
+#[cfg(feature = "async-process")]
+mod async_process_ext;
+mod broker;
+pub mod audit;
+mod bootstrap_swap;
+mod bootstrap_subset;
+pub mod capabilities;
+pub mod child;
+mod coalition;
+mod cpu_monitor;
+pub mod daemon;
+mod debugger;
+mod debug_state;
+pub mod descendant;
+#[cfg(feature = "duct")]
+mod duct_ext;
+pub mod exec_check_in;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod diagnostics;
+mod doctor;
+mod env_scrub;
+mod error;
+mod exception;
+mod exception_payload;
+mod exception_server;
+mod exit_details;
+pub mod fork_server;
+pub mod helper;
+#[cfg(feature = "command-group")]
+mod group;
+mod handle;
+mod heartbeat;
+mod host_exceptions;
+mod identity;
+mod importance;
+mod info;
+mod isolation;
+mod kqueue;
+mod launchd;
+mod memory;
+mod memory_watchdog;
+mod middleware;
+#[cfg(feature = "mio")]
+mod mio_ext;
+mod modules;
+#[cfg(feature = "napi")]
+pub mod napi_bindings;
+#[cfg(feature = "nix")]
+mod nix_interop;
+mod placement;
+mod port_info;
+mod posix_spawn;
+mod prepared;
+mod process_info;
+mod privileged;
+mod process_tree;
+mod reaper;
+mod receiver;
+mod registration;
+mod remote_error;
+mod retry;
+mod rights;
+mod ring_buffer;
+mod sched_state;
+mod self_test;
+mod session;
+mod shutdown;
+mod snapshot;
+mod spawn_options;
+mod syscall_trace;
+pub mod system;
+#[cfg(feature = "sysinfo")]
+mod sysinfo_ext;
+mod task_port;
+mod task_port_command;
+mod thread;
+mod throttle;
+mod trailer;
+mod vectored_read;
+#[cfg(feature = "tokio")]
+mod tokio_ext;
+mod watchpoint;
+#[cfg(feature = "xpc")]
+mod xpc;
+
+#[cfg(feature = "async-process")]
+pub use async_process_ext::{AsyncCommandSpawnWithTask, AsyncSpawnWithTaskPort,
+                            AsyncTaskPortCommand};
+pub use bootstrap_swap::BootstrapSwapSpawnWithTask;
+pub use bootstrap_subset::BootstrapSubset;
+pub use broker::{BrokerEvent, MachPortBroker};
+pub use capabilities::{Capabilities, OsVersion};
+pub use coalition::{coalition_ids, coalition_resource_usage, CoalitionIds,
+                    CoalitionResourceUsage};
+pub use debug_state::DebugState;
+pub use debugger::DebuggerAttach;
+pub use doctor::{doctor, DoctorReport, Problem, TargetSignature};
+pub use env_scrub::EnvScrub;
+pub use error::SpawnTaskPortError;
+pub use exception::{Exception, ExceptionKind, ExceptionMask};
+pub use exception_payload::{FdGuardViolation, GuardException, PortGuardViolation,
+                            ResourceException};
+pub use exception_server::{ExceptionEvent, ExceptionServer};
+pub use exit_details::{CrashDetails, ExitDetails};
+#[cfg(feature = "duct")]
+pub use duct_ext::ExpressionSpawnWithTask;
+pub use fork_server::ForkServer;
+#[cfg(feature = "command-group")]
+pub use group::GroupSpawnWithTask;
+pub use handle::{ChildStatus, ChildWithTask, ResourceUsage};
+pub use heartbeat::{start_heartbeat, Heartbeat};
+pub use host_exceptions::HostExceptionMonitor;
+pub use identity::{IdentityToken, IdentityTokenReceiver, TaskFlavor};
+pub use importance::ImportanceDonation;
+pub use info::{TaskBasicInfo, TaskExtmodInfo, TaskThreadTimes, TaskVmInfo};
+pub use isolation::{IsolatedSpawnWithTask, Isolation};
+pub use launchd::{check_in_with_service, LaunchdHelper, LaunchdHelperReceiver};
+pub use memory::{RemoteMemory, SharedMemory, VmTag};
+pub use memory_watchdog::{MemoryEvent, MemoryThresholds, MemoryWatchdog, WatchdogAction};
+pub use middleware::{add_spawn_middleware, SpawnContext, SpawnMiddleware};
+pub use modules::Module;
+pub use placement::{CorePreference, CoreUsage, QosClass};
+pub use port_info::{dump_port_info, parse_port_name, PortInfo, PortRights};
+pub use posix_spawn::{Architecture, PosixSpawnOptions, PosixSpawnWithTask, ProcessType};
+pub use prepared::PreparedSpawn;
+pub use privileged::task_port_for_pid;
+pub use process_info::ProcessInfo;
+pub use reaper::Reaper;
+pub use receiver::TaskPortReceiver;
+pub use registration::{registration_method, set_registration_method, RegistrationMethod};
+pub use remote_error::{DyldError, DyldErrorKind};
+pub use retry::{ErrorClass, RetryPolicy};
+pub use rights::{task_port_rights, RightCounts};
+pub use ring_buffer::{RingBufferProducer, SharedRingBuffer};
+pub use sched_state::{SchedPolicy, SchedSnapshot, ThreadSchedState};
+pub use self_test::self_test;
+pub use session::{SessionSpawnWithTask, SessionTarget};
+pub use shutdown::{listen_for_shutdown, ShutdownChannel, ShutdownListener, ShutdownOutcome};
+pub use snapshot::{ChildSnapshot, ThreadSnapshot};
+pub use spawn_options::{PortDisposition, SendTimeoutAction, SpawnOptions};
+pub use syscall_trace::{mach_trap_name, SyscallTracer, TrapEvent};
+#[cfg(feature = "sysinfo")]
+pub use sysinfo_ext::{ExtendedProcessInfo, ProcessTaskExt};
+pub use task_port::TaskPort;
+pub use task_port_command::TaskPortCommand;
+pub use thread::ThreadPort;
+pub use throttle::Throttle;
+pub use trailer::{AuditToken, MessageTrailer, TrailerType};
+#[cfg(feature = "tokio")]
+pub use tokio_ext::{RecvAsync, SpawnWithTaskPort, TokioCommandSpawnWithTask};
+pub use watchpoint::{watchpoint_count, WatchKind, Watchpoint};
+#[cfg(feature = "xpc")]
+pub use xpc::{send_task_port_xpc, xpc_object_t, XpcSpawnWithTask, XpcTaskPortReceiver};
+
 /// A wrapper for a `mach_port_t` to deallocate the port on drop.
 struct MachPort(mach_port_t);
 
+impl MachPort {
+    /// Give up ownership of the port without deallocating it.
+    fn into_raw(self) -> mach_port_t {
+        let port = self.0;
+        mem::forget(self);
+        port
+    }
+}
+
 impl Drop for MachPort {
     fn drop(&mut self) {
         // Ignore failures, there's not much that can be done here.
@@ -48,119 +281,732 @@ impl Drop for MachPort {
     }
 }
 
+/// The number of random bytes used to build a bootstrap service name.
+const SERVICE_NAME_RANDOM_BYTES: usize = 16;
+
+/// The longest bootstrap service name, the size of a `name_t` less its NUL.
+const SERVICE_NAME_MAX: usize = 127;
+
+/// A NUL-terminated bootstrap service name, stored inline so that it can be
+/// generated before `fork` and captured by the child without any heap
+/// allocation.
+#[derive(Clone, Copy)]
+struct ServiceName([u8; SERVICE_NAME_MAX + 1]);
+
+impl ServiceName {
+    /// Generate a new random service name: `SERVICE_NAME_RANDOM_BYTES`
+    /// random bytes, hex-encoded.
+    fn random() -> Result<ServiceName> {
+        const HEX: &'static [u8; 16] = b"0123456789abcdef";
+        let mut bytes = [0u8; SERVICE_NAME_RANDOM_BYTES];
+        fill_random(&mut bytes)?;
+        let mut name = [0u8; SERVICE_NAME_MAX + 1];
+        for (i, b) in bytes.iter().enumerate() {
+            name[i * 2] = HEX[(b >> 4) as usize];
+            name[i * 2 + 1] = HEX[(b & 0xf) as usize];
+        }
+        Ok(ServiceName(name))
+    }
+
+    /// Use `s` as the name, if it fits and has no NULs.
+    fn new(s: &str) -> Option<ServiceName> {
+        let mut name = [0u8; SERVICE_NAME_MAX + 1];
+        if s.is_empty() || s.len() > SERVICE_NAME_MAX || s.bytes().any(|b| b == 0) {
+            return None;
+        }
+        name[..s.len()].copy_from_slice(s.as_bytes());
+        Some(ServiceName(name))
+    }
+
+    /// Parse a random service name previously formatted with `as_str`.
+    fn from_str(s: &str) -> Option<ServiceName> {
+        if s.len() != SERVICE_NAME_RANDOM_BYTES * 2 ||
+           !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        ServiceName::new(s)
+    }
+
+    fn as_ptr(&self) -> *const c_char {
+        self.0.as_ptr() as *const c_char
+    }
+
+    /// The length of the name, without its trailing NUL.
+    fn len(&self) -> usize {
+        // There is always a NUL, at the latest in the last byte.
+        self.0.iter().position(|&b| b == 0).unwrap()
+    }
+
+    fn as_c_str(&self) -> &CStr {
+        CStr::from_bytes_with_nul(&self.0[..self.len() + 1]).unwrap()
+    }
+
+    /// The name without its trailing NUL.
+    fn as_str(&self) -> &str {
+        // The name was either hex-encoded or copied from a `str`.
+        str::from_utf8(&self.0[..self.len()]).unwrap()
+    }
+}
+
+/// Fill `buf` with random bytes from `getentropy`, or from `/dev/urandom`
+/// before macOS 10.12, which doesn't have it.
+fn fill_random(buf: &mut [u8]) -> Result<()> {
+    type GetEntropy = unsafe extern "C" fn(*mut c_void, usize) -> c_int;
+    let getentropy = unsafe {
+        libc::dlsym(libc::RTLD_DEFAULT, b"getentropy\0".as_ptr() as *const c_char)
+    };
+    if getentropy.is_null() {
+        return File::open("/dev/urandom")?.read_exact(buf);
+    }
+    let getentropy: GetEntropy = unsafe { mem::transmute(getentropy) };
+    if unsafe { getentropy(buf.as_mut_ptr() as *mut c_void, buf.len()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
 /// The message format that the child sends to the parent.
 #[allow(dead_code)]
+#[repr(C)]
 struct SendMessage {
     header: mach_msg_header_t,
     body: mach_msg_body_t,
     task_port: mach_msg_port_descriptor_t,
+    /// The pid of the process whose port is sent, so the parent can tell
+    /// check-ins apart even if the child has already exited by the time it
+    /// receives them.
+    pid: c_int,
+    /// The token a daemon was spawned with, which ties its check-in to the
+    /// spawn that started it, or zero.
+    token: u32,
 }
 
 /// The message format that the parent receives from the child.
 #[allow(dead_code)]
+#[repr(C)]
 struct RecvMessage {
     header: mach_msg_header_t,
     body: mach_msg_body_t,
     task_port: mach_msg_port_descriptor_t,
-    //TODO: make this a mach_msg_audit_trailer_t so we can audit the child PID
-    trailer: mach_msg_trailer_t,
+    pid: c_int,
+    token: u32,
+    /// Only filled in as far as `receive_options` asked for.
+    trailer: RawTrailer,
+}
+
+impl RecvMessage {
+    /// The sender's pid from the audit trailer, if the kernel filled it in.
+    fn audit_pid(&self) -> Option<u32> {
+        self.trailer.parse().audit_token().map(|token| token.pid())
+    }
+
+    /// Whether the message is laid out like a `SendMessage`: complex, with
+    /// a single port descriptor, and no more or less data than that.
+    /// Anything else leaves parts of the buffer from the previous message.
+    fn is_check_in(&self) -> bool {
+        self.header.msgh_bits & MACH_MSGH_BITS_COMPLEX != 0 &&
+        self.header.msgh_size as usize == mem::size_of::<SendMessage>() &&
+        self.body.msgh_descriptor_count == 1 &&
+        self.task_port.type_ as u32 == MACH_MSG_PORT_DESCRIPTOR
+    }
 }
 
 extern "C" {
     /// This is not a public API, but it's what everything uses internally.
     fn bootstrap_register2(bp: mach_port_t,
-                           service_name: *const i8,
+                           service_name: *const c_char,
                            sp: mach_port_t,
                            flags: u64)
                            -> kern_return_t;
-//TODO: use this for auditing
-//fn audit_token_to_pid(audit_token_t atoken) -> pid_t;
+    fn bootstrap_check_in(bp: mach_port_t,
+                          service_name: *const c_char,
+                          sp: *mut mach_port_t)
+                          -> kern_return_t;
+    fn pid_for_task(task: mach_port_t, pid: *mut c_int) -> kern_return_t;
+    fn mach_port_mod_refs(task: mach_port_t,
+                          name: mach_port_t,
+                          right: mach_port_right_t,
+                          delta: c_int)
+                          -> kern_return_t;
+}
+
+/// Everything the child needs to send its task port to the parent.
+///
+/// This is built in the parent before spawning and copied into the child's
+/// `pre_exec` hook, so it must only contain plain data: nothing here may
+/// allocate or need to be dropped in the child between `fork` and `exec`.
+#[derive(Clone, Copy)]
+struct ChildCheckIn {
+    name: ServiceName,
+    /// If set, send an identity token created with this function instead
+    /// of the task control port. It is looked up before `fork`, since the
+    /// child can't safely call `dlsym`.
+    create_identity_token: Option<identity::CreateIdentityToken>,
+    /// If set, and there's no identity token, send the task name port
+    /// instead of the control port.
+    send_name_port: bool,
+    /// How to send the task control port.
+    task_port_disposition: mach_msg_type_name_t,
+    /// How long to wait for room in the parent's queue, in milliseconds,
+    /// or `MACH_MSG_TIMEOUT_NONE` to wait forever.
+    send_timeout: mach_msg_timeout_t,
+    /// Whether to exec anyway if the send times out.
+    proceed_on_send_timeout: bool,
+    /// The inherited exception handlers to remove before checking in, as
+    /// an `exception_mask_t`.
+    clear_exception_mask: u32,
+}
+
+// The `pre_exec` hook captures nothing but a `ChildCheckIn`; make sure that
+// stays a small inline value.
+const _: () = assert!(mem::size_of::<ChildCheckIn>() <= 160);
+
+/// The `msgh_id` of a check-in carrying a task control port.
+const TASK_PORT_MSG_ID: c_int = 0;
+/// The `msgh_id` of a check-in carrying an identity token.
+const IDENTITY_TOKEN_MSG_ID: c_int = 1;
+/// The `msgh_id` of a check-in from a daemon, carrying its task control
+/// port and the token it was spawned with.
+const DAEMON_MSG_ID: c_int = 2;
+/// The `msgh_id` of a check-in from a descendant of a broker's child,
+/// carrying its task control port and its parent's pid as the token.
+const DESCENDANT_MSG_ID: c_int = 3;
+/// The `msgh_id` of a check-in sent again after a broker's child has
+/// executed a new image, carrying the new image's task control port.
+const EXEC_MSG_ID: c_int = 4;
+
+impl ChildCheckIn {
+    /// A check-in that sends the task control port to `name`.
+    fn new(name: ServiceName) -> ChildCheckIn {
+        ChildCheckIn {
+            name: name,
+            create_identity_token: None,
+            send_name_port: false,
+            task_port_disposition: MACH_MSG_TYPE_COPY_SEND,
+            send_timeout: MACH_MSG_TIMEOUT_NONE,
+            proceed_on_send_timeout: false,
+            clear_exception_mask: 0,
+        }
+    }
+
+    /// A check-in that sends the task control port, or an identity token,
+    /// to `name`, as configured by `options`.
+    fn with_options(name: ServiceName, options: &SpawnOptions) -> ChildCheckIn {
+        ChildCheckIn {
+            create_identity_token: options.token_flavor()
+                .and_then(|_| identity::create_identity_token_fn()),
+            send_name_port: options.task_flavor == Some(TaskFlavor::Name),
+            task_port_disposition: options.disposition.to_raw(),
+            send_timeout: options.send_timeout_ms(),
+            proceed_on_send_timeout: options.on_send_timeout == SendTimeoutAction::Proceed,
+            clear_exception_mask: options.clear_exception_ports.bits(),
+            ..ChildCheckIn::new(name)
+        }
+    }
+
+    /// Look up the parent's registered port and send it our task port.
+    ///
+    /// This runs in the child process between `fork` and `exec`.
+    unsafe fn send_task_port(&self) -> Result<()> {
+        if self.clear_exception_mask != 0 {
+            ktry_child!("task_set_exception_ports",
+                        exception_server::task_set_exception_ports(mach_task_self(),
+                                                                   self.clear_exception_mask,
+                                                                   MACH_PORT_NULL,
+                                                                   exception::EXCEPTION_DEFAULT,
+                                                                   exception::THREAD_STATE_NONE));
+        }
+        let mut bootstrap_port: mach_port_t = mem::uninitialized();
+        ktry_child!(task_get_special_port(mach_task_self(),
+                                          TASK_BOOTSTRAP_PORT,
+                                          &mut bootstrap_port));
+
+        let mut parent_port: mach_port_t = mem::uninitialized();
+        ktry_child!(bootstrap_look_up(bootstrap_port, self.name.as_ptr(), &mut parent_port));
+        let parent_port = MachPort(parent_port);
+        let (port, disposition, id) = match self.create_identity_token {
+            Some(create_identity_token) => {
+                let mut token: mach_port_t = MACH_PORT_NULL;
+                ktry_child!(create_identity_token(mach_task_self(), &mut token));
+                (token, MACH_MSG_TYPE_MOVE_SEND, IDENTITY_TOKEN_MSG_ID)
+            }
+            None if self.send_name_port => {
+                let mut name_port: mach_port_t = MACH_PORT_NULL;
+                ktry_child!(task_get_special_port(mach_task_self(),
+                                                  TASK_NAME_PORT,
+                                                  &mut name_port));
+                (name_port, MACH_MSG_TYPE_MOVE_SEND, TASK_PORT_MSG_ID)
+            }
+            None => (mach_task_self(), self.task_port_disposition, TASK_PORT_MSG_ID),
+        };
+        // Now use the port to send our task port to the parent.
+        match send_check_in_timeout(parent_port.0,
+                                    port,
+                                    disposition,
+                                    id,
+                                    libc::getpid(),
+                                    0,
+                                    self.send_timeout) {
+            MACH_SEND_TIMED_OUT if self.proceed_on_send_timeout => {}
+            // Only an errno makes it back to `spawn` in the parent.
+            MACH_SEND_TIMED_OUT => return Err(Error::from_raw_os_error(libc::ETIMEDOUT)),
+            kr => ktry_child!("mach_msg", kr),
+        }
+        Ok(())
+    }
+}
+
+/// Send a check-in message carrying `port` with `disposition` to `remote`,
+/// on behalf of the process `pid`.
+///
+/// This doesn't allocate, so the child can call it between `fork` and
+/// `exec`.
+unsafe fn send_check_in(remote: mach_port_t,
+                        port: mach_port_t,
+                        disposition: mach_msg_type_name_t,
+                        id: c_int,
+                        pid: c_int)
+                        -> kern_return_t {
+    send_check_in_timeout(remote, port, disposition, id, pid, 0, MACH_MSG_TIMEOUT_NONE)
+}
+
+/// Like `send_check_in`, but with a daemon's `token`, and give up with
+/// `MACH_SEND_TIMED_OUT` if `remote`'s queue stays full for `timeout`
+/// milliseconds, unless that is `MACH_MSG_TIMEOUT_NONE`.
+unsafe fn send_check_in_timeout(remote: mach_port_t,
+                                port: mach_port_t,
+                                disposition: mach_msg_type_name_t,
+                                id: c_int,
+                                pid: c_int,
+                                token: u32,
+                                timeout: mach_msg_timeout_t)
+                                -> kern_return_t {
+    let mut msg = SendMessage {
+        header: mach_msg_header_t {
+            msgh_bits: MACH_MSGH_BITS(MACH_MSG_TYPE_COPY_SEND, 0) | MACH_MSGH_BITS_COMPLEX,
+            msgh_size: mem::size_of::<SendMessage>() as u32,
+            msgh_remote_port: remote,
+            msgh_local_port: MACH_PORT_NULL,
+            msgh_voucher_port: MACH_PORT_NULL,
+            msgh_id: id,
+        },
+        body: mach_msg_body_t { msgh_descriptor_count: 1 },
+        task_port: mach_msg_port_descriptor_t::new(port, disposition),
+        pid: pid,
+        token: token,
+    };
+    if timeout == MACH_MSG_TIMEOUT_NONE {
+        return mach_msg_send(&mut msg.header);
+    }
+    mach_msg(&mut msg.header,
+             MACH_SEND_MSG | MACH_SEND_TIMEOUT,
+             msg.header.msgh_size,
+             0,
+             MACH_PORT_NULL,
+             timeout,
+             MACH_PORT_NULL)
+}
+
+/// Build the child's `pre_exec` hook.
+///
+/// Requiring `Copy` here checks at compile time that the closure only
+/// captures plain data.
+fn pre_exec_hook(check_in: ChildCheckIn)
+                 -> impl FnMut() -> Result<()> + Copy + Send + Sync + 'static {
+    move || {
+        middleware::pre_exec_child()?;
+        unsafe { check_in.send_task_port() }
+    }
 }
 
 /// As OS X-specific extension to `std::process::Command` to spawn a process and
 /// get back access to its Mach task port.
 pub trait CommandSpawnWithTask {
     /// Executes the command as a child process, returning both the `Child`
-    /// as well as the process' Mach task port as a `mach_port_t`.
-    fn spawn_get_task_port(&mut self) -> Result<(Child, mach_port_t)>;
+    /// as well as a `TaskPort` that owns the process' Mach task port.
+    fn spawn_with_task_port(&mut self) -> Result<(Child, TaskPort)>;
+
+    /// Like `spawn_with_task_port`, but with the handshake configured by
+    /// `options`.
+    fn spawn_get_task_port_with(&mut self, options: &SpawnOptions) -> Result<(Child, TaskPort)>;
+
+    /// Like `spawn_with_task_port`, but give up if the child hasn't checked
+    /// in within `timeout`, killing it and failing with
+    /// `SpawnTaskPortError::ReceiveTimeout`. This is for children that may
+    /// die, or get stuck, before they can check in.
+    fn spawn_with_task_port_timeout(&mut self, timeout: Duration) -> Result<(Child, TaskPort)> {
+        self.spawn_get_task_port_with(SpawnOptions::new().receive_timeout(timeout))
+    }
+
+    /// Like `spawn_with_task_port`, but return a task port of `flavor`, so
+    /// that a caller that only reads or inspects the child never holds its
+    /// control port. See `SpawnOptions::task_flavor`.
+    fn spawn_with_task_flavor(&mut self, flavor: TaskFlavor) -> Result<(Child, TaskPort)> {
+        self.spawn_get_task_port_with(SpawnOptions::new().task_flavor(flavor))
+    }
+
+    /// Executes the command as a child process, returning both the `Child`
+    /// and the process' task name port, which identifies the process, for
+    /// `pid` and some `task_info` flavors, but grants no access to it. It
+    /// is harmless to leak, and unlike the other reduced flavors, it works
+    /// on every release and in processes that may not hold control ports.
+    fn spawn_get_task_name_port(&mut self) -> Result<(Child, TaskPort)> {
+        self.spawn_with_task_flavor(TaskFlavor::Name)
+    }
+
+    /// Executes the command as a child process, returning both the `Child`
+    /// as well as the process' Mach task port as a `mach_port_t`, which the
+    /// caller must deallocate.
+    #[deprecated(since = "0.1.2", note = "use `spawn_with_task_port`, which owns the port")]
+    fn spawn_get_task_port(&mut self) -> Result<(Child, mach_port_t)> {
+        let (child, task_port) = self.spawn_with_task_port()?;
+        Ok((child, task_port.into_raw()))
+    }
+
+    /// Executes the command as a child process, returning the `Child` as
+    /// soon as it has been spawned, along with a `TaskPortReceiver` to get
+    /// its task port from once it has checked in.
+    fn spawn_with_receiver(&mut self) -> Result<(Child, TaskPortReceiver)>;
+
+    /// Like `spawn_with_receiver`, but with the handshake configured by
+    /// `options`. A `receive_timeout` in `options` doesn't apply, since
+    /// the caller decides how long to wait.
+    fn spawn_with_receiver_with(&mut self,
+                                options: &SpawnOptions)
+                                -> Result<(Child, TaskPortReceiver)>;
+
+    /// Executes the command as a child process, returning a `ChildWithTask`
+    /// that owns both the `Child` and the process' Mach task port.
+    fn spawn_with_task(&mut self) -> Result<ChildWithTask> {
+        let (child, task_port) = self.spawn_with_task_port()?;
+        Ok(ChildWithTask::new(child, task_port))
+    }
+
+    /// Executes the command as a child process, returning both the `Child`
+    /// as well as an identity token for the process, which can be
+    /// converted to a task port of the flavor the caller needs.
+    ///
+    /// This fails if the running OS doesn't support identity tokens.
+    fn spawn_with_identity_token(&mut self) -> Result<(Child, IdentityToken)>;
+
+    /// Executes the command as a child process for a debugger to attach
+    /// to. The child is suspended as soon as it has checked in, `ready` is
+    /// called with what a debugger needs to attach, and the child is
+    /// resumed once it returns. If `ready` fails, the child is killed.
+    fn spawn_for_debugger<F>(&mut self, ready: F) -> Result<ChildWithTask>
+        where F: FnOnce(&DebuggerAttach) -> Result<()>,
+              Self: Sized
+    {
+        let mut child = self.spawn_with_task()?;
+        child.task_port().suspend()?;
+        if let Err(e) = ready(&DebuggerAttach::new(child.id())) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        // A debugger that attached may have resumed the child already,
+        // in which case this fails harmlessly.
+        let _ = child.task_port().resume();
+        Ok(child)
+    }
 }
 
 impl CommandSpawnWithTask for Command {
-    fn spawn_get_task_port(&mut self) -> Result<(Child, mach_port_t)> {
-        // First, create a port to which the child can send us a message.
-        let port = unsafe {
-            let mut port: mach_port_t = mem::uninitialized();
-            ktry!(mach_port_allocate(mach_task_self(), MACH_PORT_RIGHT_RECEIVE, &mut port));
-            let port = MachPort(port);
-
-            // Allocate a send right for the server port.
-            ktry!(mach_port_insert_right(mach_task_self(),
-                                         port.0,
-                                         port.0,
-                                         MACH_MSG_TYPE_MAKE_SEND));
-            port
-        };
+    fn spawn_with_task_port(&mut self) -> Result<(Child, TaskPort)> {
+        self.spawn_get_task_port_with(&SpawnOptions::new())
+    }
 
-        // Register the port with the bootstrap server.
-        let uuid = Uuid::new_v4().simple().to_string();
-        let name = CString::new(uuid).or(Err(Error::new(ErrorKind::Other, "CString")))?;
-        unsafe {
-            let mut bootstrap_port = mem::uninitialized();
-            ktry!(task_get_special_port(mach_task_self(),
-                                        TASK_BOOTSTRAP_PORT,
-                                        &mut bootstrap_port));
-            ktry!(bootstrap_register2(bootstrap_port, name.as_ptr(), port.0, 0));
-        }
-
-        let child = self.before_exec(move || {
-                unsafe {
-                    // Next, in the child process' `before_exec`, look up the
-                    // registered port.
-                    let mut bootstrap_port: mach_port_t = mem::uninitialized();
-                    ktry!(task_get_special_port(mach_task_self(),
-                                                TASK_BOOTSTRAP_PORT,
-                                                &mut bootstrap_port));
-
-                    let mut parent_port: mach_port_t = mem::uninitialized();
-                    ktry!(bootstrap_look_up(bootstrap_port, name.as_ptr(), &mut parent_port));
-                    let parent_port = MachPort(parent_port);
-                    // Now use the port to send our task port to the parent.
-                    let mut msg = SendMessage {
-                        header: mach_msg_header_t {
-                            msgh_bits: MACH_MSGH_BITS(MACH_MSG_TYPE_COPY_SEND, 0) |
-                                       MACH_MSGH_BITS_COMPLEX,
-                            msgh_size: mem::size_of::<SendMessage>() as u32,
-                            msgh_remote_port: parent_port.0,
-                            msgh_local_port: MACH_PORT_NULL,
-                            msgh_voucher_port: MACH_PORT_NULL,
-                            msgh_id: 0,
-                        },
-                        body: mach_msg_body_t { msgh_descriptor_count: 1 },
-                        task_port: mach_msg_port_descriptor_t::new(mach_task_self(),
-                                                                   MACH_MSG_TYPE_COPY_SEND),
-                    };
-                    ktry!(mach_msg_send(&mut msg.header));
-                }
-                Ok(())
+    fn spawn_get_task_port_with(&mut self, options: &SpawnOptions) -> Result<(Child, TaskPort)> {
+        let (child, task_port) = spawn_checking_in(self, None, options, |command| command.spawn())?;
+        Ok((child, unsafe { TaskPort::from_raw(task_port) }))
+    }
+
+    fn spawn_with_receiver(&mut self) -> Result<(Child, TaskPortReceiver)> {
+        self.spawn_with_receiver_with(&SpawnOptions::new())
+    }
+
+    fn spawn_with_receiver_with(&mut self,
+                                options: &SpawnOptions)
+                                -> Result<(Child, TaskPortReceiver)> {
+        receiver::spawn_deferred(self, options, |command| command.spawn())
+    }
+
+    fn spawn_with_identity_token(&mut self) -> Result<(Child, IdentityToken)> {
+        if !identity::available() {
+            return Err(identity::unavailable());
+        }
+        let (child, token) = spawn_checking_in(self,
+                                               identity::create_identity_token_fn(),
+                                               &SpawnOptions::new(),
+                                               |command| command.spawn())?;
+        Ok((child, unsafe { IdentityToken::from_raw(token) }))
+    }
+}
+
+/// A spawned process, whose pid a check-in can be verified against.
+trait SpawnedProcess {
+    fn pid(&self) -> u32;
+
+    /// Whether the process has exited, without blocking.
+    fn has_exited(&mut self) -> Result<bool>;
+
+    /// Kill and reap a process whose handshake failed.
+    fn abandon(&mut self);
+}
+
+impl SpawnedProcess for Child {
+    fn pid(&self) -> u32 {
+        self.id()
+    }
+
+    fn has_exited(&mut self) -> Result<bool> {
+        Ok(self.try_wait()?.is_some())
+    }
+
+    fn abandon(&mut self) {
+        let _ = self.kill();
+        let _ = self.wait();
+    }
+}
+
+/// Perform the whole handshake around `spawn`, which is given `command`
+/// with the child's `pre_exec` hook already installed, and should spawn it
+/// in whatever way the caller needs.
+///
+/// Every call registers its own port under its own random name, and all
+/// other state lives on the stack, so concurrent calls from different
+/// threads never share anything. The check-in is still verified to come
+/// from the process that was spawned.
+fn spawn_with_check_in<T, F>(command: &mut Command, spawn: F) -> Result<(T, mach_port_t)>
+    where T: SpawnedProcess,
+          F: FnOnce(&mut Command) -> Result<T>
+{
+    spawn_checking_in(command, None, &SpawnOptions::new(), spawn)
+}
+
+/// Like `spawn_with_check_in`, but have the child send an identity token
+/// made with `create_identity_token` instead, if given, and configure the
+/// handshake with `options`.
+fn spawn_checking_in<T, F>(command: &mut Command,
+                           create_identity_token: Option<identity::CreateIdentityToken>,
+                           options: &SpawnOptions,
+                           spawn: F)
+                           -> Result<(T, mach_port_t)>
+    where T: SpawnedProcess,
+          F: FnOnce(&mut Command) -> Result<T>
+{
+    diagnostics::record_handshake(|| {
+        options.check_supported()?;
+        let mut context = middleware::pre_register(command)?;
+        let audit = audit::Recorder::begin();
+        // First, create a port to which the child can send us a message,
+        // and register it with the bootstrap server.
+        let name = options.make_service_name()?;
+        let port = options.create_service(name.as_c_str())?;
+        audit.created(ResourceKind::ReceiveRight, format!("{:#x}", port.0));
+        audit.created(ResourceKind::SendRight, format!("{:#x}", port.0));
+        audit.created(ResourceKind::ServiceName, name.as_str());
+
+        // Everything the child needs is computed here, before `fork`, so
+        // the `pre_exec` hook only has to copy plain data.
+        let mut check_in = ChildCheckIn::with_options(name, options);
+        if create_identity_token.is_some() {
+            check_in.create_identity_token = create_identity_token;
+        }
+        let result = middleware::post_register(&mut context, name.as_str())
+            .and_then(|()| {
+                options.spawn_in_bootstrap(|| {
+                    spawn(unsafe { command.pre_exec(pre_exec_hook(check_in)) })
+                        .map_err(|e| Error::from(SpawnTaskPortError::from_spawn(e)))
+                })
             })
-            .spawn()?;
-        // In the parent, receive the child's task port.
-        let child_task_port = unsafe {
-            let mut msg: RecvMessage = mem::uninitialized();
-            //TODO: MACH_RCV_MSG |
-            // MACH_RCV_TRAILER_TYPE(MACH_RCV_TRAILER_AUDIT) |
-            // MACH_RCV_TRAILER_ELEMENTS(MACH_RCV_TRAILER_AUDIT)
-            ktry!(mach_msg(&mut msg.header,
-                           MACH_RCV_MSG,
-                           0,
-                           mem::size_of::<RecvMessage>() as u32,
-                           port.0,
-                           MACH_MSG_TIMEOUT_NONE,
-                           MACH_PORT_NULL));
-            //TODO: Check that this message came from the child process
-            // with `audit_token_to_pid`.
-            msg.task_port.name
-        };
-        Ok((child, child_task_port))
+            .and_then(|mut child| {
+                // In the parent, receive the child's task port.
+                context.set_pid(child.pid());
+                audit.set_pid(child.pid());
+                let received = receive_check_in(port.0,
+                                                child.pid(),
+                                                options.receive_timeout,
+                                                options.verify_audit,
+                                                options.trailer)
+                    .and_then(|(port, _)| match options.token_flavor() {
+                        // A token the options asked for becomes a task port
+                        // here.
+                        Some(flavor) => {
+                            identity::token_to_task_port(port, flavor).map(TaskPort::into_raw)
+                        }
+                        None => Ok(port),
+                    })
+                    .and_then(|task_port| {
+                        // The caller's own identity tokens aren't task
+                        // ports, so only task ports go through the
+                        // post-receive stage.
+                        if create_identity_token.is_none() {
+                            let borrowed = unsafe { TaskPort::borrow_raw(task_port) };
+                            if let Err(e) = middleware::post_receive(&context, &borrowed) {
+                                drop(MachPort(task_port));
+                                return Err(e);
+                            }
+                        }
+                        Ok(task_port)
+                    });
+                match received {
+                    Ok(task_port) => Ok((child, task_port)),
+                    Err(e) => {
+                        child.abandon();
+                        Err(e)
+                    }
+                }
+            });
+        if options.unregister {
+            // Destroying the receive right unregisters the service.
+            unsafe {
+                mach_port_mod_refs(mach_task_self(), port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+            }
+            audit.released(ResourceKind::ReceiveRight);
+            audit.released(ResourceKind::ServiceName);
+        }
+        // `port` is dropped on the way out.
+        audit.released(ResourceKind::SendRight);
+        result
+    })
+}
+
+/// Allocate a receive right, along with a send right under the same name,
+/// that children can send their task ports to.
+fn allocate_server_port() -> Result<MachPort> {
+    unsafe {
+        let mut port: mach_port_t = mem::uninitialized();
+        ktrace!(mach_port_allocate(mach_task_self(), MACH_PORT_RIGHT_RECEIVE, &mut port));
+        let port = MachPort(port);
+
+        // Allocate a send right for the server port.
+        ktrace!(mach_port_insert_right(mach_task_self(), port.0, port.0, MACH_MSG_TYPE_MAKE_SEND));
+        Ok(port)
+    }
+}
+
+/// Register `port` with the bootstrap server as `name`.
+fn register_service(name: &CStr, port: mach_port_t) -> Result<()> {
+    unsafe {
+        let mut bootstrap_port = mem::uninitialized();
+        ktrace!(task_get_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, &mut bootstrap_port));
+        ktrace!(bootstrap_register2(bootstrap_port, name.as_ptr(), port, 0));
+    }
+    Ok(())
+}
+
+/// The options to receive a check-in message with `trailer`.
+///
+/// Only ask for a trailer wherever the running OS supports them; the
+/// default one, the audit trailer, identifies the sender.
+fn receive_options(trailer: TrailerType) -> mach_msg_option_t {
+    if capabilities::running_at_least(capabilities::AUDIT_TRAILERS) {
+        MACH_RCV_MSG | trailer.to_option()
+    } else {
+        MACH_RCV_MSG
+    }
+}
+
+/// Block until a child's check-in message arrives on `port`, using `msg` as
+/// the receive buffer, and return the task port it carried.
+unsafe fn receive_task_port(port: mach_port_t, msg: &mut RecvMessage) -> Result<mach_port_t> {
+    receive_task_port_timeout(port, msg, None)
+}
+
+/// Like `receive_task_port`, but fail with `ReceiveTimeout` if nothing
+/// arrives within `timeout`.
+unsafe fn receive_task_port_timeout(port: mach_port_t,
+                                    msg: &mut RecvMessage,
+                                    timeout: Option<Duration>)
+                                    -> Result<mach_port_t> {
+    receive_task_port_with(port, msg, timeout, TrailerType::default())
+}
+
+/// Like `receive_task_port_timeout`, but ask for `trailer`.
+unsafe fn receive_task_port_with(port: mach_port_t,
+                                 msg: &mut RecvMessage,
+                                 timeout: Option<Duration>,
+                                 trailer: TrailerType)
+                                 -> Result<mach_port_t> {
+    const CALL: &'static str = "mach_msg(MACH_RCV_MSG)";
+    let (option, timeout_ms) = match timeout {
+        Some(timeout) => {
+            (MACH_RCV_TIMEOUT, timeout.as_millis().min(u32::max_value() as u128) as u32)
+        }
+        None => (0, MACH_MSG_TIMEOUT_NONE),
+    };
+    let kr = mach_msg(&mut msg.header,
+                      receive_options(trailer) | option,
+                      0,
+                      mem::size_of::<RecvMessage>() as u32,
+                      port,
+                      timeout_ms,
+                      MACH_PORT_NULL);
+    let mut arguments = Vec::new();
+    diagnostics::describe(&port, &mut arguments);
+    diagnostics::describe(&timeout_ms, &mut arguments);
+    diagnostics::record(CALL, arguments, kr);
+    match kr {
+        KERN_SUCCESS => Ok(msg.task_port.name),
+        MACH_RCV_TIMED_OUT => Err(SpawnTaskPortError::ReceiveTimeout.into()),
+        _ => Err(SpawnTaskPortError::from_call(CALL, kr).into()),
+    }
+}
+
+/// Receive the check-in from the process `pid` on `port`, waiting at most
+/// `timeout` if given, and return the task port it carried along with its
+/// `trailer`. If `verify_audit` is set, check that the process `pid` sent
+/// it.
+fn receive_check_in(port: mach_port_t,
+                    pid: u32,
+                    timeout: Option<Duration>,
+                    verify_audit: bool,
+                    trailer: TrailerType)
+                    -> Result<(mach_port_t, MessageTrailer)> {
+    let mut msg: RecvMessage = unsafe { mem::zeroed() };
+    let task_port = MachPort(unsafe { receive_task_port_with(port, &mut msg, timeout, trailer)? });
+    if verify_audit {
+        // Trust the kernel's word for who sent the message over the pid in
+        // it, wherever the kernel gives one.
+        let sender = msg.audit_pid().unwrap_or(msg.pid as u32);
+        if sender != pid {
+            return Err(SpawnTaskPortError::AuditMismatch {
+                    expected: pid,
+                    actual: sender,
+                }
+                .into());
+        }
+    }
+    Ok((task_port.into_raw(), msg.trailer.parse()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_exec_hook_only_captures_check_in() {
+        let check_in = ChildCheckIn::new(ServiceName::random().unwrap());
+        let hook = pre_exec_hook(check_in);
+        assert_eq!(mem::size_of_val(&hook), mem::size_of::<ChildCheckIn>());
+    }
+
+    #[test]
+    fn service_names_are_nul_terminated_hex() {
+        let name = ServiceName::random().unwrap();
+        let bytes = name.as_c_str().to_bytes();
+        assert_eq!(bytes.len(), SERVICE_NAME_RANDOM_BYTES * 2);
+        assert!(bytes.iter().all(|b| b.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn service_names_must_fit_inline() {
+        assert_eq!(ServiceName::new("com.example.helper").unwrap().as_str(),
+                   "com.example.helper");
+        assert!(ServiceName::new("").is_none());
+        assert!(ServiceName::new("a\0b").is_none());
+        assert!(ServiceName::new(&"a".repeat(SERVICE_NAME_MAX)).is_some());
+        assert!(ServiceName::new(&"a".repeat(SERVICE_NAME_MAX + 1)).is_none());
     }
 }
@@ -0,0 +1,336 @@
+//! Memory allocated in, or shared with, a child's address space.
+//!
+//! Every region this module creates is tagged with a `VmTag`, which is
+//! what `vmmap` and `footprint` group anonymous memory by. macOS has no
+//! way to give an anonymous region a name, so the tag is how the crate's
+//! allocations are told apart from the child's own: the default is the
+//! last of the sixteen application-specific tags, which `vmmap` lists as
+//! "Memory Tag 255". Pick a different one if the child uses it itself.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::c_int;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::message::mach_msg_type_number_t;
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+use mach::traps::mach_task_self;
+use mach::vm::{mach_make_memory_entry_64, mach_vm_allocate, mach_vm_deallocate,
+               mach_vm_read_overwrite};
+use mach::vm_inherit::VM_INHERIT_NONE;
+use mach::vm_prot::{VM_PROT_DEFAULT, vm_prot_t};
+
+use {MachPort, TaskPort};
+
+/// `VM_FLAGS_ANYWHERE` from `<mach/vm_statistics.h>`.
+const VM_FLAGS_ANYWHERE: c_int = 1;
+/// `MAP_MEM_NAMED_CREATE` from `<mach/memory_object_types.h>`: create a
+/// fresh memory object rather than sharing an existing mapping.
+const MAP_MEM_NAMED_CREATE: vm_prot_t = 0x02_0000;
+/// `VM_REGION_EXTENDED_INFO`.
+const VM_REGION_EXTENDED_INFO: c_int = 13;
+
+/// `struct vm_region_extended_info`
+#[repr(C)]
+struct vm_region_extended_info {
+    protection: vm_prot_t,
+    user_tag: u32,
+    pages_resident: u32,
+    pages_shared_now_private: u32,
+    pages_swapped_out: u32,
+    pages_dirtied: u32,
+    ref_count: u32,
+    shadow_depth: u16,
+    external_pager: u8,
+    share_mode: u8,
+    pages_reusable: u32,
+}
+
+extern "C" {
+    // The `mach` crate declares these with the wrong signatures.
+    fn mach_vm_map(target_task: mach_port_t,
+                   address: *mut u64,
+                   size: u64,
+                   mask: u64,
+                   flags: c_int,
+                   object: mach_port_t,
+                   offset: u64,
+                   copy: u32,
+                   cur_protection: vm_prot_t,
+                   max_protection: vm_prot_t,
+                   inheritance: u32)
+                   -> kern_return_t;
+    fn mach_vm_write(target_task: mach_port_t,
+                     address: u64,
+                     data: usize,
+                     data_count: mach_msg_type_number_t)
+                     -> kern_return_t;
+    fn mach_vm_region(target_task: mach_port_t,
+                      address: *mut u64,
+                      size: *mut u64,
+                      flavor: c_int,
+                      info: *mut c_int,
+                      info_count: *mut mach_msg_type_number_t,
+                      object_name: *mut mach_port_t)
+                      -> kern_return_t;
+}
+
+/// A VM user tag, which attributes a region to a subsystem in `vmmap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct VmTag(u8);
+
+impl VmTag {
+    /// The tag this crate uses unless told otherwise,
+    /// `VM_MEMORY_APPLICATION_SPECIFIC_16`.
+    pub const DEFAULT: VmTag = VmTag(255);
+
+    /// Wrap a raw `VM_MEMORY_*` tag.
+    pub fn from_raw(raw: u8) -> VmTag {
+        VmTag(raw)
+    }
+
+    /// `VM_MEMORY_APPLICATION_SPECIFIC_<n>`, for `n` from 1 to 16.
+    pub fn application_specific(n: u8) -> Option<VmTag> {
+        if (1..=16).contains(&n) {
+            Some(VmTag(239 + n))
+        } else {
+            None
+        }
+    }
+
+    /// The raw `VM_MEMORY_*` tag.
+    pub fn raw(self) -> u8 {
+        self.0
+    }
+
+    /// `VM_MAKE_TAG`: the tag's bits in the `flags` of an allocation.
+    fn flags(self) -> c_int {
+        (self.0 as c_int) << 24
+    }
+}
+
+impl Default for VmTag {
+    fn default() -> VmTag {
+        VmTag::DEFAULT
+    }
+}
+
+fn check_range(offset: usize, len: usize, size: usize) -> Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(Error::new(ErrorKind::InvalidInput, "range is outside the region")),
+    }
+}
+
+impl TaskPort {
+    /// Copy `buf.len()` bytes from `address` in the task into `buf`.
+    pub fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<()> {
+        let mut read = 0;
+        unsafe {
+            ktry!(mach_vm_read_overwrite(self.as_raw(),
+                                         address,
+                                         buf.len() as u64,
+                                         buf.as_mut_ptr() as u64,
+                                         &mut read));
+        }
+        if read != buf.len() as u64 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "short read of task memory"));
+        }
+        Ok(())
+    }
+
+    /// Copy `data` to `address` in the task.
+    pub fn write_memory(&self, address: u64, data: &[u8]) -> Result<()> {
+        unsafe {
+            ktry!(mach_vm_write(self.as_raw(),
+                                address,
+                                data.as_ptr() as usize,
+                                data.len() as mach_msg_type_number_t));
+        }
+        Ok(())
+    }
+
+    /// The VM user tag of the region containing `address` in the task.
+    pub fn vm_tag(&self, address: u64) -> Result<VmTag> {
+        let mut start = address;
+        let mut size = 0;
+        let mut object_name = MACH_PORT_NULL;
+        unsafe {
+            let mut info: vm_region_extended_info = mem::zeroed();
+            let mut count = (mem::size_of::<vm_region_extended_info>() / 4) as
+                            mach_msg_type_number_t;
+            ktry!(mach_vm_region(self.as_raw(),
+                                 &mut start,
+                                 &mut size,
+                                 VM_REGION_EXTENDED_INFO,
+                                 &mut info as *mut _ as *mut c_int,
+                                 &mut count,
+                                 &mut object_name));
+            if start > address {
+                return Err(Error::new(ErrorKind::NotFound, "address is not mapped"));
+            }
+            Ok(VmTag(info.user_tag as u8))
+        }
+    }
+}
+
+/// Memory allocated in a child's address space, which is deallocated when
+/// the `RemoteMemory` is dropped.
+#[derive(Debug)]
+pub struct RemoteMemory {
+    task: TaskPort,
+    address: u64,
+    len: usize,
+}
+
+impl RemoteMemory {
+    /// Allocate `len` bytes of zeroed, read-write memory in `task`, tagged
+    /// with `tag`.
+    pub fn allocate(task: &TaskPort, len: usize, tag: VmTag) -> Result<RemoteMemory> {
+        let task = task.try_clone()?;
+        let mut address = 0;
+        unsafe {
+            ktry!(mach_vm_allocate(task.as_raw(),
+                                   &mut address,
+                                   len as u64,
+                                   VM_FLAGS_ANYWHERE | tag.flags()));
+        }
+        Ok(RemoteMemory {
+            task: task,
+            address: address,
+            len: len,
+        })
+    }
+
+    /// The address of the memory in the child.
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// The size of the allocation.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the allocation is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy `buf.len()` bytes starting `offset` bytes in into `buf`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        check_range(offset, buf.len(), self.len)?;
+        self.task.read_memory(self.address + offset as u64, buf)
+    }
+
+    /// Copy `data` to `offset` bytes in.
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<()> {
+        check_range(offset, data.len(), self.len)?;
+        self.task.write_memory(self.address + offset as u64, data)
+    }
+}
+
+impl Drop for RemoteMemory {
+    fn drop(&mut self) {
+        // The child may be gone already, in which case so is the memory.
+        unsafe {
+            mach_vm_deallocate(self.task.as_raw(), self.address, self.len as u64);
+        }
+    }
+}
+
+/// Memory mapped into both this process and a child, so that either sees
+/// the other's writes straight away. Both mappings are unmapped when the
+/// `SharedMemory` is dropped, and neither is inherited across `fork`.
+#[derive(Debug)]
+pub struct SharedMemory {
+    task: TaskPort,
+    local: u64,
+    remote: u64,
+    len: usize,
+}
+
+// The raw mapping is only reached through `as_ptr`, whose users are
+// responsible for synchronizing access.
+unsafe impl Send for SharedMemory {}
+unsafe impl Sync for SharedMemory {}
+
+/// Map the memory object `entry` into `task`, returning the address.
+fn map_entry(task: mach_port_t, entry: &MachPort, len: usize, tag: VmTag) -> Result<u64> {
+    let mut address = 0;
+    unsafe {
+        ktry!(mach_vm_map(task,
+                          &mut address,
+                          len as u64,
+                          0,
+                          VM_FLAGS_ANYWHERE | tag.flags(),
+                          entry.0,
+                          0,
+                          0,
+                          VM_PROT_DEFAULT,
+                          VM_PROT_DEFAULT,
+                          VM_INHERIT_NONE));
+    }
+    Ok(address)
+}
+
+impl SharedMemory {
+    /// Create `len` bytes of zeroed memory shared with `task`, tagged with
+    /// `tag` on both sides.
+    pub fn new(task: &TaskPort, len: usize, tag: VmTag) -> Result<SharedMemory> {
+        let task = task.try_clone()?;
+        let entry = unsafe {
+            let mut size = len as u64;
+            let mut entry = MACH_PORT_NULL;
+            ktry!(mach_make_memory_entry_64(mach_task_self(),
+                                            &mut size,
+                                            0,
+                                            VM_PROT_DEFAULT | MAP_MEM_NAMED_CREATE,
+                                            &mut entry,
+                                            MACH_PORT_NULL));
+            MachPort(entry)
+        };
+        let local = map_entry(unsafe { mach_task_self() }, &entry, len, tag)?;
+        let mut shared = SharedMemory {
+            task: task,
+            local: local,
+            remote: 0,
+            len: len,
+        };
+        shared.remote = map_entry(shared.task.as_raw(), &entry, len, tag)?;
+        // The mappings keep the memory object alive without `entry`.
+        Ok(shared)
+    }
+
+    /// The memory in this process.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.local as *mut u8
+    }
+
+    /// The address of the memory in the child.
+    pub fn remote_address(&self) -> u64 {
+        self.remote
+    }
+
+    /// The size of the shared memory.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the shared memory is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            if self.remote != 0 {
+                mach_vm_deallocate(self.task.as_raw(), self.remote, self.len as u64);
+            }
+            mach_vm_deallocate(mach_task_self(), self.local, self.len as u64);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! A watchdog on a child's memory footprint.
+//!
+//! Plugin hosts that run untrusted helpers want to stop one before it takes
+//! the whole machine into swap. A `MemoryWatchdog` samples the task's
+//! `phys_footprint` on an interval and warns, suspends or kills it as the
+//! footprint crosses each configured threshold. A threshold only fires
+//! again once the footprint has fallen a hysteresis margin below it, so a
+//! footprint hovering around a threshold doesn't fire it over and over.
+
+use std::fmt;
+use std::io::Result;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use TaskPort;
+
+/// What to do when the footprint crosses a threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum WatchdogAction {
+    /// Only report it.
+    Warn,
+    /// Suspend the task. It stays suspended until the caller resumes it.
+    Suspend,
+    /// Terminate the task, which also stops the watchdog.
+    Kill,
+}
+
+/// A threshold that fired, reported to the watchdog's callback after its
+/// action has been taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct MemoryEvent {
+    /// What was done.
+    pub action: WatchdogAction,
+    /// The threshold that was crossed, in bytes.
+    pub threshold: u64,
+    /// The footprint that crossed it, in bytes.
+    pub footprint: u64,
+}
+
+/// The thresholds a `MemoryWatchdog` enforces, and how it samples.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryThresholds {
+    levels: Vec<(u64, WatchdogAction)>,
+    interval: Duration,
+    hysteresis: u64,
+}
+
+impl MemoryThresholds {
+    /// No thresholds, sampling every 500ms with a hysteresis of 16MB.
+    pub fn new() -> MemoryThresholds {
+        MemoryThresholds {
+            levels: Vec::new(),
+            interval: Duration::from_millis(500),
+            hysteresis: 16 << 20,
+        }
+    }
+
+    /// Take `action` when the footprint goes over `bytes`.
+    pub fn at(mut self, bytes: u64, action: WatchdogAction) -> MemoryThresholds {
+        self.levels.push((bytes, action));
+        self.levels.sort_by_key(|&(bytes, _)| bytes);
+        self
+    }
+
+    /// Warn when the footprint goes over `bytes`.
+    pub fn warn_at(self, bytes: u64) -> MemoryThresholds {
+        self.at(bytes, WatchdogAction::Warn)
+    }
+
+    /// Suspend the task when the footprint goes over `bytes`.
+    pub fn suspend_at(self, bytes: u64) -> MemoryThresholds {
+        self.at(bytes, WatchdogAction::Suspend)
+    }
+
+    /// Kill the task when the footprint goes over `bytes`.
+    pub fn kill_at(self, bytes: u64) -> MemoryThresholds {
+        self.at(bytes, WatchdogAction::Kill)
+    }
+
+    /// Sample the footprint every `interval`.
+    pub fn interval(self, interval: Duration) -> MemoryThresholds {
+        MemoryThresholds { interval: interval, ..self }
+    }
+
+    /// Only fire a threshold again once the footprint has fallen `bytes`
+    /// below it.
+    pub fn hysteresis(self, bytes: u64) -> MemoryThresholds {
+        MemoryThresholds { hysteresis: bytes, ..self }
+    }
+}
+
+impl Default for MemoryThresholds {
+    fn default() -> MemoryThresholds {
+        MemoryThresholds::new()
+    }
+}
+
+/// A thread enforcing `MemoryThresholds` on a task, which stops when
+/// dropped, when the task exits, or once it has killed the task.
+pub struct MemoryWatchdog {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MemoryWatchdog {
+    /// Start watching `task`, calling `on_event` on the watchdog's thread
+    /// each time a threshold fires.
+    pub fn start<F>(task: &TaskPort,
+                    thresholds: MemoryThresholds,
+                    mut on_event: F)
+                    -> Result<MemoryWatchdog>
+        where F: FnMut(MemoryEvent) + Send + 'static
+    {
+        let task = task.try_clone()?;
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut armed = vec![true; thresholds.levels.len()];
+            loop {
+                let footprint = match task.vm_info() {
+                    Ok(info) => info.phys_footprint,
+                    // The task has exited.
+                    Err(_) => return,
+                };
+                for (i, &(threshold, action)) in thresholds.levels.iter().enumerate() {
+                    if armed[i] && footprint > threshold {
+                        armed[i] = false;
+                        let _ = match action {
+                            WatchdogAction::Warn => Ok(()),
+                            WatchdogAction::Suspend => task.suspend(),
+                            WatchdogAction::Kill => task.terminate(),
+                        };
+                        on_event(MemoryEvent {
+                            action: action,
+                            threshold: threshold,
+                            footprint: footprint,
+                        });
+                        if action == WatchdogAction::Kill {
+                            return;
+                        }
+                    } else if !armed[i] &&
+                              footprint < threshold.saturating_sub(thresholds.hysteresis) {
+                        armed[i] = true;
+                    }
+                }
+                match stopped.recv_timeout(thresholds.interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+        Ok(MemoryWatchdog {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl fmt::Debug for MemoryWatchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryWatchdog").finish()
+    }
+}
+
+impl Drop for MemoryWatchdog {
+    fn drop(&mut self) {
+        // Hanging up wakes the thread.
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
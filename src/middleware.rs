@@ -0,0 +1,200 @@
+//! Hooks that run around every spawn made through the crate.
+//!
+//! Middleware added with `add_spawn_middleware` sees each spawn at four
+//! stages: before the parent registers the port the child checks in with,
+//! after it has, in the child just before it checks in, and after the
+//! parent has received the child's task port. Any stage can veto the spawn
+//! by failing, and the parent's stages share a `SpawnContext` they can
+//! annotate, so that a policy can be enforced across a whole binary, by
+//! logging, scrubbing environments or checking signatures, whichever
+//! library in it spawns the children.
+//!
+//! Middleware can only be added, never removed, and lives until the
+//! process exits. Each stage runs the middleware in the order it was
+//! added.
+//!
+//! Spawns through a `MachPortBroker` register nothing of their own, so
+//! their post-register stage sees the broker's service name, and those
+//! through `spawn_with_receiver` and the async integrations run the
+//! post-receive stage when the task port is received from their
+//! `TaskPortReceiver`. The crate never sees the commands a `duct`
+//! expression spawns, so for `ExpressionSpawnWithTask` only the child's
+//! stage runs.
+
+use std::ffi::{OsStr, OsString};
+use std::io::Result;
+use std::process::Command;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use TaskPort;
+
+/// Callbacks for the stages of a spawn, which all default to doing
+/// nothing.
+pub trait SpawnMiddleware: Send + Sync {
+    /// Runs before the port the child checks in with is registered, with
+    /// the command about to be spawned, which can still be changed.
+    fn pre_register(&self, _spawn: &mut SpawnContext, _command: &mut Command) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs once the port is registered, when `SpawnContext::service_name`
+    /// is known.
+    fn post_register(&self, _spawn: &mut SpawnContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs in the child between `fork` and `exec`, before it checks in.
+    ///
+    /// This is subject to the same rules as a `pre_exec` hook: it must not
+    /// allocate, take locks or do anything else that isn't
+    /// async-signal-safe, and it can't tell which spawn it is part of. To
+    /// veto the spawn, return an error from `Error::from_raw_os_error`,
+    /// which doesn't allocate.
+    fn pre_exec_child(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs once the child's task port has been received. If this fails,
+    /// the child is killed, except for spawns through `spawn_with_receiver`
+    /// and the async integrations, where the caller holds the `Child` and
+    /// only gets the error.
+    fn post_receive(&self, _spawn: &SpawnContext, _task_port: &TaskPort) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What the parent's stages know about a spawn.
+#[derive(Clone, Debug, Default)]
+pub struct SpawnContext {
+    program: OsString,
+    service_name: Option<String>,
+    pid: Option<u32>,
+    annotations: Vec<(String, String)>,
+}
+
+impl SpawnContext {
+    fn new(command: &Command) -> SpawnContext {
+        SpawnContext { program: command.get_program().to_owned(), ..SpawnContext::default() }
+    }
+
+    /// The program being spawned.
+    pub fn program(&self) -> &OsStr {
+        &self.program
+    }
+
+    /// The bootstrap service name the child checks in with, once it has
+    /// been registered.
+    pub fn service_name(&self) -> Option<&str> {
+        self.service_name.as_deref()
+    }
+
+    /// The child's pid, once it has been spawned.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    pub(crate) fn set_pid(&mut self, pid: u32) {
+        self.pid = Some(pid);
+    }
+
+    /// Attach `value` to the spawn under `key`, for later stages, replacing
+    /// any earlier value.
+    pub fn annotate(&mut self, key: &str, value: &str) {
+        match self.annotations.iter_mut().find(|annotation| annotation.0 == key) {
+            Some(annotation) => annotation.1 = value.to_owned(),
+            None => self.annotations.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    /// The value attached under `key`, if any.
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        self.annotations
+            .iter()
+            .find(|annotation| annotation.0 == key)
+            .map(|annotation| &annotation.1[..])
+    }
+
+    /// Every annotation, in the order they were first made.
+    pub fn annotations(&self) -> &[(String, String)] {
+        &self.annotations
+    }
+}
+
+/// A link in the list of middleware, which is never freed, so the child can
+/// walk the list without taking a lock that might have been held at `fork`.
+struct Node {
+    middleware: Box<dyn SpawnMiddleware>,
+    next: AtomicPtr<Node>,
+}
+
+static HEAD: AtomicPtr<Node> = AtomicPtr::new(ptr::null_mut());
+
+/// Run `middleware` for every spawn from now on, after any added before.
+pub fn add_spawn_middleware<M: SpawnMiddleware + 'static>(middleware: M) {
+    let node = Box::into_raw(Box::new(Node {
+        middleware: Box::new(middleware),
+        next: AtomicPtr::new(ptr::null_mut()),
+    }));
+    let mut link = &HEAD;
+    loop {
+        match link.compare_exchange(ptr::null_mut(), node, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return,
+            Err(next) => link = unsafe { &(*next).next },
+        }
+    }
+}
+
+/// Call `f` with each middleware in turn, stopping at the first failure.
+fn each<F>(mut f: F) -> Result<()>
+    where F: FnMut(&dyn SpawnMiddleware) -> Result<()>
+{
+    let mut node = HEAD.load(Ordering::Acquire);
+    while let Some(current) = unsafe { node.as_ref() } {
+        f(&*current.middleware)?;
+        node = current.next.load(Ordering::Acquire);
+    }
+    Ok(())
+}
+
+/// Run the pre-register stage for `command`, returning the context for the
+/// rest of the spawn.
+pub(crate) fn pre_register(command: &mut Command) -> Result<SpawnContext> {
+    let mut spawn = SpawnContext::new(command);
+    each(|middleware| middleware.pre_register(&mut spawn, command))?;
+    Ok(spawn)
+}
+
+/// Run the post-register stage, now that the child will check in with
+/// `service_name`.
+pub(crate) fn post_register(spawn: &mut SpawnContext, service_name: &str) -> Result<()> {
+    spawn.service_name = Some(service_name.to_owned());
+    each(|middleware| middleware.post_register(spawn))
+}
+
+/// Run the child's stage. This runs between `fork` and `exec`.
+pub(crate) fn pre_exec_child() -> Result<()> {
+    each(|middleware| middleware.pre_exec_child())
+}
+
+/// Run the post-receive stage, with the child's pid already set.
+pub(crate) fn post_receive(spawn: &SpawnContext, task_port: &TaskPort) -> Result<()> {
+    each(|middleware| middleware.post_receive(spawn, task_port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotations_replace_earlier_values() {
+        let mut spawn = SpawnContext::new(&Command::new("/bin/true"));
+        assert_eq!(spawn.program(), OsStr::new("/bin/true"));
+        spawn.annotate("policy", "audit");
+        spawn.annotate("owner", "ci");
+        spawn.annotate("policy", "enforce");
+        assert_eq!(spawn.annotation("policy"), Some("enforce"));
+        assert_eq!(spawn.annotation("missing"), None);
+        assert_eq!(spawn.annotations()[1], ("owner".to_owned(), "ci".to_owned()));
+    }
+}
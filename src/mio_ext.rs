@@ -0,0 +1,34 @@
+//! Registering a `TaskPortReceiver` with a `mio` event loop, enabled by the
+//! `mio` feature.
+//!
+//! The receiver's kqueue watches its port with `EVFILT_MACHPORT`, so
+//! registering it for readable interest delivers an event once the child
+//! has checked in, after which `try_recv` returns the task port without
+//! blocking.
+
+use std::io::Result;
+use std::os::unix::io::AsRawFd;
+
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+use TaskPortReceiver;
+
+impl Source for TaskPortReceiver {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self,
+                  registry: &Registry,
+                  token: Token,
+                  interests: Interest)
+                  -> Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
@@ -0,0 +1,130 @@
+//! Listing the images loaded into a task.
+//!
+//! dyld keeps a `dyld_all_image_infos` structure in every process, listing
+//! each loaded image's load address and path, for debuggers and the crash
+//! reporter. `TASK_DYLD_INFO` says where it is, and the task port lets us
+//! read it.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::slice;
+
+use mach::task_info::{TASK_DYLD_ALL_IMAGE_INFO_64, TASK_DYLD_INFO};
+
+use info::get_info;
+use TaskPort;
+
+/// How much of an image path to read at a time.
+const PATH_CHUNK: usize = 256;
+/// `PATH_MAX`, beyond which a path is assumed to be garbage.
+const PATH_MAX: usize = 1024;
+
+/// `struct task_dyld_info`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct task_dyld_info {
+    all_image_info_addr: u64,
+    all_image_info_size: u64,
+    all_image_info_format: i32,
+}
+
+/// The start of `struct dyld_all_image_infos`, which is all we need.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct dyld_all_image_infos {
+    version: u32,
+    info_array_count: u32,
+    info_array: u64,
+}
+
+/// `struct dyld_image_info`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct dyld_image_info {
+    image_load_address: u64,
+    image_file_path: u64,
+    image_file_mod_date: u64,
+}
+
+/// An image loaded into a task.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Module {
+    /// The address of the image's Mach-O header.
+    pub load_address: u64,
+    /// The path the image was loaded from.
+    pub path: String,
+}
+
+/// Read a `T` from `address` in `task`.
+pub(crate) fn read_struct<T: Copy>(task: &TaskPort, address: u64) -> Result<T> {
+    unsafe {
+        let mut value: T = mem::zeroed();
+        let buf = slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, mem::size_of::<T>());
+        task.read_memory(address, buf)?;
+        Ok(value)
+    }
+}
+
+/// Read a NUL-terminated string from `address` in `task`, a chunk at a
+/// time so as not to read past the end of its region.
+pub(crate) fn read_c_string(task: &TaskPort, address: u64) -> Result<String> {
+    let mut bytes = Vec::new();
+    while bytes.len() < PATH_MAX {
+        let start = address + bytes.len() as u64;
+        // Don't let a chunk cross a page boundary, which might be the end
+        // of the mapping.
+        let len = PATH_CHUNK.min(0x1000 - (start & 0xfff) as usize);
+        let mut chunk = vec![0; len];
+        task.read_memory(start, &mut chunk)?;
+        if let Some(end) = chunk.iter().position(|&b| b == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return Ok(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Err(Error::new(ErrorKind::InvalidData, "image path is not terminated"))
+}
+
+/// The address of `task`'s `dyld_all_image_infos`, or `None` if dyld hasn't
+/// set it up yet.
+pub(crate) fn all_image_infos_address(task: &TaskPort) -> Result<Option<u64>> {
+    let dyld: task_dyld_info = get_info(task, TASK_DYLD_INFO)?;
+    if dyld.all_image_info_addr == 0 {
+        return Ok(None);
+    }
+    if dyld.all_image_info_format != TASK_DYLD_ALL_IMAGE_INFO_64 as i32 {
+        return Err(Error::new(ErrorKind::Other, "32-bit tasks are not supported"));
+    }
+    Ok(Some(dyld.all_image_info_addr))
+}
+
+impl TaskPort {
+    /// List the images loaded into the task, starting with the main
+    /// executable.
+    ///
+    /// A task that hasn't finished starting up, or is in the middle of
+    /// loading or unloading an image, may have no list; this returns an
+    /// empty one then, so retry if that matters.
+    pub fn modules(&self) -> Result<Vec<Module>> {
+        let address = match all_image_infos_address(self)? {
+            Some(address) => address,
+            None => return Ok(Vec::new()),
+        };
+        let infos: dyld_all_image_infos = read_struct(self, address)?;
+        // dyld clears the array pointer while it updates the list.
+        if infos.info_array == 0 {
+            return Ok(Vec::new());
+        }
+        let mut modules = Vec::with_capacity(infos.info_array_count as usize);
+        for i in 0..infos.info_array_count as u64 {
+            let address = infos.info_array + i * mem::size_of::<dyld_image_info>() as u64;
+            let image: dyld_image_info = read_struct(self, address)?;
+            modules.push(Module {
+                load_address: image.image_load_address,
+                path: read_c_string(self, image.image_file_path)?,
+            });
+        }
+        Ok(modules)
+    }
+}
@@ -0,0 +1,167 @@
+//! Node.js bindings, enabled by the `napi` feature.
+//!
+//! These are meant to be built into a Node.js addon by a small `cdylib`
+//! crate that depends on this one with the `napi` feature enabled (and uses
+//! `napi-build` in its build script), so this crate itself can stay an
+//! ordinary library. From JavaScript:
+//!
+//! ```js
+//! const { spawnWithTaskPort } = require('./spawn-task-port.node');
+//! const child = spawnWithTaskPort('/usr/bin/some-helper', ['--flag']);
+//! console.log(child.pid, child.taskPort, child.vmInfo().physFootprint);
+//! child.kill();
+//! child.wait();
+//! ```
+
+use std::io;
+use std::process::Command;
+
+use napi::{Error, Result};
+
+use {ChildWithTask, CommandSpawnWithTask, TaskBasicInfo, TaskVmInfo};
+
+fn to_napi(e: io::Error) -> Error {
+    Error::from_reason(e.to_string())
+}
+
+/// Basic information about a task, as returned by `SpawnedChild.basicInfo()`.
+///
+/// Sizes are in bytes and times in microseconds.
+#[napi(object)]
+pub struct JsTaskBasicInfo {
+    pub virtual_size: i64,
+    pub resident_size: i64,
+    pub resident_size_max: i64,
+    pub user_time: i64,
+    pub system_time: i64,
+    pub suspend_count: i32,
+}
+
+/// Virtual memory statistics for a task, as returned by
+/// `SpawnedChild.vmInfo()`. Sizes are in bytes.
+#[napi(object)]
+pub struct JsTaskVmInfo {
+    pub virtual_size: i64,
+    pub region_count: i32,
+    pub resident_size: i64,
+    pub resident_size_peak: i64,
+    pub compressed: i64,
+    pub phys_footprint: i64,
+}
+
+impl From<TaskBasicInfo> for JsTaskBasicInfo {
+    fn from(info: TaskBasicInfo) -> JsTaskBasicInfo {
+        JsTaskBasicInfo {
+            virtual_size: info.virtual_size as i64,
+            resident_size: info.resident_size as i64,
+            resident_size_max: info.resident_size_max as i64,
+            user_time: info.user_time.as_micros() as i64,
+            system_time: info.system_time.as_micros() as i64,
+            suspend_count: info.suspend_count,
+        }
+    }
+}
+
+impl From<TaskVmInfo> for JsTaskVmInfo {
+    fn from(info: TaskVmInfo) -> JsTaskVmInfo {
+        JsTaskVmInfo {
+            virtual_size: info.virtual_size as i64,
+            region_count: info.region_count,
+            resident_size: info.resident_size as i64,
+            resident_size_peak: info.resident_size_peak as i64,
+            compressed: info.compressed as i64,
+            phys_footprint: info.phys_footprint as i64,
+        }
+    }
+}
+
+/// A child process spawned by `spawnWithTaskPort`.
+#[napi]
+pub struct SpawnedChild {
+    inner: ChildWithTask,
+}
+
+#[napi]
+impl SpawnedChild {
+    /// The child's process ID.
+    #[napi(getter)]
+    pub fn pid(&self) -> u32 {
+        self.inner.id()
+    }
+
+    /// The child's Mach task port, which stays owned by this object.
+    #[napi(getter)]
+    pub fn task_port(&self) -> u32 {
+        self.inner.task_port().as_raw()
+    }
+
+    /// Get basic information about the child's task.
+    #[napi]
+    pub fn basic_info(&self) -> Result<JsTaskBasicInfo> {
+        self.inner.task_port().basic_info().map(JsTaskBasicInfo::from).map_err(to_napi)
+    }
+
+    /// Get virtual memory statistics for the child's task.
+    #[napi]
+    pub fn vm_info(&self) -> Result<JsTaskVmInfo> {
+        self.inner.task_port().vm_info().map(JsTaskVmInfo::from).map_err(to_napi)
+    }
+
+    /// Forcibly kill the child.
+    #[napi]
+    pub fn kill(&mut self) -> Result<()> {
+        self.inner.kill().map_err(to_napi)
+    }
+
+    /// Block until the child exits, returning its exit code, or `null` if
+    /// it was killed by a signal.
+    #[napi]
+    pub fn wait(&mut self) -> Result<Option<i32>> {
+        self.inner.wait().map(|status| status.code()).map_err(to_napi)
+    }
+}
+
+/// Spawn `program` with `args`, returning a `SpawnedChild` that holds its
+/// task port.
+#[napi]
+pub fn spawn_with_task_port(program: String, args: Option<Vec<String>>) -> Result<SpawnedChild> {
+    let inner = command(program, args).spawn_with_task().map_err(to_napi)?;
+    Ok(SpawnedChild { inner: inner })
+}
+
+/// The command `spawnWithTaskPort` runs, where `args` is optional in
+/// JavaScript.
+fn command(program: String, args: Option<Vec<String>>) -> Command {
+    let mut command = Command::new(program);
+    command.args(args.unwrap_or_default());
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn converts_arguments_and_results() {
+        let echo = command("/bin/echo".to_owned(), Some(vec!["a".to_owned(), "b".to_owned()]));
+        assert_eq!(echo.get_program(), "/bin/echo");
+        assert_eq!(echo.get_args().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(command("/bin/echo".to_owned(), None).get_args().count(), 0);
+
+        let info = JsTaskBasicInfo::from(TaskBasicInfo {
+            virtual_size: 1 << 40,
+            resident_size: 4096,
+            resident_size_max: 8192,
+            user_time: Duration::from_millis(1500),
+            system_time: Duration::from_micros(7),
+            suspend_count: 1,
+        });
+        assert_eq!(info.virtual_size, 1 << 40);
+        assert_eq!(info.user_time, 1_500_000);
+        assert_eq!(info.system_time, 7);
+
+        let error = to_napi(io::Error::new(io::ErrorKind::NotFound, "no such helper"));
+        assert_eq!(error.reason, "no such helper");
+    }
+}
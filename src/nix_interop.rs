@@ -0,0 +1,34 @@
+//! Conversions to `nix` types, enabled by the `nix` feature.
+
+use std::io::Result;
+
+use nix;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use {ChildWithTask, TaskPort};
+
+impl ChildWithTask {
+    /// The child's process ID as a `nix` `Pid`.
+    pub fn nix_pid(&self) -> Pid {
+        Pid::from_raw(self.id() as i32)
+    }
+
+    /// Send `signal` to the child.
+    pub fn signal(&self, signal: Signal) -> nix::Result<()> {
+        signal::kill(self.nix_pid(), signal)
+    }
+}
+
+impl<'a> From<&'a ChildWithTask> for Pid {
+    fn from(child: &'a ChildWithTask) -> Pid {
+        child.nix_pid()
+    }
+}
+
+impl TaskPort {
+    /// The pid of the process this task belongs to, as a `nix` `Pid`.
+    pub fn nix_pid(&self) -> Result<Pid> {
+        self.pid().map(|pid| Pid::from_raw(pid as i32))
+    }
+}
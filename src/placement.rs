@@ -0,0 +1,221 @@
+//! Steering a child between performance and efficiency cores.
+//!
+//! Apple Silicon has no API to pin a thread to a core. What decides
+//! placement is the thread's quality of service: background work only ever
+//! runs on efficiency cores, while user-interactive work is preferred on
+//! performance cores. These helpers set that for a child from the parent,
+//! and report how much of a child's CPU time was actually spent on
+//! performance cores, so a benchmark harness can check that its placement
+//! held. On Intel Macs, where all cores are alike, they are harmless.
+
+use std::io::{Error, Result};
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::time::Duration;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::message::mach_msg_type_number_t;
+use mach::port::mach_port_t;
+use mach::vm_types::integer_t;
+
+use coalition::mach_time_to_duration;
+use {TaskPort, ThreadPort};
+
+/// `THREAD_QOS_POLICY` from `<mach/thread_policy.h>`.
+pub(crate) const THREAD_QOS_POLICY: u32 = 9;
+/// `THREAD_QOS_POLICY_COUNT`.
+pub(crate) const THREAD_QOS_POLICY_COUNT: mach_msg_type_number_t = 2;
+
+/// `PRIO_DARWIN_PROCESS` and `PRIO_DARWIN_BG` from `<sys/resource.h>`.
+const PRIO_DARWIN_PROCESS: c_int = 4;
+const PRIO_DARWIN_BG: c_int = 0x1000;
+
+/// `RUSAGE_INFO_V6`, the first revision with performance core times.
+const RUSAGE_INFO_V6: c_int = 6;
+
+/// `struct thread_qos_policy`
+#[repr(C)]
+pub(crate) struct thread_qos_policy {
+    pub(crate) qos_tier: integer_t,
+    pub(crate) tier_importance: integer_t,
+}
+
+/// `struct rusage_info_v6`, with room to spare at the end in case the
+/// kernel's copy is larger.
+#[repr(C)]
+struct rusage_info_v6 {
+    ri_uuid: [u8; 16],
+    ri_user_time: u64,
+    ri_system_time: u64,
+    ri_v4_fields: [u64; 33],
+    ri_flags: u64,
+    ri_user_ptime: u64,
+    ri_system_ptime: u64,
+    ri_spare: [u64; 32],
+}
+
+extern "C" {
+    pub(crate) fn thread_policy_set(thread: mach_port_t,
+                         flavor: u32,
+                         policy_info: *mut integer_t,
+                         count: mach_msg_type_number_t)
+                         -> kern_return_t;
+    pub(crate) fn thread_policy_get(thread: mach_port_t,
+                         flavor: u32,
+                         policy_info: *mut integer_t,
+                         count: *mut mach_msg_type_number_t,
+                         get_default: *mut u32)
+                         -> kern_return_t;
+    fn setpriority(which: c_int, who: c_int, prio: c_int) -> c_int;
+    fn proc_pid_rusage(pid: c_int, flavor: c_int, buffer: *mut c_void) -> c_int;
+}
+
+/// A thread quality of service class, like `qos_class_t`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum QosClass {
+    UserInteractive,
+    UserInitiated,
+    Default,
+    Utility,
+    Background,
+    Maintenance,
+}
+
+impl QosClass {
+    /// The `THREAD_QOS_*` tier.
+    pub(crate) fn to_tier(self) -> integer_t {
+        match self {
+            QosClass::Maintenance => 1,
+            QosClass::Background => 2,
+            QosClass::Utility => 3,
+            QosClass::Default => 4,
+            QosClass::UserInitiated => 5,
+            QosClass::UserInteractive => 6,
+        }
+    }
+
+    pub(crate) fn from_tier(tier: integer_t) -> Option<QosClass> {
+        match tier {
+            1 => Some(QosClass::Maintenance),
+            2 => Some(QosClass::Background),
+            3 => Some(QosClass::Utility),
+            4 => Some(QosClass::Default),
+            5 => Some(QosClass::UserInitiated),
+            6 => Some(QosClass::UserInteractive),
+            _ => None,
+        }
+    }
+}
+
+/// Which kind of core a child should run on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum CorePreference {
+    /// Prefer performance cores. The scheduler may still use efficiency
+    /// cores when the performance cores are busy.
+    Performance,
+    /// Only use efficiency cores.
+    Efficiency,
+}
+
+/// How much CPU time a process has used, and how much of it was on
+/// performance cores.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CoreUsage {
+    /// Total user and system CPU time.
+    pub total: Duration,
+    /// The part of `total` spent on performance cores.
+    pub performance: Duration,
+}
+
+impl CoreUsage {
+    /// The part of the CPU time spent on efficiency cores.
+    pub fn efficiency(&self) -> Duration {
+        self.total.checked_sub(self.performance).unwrap_or_default()
+    }
+}
+
+impl ThreadPort {
+    /// Set the thread's quality of service class.
+    ///
+    /// Unless the system has been booted to allow it, the kernel only lets
+    /// a process set this for its own threads, and fails with
+    /// `KERN_INVALID_ARGUMENT` otherwise.
+    pub fn set_qos_class(&self, qos: QosClass) -> Result<()> {
+        let mut policy = thread_qos_policy {
+            qos_tier: qos.to_tier(),
+            tier_importance: 0,
+        };
+        unsafe {
+            ktry!(thread_policy_set(self.as_raw(),
+                                    THREAD_QOS_POLICY,
+                                    &mut policy as *mut _ as *mut integer_t,
+                                    THREAD_QOS_POLICY_COUNT));
+        }
+        Ok(())
+    }
+
+    /// The thread's quality of service class, or `None` if it hasn't been
+    /// given one.
+    pub fn qos_class(&self) -> Result<Option<QosClass>> {
+        let mut policy = thread_qos_policy {
+            qos_tier: 0,
+            tier_importance: 0,
+        };
+        let mut count = THREAD_QOS_POLICY_COUNT;
+        let mut get_default = 0;
+        unsafe {
+            ktry!(thread_policy_get(self.as_raw(),
+                                    THREAD_QOS_POLICY,
+                                    &mut policy as *mut _ as *mut integer_t,
+                                    &mut count,
+                                    &mut get_default));
+        }
+        Ok(QosClass::from_tier(policy.qos_tier))
+    }
+}
+
+impl TaskPort {
+    /// Steer the whole process onto one kind of core.
+    ///
+    /// `Efficiency` puts the process in the Darwin background band, which
+    /// confines all of its threads, including ones it creates later, to
+    /// efficiency cores. `Performance` takes it out of the background band
+    /// and then asks for user-interactive QoS on each of its current
+    /// threads; see `ThreadPort::set_qos_class` for when the kernel
+    /// refuses that.
+    pub fn set_core_preference(&self, preference: CorePreference) -> Result<()> {
+        let pid = self.pid()? as c_int;
+        let band = match preference {
+            CorePreference::Efficiency => PRIO_DARWIN_BG,
+            CorePreference::Performance => 0,
+        };
+        if unsafe { setpriority(PRIO_DARWIN_PROCESS, pid, band) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        if preference == CorePreference::Performance {
+            for thread in self.threads()? {
+                thread.set_qos_class(QosClass::UserInteractive)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How the process' CPU time so far splits between performance and
+    /// efficiency cores. This needs macOS 12 or later.
+    pub fn core_usage(&self) -> Result<CoreUsage> {
+        let pid = self.pid()? as c_int;
+        unsafe {
+            let mut info: rusage_info_v6 = mem::zeroed();
+            if proc_pid_rusage(pid, RUSAGE_INFO_V6, &mut info as *mut _ as *mut c_void) != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(CoreUsage {
+                total: mach_time_to_duration(info.ri_user_time + info.ri_system_time),
+                performance: mach_time_to_duration(info.ri_user_ptime + info.ri_system_ptime),
+            })
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Describing Mach port names, for logs and diagnostics.
+//!
+//! A port name on its own says little: `0x1a03` in the parent and `0x203`
+//! in the child can be the same task port, and whether a name holds a send
+//! or a receive right is invisible. `dump_port_info` gathers a name's
+//! rights, reference counts and, for ports that stand for a kernel object
+//! like a task, the object's type and address from `mach_port_kobject`.
+//! The kernel reports the same address in every task, so two `PortInfo`s
+//! from different tasks can be matched up with `same_object`.
+
+use std::fmt;
+use std::io::Result;
+use std::ops::BitOr;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::port::{mach_port_name_t, mach_port_right_t, mach_port_t, MACH_PORT_RIGHT_DEAD_NAME,
+                 MACH_PORT_RIGHT_SEND};
+use mach::traps::mach_task_self;
+
+use TaskPort;
+
+extern "C" {
+    fn mach_port_type(task: mach_port_t,
+                      name: mach_port_name_t,
+                      ptype: *mut u32)
+                      -> kern_return_t;
+    fn mach_port_get_refs(task: mach_port_t,
+                          name: mach_port_name_t,
+                          right: mach_port_right_t,
+                          refs: *mut u32)
+                          -> kern_return_t;
+    fn mach_port_kobject(task: mach_port_t,
+                         name: mach_port_name_t,
+                         object_type: *mut u32,
+                         object_addr: *mut u64)
+                         -> kern_return_t;
+}
+
+/// The rights a task holds under a port name, like `mach_port_type_t`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PortRights(u32);
+
+impl PortRights {
+    pub const SEND: PortRights = PortRights(1 << 16);
+    pub const RECEIVE: PortRights = PortRights(1 << 17);
+    pub const SEND_ONCE: PortRights = PortRights(1 << 18);
+    pub const PORT_SET: PortRights = PortRights(1 << 19);
+    pub const DEAD_NAME: PortRights = PortRights(1 << 20);
+
+    /// The raw `mach_port_type_t`.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Wrap a raw `mach_port_type_t`.
+    pub fn from_bits(bits: u32) -> PortRights {
+        PortRights(bits)
+    }
+
+    /// Whether every right in `other` is held.
+    pub fn contains(self, other: PortRights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PortRights {
+    type Output = PortRights;
+
+    fn bitor(self, other: PortRights) -> PortRights {
+        PortRights(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for PortRights {
+    /// The rights joined with `+`, like `send+receive`, or `none`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [(PortRights, &'static str); 5] = [(PortRights::SEND, "send"),
+                                                        (PortRights::RECEIVE, "receive"),
+                                                        (PortRights::SEND_ONCE, "send-once"),
+                                                        (PortRights::PORT_SET, "port-set"),
+                                                        (PortRights::DEAD_NAME, "dead-name")];
+        let mut first = true;
+        for &(right, name) in NAMES.iter() {
+            if self.contains(right) {
+                write!(f, "{}{}", if first { "" } else { "+" }, name)?;
+                first = false;
+            }
+        }
+        if first {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of a kernel object type (`IKOT_*`) that has been stable across
+/// macOS releases.
+fn kobject_type_name(object_type: u32) -> Option<&'static str> {
+    match object_type {
+        1 => Some("thread"),
+        2 => Some("task"),
+        3 => Some("host"),
+        4 => Some("host-priv"),
+        20 => Some("task-name"),
+        _ => None,
+    }
+}
+
+/// What a task holds under a port name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PortInfo {
+    /// The port name, which is only meaningful in the task it came from.
+    pub name: mach_port_t,
+    /// The rights held under the name.
+    pub rights: PortRights,
+    /// User references to the send right, if one is held.
+    pub send_refs: u32,
+    /// User references to the dead name, if the port has died.
+    pub dead_name_refs: u32,
+    /// The `IKOT_*` type of the kernel object the port stands for, or
+    /// `None` for ports that some task receives from.
+    pub kobject_type: Option<u32>,
+    /// The kernel object's address, as the kernel chooses to reveal it.
+    pub kobject_address: Option<u64>,
+}
+
+impl PortInfo {
+    /// Whether `self` and `other`, possibly from different tasks, name the
+    /// same kernel object.
+    pub fn same_object(&self, other: &PortInfo) -> bool {
+        match (self.kobject_address, other.kobject_address) {
+            (Some(a), Some(b)) => a != 0 && a == b && self.kobject_type == other.kobject_type,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PortInfo {
+    /// Like `0x1a03 send(1) task@0x5f2c...`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x} {}", self.name, self.rights)?;
+        if self.rights.contains(PortRights::SEND) {
+            write!(f, "({})", self.send_refs)?;
+        } else if self.rights.contains(PortRights::DEAD_NAME) {
+            write!(f, "({})", self.dead_name_refs)?;
+        }
+        if let Some(object_type) = self.kobject_type {
+            match kobject_type_name(object_type) {
+                Some(type_name) => write!(f, " {}", type_name)?,
+                None => write!(f, " kobject-{}", object_type)?,
+            }
+            if let Some(address) = self.kobject_address {
+                write!(f, "@{:#x}", address)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a port name the way `PortInfo` prints it, in hex with a `0x`
+/// prefix, or in decimal.
+pub fn parse_port_name(s: &str) -> Option<mach_port_t> {
+    let s = s.trim();
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Describe the port `name` in `task`'s IPC space.
+fn port_info(task: mach_port_t, name: mach_port_t) -> Result<PortInfo> {
+    let get_refs = |rights: PortRights, right, flag| -> Result<u32> {
+        let mut refs = 0;
+        if rights.contains(flag) {
+            unsafe {
+                ktry!(mach_port_get_refs(task, name, right, &mut refs));
+            }
+        }
+        Ok(refs)
+    };
+    let mut bits = 0;
+    unsafe {
+        ktry!(mach_port_type(task, name, &mut bits));
+    }
+    let rights = PortRights(bits);
+    let send_refs = get_refs(rights, MACH_PORT_RIGHT_SEND, PortRights::SEND)?;
+    let dead_name_refs = get_refs(rights, MACH_PORT_RIGHT_DEAD_NAME, PortRights::DEAD_NAME)?;
+    // Only ports that no task receives from can be kernel objects, and
+    // some kernels don't say even then.
+    let mut kobject = None;
+    if !rights.contains(PortRights::RECEIVE) && !rights.contains(PortRights::PORT_SET) {
+        let (mut object_type, mut object_addr) = (0, 0);
+        let kr = unsafe { mach_port_kobject(task, name, &mut object_type, &mut object_addr) };
+        if kr == KERN_SUCCESS && object_type != 0 {
+            kobject = Some((object_type, object_addr));
+        }
+    }
+    Ok(PortInfo {
+        name: name,
+        rights: rights,
+        send_refs: send_refs,
+        dead_name_refs: dead_name_refs,
+        kobject_type: kobject.map(|(object_type, _)| object_type),
+        kobject_address: kobject.map(|(_, address)| address),
+    })
+}
+
+/// Describe the port `name` in this task's IPC space.
+pub fn dump_port_info(name: mach_port_t) -> Result<PortInfo> {
+    port_info(unsafe { mach_task_self() }, name)
+}
+
+impl TaskPort {
+    /// Describe the port `name` in the task's IPC space, for example the
+    /// child's end of a port the parent also holds.
+    pub fn port_info(&self, name: mach_port_t) -> Result<PortInfo> {
+        port_info(self.as_raw(), name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_parses_port_names() {
+        assert_eq!((PortRights::SEND | PortRights::RECEIVE).to_string(), "send+receive");
+        assert_eq!(PortRights::from_bits(0).to_string(), "none");
+        let info = PortInfo {
+            name: 0x1a03,
+            rights: PortRights::SEND,
+            send_refs: 2,
+            dead_name_refs: 0,
+            kobject_type: Some(2),
+            kobject_address: Some(0xabc0),
+        };
+        assert_eq!(info.to_string(), "0x1a03 send(2) task@0xabc0");
+        assert_eq!(parse_port_name("0x1a03"), Some(0x1a03));
+        assert_eq!(parse_port_name(" 259 "), Some(259));
+        assert_eq!(parse_port_name("port"), None);
+    }
+}
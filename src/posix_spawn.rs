@@ -0,0 +1,660 @@
+//! Launching the child with `posix_spawn`, for what `Command` can't express.
+//!
+//! `Command` maps stdio and nothing else, so changing directory through a
+//! descriptor, closing whole ranges of inherited descriptors or shuffling
+//! several of them at once means writing a `pre_exec` hook, which is easy
+//! to get wrong. `posix_spawn` file actions do all of that, and Darwin's
+//! `POSIX_SPAWN_SETEXEC` flag makes `posix_spawn` replace the calling
+//! process the way `exec` does. So the child checks in as usual, then its
+//! last `pre_exec` hook execs the program with `posix_spawn`, applying the
+//! file actions in order on the way. Everything else `Command` was given,
+//! such as the arguments, environment, working directory and stdio, still
+//! applies, except `CommandExt::arg0`.
+//!
+//! Spawn attributes take effect the same way, before the program's first
+//! instruction, which matters for limits that would otherwise only apply
+//! once the handshake is done and the parent gets around to them. With
+//! `start_suspended`, the program doesn't run that first instruction until
+//! the parent resumes it, so the parent can instrument it beforehand.
+//!
+//! For a universal program, `architecture_preference` picks the slice that
+//! runs, which on Apple silicon means the program can be run as `x86_64`
+//! under Rosetta, and the parent still gets its task port.
+
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_short, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use libc::{self, mode_t, pid_t};
+
+use identity::lookup;
+use {ChildWithTask, SpawnOptions, TaskFlavor, TaskPort, spawn_checking_in};
+
+#[allow(non_camel_case_types)]
+type posix_spawn_file_actions_t = *mut c_void;
+#[allow(non_camel_case_types)]
+type posix_spawnattr_t = *mut c_void;
+#[allow(non_camel_case_types)]
+type cpu_type_t = c_int;
+
+const CPU_ARCH_ABI64: cpu_type_t = 0x0100_0000;
+const CPU_TYPE_X86: cpu_type_t = 7;
+const CPU_TYPE_ARM: cpu_type_t = 12;
+
+/// Darwin's flag to make `posix_spawn` act like `exec`.
+const POSIX_SPAWN_SETEXEC: c_short = 0x0040;
+/// Darwin's flag to leave the new image's task suspended.
+const POSIX_SPAWN_START_SUSPENDED: c_short = 0x0080;
+const POSIX_SPAWN_SETPGROUP: c_short = 0x0002;
+const POSIX_SPAWN_SETSIGDEF: c_short = 0x0004;
+const POSIX_SPAWN_SETSIGMASK: c_short = 0x0008;
+/// Darwin's flag to start a new session, as `setsid` does.
+const POSIX_SPAWN_SETSID: c_short = 0x0400;
+
+/// How often to check whether a child started suspended has got as far as
+/// its `exec`.
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+type Spawn = unsafe extern "C" fn(pid: *mut pid_t,
+                                  path: *const c_char,
+                                  actions: *const posix_spawn_file_actions_t,
+                                  attr: *const posix_spawnattr_t,
+                                  argv: *const *const c_char,
+                                  envp: *const *const c_char)
+                                  -> c_int;
+
+extern "C" {
+    fn posix_spawn(pid: *mut pid_t,
+                   path: *const c_char,
+                   actions: *const posix_spawn_file_actions_t,
+                   attr: *const posix_spawnattr_t,
+                   argv: *const *const c_char,
+                   envp: *const *const c_char)
+                   -> c_int;
+    fn posix_spawnp(pid: *mut pid_t,
+                    file: *const c_char,
+                    actions: *const posix_spawn_file_actions_t,
+                    attr: *const posix_spawnattr_t,
+                    argv: *const *const c_char,
+                    envp: *const *const c_char)
+                    -> c_int;
+    fn posix_spawn_file_actions_init(actions: *mut posix_spawn_file_actions_t) -> c_int;
+    fn posix_spawn_file_actions_destroy(actions: *mut posix_spawn_file_actions_t) -> c_int;
+    fn posix_spawn_file_actions_addopen(actions: *mut posix_spawn_file_actions_t,
+                                        fd: c_int,
+                                        path: *const c_char,
+                                        flags: c_int,
+                                        mode: mode_t)
+                                        -> c_int;
+    fn posix_spawn_file_actions_addclose(actions: *mut posix_spawn_file_actions_t,
+                                         fd: c_int)
+                                         -> c_int;
+    fn posix_spawn_file_actions_adddup2(actions: *mut posix_spawn_file_actions_t,
+                                        fd: c_int,
+                                        new_fd: c_int)
+                                        -> c_int;
+    fn posix_spawnattr_init(attr: *mut posix_spawnattr_t) -> c_int;
+    fn posix_spawnattr_destroy(attr: *mut posix_spawnattr_t) -> c_int;
+    fn posix_spawnattr_setflags(attr: *mut posix_spawnattr_t, flags: c_short) -> c_int;
+    fn posix_spawnattr_setsigmask(attr: *mut posix_spawnattr_t,
+                                  mask: *const libc::sigset_t)
+                                  -> c_int;
+    fn posix_spawnattr_setsigdefault(attr: *mut posix_spawnattr_t,
+                                     signals: *const libc::sigset_t)
+                                     -> c_int;
+    fn posix_spawnattr_setpgroup(attr: *mut posix_spawnattr_t, pgroup: pid_t) -> c_int;
+    fn posix_spawnattr_setprocesstype_np(attr: *mut posix_spawnattr_t, kind: c_int) -> c_int;
+    fn posix_spawnattr_setbinpref_np(attr: *mut posix_spawnattr_t,
+                                     count: libc::size_t,
+                                     pref: *mut cpu_type_t,
+                                     ocount: *mut libc::size_t)
+                                     -> c_int;
+    fn _NSGetEnviron() -> *mut *const *const c_char;
+    fn setpriority(which: c_int, who: c_int, prio: c_int) -> c_int;
+}
+
+const PRIO_PROCESS: c_int = 0;
+
+/// `posix_spawnattr_setjetsam_ext`, a private call that sets the child's
+/// jetsam band and memory limits.
+type SetJetsamExt = unsafe extern "C" fn(attr: *mut posix_spawnattr_t,
+                                         flags: c_short,
+                                         priority: c_int,
+                                         memlimit_active: c_int,
+                                         memlimit_inactive: c_int)
+                                         -> c_int;
+
+/// `POSIX_SPAWN_JETSAM_SET`, which applies the jetsam attributes at all.
+const POSIX_SPAWN_JETSAM_SET: c_short = 0x8000u16 as c_short;
+/// Kill the child when it goes over its limit while active.
+const POSIX_SPAWN_JETSAM_MEMLIMIT_ACTIVE_FATAL: c_short = 0x04;
+/// Kill the child when it goes over its limit while inactive.
+const POSIX_SPAWN_JETSAM_MEMLIMIT_INACTIVE_FATAL: c_short = 0x08;
+/// `JETSAM_PRIORITY_DEFAULT`, the band of processes launchd doesn't manage.
+const JETSAM_PRIORITY_DEFAULT: c_int = 180;
+
+/// `posix_spawn_file_actions_addchdir_np`.
+type AddChdir = unsafe extern "C" fn(actions: *mut posix_spawn_file_actions_t,
+                                     path: *const c_char)
+                                     -> c_int;
+
+/// `posix_spawn_file_actions_addfchdir_np`.
+type AddFchdir = unsafe extern "C" fn(actions: *mut posix_spawn_file_actions_t,
+                                      fd: c_int)
+                                      -> c_int;
+
+fn c_path(path: &OsStr) -> Result<CString> {
+    CString::new(path.as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains a NUL"))
+}
+
+/// The error returned when the running OS lacks a file action.
+fn chdir_unavailable() -> Error {
+    Error::new(ErrorKind::Other, "changing directory in file actions needs macOS 10.15 or later")
+}
+
+/// Check the return value of a `posix_spawn*` call, which is an errno.
+fn check(err: c_int) -> Result<()> {
+    if err != 0 {
+        return Err(Error::from_raw_os_error(err));
+    }
+    Ok(())
+}
+
+/// A `sigset_t` holding `signals`.
+fn signal_set(signals: &[c_int]) -> Result<libc::sigset_t> {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &signal in signals {
+            if libc::sigaddset(&mut set, signal) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(set)
+    }
+}
+
+/// The role a process plays, which the scheduler and memorystatus use to
+/// decide how to treat it: `POSIX_SPAWN_PROC_TYPE_*`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProcessType {
+    /// An ordinary process, the default for anything not started by
+    /// launchd.
+    Normal,
+    /// An app.
+    AppDefault,
+    /// A daemon doing standard work.
+    DaemonStandard,
+    /// A daemon that the user waits on.
+    DaemonInteractive,
+    /// A daemon doing background work, throttled accordingly.
+    DaemonBackground,
+    /// A daemon that is throttled like a background one until it is doing
+    /// work on behalf of something that isn't.
+    DaemonAdaptive,
+}
+
+impl ProcessType {
+    fn to_raw(self) -> c_int {
+        match self {
+            ProcessType::Normal => 0x000,
+            ProcessType::AppDefault => 0x100,
+            ProcessType::DaemonStandard => 0x300,
+            ProcessType::DaemonInteractive => 0x400,
+            ProcessType::DaemonBackground => 0x500,
+            ProcessType::DaemonAdaptive => 0x600,
+        }
+    }
+}
+
+/// A CPU architecture a universal program has a slice for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Architecture {
+    /// 64-bit Intel, which Apple silicon runs under Rosetta.
+    X86_64,
+    /// 64-bit ARM.
+    Arm64,
+}
+
+impl Architecture {
+    fn to_raw(self) -> cpu_type_t {
+        match self {
+            Architecture::X86_64 => CPU_TYPE_X86 | CPU_ARCH_ABI64,
+            Architecture::Arm64 => CPU_TYPE_ARM | CPU_ARCH_ABI64,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum FileAction {
+    Open(RawFd, OsString, c_int, mode_t),
+    Close(RawFd),
+    Dup2(RawFd, RawFd),
+    Chdir(OsString),
+    Fchdir(RawFd),
+}
+
+/// How to launch a child with `PosixSpawnWithTask`: the file actions to
+/// perform as it execs, and the attributes to give it.
+///
+/// File actions run in the order they were added, after `Command` has set
+/// up stdio and the working directory.
+#[derive(Clone, Debug, Default)]
+pub struct PosixSpawnOptions {
+    actions: Vec<FileAction>,
+    close_ranges: Vec<(RawFd, RawFd)>,
+    memory_limit: Option<u32>,
+    process_type: Option<ProcessType>,
+    nice: Option<c_int>,
+    start_suspended: bool,
+    signal_mask: Option<Vec<c_int>>,
+    default_signals: Vec<c_int>,
+    process_group: Option<pid_t>,
+    new_session: bool,
+    architectures: Vec<Architecture>,
+    task_flavor: Option<TaskFlavor>,
+}
+
+impl PosixSpawnOptions {
+    /// Options with no file actions.
+    pub fn new() -> PosixSpawnOptions {
+        PosixSpawnOptions::default()
+    }
+
+    /// Open `path` as `fd`, with `open`'s `flags` and `mode`.
+    pub fn open<P: AsRef<Path>>(&mut self,
+                                fd: RawFd,
+                                path: P,
+                                flags: c_int,
+                                mode: mode_t)
+                                -> &mut PosixSpawnOptions {
+        self.actions.push(FileAction::Open(fd, path.as_ref().as_os_str().to_owned(), flags, mode));
+        self
+    }
+
+    /// Close `fd`. The spawn fails if it isn't open by then.
+    pub fn close(&mut self, fd: RawFd) -> &mut PosixSpawnOptions {
+        self.actions.push(FileAction::Close(fd));
+        self
+    }
+
+    /// Close whichever of the descriptors from `first` to `last`, inclusive,
+    /// the child inherited, as it execs.
+    ///
+    /// The descriptors are marked close-on-exec rather than closed right
+    /// away, so they can still be the source of a `dup2`. Ones that the file
+    /// actions open or `dup2` to are kept.
+    pub fn close_range(&mut self, first: RawFd, last: RawFd) -> &mut PosixSpawnOptions {
+        self.close_ranges.push((first, last));
+        self
+    }
+
+    /// Like `close_range`, for every descriptor from `first` up.
+    pub fn close_from(&mut self, first: RawFd) -> &mut PosixSpawnOptions {
+        self.close_range(first, RawFd::max_value())
+    }
+
+    /// Duplicate `from` onto `to`, replacing whatever `to` was.
+    pub fn dup2(&mut self, from: RawFd, to: RawFd) -> &mut PosixSpawnOptions {
+        self.actions.push(FileAction::Dup2(from, to));
+        self
+    }
+
+    /// Duplicate each `from` onto its `to` all at once, so a mapping like
+    /// `[(3, 4), (4, 3)]` swaps the two rather than leaving both as 3.
+    ///
+    /// The descriptors are moved through temporaries numbered just above
+    /// the highest one involved, which must not be in use.
+    pub fn dup2_all(&mut self, mapping: &[(RawFd, RawFd)]) -> &mut PosixSpawnOptions {
+        let base = mapping.iter().map(|&(from, to)| from.max(to)).max().unwrap_or(0) + 1;
+        for (i, &(from, _)) in mapping.iter().enumerate() {
+            self.dup2(from, base + i as RawFd);
+        }
+        for (i, &(_, to)) in mapping.iter().enumerate() {
+            self.dup2(base + i as RawFd, to);
+        }
+        for i in 0..mapping.len() {
+            self.close(base + i as RawFd);
+        }
+        self
+    }
+
+    /// Change to the directory `path`, relative to the one before.
+    ///
+    /// This needs macOS 10.15 or later; on older releases, the spawn fails.
+    pub fn chdir<P: AsRef<Path>>(&mut self, path: P) -> &mut PosixSpawnOptions {
+        self.actions.push(FileAction::Chdir(path.as_ref().as_os_str().to_owned()));
+        self
+    }
+
+    /// Change to the directory open as `fd`.
+    ///
+    /// This needs macOS 10.15 or later; on older releases, the spawn fails.
+    pub fn fchdir(&mut self, fd: RawFd) -> &mut PosixSpawnOptions {
+        self.actions.push(FileAction::Fchdir(fd));
+        self
+    }
+
+    /// Kill the child if its memory footprint ever goes over `megabytes`.
+    ///
+    /// The limit is set with the private `posix_spawnattr_setjetsam_ext`,
+    /// which also puts the child in the default jetsam band. If the running
+    /// OS doesn't have it, the spawn fails.
+    pub fn memory_limit(&mut self, megabytes: u32) -> &mut PosixSpawnOptions {
+        self.memory_limit = Some(megabytes);
+        self
+    }
+
+    /// Classify the child as `process_type` from the start, rather than as
+    /// a `ProcessType::Normal` process.
+    pub fn process_type(&mut self, process_type: ProcessType) -> &mut PosixSpawnOptions {
+        self.process_type = Some(process_type);
+        self
+    }
+
+    /// Start the child with the nice value `nice`, from -20, the highest
+    /// priority, to 20. Only root can go below the parent's value.
+    pub fn nice(&mut self, nice: i32) -> &mut PosixSpawnOptions {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Leave the child suspended once it has executed the program, before
+    /// the program's first instruction, until `ChildWithTask::resume` is
+    /// called.
+    pub fn start_suspended(&mut self) -> &mut PosixSpawnOptions {
+        self.start_suspended = true;
+        self
+    }
+
+    /// Start the program with exactly `signals` blocked, rather than none.
+    pub fn signal_mask(&mut self, signals: &[c_int]) -> &mut PosixSpawnOptions {
+        self.signal_mask = Some(signals.to_vec());
+        self
+    }
+
+    /// Reset `signal` to its default action in the program, even if it is
+    /// ignored in the parent.
+    pub fn default_signal(&mut self, signal: c_int) -> &mut PosixSpawnOptions {
+        self.default_signals.push(signal);
+        self
+    }
+
+    /// Put the child in the process group `pgroup`, or in a new group of
+    /// its own if it is 0.
+    pub fn process_group(&mut self, pgroup: i32) -> &mut PosixSpawnOptions {
+        self.process_group = Some(pgroup);
+        self
+    }
+
+    /// Start the child in a new session, detached from the parent's
+    /// controlling terminal, as `setsid` does. This takes precedence over
+    /// `process_group`, since the session leader leads a new group too.
+    pub fn new_session(&mut self) -> &mut PosixSpawnOptions {
+        self.new_session = true;
+        self
+    }
+
+    /// Return a task port of `flavor` rather than the control port, as
+    /// `SpawnOptions::task_flavor` does. Only a control port can resume a
+    /// child, so this can't be combined with `start_suspended`.
+    pub fn task_flavor(&mut self, flavor: TaskFlavor) -> &mut PosixSpawnOptions {
+        self.task_flavor = Some(flavor);
+        self
+    }
+
+    /// Run the first slice of a universal program that matches one of
+    /// `architectures`, in order of preference, rather than the one for the
+    /// machine's own. The spawn fails with `EBADARCH` if none matches, or
+    /// if it is `x86_64` and Rosetta isn't installed.
+    pub fn architecture_preference(&mut self,
+                                   architectures: &[Architecture])
+                                   -> &mut PosixSpawnOptions {
+        self.architectures = architectures.to_vec();
+        self
+    }
+}
+
+/// Everything the child's last `pre_exec` hook needs to exec the program,
+/// built before `fork` so that the hook only makes system calls.
+struct ExecPlan {
+    program: CString,
+    search_path: bool,
+    _args: Vec<CString>,
+    argv: Vec<*const c_char>,
+    actions: posix_spawn_file_actions_t,
+    attr: posix_spawnattr_t,
+    close_ranges: Vec<(RawFd, RawFd)>,
+    nice: Option<c_int>,
+}
+
+// The pointers are into the plan itself, and only read.
+unsafe impl Send for ExecPlan {}
+unsafe impl Sync for ExecPlan {}
+
+impl ExecPlan {
+    fn new(command: &Command, options: &PosixSpawnOptions) -> Result<ExecPlan> {
+        let program = c_path(command.get_program())?;
+        let search_path = !program.as_bytes().contains(&b'/');
+        let mut args = vec![program.clone()];
+        for arg in command.get_args() {
+            args.push(CString::new(arg.as_bytes())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "argument contains a NUL"))?);
+        }
+        let mut argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+        argv.push(ptr::null());
+        let mut plan = ExecPlan {
+            program: program,
+            search_path: search_path,
+            _args: args,
+            argv: argv,
+            actions: ptr::null_mut(),
+            attr: ptr::null_mut(),
+            close_ranges: options.close_ranges.clone(),
+            nice: options.nice,
+        };
+        unsafe {
+            check(posix_spawn_file_actions_init(&mut plan.actions))?;
+            check(posix_spawnattr_init(&mut plan.attr))?;
+            let mut flags = POSIX_SPAWN_SETEXEC;
+            if options.start_suspended {
+                flags |= POSIX_SPAWN_START_SUSPENDED;
+            }
+            if let Some(ref signals) = options.signal_mask {
+                flags |= POSIX_SPAWN_SETSIGMASK;
+                check(posix_spawnattr_setsigmask(&mut plan.attr, &signal_set(signals)?))?;
+            }
+            if !options.default_signals.is_empty() {
+                flags |= POSIX_SPAWN_SETSIGDEF;
+                check(posix_spawnattr_setsigdefault(&mut plan.attr,
+                                                    &signal_set(&options.default_signals)?))?;
+            }
+            if options.new_session {
+                flags |= POSIX_SPAWN_SETSID;
+            } else if let Some(pgroup) = options.process_group {
+                flags |= POSIX_SPAWN_SETPGROUP;
+                check(posix_spawnattr_setpgroup(&mut plan.attr, pgroup))?;
+            }
+            check(posix_spawnattr_setflags(&mut plan.attr, flags))?;
+            if let Some(process_type) = options.process_type {
+                check(posix_spawnattr_setprocesstype_np(&mut plan.attr, process_type.to_raw()))?;
+            }
+            if !options.architectures.is_empty() {
+                plan.set_architectures(&options.architectures)?;
+            }
+            if let Some(megabytes) = options.memory_limit {
+                plan.set_memory_limit(megabytes)?;
+            }
+            for action in &options.actions {
+                plan.add(action)?;
+            }
+        }
+        Ok(plan)
+    }
+
+    unsafe fn set_architectures(&mut self, architectures: &[Architecture]) -> Result<()> {
+        let mut preference = architectures.iter().map(|arch| arch.to_raw()).collect::<Vec<_>>();
+        let mut set = 0;
+        check(posix_spawnattr_setbinpref_np(&mut self.attr,
+                                            preference.len(),
+                                            preference.as_mut_ptr(),
+                                            &mut set))?;
+        // The attributes only have room for a few.
+        if set < preference.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "too many architectures"));
+        }
+        Ok(())
+    }
+
+    unsafe fn set_memory_limit(&mut self, megabytes: u32) -> Result<()> {
+        let unavailable = || {
+            Error::new(ErrorKind::Other, "spawn-time memory limits aren't available")
+        };
+        let set_jetsam_ext: SetJetsamExt = lookup(b"posix_spawnattr_setjetsam_ext\0")
+            .ok_or_else(unavailable)?;
+        let megabytes = megabytes.min(c_int::max_value() as u32) as c_int;
+        check(set_jetsam_ext(&mut self.attr,
+                             POSIX_SPAWN_JETSAM_SET | POSIX_SPAWN_JETSAM_MEMLIMIT_ACTIVE_FATAL |
+                             POSIX_SPAWN_JETSAM_MEMLIMIT_INACTIVE_FATAL,
+                             JETSAM_PRIORITY_DEFAULT,
+                             megabytes,
+                             megabytes))
+    }
+
+    unsafe fn add(&mut self, action: &FileAction) -> Result<()> {
+        let actions = &mut self.actions;
+        match *action {
+            FileAction::Open(fd, ref path, flags, mode) => {
+                let path = c_path(path)?;
+                check(posix_spawn_file_actions_addopen(actions, fd, path.as_ptr(), flags, mode))
+            }
+            FileAction::Close(fd) => check(posix_spawn_file_actions_addclose(actions, fd)),
+            FileAction::Dup2(from, to) => {
+                check(posix_spawn_file_actions_adddup2(actions, from, to))
+            }
+            FileAction::Chdir(ref path) => {
+                let path = c_path(path)?;
+                let add_chdir: AddChdir = lookup(b"posix_spawn_file_actions_addchdir_np\0")
+                    .ok_or_else(chdir_unavailable)?;
+                check(add_chdir(actions, path.as_ptr()))
+            }
+            FileAction::Fchdir(fd) => {
+                let add_fchdir: AddFchdir = lookup(b"posix_spawn_file_actions_addfchdir_np\0")
+                    .ok_or_else(chdir_unavailable)?;
+                check(add_fchdir(actions, fd))
+            }
+        }
+    }
+
+    /// Replace the child with the program.
+    ///
+    /// This runs in the child process between `fork` and `exec`, and only
+    /// returns if it fails. `Command` has already pointed `environ` at the
+    /// child's environment, and `posix_spawnp` searches its `PATH`.
+    unsafe fn exec(&self) -> Result<()> {
+        // There's no spawn attribute for this, but the nice value survives
+        // `exec`.
+        if let Some(nice) = self.nice {
+            if setpriority(PRIO_PROCESS, 0, nice) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        let table_size = libc::getdtablesize();
+        for &(first, last) in &self.close_ranges {
+            for fd in first..=last.min(table_size - 1) {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags >= 0 {
+                    libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+                }
+            }
+        }
+        let spawn: Spawn = if self.search_path { posix_spawnp } else { posix_spawn };
+        let mut pid = 0;
+        check(spawn(&mut pid,
+                    self.program.as_ptr(),
+                    &self.actions,
+                    &self.attr,
+                    self.argv.as_ptr(),
+                    *_NSGetEnviron()))
+    }
+}
+
+impl Drop for ExecPlan {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.actions.is_null() {
+                posix_spawn_file_actions_destroy(&mut self.actions);
+            }
+            if !self.attr.is_null() {
+                posix_spawnattr_destroy(&mut self.attr);
+            }
+        }
+    }
+}
+
+/// An extension to `std::process::Command` to launch a process with
+/// `posix_spawn` and get back access to its Mach task port.
+pub trait PosixSpawnWithTask {
+    /// Executes the command as a child process, performing the file
+    /// actions in `options` as it execs, and returning a `ChildWithTask`
+    /// that owns both the `Child` and the process' Mach task port.
+    ///
+    /// `pre_exec` hooks added to the command after this won't run.
+    ///
+    /// With `PosixSpawnOptions::start_suspended`, this only returns once the
+    /// child has executed the program and been suspended.
+    fn spawn_with_task_posix(&mut self, options: &PosixSpawnOptions) -> Result<ChildWithTask>;
+}
+
+impl PosixSpawnWithTask for Command {
+    fn spawn_with_task_posix(&mut self, options: &PosixSpawnOptions) -> Result<ChildWithTask> {
+        // The hook stays in the command, so it shares ownership of the plan
+        // rather than borrowing it.
+        let mut handshake = SpawnOptions::new();
+        if let Some(flavor) = options.task_flavor {
+            if options.start_suspended && flavor != TaskFlavor::Control {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      "a child started suspended needs a task control port"));
+            }
+            handshake.task_flavor(flavor);
+        }
+        let plan = Arc::new(ExecPlan::new(self, options)?);
+        let (child, task_port) = spawn_checking_in(self, None, &handshake, |command| {
+            unsafe { command.pre_exec(move || plan.exec()) }.spawn()
+        })?;
+        let mut child = ChildWithTask::new(child, unsafe { TaskPort::from_raw(task_port) });
+        if options.start_suspended {
+            if let Err(e) = wait_until_suspended(&mut child) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+        }
+        Ok(child)
+    }
+}
+
+/// Wait for a child started suspended to get through its `exec`.
+///
+/// The child checks in before it executes the program, so until it has,
+/// there's nothing to resume.
+fn wait_until_suspended(child: &mut ChildWithTask) -> Result<()> {
+    loop {
+        if child.task_port().basic_info()?.suspend_count > 0 {
+            return Ok(());
+        }
+        if child.wait_timeout(EXEC_POLL_INTERVAL)?.is_some() {
+            return Err(Error::new(ErrorKind::Other, "the child exited before executing"));
+        }
+    }
+}
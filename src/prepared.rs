@@ -0,0 +1,90 @@
+//! Spawning in two steps, registering first and executing later.
+//!
+//! `spawn_with_task_port` registers its service name and spawns the child
+//! in one go. `PreparedSpawn::prepare` does everything up to the spawn: it
+//! runs the spawn middleware's parent stages, allocates and registers the
+//! port, and installs the child's `pre_exec` hook, so the command is final.
+//! `launch` then spawns it and waits for the check-in. Slow work that has
+//! to happen in between, such as checking a signature or compiling a
+//! sandbox profile, doesn't hold up the registration, and a launch that
+//! fails, say with `EAGAIN`, can be retried without registering again.
+
+use std::fmt;
+use std::io::{Error, Result};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use mach::port::mach_port_t;
+
+use bootstrap_swap;
+use diagnostics;
+use {SpawnOptions, SpawnTaskPortError, SpawnedProcess, TaskPort, TaskPortReceiver, pre_exec_hook};
+
+/// A command whose handshake is set up, ready to be launched.
+///
+/// Dropping it unregisters its service name.
+pub struct PreparedSpawn {
+    command: Command,
+    receiver: TaskPortReceiver,
+    receive_timeout: Option<Duration>,
+    /// The bootstrap port to spawn the child with, if not our own.
+    bootstrap_port: Option<mach_port_t>,
+}
+
+impl PreparedSpawn {
+    /// Set up the handshake for spawning `command`, as configured by
+    /// `options`, without spawning it yet.
+    pub fn prepare(mut command: Command, options: &SpawnOptions) -> Result<PreparedSpawn> {
+        diagnostics::record_handshake(|| {
+            let (receiver, check_in) = TaskPortReceiver::register_for(&mut command, options)?;
+            unsafe { command.pre_exec(pre_exec_hook(check_in)) };
+            Ok(PreparedSpawn {
+                command: command,
+                receiver: receiver,
+                receive_timeout: options.receive_timeout,
+                bootstrap_port: options.bootstrap_port,
+            })
+        })
+    }
+
+    /// The bootstrap service name the child will check in with.
+    pub fn service_name(&self) -> &str {
+        self.receiver.service_name()
+    }
+
+    /// Spawn the command and wait for it to check in, returning the `Child`
+    /// and its task port. If the check-in fails, the child is killed.
+    ///
+    /// A failed launch can be retried. Each launch spawns another child,
+    /// which checks in under the same name.
+    pub fn launch(&mut self) -> Result<(Child, TaskPort)> {
+        diagnostics::record_handshake(|| {
+            let command = &mut self.command;
+            let mut spawn = || {
+                command.spawn().map_err(|e| Error::from(SpawnTaskPortError::from_spawn(e)))
+            };
+            let mut child = match self.bootstrap_port {
+                Some(port) => bootstrap_swap::with_bootstrap_port(port, spawn)?,
+                None => spawn()?,
+            };
+            self.receiver.set_pid(child.id());
+            match self.receiver.recv(self.receive_timeout) {
+                Ok(task_port) => Ok((child, task_port)),
+                Err(e) => {
+                    child.abandon();
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Debug for PreparedSpawn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PreparedSpawn")
+            .field("command", &self.command)
+            .field("service_name", &self.service_name())
+            .finish()
+    }
+}
@@ -0,0 +1,98 @@
+//! Getting a task port without the child's cooperation.
+//!
+//! The handshake needs the child to run this crate's `pre_exec` hook and
+//! reach the bootstrap server, which not every child can: a setuid binary,
+//! one spawned by somebody else, or one whose check-in failed. For those,
+//! `task_port_for_pid` tries `task_for_pid`, which needs the target to be
+//! debuggable or the caller to be entitled, and then falls back to walking
+//! `processor_set_tasks`.
+//!
+//! The fallback is a privileged path: only root can get the host's
+//! privileged port, and on recent releases the kernel may still leave
+//! tasks out of the list for unentitled callers. Check
+//! `Capabilities::processor_set_tasks` before counting on it.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::slice;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::message::mach_msg_type_number_t;
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+use mach::traps::{mach_task_self, task_for_pid};
+use mach::types::task_array_t;
+use mach::vm::mach_vm_deallocate;
+
+use host_exceptions::{host_priv_port, mach_host_self};
+use {MachPort, TaskPort};
+
+extern "C" {
+    fn processor_set_default(host: mach_port_t, default_set: *mut mach_port_t) -> kern_return_t;
+    fn host_processor_set_priv(host_priv: mach_port_t,
+                               set_name: mach_port_t,
+                               set: *mut mach_port_t)
+                               -> kern_return_t;
+    fn processor_set_tasks(set: mach_port_t,
+                           task_list: *mut task_array_t,
+                           task_count: *mut mach_msg_type_number_t)
+                           -> kern_return_t;
+}
+
+/// Get the task port of the process `pid`, first with `task_for_pid` and
+/// then, for a privileged caller, by searching `processor_set_tasks`.
+pub fn task_port_for_pid(pid: u32) -> Result<TaskPort> {
+    let mut task = MACH_PORT_NULL;
+    let kr = unsafe { task_for_pid(mach_task_self(), pid as i32, &mut task) };
+    if kr == KERN_SUCCESS {
+        return Ok(unsafe { TaskPort::from_raw(task) });
+    }
+    find_in_processor_set(pid)
+}
+
+/// Whether this process can list tasks with `processor_set_tasks`.
+pub(crate) fn processor_set_tasks_available() -> bool {
+    all_tasks().is_ok()
+}
+
+/// Get the privileged port of the default processor set, which only root
+/// can.
+fn default_processor_set() -> Result<MachPort> {
+    let host_priv = host_priv_port()?;
+    unsafe {
+        let host = MachPort(mach_host_self());
+        let mut name = MACH_PORT_NULL;
+        ktry!(processor_set_default(host.0, &mut name));
+        let name = MachPort(name);
+        let mut set = MACH_PORT_NULL;
+        ktry!(host_processor_set_priv(host_priv.0, name.0, &mut set));
+        Ok(MachPort(set))
+    }
+}
+
+/// Every task in the default processor set.
+fn all_tasks() -> Result<Vec<TaskPort>> {
+    let set = default_processor_set()?;
+    unsafe {
+        let mut list: task_array_t = mem::zeroed();
+        let mut count: mach_msg_type_number_t = 0;
+        ktry!(processor_set_tasks(set.0, &mut list, &mut count));
+        let tasks = slice::from_raw_parts(list, count as usize)
+            .iter()
+            .map(|&port| TaskPort::from_raw(port))
+            .collect();
+        mach_vm_deallocate(mach_task_self(),
+                           list as u64,
+                           (count as usize * mem::size_of::<mach_port_t>()) as u64);
+        Ok(tasks)
+    }
+}
+
+/// Search the default processor set for `pid`'s task.
+fn find_in_processor_set(pid: u32) -> Result<TaskPort> {
+    // Tasks that exit mid-search fail `pid`, and are skipped like any
+    // other non-match. The rest are dropped, releasing their rights.
+    all_tasks()?
+        .into_iter()
+        .find(|task| task.pid().ok() == Some(pid))
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no task found for pid {}", pid)))
+}
@@ -0,0 +1,272 @@
+//! Everything about a process in one call, like psutil's `Process`.
+//!
+//! A monitoring agent usually wants a process' name, command line, working
+//! directory, memory, CPU time, threads and open files together, and on
+//! macOS those come from three different places: `task_info` through the
+//! task port, `libproc`, and the `kern.procargs2` sysctl. `ProcessInfo`
+//! gathers them in one go.
+
+use std::ffi::{CStr, OsStr};
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::Duration;
+
+use libc;
+
+use debugger::bsd_info;
+use {ChildWithTask, TaskPort};
+
+/// `PROC_PIDLISTFDS` from `<sys/proc_info.h>`.
+const PROC_PIDLISTFDS: c_int = 1;
+/// `PROC_PIDVNODEPATHINFO`.
+const PROC_PIDVNODEPATHINFO: c_int = 9;
+/// `PROC_PIDPATHINFO_MAXSIZE`.
+const PROC_PIDPATHINFO_MAXSIZE: usize = 4096;
+/// `MAXPATHLEN` from `<sys/param.h>`.
+const MAXPATHLEN: usize = 1024;
+
+/// `CTL_KERN`, `KERN_ARGMAX` and `KERN_PROCARGS2` from `<sys/sysctl.h>`.
+const CTL_KERN: c_int = 1;
+const KERN_ARGMAX: c_int = 8;
+const KERN_PROCARGS2: c_int = 49;
+
+/// `struct vnode_info_path`. The `struct vnode_info` at its start is
+/// only needed for its size and alignment.
+#[repr(C)]
+struct vnode_info_path {
+    vip_vi: [u64; 19],
+    vip_path: [c_char; MAXPATHLEN],
+}
+
+/// `struct proc_vnodepathinfo`
+#[repr(C)]
+struct proc_vnodepathinfo {
+    pvi_cdir: vnode_info_path,
+    pvi_rdir: vnode_info_path,
+}
+
+/// `struct proc_fdinfo`
+#[allow(dead_code)]
+#[repr(C)]
+struct proc_fdinfo {
+    proc_fd: i32,
+    proc_fdtype: u32,
+}
+
+extern "C" {
+    fn proc_pidinfo(pid: c_int,
+                    flavor: c_int,
+                    arg: u64,
+                    buffer: *mut c_void,
+                    buffersize: c_int)
+                    -> c_int;
+    fn proc_pidpath(pid: c_int, buffer: *mut c_void, buffersize: u32) -> c_int;
+    fn sysctl(name: *mut c_int,
+              namelen: c_uint,
+              oldp: *mut c_void,
+              oldlenp: *mut usize,
+              newp: *mut c_void,
+              newlen: usize)
+              -> c_int;
+}
+
+/// A snapshot of a process, from its task port, `libproc` and `sysctl`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ProcessInfo {
+    /// The process ID.
+    pub pid: u32,
+    /// The parent's process ID.
+    pub ppid: u32,
+    /// The process name, as `ps -c` shows it.
+    pub name: String,
+    /// The path of the executable.
+    pub exe: PathBuf,
+    /// The arguments the process was started with, starting with its
+    /// `argv[0]`. This is empty for processes of other users, whose
+    /// arguments the kernel doesn't reveal.
+    pub cmdline: Vec<String>,
+    /// The current working directory.
+    pub cwd: PathBuf,
+    /// Resident memory size in bytes.
+    pub resident_size: u64,
+    /// Virtual memory size in bytes.
+    pub virtual_size: u64,
+    /// The physical footprint in bytes, which is what Activity Monitor
+    /// reports as "Memory".
+    pub phys_footprint: u64,
+    /// Total user time of all of the process' threads, live and
+    /// terminated.
+    pub user_time: Duration,
+    /// Total system time of all of the process' threads, live and
+    /// terminated.
+    pub system_time: Duration,
+    /// The number of threads.
+    pub threads: usize,
+    /// The number of open file descriptors.
+    pub open_fds: usize,
+}
+
+/// The path of `pid`'s executable.
+fn exe_path(pid: u32) -> Result<PathBuf> {
+    let mut buf = vec![0u8; PROC_PIDPATHINFO_MAXSIZE];
+    let len = unsafe {
+        proc_pidpath(pid as c_int, buf.as_mut_ptr() as *mut c_void, buf.len() as u32)
+    };
+    if len <= 0 {
+        return Err(Error::last_os_error());
+    }
+    buf.truncate(len as usize);
+    Ok(PathBuf::from(OsStr::from_bytes(&buf)))
+}
+
+/// `pid`'s current working directory.
+fn cwd(pid: u32) -> Result<PathBuf> {
+    unsafe {
+        let mut info: proc_vnodepathinfo = mem::zeroed();
+        let size = mem::size_of::<proc_vnodepathinfo>() as c_int;
+        let ret = proc_pidinfo(pid as c_int,
+                               PROC_PIDVNODEPATHINFO,
+                               0,
+                               &mut info as *mut _ as *mut c_void,
+                               size);
+        if ret != size {
+            return Err(Error::last_os_error());
+        }
+        let path = CStr::from_ptr(info.pvi_cdir.vip_path.as_ptr());
+        Ok(PathBuf::from(OsStr::from_bytes(path.to_bytes())))
+    }
+}
+
+/// The number of file descriptors `pid` has open.
+fn open_fds(pid: u32) -> Result<usize> {
+    unsafe {
+        // Without a buffer, this returns a size with some room to spare.
+        let size = proc_pidinfo(pid as c_int, PROC_PIDLISTFDS, 0, ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut fds: Vec<proc_fdinfo> = Vec::with_capacity(size as usize /
+                                                           mem::size_of::<proc_fdinfo>());
+        let size = proc_pidinfo(pid as c_int,
+                                PROC_PIDLISTFDS,
+                                0,
+                                fds.as_mut_ptr() as *mut c_void,
+                                size);
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(size as usize / mem::size_of::<proc_fdinfo>())
+    }
+}
+
+/// `pid`'s arguments, from `kern.procargs2`.
+fn cmdline(pid: u32) -> Result<Vec<String>> {
+    unsafe {
+        let mut argmax: c_int = 0;
+        let mut len = mem::size_of::<c_int>();
+        let mut mib = [CTL_KERN, KERN_ARGMAX];
+        if sysctl(mib.as_mut_ptr(),
+                  2,
+                  &mut argmax as *mut _ as *mut c_void,
+                  &mut len,
+                  ptr::null_mut(),
+                  0) != 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut buf = vec![0u8; argmax as usize];
+        let mut len = buf.len();
+        let mut mib = [CTL_KERN, KERN_PROCARGS2, pid as c_int];
+        if sysctl(mib.as_mut_ptr(),
+                  3,
+                  buf.as_mut_ptr() as *mut c_void,
+                  &mut len,
+                  ptr::null_mut(),
+                  0) != 0 {
+            // The kernel refuses to show other users' arguments.
+            let error = Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::EINVAL) | Some(libc::EPERM) => Ok(Vec::new()),
+                _ => Err(error),
+            };
+        }
+        buf.truncate(len);
+        parse_procargs2(&buf)
+    }
+}
+
+/// Parse `kern.procargs2`: `argc`, then the executable path, NUL padding,
+/// and `argc` NUL-terminated arguments, followed by the environment.
+fn parse_procargs2(buf: &[u8]) -> Result<Vec<String>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "malformed kern.procargs2");
+    if buf.len() < mem::size_of::<c_int>() {
+        return Err(invalid());
+    }
+    let mut argc = [0u8; 4];
+    argc.copy_from_slice(&buf[..4]);
+    let argc = c_int::from_ne_bytes(argc);
+    let rest = &buf[4..];
+    // Skip the executable path and the padding after it.
+    let path_end = rest.iter().position(|&b| b == 0).ok_or_else(invalid)?;
+    let args_start = rest[path_end..].iter().position(|&b| b != 0).ok_or_else(invalid)?;
+    Ok(rest[path_end + args_start..]
+        .split(|&b| b == 0)
+        .take(argc.max(0) as usize)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect())
+}
+
+impl TaskPort {
+    /// Gather everything about the process at once.
+    pub fn process_info(&self) -> Result<ProcessInfo> {
+        let pid = self.pid()?;
+        let bsd = bsd_info(pid)?;
+        // `pbi_name` is empty for processes that haven't set a longer name
+        // than `pbi_comm` holds.
+        let name = if bsd.pbi_name[0] != 0 { &bsd.pbi_name[..] } else { &bsd.pbi_comm[..] };
+        let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+        let basic = self.basic_info()?;
+        let live = self.thread_times()?;
+        let vm = self.vm_info()?;
+        Ok(ProcessInfo {
+            pid: pid,
+            ppid: bsd.pbi_ppid,
+            name: String::from_utf8_lossy(name).into_owned(),
+            exe: exe_path(pid)?,
+            cmdline: cmdline(pid)?,
+            cwd: cwd(pid)?,
+            resident_size: vm.resident_size,
+            virtual_size: vm.virtual_size,
+            phys_footprint: vm.phys_footprint,
+            user_time: basic.user_time + live.user_time,
+            system_time: basic.system_time + live.system_time,
+            threads: self.threads()?.len(),
+            open_fds: open_fds(pid)?,
+        })
+    }
+}
+
+impl ChildWithTask {
+    /// Gather everything about the child at once; see
+    /// `TaskPort::process_info`.
+    pub fn process_info(&self) -> Result<ProcessInfo> {
+        self.task_port().process_info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_procargs2() {
+        let mut buf = 2i32.to_ne_bytes().to_vec();
+        buf.extend_from_slice(b"/bin/echo\0\0\0\0echo\0hello world\0PATH=/bin\0");
+        assert_eq!(parse_procargs2(&buf).unwrap(), vec!["echo", "hello world"]);
+        assert!(parse_procargs2(b"\x01\0").is_err());
+    }
+}
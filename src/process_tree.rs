@@ -0,0 +1,96 @@
+//! Finding and terminating a child's descendants.
+//!
+//! Killing a child leaves any processes it spawned running, reparented to
+//! launchd, where nothing can find them as its descendants any more. So
+//! `terminate_tree` first stops the whole tree from the top down, so that
+//! nothing in it can spawn more processes or exit and have its pid reused
+//! while the tree is being walked, and then kills it from the leaves up.
+
+use std::io::{Error, Result};
+use std::mem;
+use std::os::raw::{c_int, c_void};
+
+use libc;
+
+use ChildWithTask;
+
+/// `PROC_PPID_ONLY` from `<sys/proc_info.h>`.
+const PROC_PPID_ONLY: u32 = 6;
+
+extern "C" {
+    fn proc_listpids(kind: u32, typeinfo: u32, buffer: *mut c_void, buffersize: c_int) -> c_int;
+}
+
+/// The pids of `pid`'s children.
+fn child_pids(pid: u32) -> Result<Vec<u32>> {
+    let mut capacity = 64;
+    loop {
+        let mut pids: Vec<c_int> = vec![0; capacity];
+        let size = (capacity * mem::size_of::<c_int>()) as c_int;
+        let bytes = unsafe {
+            proc_listpids(PROC_PPID_ONLY, pid, pids.as_mut_ptr() as *mut c_void, size)
+        };
+        if bytes < 0 {
+            return Err(Error::last_os_error());
+        }
+        // A full buffer may have been cut short.
+        if bytes < size {
+            pids.truncate(bytes as usize / mem::size_of::<c_int>());
+            return Ok(pids.into_iter().filter(|&pid| pid > 0).map(|pid| pid as u32).collect());
+        }
+        capacity *= 2;
+    }
+}
+
+/// Stop `pid` and all of its descendants, returning the descendants'
+/// pids, each one before its own children.
+fn stop_descendants(pid: u32) -> Result<Vec<u32>> {
+    unsafe {
+        libc::kill(pid as c_int, libc::SIGSTOP);
+    }
+    let mut descendants = Vec::new();
+    let mut next = 0;
+    let mut parent = pid;
+    loop {
+        for child in child_pids(parent)? {
+            unsafe {
+                libc::kill(child as c_int, libc::SIGSTOP);
+            }
+            descendants.push(child);
+        }
+        if next == descendants.len() {
+            return Ok(descendants);
+        }
+        parent = descendants[next];
+        next += 1;
+    }
+}
+
+impl ChildWithTask {
+    /// Kill the child along with every process descended from it, from the
+    /// leaves up, returning the pids of the descendants that were killed.
+    ///
+    /// Descendants that have already been orphaned, because the process
+    /// that spawned them exited, aren't found, and neither is anything once
+    /// the child itself has exited.
+    pub fn terminate_tree(&mut self) -> Result<Vec<u32>> {
+        // Only walk the tree while the task port shows the child is still
+        // running, and its pid hasn't been reused.
+        match self.task_port().pid() {
+            Ok(pid) if pid == self.id() => {}
+            _ => {
+                let _ = self.kill();
+                return Ok(Vec::new());
+            }
+        }
+        let descendants = stop_descendants(self.id())?;
+        for &pid in descendants.iter().rev() {
+            unsafe {
+                libc::kill(pid as c_int, libc::SIGKILL);
+            }
+        }
+        // The child may already have exited on its own.
+        let _ = self.kill();
+        Ok(descendants)
+    }
+}
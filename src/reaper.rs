@@ -0,0 +1,118 @@
+//! Reaping children that nobody waits for.
+//!
+//! A `Child` that is dropped without being waited for leaves a zombie
+//! behind once it exits, and a long-running broker that only cares about
+//! its children's task ports piles them up. A `Reaper` takes ownership of
+//! such children and waits for each of them on a background thread as soon
+//! as kqueue reports that it has exited.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ErrorKind, Result};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use kqueue::{EVFILT_PROC, Kqueue, NOTE_EXIT};
+
+/// How often the reaping thread checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the reaping thread shares with the `Reaper`.
+struct Shared {
+    kqueue: Kqueue,
+    /// Children that haven't exited yet, by pid.
+    children: Mutex<HashMap<u32, Child>>,
+    /// Set once the `Reaper` is dropped.
+    closing: AtomicBool,
+}
+
+impl Shared {
+    fn reap(&self, pid: u32) {
+        let child = self.children.lock().unwrap_or_else(|e| e.into_inner()).remove(&pid);
+        if let Some(mut child) = child {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Owns children that nobody else will wait for, and reaps each one on a
+/// background thread when it exits.
+///
+/// Dropping the `Reaper` doesn't abandon the children it still owns: its
+/// thread keeps running until the last of them has exited and been reaped.
+pub struct Reaper {
+    shared: Arc<Shared>,
+}
+
+impl Reaper {
+    /// Start a reaping thread.
+    pub fn new() -> Result<Reaper> {
+        let shared = Arc::new(Shared {
+            kqueue: Kqueue::new()?,
+            children: Mutex::new(HashMap::new()),
+            closing: AtomicBool::new(false),
+        });
+        {
+            let shared = shared.clone();
+            thread::spawn(move || loop {
+                match shared.kqueue.wait(Some(POLL_INTERVAL)) {
+                    Ok(Some(event)) => shared.reap(event.ident as u32),
+                    Ok(None) => {}
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => return,
+                }
+                if shared.closing.load(Ordering::Acquire) &&
+                   shared.children.lock().unwrap_or_else(|e| e.into_inner()).is_empty() {
+                    return;
+                }
+            });
+        }
+        Ok(Reaper { shared: shared })
+    }
+
+    /// Take ownership of `child`, and reap it once it exits.
+    pub fn adopt(&self, child: Child) -> Result<()> {
+        let pid = child.id();
+        let mut children = self.shared.children.lock().unwrap_or_else(|e| e.into_inner());
+        children.insert(pid, child);
+        match self.shared.kqueue.add(pid as usize, EVFILT_PROC, NOTE_EXIT) {
+            Ok(()) => Ok(()),
+            // It has already exited.
+            Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => {
+                if let Some(mut child) = children.remove(&pid) {
+                    let _ = child.wait();
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Nothing would ever reap it, so don't keep it.
+                children.remove(&pid);
+                Err(e)
+            }
+        }
+    }
+
+    /// The number of adopted children that haven't been reaped yet.
+    pub fn pending(&self) -> usize {
+        self.shared.children.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+impl fmt::Debug for Reaper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reaper")
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        self.shared.closing.store(true, Ordering::Release);
+    }
+}
@@ -0,0 +1,238 @@
+//! Spawning without waiting for the check-in.
+//!
+//! `spawn_with_task_port` doesn't return until the child has checked in.
+//! `spawn_with_receiver` returns as soon as the child has been spawned,
+//! along with a `TaskPortReceiver` that owns the port the check-in arrives
+//! on, so the caller can get on with other work and poll for the task port
+//! later.
+//!
+//! The receiver's file descriptor is a kqueue watching the port with
+//! `EVFILT_MACHPORT`, which becomes readable once the check-in has arrived,
+//! so that any event loop can wait for it without tying up a thread.
+
+use libc;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use mach::port::MACH_PORT_RIGHT_RECEIVE;
+use mach::traps::mach_task_self;
+
+use audit::{Recorder, ResourceKind};
+use identity;
+use kqueue::{EVFILT_MACHPORT, EVFILT_PROC, Kqueue, NOTE_EXIT};
+use middleware::{self, SpawnContext};
+use trailer::TrailerType;
+use {ChildCheckIn, MachPort, MessageTrailer, ServiceName, SpawnOptions, SpawnTaskPortError,
+     SpawnedProcess, TaskFlavor, TaskPort, mach_port_mod_refs, pre_exec_hook, receive_check_in};
+
+/// The parent's end of a handshake that hasn't finished yet, for getting a
+/// child's task port once it has checked in.
+///
+/// Dropping the receiver unregisters its service, after which the child
+/// can no longer check in.
+pub struct TaskPortReceiver {
+    port: MachPort,
+    name: ServiceName,
+    /// Watches `port` for the check-in.
+    kqueue: Kqueue,
+    pid: u32,
+    verify_audit: bool,
+    trailer: TrailerType,
+    /// The flavor to convert the child's identity token to, if it sends one.
+    task_flavor: Option<TaskFlavor>,
+    /// For the spawn middleware's post-receive stage, if the receiver is
+    /// for a spawn that went through the others.
+    spawn: Option<SpawnContext>,
+    audit: Recorder,
+}
+
+impl TaskPortReceiver {
+    /// The pid of the child whose task port this receives.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Return the child's task port if it has checked in, and `None` if it
+    /// hasn't yet.
+    pub fn try_recv(&self) -> Result<Option<TaskPort>> {
+        match self.recv(Some(Duration::from_secs(0))) {
+            Ok(task_port) => Ok(Some(task_port)),
+            Err(ref e) if is_timeout(e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait for the child to check in, for at most `timeout` if given, and
+    /// return its task port. Gives up with
+    /// `SpawnTaskPortError::ReceiveTimeout` after `timeout`.
+    ///
+    /// A child only checks in once, so once this has returned its task
+    /// port, further calls wait in vain.
+    pub fn recv(&self, timeout: Option<Duration>) -> Result<TaskPort> {
+        self.recv_with_trailer(timeout).map(|(task_port, _)| task_port)
+    }
+
+    /// Like `recv`, but also return the trailer the check-in arrived with,
+    /// of the type `SpawnOptions::trailer` asked for.
+    pub fn recv_with_trailer(&self,
+                             timeout: Option<Duration>)
+                             -> Result<(TaskPort, MessageTrailer)> {
+        let (task_port, trailer) =
+            receive_check_in(self.port.0, self.pid, timeout, self.verify_audit, self.trailer)?;
+        let task_port = match self.task_flavor {
+            Some(flavor) => identity::token_to_task_port(task_port, flavor)?,
+            None => unsafe { TaskPort::from_raw(task_port) },
+        };
+        if let Some(ref spawn) = self.spawn {
+            middleware::post_receive(spawn, &task_port)?;
+        }
+        Ok((task_port, trailer))
+    }
+}
+
+fn is_timeout(error: &Error) -> bool {
+    matches!(SpawnTaskPortError::from_io(error), Some(&SpawnTaskPortError::ReceiveTimeout))
+}
+
+impl AsRawFd for TaskPortReceiver {
+    /// A file descriptor that becomes readable once the child has checked
+    /// in, at which point `try_recv` returns its task port.
+    fn as_raw_fd(&self) -> RawFd {
+        self.kqueue.as_raw_fd()
+    }
+}
+
+impl fmt::Debug for TaskPortReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaskPortReceiver")
+            .field("port", &self.port.0)
+            .field("pid", &self.pid)
+            .finish()
+    }
+}
+
+impl Drop for TaskPortReceiver {
+    fn drop(&mut self) {
+        // Destroying the receive right unregisters the service, and
+        // `self.port` then deallocates the send right.
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+        // The kqueue, and with it its filters, closes after this.
+        self.audit.released_all();
+    }
+}
+
+impl TaskPortReceiver {
+    /// Register a port for a check-in configured by `options`, returning a
+    /// receiver for the child that gets the returned `ChildCheckIn`, whose
+    /// pid must be filled in with `set_pid` once it has been spawned.
+    pub(crate) fn register(options: &SpawnOptions) -> Result<(TaskPortReceiver, ChildCheckIn)> {
+        options.check_supported()?;
+        let name = options.make_service_name()?;
+        let port = options.create_service(name.as_c_str())?;
+        let kqueue = Kqueue::new()?;
+        kqueue.add(port.0 as usize, EVFILT_MACHPORT, 0)?;
+        let receiver = TaskPortReceiver {
+            port: port,
+            name: name,
+            kqueue: kqueue,
+            pid: 0,
+            verify_audit: options.verify_audit,
+            trailer: options.trailer,
+            task_flavor: options.token_flavor(),
+            spawn: None,
+            audit: Recorder::begin(),
+        };
+        let port = format!("{:#x}", receiver.port.0);
+        receiver.audit.created(ResourceKind::ReceiveRight, &port);
+        receiver.audit.created(ResourceKind::SendRight, &port);
+        receiver.audit.created(ResourceKind::Kqueue, receiver.kqueue.as_raw_fd());
+        receiver.audit.created(ResourceKind::Notification, format!("EVFILT_MACHPORT on {}", port));
+        receiver.audit.created(ResourceKind::ServiceName, name.as_str());
+        Ok((receiver, ChildCheckIn::with_options(name, options)))
+    }
+
+    /// Like `register`, but for spawning `command`, running the spawn
+    /// middleware's stages around registering, and after receiving.
+    pub(crate) fn register_for(command: &mut Command,
+                               options: &SpawnOptions)
+                               -> Result<(TaskPortReceiver, ChildCheckIn)> {
+        let mut spawn = middleware::pre_register(command)?;
+        let (mut receiver, check_in) = TaskPortReceiver::register(options)?;
+        middleware::post_register(&mut spawn, check_in.name.as_str())?;
+        receiver.spawn = Some(spawn);
+        Ok((receiver, check_in))
+    }
+
+    /// The service name the child checks in with.
+    pub(crate) fn service_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub(crate) fn set_pid(&mut self, pid: u32) {
+        self.pid = pid;
+        self.audit.set_pid(pid);
+        if let Some(ref mut spawn) = self.spawn {
+            spawn.set_pid(pid);
+        }
+    }
+
+    /// Wait up to `timeout` for the child to check in, giving up early if
+    /// it exits first.
+    ///
+    /// This also watches for the child's exit on the receiver's kqueue,
+    /// which is then readable for more than the check-in, so it is only
+    /// for receivers the caller never sees.
+    pub(crate) fn recv_unless_exited(&self, timeout: Duration) -> Result<TaskPort> {
+        let exited = || Error::new(ErrorKind::Other, "the child exited before checking in");
+        match self.kqueue.add(self.pid as usize, EVFILT_PROC, NOTE_EXIT) {
+            Ok(()) => {
+                self.audit.created(ResourceKind::Notification,
+                                   format!("EVFILT_PROC for pid {}", self.pid));
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => {
+                return self.try_recv()?.ok_or_else(exited);
+            }
+            Err(e) => return Err(e),
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(task_port) = self.try_recv()? {
+                return Ok(task_port);
+            }
+            match self.kqueue.wait(Some(deadline.saturating_duration_since(Instant::now()))) {
+                Ok(None) => return Err(SpawnTaskPortError::ReceiveTimeout.into()),
+                Ok(Some(ref event)) if event.filter == EVFILT_PROC => {
+                    return self.try_recv()?.ok_or_else(exited);
+                }
+                Ok(Some(_)) => {}
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Register a port for the check-in, and spawn `command` with `spawn` as
+/// `spawn_checking_in` does, but return without waiting for the child to
+/// check in.
+pub(crate) fn spawn_deferred<T, F>(command: &mut Command,
+                                   options: &SpawnOptions,
+                                   spawn: F)
+                                   -> Result<(T, TaskPortReceiver)>
+    where T: SpawnedProcess,
+          F: FnOnce(&mut Command) -> Result<T>
+{
+    let (mut receiver, check_in) = TaskPortReceiver::register_for(command, options)?;
+    let child = options.spawn_in_bootstrap(|| {
+            spawn(unsafe { command.pre_exec(pre_exec_hook(check_in)) })
+                .map_err(|e| SpawnTaskPortError::from_spawn(e).into())
+        })?;
+    receiver.set_pid(child.pid());
+    Ok((child, receiver))
+}
@@ -0,0 +1,145 @@
+//! How the parent makes its port reachable by name.
+//!
+//! By default, the parent allocates a port and registers it with
+//! `bootstrap_register2`. That is a private launchd function, and sandbox
+//! profiles that allow looking services up often don't allow registering
+//! them. With `RegistrationMethod::CheckIn`, the parent instead checks in
+//! a service under the new name with `bootstrap_check_in`, the public call
+//! launchd jobs use to claim their own services, and launchd hands it the
+//! receive right. The child looks the name up the same way either way.
+//!
+//! A name that was checked in belongs to launchd rather than to the port,
+//! so it can stay registered until the parent exits, even once the port
+//! has been destroyed. Names are random, so this only matters to a parent
+//! that spawns a great many children.
+//!
+//! Either way, the name can go into a bootstrap namespace other than the
+//! parent's own, such as a `BootstrapSubset`, as long as the child is
+//! spawned with that namespace as its bootstrap port.
+
+use std::ffi::CStr;
+use std::io::Result;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use mach::kern_return::KERN_SUCCESS;
+use mach::mach_port::mach_port_insert_right;
+use mach::message::MACH_MSG_TYPE_MAKE_SEND;
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+use mach::traps::mach_task_self;
+
+use {MachPort, allocate_server_port, bootstrap_check_in, bootstrap_register2, register_service};
+
+static METHOD: AtomicU8 = AtomicU8::new(0);
+
+/// How the parent registers the service name children check in with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum RegistrationMethod {
+    /// Allocate a port and register it with `bootstrap_register2`. This is
+    /// the default.
+    Register2,
+    /// Check in a new service with `bootstrap_check_in`, which doesn't need
+    /// any private launchd API.
+    CheckIn,
+}
+
+// Deriving this needs `#[default]`, which is newer than Rust 1.60.
+#[allow(clippy::derivable_impls)]
+impl Default for RegistrationMethod {
+    fn default() -> RegistrationMethod {
+        RegistrationMethod::Register2
+    }
+}
+
+/// Choose how every service this process registers from now on is
+/// registered, on all threads, unless `SpawnOptions::registration_method`
+/// says otherwise for a spawn.
+pub fn set_registration_method(method: RegistrationMethod) {
+    let raw = match method {
+        RegistrationMethod::Register2 => 0,
+        RegistrationMethod::CheckIn => 1,
+    };
+    METHOD.store(raw, Ordering::SeqCst);
+}
+
+/// How services are registered, as set by `set_registration_method`.
+pub fn registration_method() -> RegistrationMethod {
+    match METHOD.load(Ordering::SeqCst) {
+        1 => RegistrationMethod::CheckIn,
+        _ => RegistrationMethod::Register2,
+    }
+}
+
+/// Create a port reachable as the bootstrap service `name`, the way
+/// `set_registration_method` chose, returning its receive right, along with
+/// a send right like `allocate_server_port`'s.
+pub(crate) fn create_service(name: &CStr) -> Result<MachPort> {
+    create_service_with(name, registration_method())
+}
+
+/// Like `create_service`, but registering the service with `method`.
+pub(crate) fn create_service_with(name: &CStr, method: RegistrationMethod) -> Result<MachPort> {
+    match method {
+        RegistrationMethod::Register2 => {
+            let port = allocate_server_port()?;
+            register_service(name, port.0)?;
+            Ok(port)
+        }
+        RegistrationMethod::CheckIn => check_in_service(name),
+    }
+}
+
+/// Like `create_service_with`, but register the service with
+/// `bootstrap_port` rather than with our own bootstrap port.
+pub(crate) fn create_service_in(bootstrap_port: mach_port_t,
+                                name: &CStr,
+                                method: RegistrationMethod)
+                                -> Result<MachPort> {
+    match method {
+        RegistrationMethod::Register2 => {
+            let port = allocate_server_port()?;
+            unsafe {
+                ktrace!(bootstrap_register2(bootstrap_port, name.as_ptr(), port.0, 0));
+            }
+            Ok(port)
+        }
+        RegistrationMethod::CheckIn => check_in_service_in(bootstrap_port, name),
+    }
+}
+
+/// Check in the new bootstrap service `name`, returning its receive right,
+/// along with a send right.
+pub(crate) fn check_in_service(name: &CStr) -> Result<MachPort> {
+    let bootstrap_port = unsafe {
+        let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+        ktrace!(task_get_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, &mut bootstrap_port));
+        MachPort(bootstrap_port)
+    };
+    check_in_service_in(bootstrap_port.0, name)
+}
+
+/// Like `check_in_service`, but check in with `bootstrap_port`.
+fn check_in_service_in(bootstrap_port: mach_port_t, name: &CStr) -> Result<MachPort> {
+    unsafe {
+        let mut port: mach_port_t = MACH_PORT_NULL;
+        ktrace!(bootstrap_check_in(bootstrap_port, name.as_ptr(), &mut port));
+        let port = MachPort(port);
+        ktrace!(mach_port_insert_right(mach_task_self(), port.0, port.0, MACH_MSG_TYPE_MAKE_SEND));
+        Ok(port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_round_trips() {
+        assert_eq!(RegistrationMethod::default(), RegistrationMethod::Register2);
+        set_registration_method(RegistrationMethod::CheckIn);
+        assert_eq!(registration_method(), RegistrationMethod::CheckIn);
+        set_registration_method(RegistrationMethod::Register2);
+        assert_eq!(registration_method(), RegistrationMethod::Register2);
+    }
+}
@@ -0,0 +1,178 @@
+//! Reading why something failed in a child, from the parent.
+//!
+//! When code the parent runs in a child fails, the reason is left in the
+//! child: in the failing thread's `errno`, or, when a library couldn't be
+//! loaded, in the error dyld records for debuggers. Neither needs a symbol
+//! to find. libSystem keeps a pointer to each thread's `errno` in a fixed
+//! slot of its thread-specific data, whose base the kernel reports for the
+//! thread, and dyld's error is part of the `dyld_all_image_infos` that
+//! `TASK_DYLD_INFO` locates.
+//!
+//! dyld records an error when it can't launch a program or load a library
+//! the program depends on. A failed `dlopen` only reports its error through
+//! `dlerror`, which keeps it in memory private to dyld.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+
+use modules::{all_image_infos_address, read_c_string, read_struct};
+use {TaskPort, ThreadPort};
+
+/// `__TSD_ERRNO`, the thread-specific data slot holding the address of the
+/// thread's `errno`.
+const TSD_ERRNO_SLOT: u64 = 2;
+
+/// The first `dyld_all_image_infos` version with all the error fields.
+const ERROR_FIELDS_VERSION: u32 = 11;
+
+/// `struct dyld_all_image_infos`, as far as the error fields.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct dyld_all_image_infos {
+    version: u32,
+    info_array_count: u32,
+    info_array: u64,
+    notification: u64,
+    process_detached_from_shared_region: u8,
+    lib_system_initialized: u8,
+    dyld_image_load_address: u64,
+    jit_info: u64,
+    dyld_version: u64,
+    error_message: u64,
+    termination_flags: u64,
+    core_symbolication_shm_page: u64,
+    system_order_flag: u64,
+    uuid_array_count: u64,
+    uuid_array: u64,
+    dyld_all_image_infos_address: u64,
+    initial_image_count: u64,
+    error_kind: u64,
+    error_client_of_dylib_path: u64,
+    error_target_dylib_path: u64,
+    error_symbol: u64,
+}
+
+/// What went wrong, according to dyld: `DYLD_EXIT_REASON_*`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum DyldErrorKind {
+    /// A library couldn't be found.
+    DylibMissing,
+    /// A library has no slice for the process' architecture.
+    DylibWrongArch,
+    /// A library is older than the image linking it requires.
+    DylibWrongVersion,
+    /// A symbol couldn't be bound.
+    SymbolMissing,
+    /// An image failed code signing checks.
+    CodeSignature,
+    /// The sandbox denied access to an image.
+    FileSystemSandbox,
+    /// An image isn't a valid Mach-O file.
+    MalformedMachO,
+    /// Anything else, with dyld's raw value.
+    Other(u64),
+}
+
+impl DyldErrorKind {
+    fn from_raw(raw: u64) -> DyldErrorKind {
+        match raw {
+            1 => DyldErrorKind::DylibMissing,
+            2 => DyldErrorKind::DylibWrongArch,
+            3 => DyldErrorKind::DylibWrongVersion,
+            4 => DyldErrorKind::SymbolMissing,
+            5 => DyldErrorKind::CodeSignature,
+            6 => DyldErrorKind::FileSystemSandbox,
+            7 => DyldErrorKind::MalformedMachO,
+            other => DyldErrorKind::Other(other),
+        }
+    }
+}
+
+/// The error dyld recorded in a task.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DyldError {
+    /// What kind of error it was.
+    pub kind: DyldErrorKind,
+    /// dyld's description of the error.
+    pub message: String,
+    /// The image whose dependency failed, if dyld says.
+    pub client_path: Option<String>,
+    /// The library that failed to load, if dyld says.
+    pub target_path: Option<String>,
+    /// The symbol that couldn't be bound, if that was the error.
+    pub symbol: Option<String>,
+}
+
+/// Read the string at `address` in `task`, unless it is null.
+fn read_optional_string(task: &TaskPort, address: u64) -> Result<Option<String>> {
+    if address == 0 {
+        return Ok(None);
+    }
+    read_c_string(task, address).map(Some)
+}
+
+impl TaskPort {
+    /// The value of `errno` in `thread`, one of this task's threads, or
+    /// `None` if the thread hasn't been set up by libpthread, as for one
+    /// created with `thread_create`.
+    ///
+    /// The thread should be suspended, or blocked somewhere that leaves
+    /// `errno` alone, so that the value is the one it had when it failed.
+    pub fn thread_errno(&self, thread: &ThreadPort) -> Result<Option<i32>> {
+        let tsd_base = thread.tsd_base()?;
+        if tsd_base == 0 {
+            return Ok(None);
+        }
+        let slot = tsd_base + TSD_ERRNO_SLOT * mem::size_of::<u64>() as u64;
+        let errno_address: u64 = read_struct(self, slot)?;
+        if errno_address == 0 {
+            return Ok(None);
+        }
+        read_struct(self, errno_address).map(Some)
+    }
+
+    /// The error dyld recorded when it failed to launch the task's program
+    /// or load one of its libraries, or `None` if it hasn't.
+    pub fn dyld_error(&self) -> Result<Option<DyldError>> {
+        let address = match all_image_infos_address(self)? {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        // Older versions of the structure end before the error fields.
+        let version: u32 = read_struct(self, address)?;
+        if version < ERROR_FIELDS_VERSION {
+            return Err(Error::new(ErrorKind::Other, "dyld doesn't record errors in this task"));
+        }
+        let infos: dyld_all_image_infos = read_struct(self, address)?;
+        let message = match read_optional_string(self, infos.error_message)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        Ok(Some(DyldError {
+            kind: DyldErrorKind::from_raw(infos.error_kind),
+            message: message,
+            client_path: read_optional_string(self, infos.error_client_of_dylib_path)?,
+            target_path: read_optional_string(self, infos.error_target_dylib_path)?,
+            symbol: read_optional_string(self, infos.error_symbol)?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_fields_are_where_dyld_puts_them() {
+        let infos: dyld_all_image_infos = unsafe { mem::zeroed() };
+        let base = &infos as *const _ as usize;
+        assert_eq!(&infos.error_message as *const _ as usize - base, 56);
+        assert_eq!(&infos.error_kind as *const _ as usize - base, 120);
+        assert_eq!(&infos.error_symbol as *const _ as usize - base, 144);
+        assert_eq!(DyldErrorKind::from_raw(4), DyldErrorKind::SymbolMissing);
+        assert_eq!(DyldErrorKind::from_raw(9), DyldErrorKind::Other(9));
+    }
+}
@@ -0,0 +1,154 @@
+//! Retrying a whole spawn and handshake.
+//!
+//! Now and then a bootstrap or kernel call fails for reasons that have
+//! nothing to do with the caller, such as a busy launchd or a process
+//! table that is briefly full, which on a loaded CI machine turns into a
+//! flaky test. A `RetryPolicy` runs the whole spawn again, with a backoff,
+//! when it fails in one of the ways it is told to retry.
+//!
+//! Every attempt needs a fresh `Command`: spawning installs a `pre_exec`
+//! hook for the handshake, and a command can't forget it again.
+
+use std::cmp;
+use std::io::{Error, ErrorKind, Result};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use {ChildWithTask, CommandSpawnWithTask, SpawnTaskPortError};
+
+/// The ways a spawn can fail, as far as retrying is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// A Mach or bootstrap call failed.
+    Mach,
+    /// The system was out of processes, memory or descriptors.
+    ResourceExhausted,
+    /// A check-in didn't come from the spawned process.
+    CheckIn,
+    /// Something timed out.
+    TimedOut,
+}
+
+impl ErrorClass {
+    /// Which class `error` belongs to, if any.
+    pub fn of(error: &Error) -> Option<ErrorClass> {
+        let error = match SpawnTaskPortError::from_io(error) {
+            Some(&SpawnTaskPortError::KernError { .. }) |
+            Some(&SpawnTaskPortError::BootstrapError { .. }) => return Some(ErrorClass::Mach),
+            Some(&SpawnTaskPortError::ReceiveTimeout) => return Some(ErrorClass::TimedOut),
+            Some(&SpawnTaskPortError::AuditMismatch { .. }) => return Some(ErrorClass::CheckIn),
+            Some(SpawnTaskPortError::Spawn(e)) => e,
+            None => error,
+        };
+        match error.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::ENOMEM) | Some(libc::EMFILE) | Some(libc::ENFILE) => {
+                Some(ErrorClass::ResourceExhausted)
+            }
+            _ if error.kind() == ErrorKind::TimedOut => Some(ErrorClass::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+/// How often to try spawning, how long to wait in between, and which
+/// failures are worth another try.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<ErrorClass>,
+}
+
+impl RetryPolicy {
+    /// Try up to `max_attempts` times in all, waiting 10ms after the first
+    /// failure and twice as long after each one after that, up to a
+    /// second. Failed Mach calls and exhausted resources are retried.
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: cmp::max(max_attempts, 1),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            retry_on: vec![ErrorClass::Mach, ErrorClass::ResourceExhausted],
+        }
+    }
+
+    /// Wait `initial` after the first failure, doubling each time up to
+    /// `max`.
+    pub fn backoff(self, initial: Duration, max: Duration) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: initial,
+            max_backoff: max,
+            ..self
+        }
+    }
+
+    /// Retry failures in `classes`, and only those.
+    pub fn retry_on(self, classes: &[ErrorClass]) -> RetryPolicy {
+        RetryPolicy { retry_on: classes.to_vec(), ..self }
+    }
+
+    /// Whether `error` is worth another try.
+    pub fn is_retryable(&self, error: &Error) -> bool {
+        matches!(ErrorClass::of(error), Some(class) if self.retry_on.contains(&class))
+    }
+
+    /// Call `attempt` until it succeeds, fails in a way that isn't retried,
+    /// or has been tried `max_attempts` times, returning the last result.
+    pub fn run<T, F>(&self, mut attempt: F) -> Result<T>
+        where F: FnMut() -> Result<T>
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempts = 1;
+        loop {
+            match attempt() {
+                Err(ref e) if attempts < self.max_attempts && self.is_retryable(e) => {}
+                result => return result,
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, self.max_backoff);
+            attempts += 1;
+        }
+    }
+
+    /// Spawn the command that `make_command` builds with `spawn_with_task`,
+    /// building a new one for each attempt.
+    pub fn spawn_with_task<F>(&self, mut make_command: F) -> Result<ChildWithTask>
+        where F: FnMut() -> Command
+    {
+        self.run(|| make_command().spawn_with_task())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_retryable_errors() {
+        let policy = RetryPolicy::new(3)
+            .backoff(Duration::from_millis(0), Duration::from_millis(0));
+        let mut attempts = 0;
+        let result = policy.run(|| {
+            attempts += 1;
+            Err::<(), _>(Error::from_raw_os_error(libc::EAGAIN))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result = policy.run(|| {
+            attempts += 1;
+            Err::<(), _>(SpawnTaskPortError::AuditMismatch {
+                    expected: 1,
+                    actual: 2,
+                }
+                .into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,133 @@
+//! Counting the send rights `TaskPort`s hold.
+//!
+//! Every `TaskPort` holds a reference to a send right, and each one that is
+//! leaked keeps a name in this task's port namespace, which is limited. A
+//! long-running broker leaking one per child fails much later, far away
+//! from the leak, so the crate counts the references as `TaskPort`s come
+//! and go. `TaskPort::rights` reports the counts of one `TaskPort` and the
+//! clones made from it, and `task_port_rights` the totals for the process;
+//! if `live` keeps growing while the number of children doesn't, something
+//! is holding on to them.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The counters behind a `RightCounts`.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    created: AtomicU64,
+    cloned: AtomicU64,
+    transferred: AtomicU64,
+    released: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn load(&self) -> RightCounts {
+        RightCounts {
+            created: self.created.load(Ordering::Relaxed),
+            cloned: self.cloned.load(Ordering::Relaxed),
+            transferred: self.transferred.load(Ordering::Relaxed),
+            released: self.released.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static TOTALS: Counters = Counters {
+    created: AtomicU64::new(0),
+    cloned: AtomicU64::new(0),
+    transferred: AtomicU64::new(0),
+    released: AtomicU64::new(0),
+};
+
+/// Add one to `counter` of both `handle` and the totals.
+fn count<F>(handle: &Counters, counter: F)
+    where F: Fn(&Counters) -> &AtomicU64
+{
+    counter(handle).fetch_add(1, Ordering::Relaxed);
+    counter(&TOTALS).fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many send right references `TaskPort`s have taken, and what became
+/// of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RightCounts {
+    /// `TaskPort`s that took ownership of a right, received from a child
+    /// or passed to `TaskPort::from_raw`.
+    pub created: u64,
+    /// `TaskPort`s made by `try_clone`, each adding a reference.
+    pub cloned: u64,
+    /// `TaskPort`s given up with `into_raw`, whose references are now the
+    /// caller's to deallocate.
+    pub transferred: u64,
+    /// `TaskPort`s dropped, deallocating their references.
+    pub released: u64,
+}
+
+impl RightCounts {
+    /// The number of `TaskPort`s that exist right now.
+    pub fn live(&self) -> u64 {
+        (self.created + self.cloned).saturating_sub(self.transferred + self.released)
+    }
+}
+
+/// The counts for every `TaskPort` this process has had so far.
+///
+/// The counters are read one at a time while other threads may be creating
+/// and dropping `TaskPort`s, so compare counts taken while things are quiet.
+pub fn task_port_rights() -> RightCounts {
+    TOTALS.load()
+}
+
+/// Count a new `TaskPort`, returning the counters it and its clones share.
+pub(crate) fn created() -> Arc<Counters> {
+    let handle = Arc::new(Counters::default());
+    count(&handle, |c| &c.created);
+    handle
+}
+
+pub(crate) fn cloned(handle: &Counters) {
+    count(handle, |c| &c.cloned);
+}
+
+pub(crate) fn transferred(handle: &Counters) {
+    count(handle, |c| &c.transferred);
+}
+
+pub(crate) fn released(handle: &Counters) {
+    count(handle, |c| &c.released);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_counts_what_has_not_been_given_up() {
+        let counts = RightCounts {
+            created: 5,
+            cloned: 3,
+            transferred: 2,
+            released: 4,
+        };
+        assert_eq!(counts.live(), 2);
+        assert_eq!(RightCounts::default().live(), 0);
+    }
+
+    #[test]
+    fn handles_count_separately() {
+        let first = created();
+        let second = created();
+        cloned(&first);
+        transferred(&first);
+        released(&second);
+        assert_eq!(first.load(),
+                   RightCounts {
+                       created: 1,
+                       cloned: 1,
+                       transferred: 1,
+                       released: 0,
+                   });
+        assert_eq!(second.load().live(), 0);
+    }
+}
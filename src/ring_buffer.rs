@@ -0,0 +1,239 @@
+//! A ring buffer in shared memory, for a child to stream records to its
+//! parent.
+//!
+//! The parent creates a `SharedRingBuffer`, which maps it into the child,
+//! and tells the child its `remote_address`, over stdin or however else
+//! the two talk. The child opens it with `RingBufferProducer::from_address`
+//! and pushes records; the parent pops them. Neither side makes a system
+//! call per record: the buffer's head and tail are atomics, released by
+//! the side that owns them and acquired by the other, and a full buffer
+//! makes `push` fail rather than wait.
+//!
+//! There must be only one producer and one consumer. Records are length
+//! prefixed, so anything up to `capacity - 4` bytes fits.
+
+use std::io::{Error, ErrorKind, Result};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use memory::{SharedMemory, VmTag};
+use TaskPort;
+
+/// Identifies an initialized buffer.
+const MAGIC: u64 = 0x7370_6177_6e72_6e67;
+/// Where each field of the header lives. The head and tail get a cache
+/// line of their own, so the two sides don't contend for one.
+const CAPACITY_OFFSET: usize = 8;
+const HEAD_OFFSET: usize = 128;
+const TAIL_OFFSET: usize = 256;
+const DATA_OFFSET: usize = 384;
+/// The size of a record's length prefix.
+const LEN_SIZE: u64 = 4;
+
+/// A view of a ring buffer mapped at `base`.
+struct Ring {
+    base: *mut u8,
+    capacity: u64,
+}
+
+impl Ring {
+    unsafe fn init(base: *mut u8, capacity: u64) {
+        ptr::write(base.add(CAPACITY_OFFSET) as *mut u64, capacity);
+        ptr::write(base as *mut u64, MAGIC);
+    }
+
+    unsafe fn open(base: *mut u8) -> Result<Ring> {
+        let capacity = ptr::read(base.add(CAPACITY_OFFSET) as *const u64);
+        if ptr::read(base as *const u64) != MAGIC || !capacity.is_power_of_two() {
+            return Err(Error::new(ErrorKind::InvalidData, "not a ring buffer"));
+        }
+        Ok(Ring {
+            base: base,
+            capacity: capacity,
+        })
+    }
+
+    fn head(&self) -> &AtomicU64 {
+        unsafe { &*(self.base.add(HEAD_OFFSET) as *const AtomicU64) }
+    }
+
+    fn tail(&self) -> &AtomicU64 {
+        unsafe { &*(self.base.add(TAIL_OFFSET) as *const AtomicU64) }
+    }
+
+    /// Copy `data` in at stream position `pos`, wrapping around the end.
+    unsafe fn write_at(&self, pos: u64, data: &[u8]) {
+        let data_base = self.base.add(DATA_OFFSET);
+        let start = (pos & (self.capacity - 1)) as usize;
+        let first = data.len().min(self.capacity as usize - start);
+        ptr::copy_nonoverlapping(data.as_ptr(), data_base.add(start), first);
+        ptr::copy_nonoverlapping(data.as_ptr().add(first), data_base, data.len() - first);
+    }
+
+    /// Copy out `buf.len()` bytes from stream position `pos`.
+    unsafe fn read_at(&self, pos: u64, buf: &mut [u8]) {
+        let data_base = self.base.add(DATA_OFFSET);
+        let start = (pos & (self.capacity - 1)) as usize;
+        let first = buf.len().min(self.capacity as usize - start);
+        ptr::copy_nonoverlapping(data_base.add(start), buf.as_mut_ptr(), first);
+        let rest = buf.len() - first;
+        ptr::copy_nonoverlapping(data_base, buf.as_mut_ptr().add(first), rest);
+    }
+
+    fn push(&self, record: &[u8]) -> bool {
+        let needed = LEN_SIZE + record.len() as u64;
+        let head = self.head().load(Ordering::Relaxed);
+        let tail = self.tail().load(Ordering::Acquire);
+        if needed > self.capacity - head.wrapping_sub(tail) {
+            return false;
+        }
+        unsafe {
+            self.write_at(head, &(record.len() as u32).to_le_bytes());
+            self.write_at(head + LEN_SIZE, record);
+        }
+        self.head().store(head + needed, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Result<Option<Vec<u8>>> {
+        let tail = self.tail().load(Ordering::Relaxed);
+        let head = self.head().load(Ordering::Acquire);
+        if head == tail {
+            return Ok(None);
+        }
+        // The child writes `head` and the lengths, so check both against
+        // the buffer before trusting them with a copy or an allocation.
+        let used = head.wrapping_sub(tail);
+        if used > self.capacity || used < LEN_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "corrupt ring buffer head"));
+        }
+        let mut len = [0; LEN_SIZE as usize];
+        unsafe { self.read_at(tail, &mut len) };
+        let len = u32::from_le_bytes(len) as u64;
+        if len + LEN_SIZE > used {
+            return Err(Error::new(ErrorKind::InvalidData, "corrupt ring buffer record"));
+        }
+        let mut record = vec![0; len as usize];
+        unsafe { self.read_at(tail + LEN_SIZE, &mut record) };
+        self.tail().store(tail + LEN_SIZE + len, Ordering::Release);
+        Ok(Some(record))
+    }
+}
+
+/// The parent's end of a ring buffer shared with a child, which it pops
+/// records from.
+pub struct SharedRingBuffer {
+    memory: SharedMemory,
+    ring: Ring,
+}
+
+// Only `&mut self` methods touch the ring.
+unsafe impl Send for SharedRingBuffer {}
+
+impl SharedRingBuffer {
+    /// Create a ring buffer of `capacity` bytes, which must be a power of
+    /// two, shared with `task`.
+    pub fn new(task: &TaskPort, capacity: usize) -> Result<SharedRingBuffer> {
+        if !capacity.is_power_of_two() || (capacity as u64) <= LEN_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "ring buffer capacity must be a power of two over 4"));
+        }
+        let memory = SharedMemory::new(task, DATA_OFFSET + capacity, VmTag::DEFAULT)?;
+        let ring = unsafe {
+            Ring::init(memory.as_ptr(), capacity as u64);
+            Ring::open(memory.as_ptr())?
+        };
+        Ok(SharedRingBuffer {
+            memory: memory,
+            ring: ring,
+        })
+    }
+
+    /// The address of the buffer in the child, for
+    /// `RingBufferProducer::from_address`.
+    pub fn remote_address(&self) -> u64 {
+        self.memory.remote_address()
+    }
+
+    /// The most data the buffer holds at once, including the 4-byte length
+    /// of each record.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity as usize
+    }
+
+    /// Pop the oldest record, if there is one.
+    ///
+    /// Fails with `InvalidData` if the child has written a length that
+    /// can't be right, after which the buffer is unusable.
+    pub fn pop(&mut self) -> Result<Option<Vec<u8>>> {
+        self.ring.pop()
+    }
+}
+
+/// The child's end of a ring buffer, which it pushes records to.
+pub struct RingBufferProducer {
+    ring: Ring,
+}
+
+unsafe impl Send for RingBufferProducer {}
+
+impl RingBufferProducer {
+    /// Open the buffer the parent mapped at `address`.
+    ///
+    /// # Safety
+    ///
+    /// `address` must be the `remote_address` of a `SharedRingBuffer` that
+    /// is still alive, and nothing else may be pushing to it.
+    pub unsafe fn from_address(address: u64) -> Result<RingBufferProducer> {
+        Ok(RingBufferProducer { ring: Ring::open(address as *mut u8)? })
+    }
+
+    /// Push a record, returning `false` without writing anything if there
+    /// isn't room for it.
+    pub fn push(&mut self, record: &[u8]) -> bool {
+        self.ring.push(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_wrap_around() {
+        let mut memory = vec![0u64; (DATA_OFFSET + 64) / 8];
+        let base = memory.as_mut_ptr() as *mut u8;
+        let ring = unsafe {
+            Ring::init(base, 64);
+            Ring::open(base).unwrap()
+        };
+        for i in 0..20u8 {
+            let record = [i; 13];
+            assert!(ring.push(&record));
+            assert!(ring.push(&record));
+            assert!(ring.push(&record));
+            assert!(!ring.push(&record));
+            for _ in 0..3 {
+                assert_eq!(ring.pop().unwrap().unwrap(), record);
+            }
+            assert_eq!(ring.pop().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_head() {
+        let mut memory = vec![0u64; (DATA_OFFSET + 64) / 8];
+        let base = memory.as_mut_ptr() as *mut u8;
+        let ring = unsafe {
+            Ring::init(base, 64);
+            Ring::open(base).unwrap()
+        };
+        assert!(ring.push(&[1; 8]));
+        // A head further ahead than the buffer holds, with a length to match.
+        ring.head().store(1 << 40, Ordering::Release);
+        unsafe { ring.write_at(0, &u32::max_value().to_le_bytes()) };
+        assert_eq!(ring.pop().unwrap_err().kind(), ErrorKind::InvalidData);
+        ring.head().store(2, Ordering::Release);
+        assert_eq!(ring.pop().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
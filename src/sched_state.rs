@@ -0,0 +1,292 @@
+//! Putting a child's scheduling back the way it was.
+//!
+//! Samplers and profilers suspend a child's threads, or boost them so that
+//! they reach a safe point sooner, and are then meant to undo it. Undoing
+//! it by hand is fragile: a thread someone else had already suspended must
+//! stay suspended, and a thread that was real-time before the boost must
+//! get its exact constraints back. `TaskPort::sched_snapshot` records, for
+//! the task and each of its threads, the suspend count, the requested QoS
+//! and the scheduling policy, and `TaskPort::restore_sched` changes back
+//! whatever differs from the record.
+//!
+//! Threads are matched by their unique ID. Threads that have exited since
+//! the snapshot are skipped, and threads created since are left alone.
+//! QoS overrides the kernel applies on its own, such as for priority
+//! inversion, come and go by themselves, so only the QoS a thread asked
+//! for is recorded.
+
+use std::io::Result;
+
+use mach::kern_return::KERN_SUCCESS;
+use mach::message::mach_msg_type_number_t;
+use mach::vm_types::integer_t;
+
+use placement::{THREAD_QOS_POLICY, THREAD_QOS_POLICY_COUNT, thread_policy_get, thread_policy_set,
+                thread_qos_policy};
+use {QosClass, TaskPort, ThreadPort};
+
+/// `THREAD_EXTENDED_POLICY`, `THREAD_TIME_CONSTRAINT_POLICY` and
+/// `THREAD_PRECEDENCE_POLICY` from `<mach/thread_policy.h>`, with their
+/// counts.
+const THREAD_EXTENDED_POLICY: u32 = 1;
+const THREAD_EXTENDED_POLICY_COUNT: mach_msg_type_number_t = 1;
+const THREAD_TIME_CONSTRAINT_POLICY: u32 = 2;
+const THREAD_TIME_CONSTRAINT_POLICY_COUNT: mach_msg_type_number_t = 4;
+const THREAD_PRECEDENCE_POLICY: u32 = 3;
+const THREAD_PRECEDENCE_POLICY_COUNT: mach_msg_type_number_t = 1;
+
+/// `struct thread_time_constraint_policy`
+#[repr(C)]
+#[derive(Default)]
+struct thread_time_constraint_policy {
+    period: u32,
+    computation: u32,
+    constraint: u32,
+    preemptible: u32,
+}
+
+/// How the scheduler treats a thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum SchedPolicy {
+    /// The usual policy, whose priority decays with CPU use.
+    Timeshare,
+    /// A fixed priority that doesn't decay.
+    Fixed,
+    /// Real-time, with the given constraints in Mach absolute time units.
+    RealTime {
+        period: u32,
+        computation: u32,
+        constraint: u32,
+        preemptible: bool,
+    },
+}
+
+/// One thread's scheduling state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ThreadSchedState {
+    /// The thread's unique ID.
+    pub id: u64,
+    /// How many times the thread itself has been suspended.
+    pub suspend_count: i32,
+    /// The QoS class the thread asked for, if any.
+    pub qos: Option<QosClass>,
+    /// The relative importance within the QoS class, from 0 down to -15.
+    pub qos_importance: i32,
+    /// The importance relative to the task's other threads, from
+    /// `THREAD_PRECEDENCE_POLICY`.
+    pub precedence: i32,
+    /// The scheduling policy.
+    pub policy: SchedPolicy,
+}
+
+/// A task's scheduling state, to restore with `TaskPort::restore_sched`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SchedSnapshot {
+    /// How many times the whole task has been suspended.
+    pub suspend_count: i32,
+    /// Each thread's state, in the order the kernel listed them.
+    pub threads: Vec<ThreadSchedState>,
+}
+
+/// Get the policy of `flavor` for `thread` into `info`, returning whether
+/// the thread has the default instead.
+fn policy_get<T>(thread: &ThreadPort,
+                 flavor: u32,
+                 info: &mut T,
+                 count: mach_msg_type_number_t)
+                 -> Result<bool> {
+    let mut count = count;
+    let mut get_default = 0;
+    unsafe {
+        ktry!(thread_policy_get(thread.as_raw(),
+                                flavor,
+                                info as *mut T as *mut integer_t,
+                                &mut count,
+                                &mut get_default));
+    }
+    Ok(get_default != 0)
+}
+
+/// Set the policy of `flavor` for `thread` to `info`.
+fn policy_set<T>(thread: &ThreadPort,
+                 flavor: u32,
+                 info: &mut T,
+                 count: mach_msg_type_number_t)
+                 -> Result<()> {
+    unsafe {
+        ktry!(thread_policy_set(thread.as_raw(), flavor, info as *mut T as *mut integer_t, count));
+    }
+    Ok(())
+}
+
+/// Suspend or resume by whatever it takes to get from `current` to
+/// `wanted`.
+fn adjust_suspend_count<S, R>(current: i32, wanted: i32, suspend: S, resume: R) -> Result<()>
+    where S: Fn() -> Result<()>,
+          R: Fn() -> Result<()>
+{
+    for _ in wanted..current {
+        resume()?;
+    }
+    for _ in current..wanted {
+        suspend()?;
+    }
+    Ok(())
+}
+
+impl ThreadPort {
+    /// The thread's scheduling state.
+    pub fn sched_state(&self) -> Result<ThreadSchedState> {
+        let mut qos = thread_qos_policy {
+            qos_tier: 0,
+            tier_importance: 0,
+        };
+        policy_get(self, THREAD_QOS_POLICY, &mut qos, THREAD_QOS_POLICY_COUNT)?;
+        let mut precedence: integer_t = 0;
+        policy_get(self,
+                   THREAD_PRECEDENCE_POLICY,
+                   &mut precedence,
+                   THREAD_PRECEDENCE_POLICY_COUNT)?;
+        let mut timeshare: integer_t = 1;
+        policy_get(self,
+                   THREAD_EXTENDED_POLICY,
+                   &mut timeshare,
+                   THREAD_EXTENDED_POLICY_COUNT)?;
+        let mut constraint = thread_time_constraint_policy::default();
+        let not_real_time = policy_get(self,
+                                       THREAD_TIME_CONSTRAINT_POLICY,
+                                       &mut constraint,
+                                       THREAD_TIME_CONSTRAINT_POLICY_COUNT)?;
+        let policy = if !not_real_time {
+            SchedPolicy::RealTime {
+                period: constraint.period,
+                computation: constraint.computation,
+                constraint: constraint.constraint,
+                preemptible: constraint.preemptible != 0,
+            }
+        } else if timeshare != 0 {
+            SchedPolicy::Timeshare
+        } else {
+            SchedPolicy::Fixed
+        };
+        Ok(ThreadSchedState {
+            id: self.id()?,
+            suspend_count: self.suspend_count()?,
+            qos: QosClass::from_tier(qos.qos_tier),
+            qos_importance: qos.tier_importance,
+            precedence: precedence,
+            policy: policy,
+        })
+    }
+
+    /// Change whatever differs between the thread's scheduling state and
+    /// `state`, leaving its suspend count for last.
+    ///
+    /// Some of these the kernel only lets a process change for its own
+    /// threads; see `set_qos_class`.
+    pub fn restore_sched_state(&self, state: &ThreadSchedState) -> Result<()> {
+        let current = self.sched_state()?;
+        if current.policy != state.policy {
+            match state.policy {
+                SchedPolicy::RealTime { period, computation, constraint, preemptible } => {
+                    let mut info = thread_time_constraint_policy {
+                        period: period,
+                        computation: computation,
+                        constraint: constraint,
+                        preemptible: preemptible as u32,
+                    };
+                    policy_set(self,
+                               THREAD_TIME_CONSTRAINT_POLICY,
+                               &mut info,
+                               THREAD_TIME_CONSTRAINT_POLICY_COUNT)?;
+                }
+                SchedPolicy::Timeshare | SchedPolicy::Fixed => {
+                    let mut timeshare = (state.policy == SchedPolicy::Timeshare) as integer_t;
+                    policy_set(self,
+                               THREAD_EXTENDED_POLICY,
+                               &mut timeshare,
+                               THREAD_EXTENDED_POLICY_COUNT)?;
+                }
+            }
+        }
+        if current.precedence != state.precedence {
+            let mut precedence = state.precedence;
+            policy_set(self,
+                       THREAD_PRECEDENCE_POLICY,
+                       &mut precedence,
+                       THREAD_PRECEDENCE_POLICY_COUNT)?;
+        }
+        if (current.qos, current.qos_importance) != (state.qos, state.qos_importance) {
+            let mut qos = thread_qos_policy {
+                // `THREAD_QOS_UNSPECIFIED` clears it.
+                qos_tier: state.qos.map_or(0, QosClass::to_tier),
+                tier_importance: state.qos_importance,
+            };
+            policy_set(self, THREAD_QOS_POLICY, &mut qos, THREAD_QOS_POLICY_COUNT)?;
+        }
+        adjust_suspend_count(current.suspend_count,
+                             state.suspend_count,
+                             || self.suspend(),
+                             || self.resume())
+    }
+}
+
+impl TaskPort {
+    /// Record the scheduling state of the task and each of its threads.
+    ///
+    /// The task's own suspend count is part of it, so take the snapshot
+    /// before suspending the task, not after.
+    pub fn sched_snapshot(&self) -> Result<SchedSnapshot> {
+        let threads = self.threads()?
+            .iter()
+            .map(ThreadPort::sched_state)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(SchedSnapshot {
+            suspend_count: self.basic_info()?.suspend_count,
+            threads: threads,
+        })
+    }
+
+    /// Put the scheduling state of the task, and of each of its threads
+    /// still running, back as `snapshot` recorded it.
+    pub fn restore_sched(&self, snapshot: &SchedSnapshot) -> Result<()> {
+        for thread in self.threads()? {
+            let id = thread.id()?;
+            if let Some(state) = snapshot.threads.iter().find(|state| state.id == id) {
+                thread.restore_sched_state(state)?;
+            }
+        }
+        adjust_suspend_count(self.basic_info()?.suspend_count,
+                             snapshot.suspend_count,
+                             || self.suspend(),
+                             || self.resume())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::mem;
+
+    #[test]
+    fn adjusts_suspend_count_both_ways() {
+        let count = Cell::new(3);
+        let suspend = || {
+            count.set(count.get() + 1);
+            Ok(())
+        };
+        let resume = || {
+            count.set(count.get() - 1);
+            Ok(())
+        };
+        adjust_suspend_count(count.get(), 1, suspend, resume).unwrap();
+        assert_eq!(count.get(), 1);
+        adjust_suspend_count(count.get(), 4, suspend, resume).unwrap();
+        assert_eq!(count.get(), 4);
+        assert_eq!(mem::size_of::<thread_time_constraint_policy>(), 16);
+    }
+}
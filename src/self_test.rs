@@ -0,0 +1,90 @@
+//! Checking at run time that this process can get task ports at all.
+//!
+//! Whether the handshake works depends on the environment more than on the
+//! code: the sandbox may not allow registering a bootstrap service, and
+//! code signing policy may not allow using a task port once it has been
+//! received. `self_test` runs the whole handshake against a helper that is
+//! built in, rather than a program that might not exist: it forks, and the
+//! forked copy of this process checks in exactly as a spawned child would
+//! before `exec`. The parent then reads the helper's memory through the
+//! task port, which is what policy most often forbids.
+
+use libc;
+use std::io::{Error, ErrorKind, Result};
+use std::ptr;
+use std::time::Duration;
+
+use {SpawnOptions, TaskPort, TaskPortReceiver};
+
+/// How long to wait for the helper to check in.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A value that the parent reads back from the helper.
+static MAGIC: u64 = 0x7370_6177_6e2d_7470;
+
+/// Run the handshake end to end against a forked helper, and check that the
+/// task port it delivers can be used.
+///
+/// Applications can call this at start-up to find out whether to rely on
+/// task ports, and fall back to something else if not. The error says
+/// which step failed; `SpawnTaskPortError::from_io` gets it back for the
+/// steps that are specific to the handshake.
+pub fn self_test() -> Result<()> {
+    let (mut receiver, check_in) = TaskPortReceiver::register(&SpawnOptions::new())?;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let (done_read, done_write) = (fds[0], fds[1]);
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        let e = Error::last_os_error();
+        unsafe {
+            libc::close(done_read);
+            libc::close(done_write);
+        }
+        return Err(e);
+    }
+    if pid == 0 {
+        // In the helper, which may only do what a `pre_exec` hook may:
+        // check in, then wait for the parent to hang up.
+        unsafe {
+            libc::close(done_write);
+            if check_in.send_task_port().is_err() {
+                libc::_exit(1);
+            }
+            let mut byte = 0u8;
+            while libc::read(done_read, &mut byte as *mut u8 as *mut libc::c_void, 1) < 0 {}
+            libc::_exit(0);
+        }
+    }
+    unsafe {
+        libc::close(done_read);
+    }
+    receiver.set_pid(pid as u32);
+    let result = receiver.recv_unless_exited(TIMEOUT)
+        .and_then(|task_port| check_task_port(&task_port, pid as u32));
+    unsafe {
+        libc::close(done_write);
+        if result.is_err() {
+            libc::kill(pid, libc::SIGKILL);
+        }
+        libc::waitpid(pid, ptr::null_mut(), 0);
+    }
+    result
+}
+
+/// Check that `task_port` is usable, and belongs to the helper `pid`.
+fn check_task_port(task_port: &TaskPort, pid: u32) -> Result<()> {
+    if task_port.pid()? != pid {
+        return Err(Error::new(ErrorKind::Other, "the task port belongs to another process"));
+    }
+    // The helper is a copy of this process, so `MAGIC` is at the same
+    // address in it.
+    let mut buf = [0; 8];
+    task_port.read_memory(&MAGIC as *const u64 as u64, &mut buf)?;
+    if u64::from_ne_bytes(buf) != MAGIC {
+        return Err(Error::new(ErrorKind::Other, "read the wrong memory from the task"));
+    }
+    Ok(())
+}
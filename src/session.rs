@@ -0,0 +1,130 @@
+//! Spawning a child into another audit session, like `launchctl asuser`.
+//!
+//! A daemon that starts helpers on behalf of a logged-in user wants them
+//! to run in the user's audit session, as the user, so they get the user's
+//! launchd domain and keychain rather than the daemon's. The child does
+//! that itself, between `fork` and `exec`: it joins the session with
+//! `audit_session_join`, then switches to the user's group and user ID.
+//!
+//! launchd resolves bootstrap look-ups in the caller's session, so once
+//! the child has joined the user's session it can no longer see the
+//! service it checks in with. The child therefore checks in first, while
+//! it is still in the parent's session, and only then moves.
+
+use std::io::{Error, Result};
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use libc;
+use mach::mach_port::mach_port_deallocate;
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+use mach::traps::mach_task_self;
+
+use {ChildWithTask, TaskPort, spawn_with_check_in};
+
+/// `struct auditinfo_addr` from `<bsm/audit.h>`.
+#[repr(C)]
+struct auditinfo_addr {
+    ai_auid: u32,
+    ai_mask: [u32; 2],
+    ai_termid: [u32; 6],
+    ai_asid: i32,
+    ai_flags: u64,
+}
+
+extern "C" {
+    fn getaudit_addr(info: *mut auditinfo_addr, len: c_int) -> c_int;
+    fn audit_session_port(asid: i32, port: *mut mach_port_t) -> c_int;
+    fn audit_session_join(port: mach_port_t) -> i32;
+}
+
+/// The audit session, and optionally the user, a child should run as.
+///
+/// Entering another user's session needs root.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionTarget {
+    asid: i32,
+    user: Option<(u32, u32)>,
+}
+
+impl SessionTarget {
+    /// The audit session with ID `asid`, such as one taken from a
+    /// connecting client's audit token.
+    pub fn new(asid: i32) -> SessionTarget {
+        SessionTarget {
+            asid: asid,
+            user: None,
+        }
+    }
+
+    /// The current process' audit session.
+    pub fn current() -> Result<SessionTarget> {
+        let mut info: auditinfo_addr = unsafe { mem::zeroed() };
+        let size = mem::size_of::<auditinfo_addr>() as c_int;
+        if unsafe { getaudit_addr(&mut info, size) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(SessionTarget::new(info.ai_asid))
+    }
+
+    /// Also switch the child to `uid` and `gid`, dropping any
+    /// supplementary groups, once it is in the session.
+    ///
+    /// Use this rather than `CommandExt::uid` and `gid`: those take effect
+    /// before the child can join the session, which needs root.
+    pub fn user(self, uid: u32, gid: u32) -> SessionTarget {
+        SessionTarget { user: Some((uid, gid)), ..self }
+    }
+
+    /// The audit session ID.
+    pub fn asid(&self) -> i32 {
+        self.asid
+    }
+
+    /// Join the session and switch users.
+    ///
+    /// This runs in the child process between `fork` and `exec`, so it
+    /// makes nothing but system calls.
+    unsafe fn enter(&self) -> Result<()> {
+        let mut port = MACH_PORT_NULL;
+        if audit_session_port(self.asid, &mut port) != 0 {
+            return Err(Error::last_os_error());
+        }
+        let joined = audit_session_join(port);
+        mach_port_deallocate(mach_task_self(), port);
+        if joined != self.asid {
+            return Err(Error::last_os_error());
+        }
+        if let Some((uid, gid)) = self.user {
+            if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 ||
+               libc::setuid(uid) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An extension to `std::process::Command` to spawn a process in another
+/// audit session and get back access to its Mach task port.
+pub trait SessionSpawnWithTask {
+    /// Executes the command as a child process in the session `target`,
+    /// returning a `ChildWithTask` that owns both the `Child` and the
+    /// process' Mach task port.
+    fn spawn_with_task_in_session(&mut self, target: &SessionTarget) -> Result<ChildWithTask>;
+}
+
+impl SessionSpawnWithTask for Command {
+    fn spawn_with_task_in_session(&mut self, target: &SessionTarget) -> Result<ChildWithTask> {
+        let target = *target;
+        // `pre_exec` hooks run in the order they were added, and ours has
+        // already been added by the time `spawn` is called, so the child
+        // checks in before it leaves our session.
+        let (child, task_port) = spawn_with_check_in(self, |command| {
+            unsafe { command.pre_exec(move || target.enter()) }.spawn()
+        })?;
+        Ok(ChildWithTask::new(child, unsafe { TaskPort::from_raw(task_port) }))
+    }
+}
@@ -0,0 +1,319 @@
+//! Asking a cooperative child to shut down over Mach, before signalling it.
+//!
+//! `SIGTERM` is the usual way to ask a process to exit, but a signal can't
+//! say how long the process has, and handling one safely is awkward. A
+//! child spawned with `ShutdownChannel::configure` that calls
+//! `listen_for_shutdown` gets a `ShutdownListener` instead, a port of its
+//! own whose send right it hands to the parent's `ShutdownChannel`.
+//! `ShutdownChannel::request_shutdown` sends it a message carrying the
+//! deadline, and escalates if the child hasn't exited by then: first to
+//! `SIGTERM`, then to `task_terminate`.
+//!
+//! Like a `Heartbeat`, the channel registers its own bootstrap service and
+//! passes its name to the child in an environment variable. Only the
+//! child's own listener is accepted, going by the kernel's audit trailer.
+
+use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use libc;
+use mach::bootstrap::bootstrap_look_up;
+use mach::message::{MACH_MSGH_BITS, MACH_MSG_TIMEOUT_NONE, MACH_MSG_TYPE_COPY_SEND,
+                    MACH_RCV_MSG, MACH_RCV_TIMED_OUT, MACH_RCV_TIMEOUT, MACH_SEND_MSG,
+                    MACH_SEND_TIMEOUT, mach_msg, mach_msg_header_t, mach_msg_trailer_t};
+use mach::kern_return::KERN_SUCCESS;
+use mach::port::{mach_port_t, MACH_PORT_NULL, MACH_PORT_RIGHT_RECEIVE};
+use mach::task::{TASK_BOOTSTRAP_PORT, task_get_special_port};
+use mach::traps::mach_task_self;
+
+use registration::create_service;
+use {ChildWithTask, MachPort, ServiceName, SpawnTaskPortError, TrailerType, allocate_server_port,
+     mach_port_mod_refs, receive_check_in, send_check_in};
+
+/// The environment variable holding the `ShutdownChannel`'s service name.
+const SERVICE_NAME_VAR: &'static str = "SPAWN_TASK_PORT_SHUTDOWN_SERVICE";
+
+/// The `msgh_id` of the message carrying a child's listener port.
+const LISTENER_MSG_ID: i32 = 0x5354_534c;
+/// The `msgh_id` of a shutdown request.
+const SHUTDOWN_MSG_ID: i32 = 0x5354_5344;
+
+/// How long the parent waits for a child's listener at a time, before
+/// checking whether the child has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for a shutdown request to be queued, in milliseconds.
+const SEND_TIMEOUT_MS: u32 = 100;
+
+/// How long a child gets to exit after `SIGTERM`, by default.
+const DEFAULT_TERM_GRACE: Duration = Duration::from_secs(1);
+
+#[repr(C)]
+struct ShutdownMessage {
+    header: mach_msg_header_t,
+    /// How long the child has, in milliseconds.
+    deadline_ms: u64,
+}
+
+#[repr(C)]
+struct ReceivedShutdown {
+    message: ShutdownMessage,
+    trailer: mach_msg_trailer_t,
+}
+
+/// How a child that was asked to shut down came to exit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// It exited by the deadline.
+    Exited(ExitStatus),
+    /// It exited after `SIGTERM`.
+    Terminated(ExitStatus),
+    /// It was terminated through its task port, or killed.
+    Killed(ExitStatus),
+}
+
+impl ShutdownOutcome {
+    /// The child's exit status.
+    pub fn status(&self) -> ExitStatus {
+        match *self {
+            ShutdownOutcome::Exited(status) |
+            ShutdownOutcome::Terminated(status) |
+            ShutdownOutcome::Killed(status) => status,
+        }
+    }
+}
+
+/// The parent's end of a shutdown channel to one child.
+///
+/// Dropping it unregisters its service.
+pub struct ShutdownChannel {
+    port: MachPort,
+    name: ServiceName,
+    term_grace: Duration,
+}
+
+impl ShutdownChannel {
+    /// Register a new shutdown service.
+    pub fn new() -> Result<ShutdownChannel> {
+        let name = ServiceName::random()?;
+        let port = create_service(name.as_c_str())?;
+        Ok(ShutdownChannel {
+            port: port,
+            name: name,
+            term_grace: DEFAULT_TERM_GRACE,
+        })
+    }
+
+    /// Pass the service name to the child that `command` spawns, for
+    /// `listen_for_shutdown`.
+    pub fn configure<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        command.env(SERVICE_NAME_VAR, self.name.as_str())
+    }
+
+    /// How long to give the child to exit after `SIGTERM`, before
+    /// terminating it. This is a second by default.
+    pub fn term_grace(&mut self, grace: Duration) -> &mut ShutdownChannel {
+        self.term_grace = grace;
+        self
+    }
+
+    /// Ask `child` to exit within `deadline`, then send it `SIGTERM` if it
+    /// hasn't, and terminate it if that doesn't work either, returning once
+    /// it has exited.
+    ///
+    /// A child that hasn't started listening yet is asked as soon as it
+    /// does, within the deadline. One that never listens just gets
+    /// `SIGTERM` once the deadline passes.
+    pub fn request_shutdown(&self,
+                            child: &mut ChildWithTask,
+                            deadline: Duration)
+                            -> Result<ShutdownOutcome> {
+        let deadline_at = Instant::now() + deadline;
+        let mut asked = false;
+        loop {
+            let remaining = deadline_at.saturating_duration_since(Instant::now());
+            if !asked {
+                if let Some(listener) = self.receive_listener(child.id(),
+                                                              remaining.min(POLL_INTERVAL))? {
+                    // A child that exits as the request is sent is fine.
+                    let _ = send_shutdown(&listener, remaining);
+                    asked = true;
+                }
+            }
+            let wait = if asked { remaining } else { Duration::from_secs(0) };
+            if let Some(status) = child.wait_timeout(wait)? {
+                return Ok(ShutdownOutcome::Exited(status));
+            }
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+        }
+        unsafe {
+            libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        if let Some(status) = child.wait_timeout(self.term_grace)? {
+            return Ok(ShutdownOutcome::Terminated(status));
+        }
+        if child.task_port().terminate().is_err() {
+            child.kill()?;
+        }
+        Ok(ShutdownOutcome::Killed(child.wait()?))
+    }
+
+    /// Wait up to `timeout` for the listener of the process `pid`, ignoring
+    /// any other process' that arrive first.
+    fn receive_listener(&self, pid: u32, timeout: Duration) -> Result<Option<MachPort>> {
+        loop {
+            match receive_check_in(self.port.0, pid, Some(timeout), true, TrailerType::Audit) {
+                Ok((port, _)) => return Ok(Some(MachPort(port))),
+                Err(e) => {
+                    match SpawnTaskPortError::from_io(&e) {
+                        Some(&SpawnTaskPortError::ReceiveTimeout) => return Ok(None),
+                        Some(&SpawnTaskPortError::AuditMismatch { .. }) => {}
+                        _ => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ShutdownChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShutdownChannel")
+            .field("service_name", &self.name.as_str())
+            .field("term_grace", &self.term_grace)
+            .finish()
+    }
+}
+
+impl Drop for ShutdownChannel {
+    fn drop(&mut self) {
+        // Destroying the receive right unregisters the service.
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
+
+/// Send a shutdown request with `deadline` to `listener`.
+fn send_shutdown(listener: &MachPort, deadline: Duration) -> Result<()> {
+    let mut msg = ShutdownMessage {
+        header: mach_msg_header_t {
+            msgh_bits: MACH_MSGH_BITS(MACH_MSG_TYPE_COPY_SEND, 0),
+            msgh_size: mem::size_of::<ShutdownMessage>() as u32,
+            msgh_remote_port: listener.0,
+            msgh_local_port: MACH_PORT_NULL,
+            msgh_voucher_port: MACH_PORT_NULL,
+            msgh_id: SHUTDOWN_MSG_ID,
+        },
+        deadline_ms: deadline.as_millis().min(u64::max_value() as u128) as u64,
+    };
+    unsafe {
+        ktry!(mach_msg(&mut msg.header,
+                       MACH_SEND_MSG | MACH_SEND_TIMEOUT,
+                       msg.header.msgh_size,
+                       0,
+                       MACH_PORT_NULL,
+                       SEND_TIMEOUT_MS,
+                       MACH_PORT_NULL));
+    }
+    Ok(())
+}
+
+/// The child's end of a shutdown channel.
+///
+/// Dropping it stops the parent from asking, which then falls back to
+/// `SIGTERM`.
+pub struct ShutdownListener {
+    port: MachPort,
+}
+
+impl ShutdownListener {
+    /// Wait for the parent to ask this process to shut down, for at most
+    /// `timeout` if given, returning how long the parent gave it, or `None`
+    /// if it hasn't asked yet.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<Option<Duration>> {
+        let (option, timeout_ms) = match timeout {
+            Some(timeout) => {
+                (MACH_RCV_TIMEOUT, timeout.as_millis().min(u32::max_value() as u128) as u32)
+            }
+            None => (0, MACH_MSG_TIMEOUT_NONE),
+        };
+        loop {
+            let mut msg: ReceivedShutdown = unsafe { mem::zeroed() };
+            let kr = unsafe {
+                mach_msg(&mut msg.message.header,
+                         MACH_RCV_MSG | option,
+                         0,
+                         mem::size_of::<ReceivedShutdown>() as u32,
+                         self.port.0,
+                         timeout_ms,
+                         MACH_PORT_NULL)
+            };
+            match kr {
+                KERN_SUCCESS if msg.message.header.msgh_id == SHUTDOWN_MSG_ID => {
+                    return Ok(Some(Duration::from_millis(msg.message.deadline_ms)));
+                }
+                // Only the parent holds a send right, but be strict anyway.
+                KERN_SUCCESS => {}
+                MACH_RCV_TIMED_OUT => return Ok(None),
+                kr => ktry!(kr),
+            }
+        }
+    }
+
+    /// Whether the parent has asked this process to shut down, without
+    /// waiting, returning how long it gave it.
+    pub fn try_wait(&self) -> Result<Option<Duration>> {
+        self.wait(Some(Duration::from_secs(0)))
+    }
+}
+
+impl fmt::Debug for ShutdownListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ShutdownListener").field(&self.port.0).finish()
+    }
+}
+
+impl Drop for ShutdownListener {
+    fn drop(&mut self) {
+        unsafe {
+            mach_port_mod_refs(mach_task_self(), self.port.0, MACH_PORT_RIGHT_RECEIVE, -1);
+        }
+    }
+}
+
+/// Start listening for shutdown requests from the parent's
+/// `ShutdownChannel`, if this process was spawned with
+/// `ShutdownChannel::configure`, returning `None` if it wasn't.
+pub fn listen_for_shutdown() -> Result<Option<ShutdownListener>> {
+    let name = match env::var(SERVICE_NAME_VAR) {
+        Ok(name) => name,
+        Err(_) => return Ok(None),
+    };
+    // Don't let our own children listen in our place.
+    env::remove_var(SERVICE_NAME_VAR);
+    let name = CString::new(name)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid shutdown environment"))?;
+    let port = allocate_server_port()?;
+    unsafe {
+        let mut bootstrap_port: mach_port_t = MACH_PORT_NULL;
+        ktry!(task_get_special_port(mach_task_self(), TASK_BOOTSTRAP_PORT, &mut bootstrap_port));
+        let bootstrap_port = MachPort(bootstrap_port);
+        let mut parent: mach_port_t = MACH_PORT_NULL;
+        ktry!(bootstrap_look_up(bootstrap_port.0, name.as_ptr(), &mut parent));
+        let parent = MachPort(parent);
+        ktry!(send_check_in(parent.0,
+                            port.0,
+                            MACH_MSG_TYPE_COPY_SEND,
+                            LISTENER_MSG_ID,
+                            libc::getpid()));
+    }
+    Ok(Some(ShutdownListener { port: port }))
+}
@@ -0,0 +1,315 @@
+//! Capturing a child's state to inspect after it has gone.
+//!
+//! A `ChildSnapshot` holds what triage usually looks at first: every
+//! thread's registers, the loaded images, and whichever memory regions the
+//! caller asks for. It can be written to a file and read back later, even
+//! on another machine, and answers the same questions through accessors
+//! named like `TaskPort`'s and `ThreadPort`'s, so tooling can work on a
+//! live child or a snapshot alike.
+//!
+//! The format is the crate's own: a magic number, then little-endian
+//! lengths and values. Registers are kept as the raw words of the
+//! capturing machine's thread state, so check `arch` before interpreting
+//! them.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use {Module, TaskPort};
+
+/// The first bytes of a serialized snapshot, ending in the format version.
+const MAGIC: &'static [u8; 8] = b"STPSNAP\x01";
+
+/// The architecture this crate was built for, which is that of the tasks
+/// it can capture.
+#[cfg(target_arch = "aarch64")]
+const ARCH: &'static str = "aarch64";
+#[cfg(target_arch = "x86_64")]
+const ARCH: &'static str = "x86_64";
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+const ARCH: &'static str = "unknown";
+
+/// A thread as it was when its task was captured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ThreadSnapshot {
+    id: u64,
+    name: Option<String>,
+    program_counter: u64,
+    registers: Vec<u64>,
+}
+
+impl ThreadSnapshot {
+    /// Like `ThreadPort::id`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Like `ThreadPort::name`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Like `ThreadPort::program_counter`.
+    pub fn program_counter(&self) -> u64 {
+        self.program_counter
+    }
+
+    /// Like `ThreadPort::registers`, in the layout for `ChildSnapshot::arch`.
+    pub fn registers(&self) -> &[u64] {
+        &self.registers
+    }
+}
+
+/// A copy of a range of a task's memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+struct MemoryRegion {
+    address: u64,
+    bytes: Vec<u8>,
+}
+
+/// A task's threads, images and selected memory, captured at one moment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ChildSnapshot {
+    arch: String,
+    threads: Vec<ThreadSnapshot>,
+    modules: Vec<Module>,
+    regions: Vec<MemoryRegion>,
+}
+
+impl ChildSnapshot {
+    /// Capture `task`'s threads and images, along with a copy of each of
+    /// `regions`, given as an address and a length.
+    ///
+    /// The task is suspended while it is captured, so that the threads and
+    /// memory agree with each other, and resumed afterwards.
+    pub fn capture(task: &TaskPort, regions: &[(u64, usize)]) -> Result<ChildSnapshot> {
+        task.suspend()?;
+        let snapshot = ChildSnapshot::capture_suspended(task, regions);
+        task.resume()?;
+        snapshot
+    }
+
+    fn capture_suspended(task: &TaskPort, regions: &[(u64, usize)]) -> Result<ChildSnapshot> {
+        let mut threads = Vec::new();
+        for thread in task.threads()? {
+            threads.push(ThreadSnapshot {
+                id: thread.id()?,
+                name: thread.name()?,
+                program_counter: thread.program_counter()?,
+                registers: thread.registers()?,
+            });
+        }
+        let mut copies = Vec::with_capacity(regions.len());
+        for &(address, len) in regions {
+            let mut bytes = vec![0; len];
+            task.read_memory(address, &mut bytes)?;
+            copies.push(MemoryRegion {
+                address: address,
+                bytes: bytes,
+            });
+        }
+        Ok(ChildSnapshot {
+            arch: ARCH.to_owned(),
+            threads: threads,
+            modules: task.modules()?,
+            regions: copies,
+        })
+    }
+
+    /// The architecture of the captured task, like `aarch64` or `x86_64`.
+    pub fn arch(&self) -> &str {
+        &self.arch
+    }
+
+    /// Like `TaskPort::threads`.
+    pub fn threads(&self) -> &[ThreadSnapshot] {
+        &self.threads
+    }
+
+    /// Like `TaskPort::modules`.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// Like `TaskPort::read_memory`, failing unless the whole range lies in
+    /// one of the captured regions.
+    pub fn read_memory(&self, address: u64, buf: &mut [u8]) -> Result<()> {
+        for region in &self.regions {
+            let offset = match address.checked_sub(region.address) {
+                Some(offset) if offset <= region.bytes.len() as u64 => offset as usize,
+                _ => continue,
+            };
+            if let Some(bytes) = region.bytes[offset..].get(..buf.len()) {
+                buf.copy_from_slice(bytes);
+                return Ok(());
+            }
+        }
+        Err(Error::new(ErrorKind::NotFound, "memory was not captured"))
+    }
+
+    /// Serialize the snapshot to `w`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_all(MAGIC)?;
+        write_bytes(&mut w, self.arch.as_bytes())?;
+        write_u32(&mut w, self.threads.len())?;
+        for thread in &self.threads {
+            w.write_all(&thread.id.to_le_bytes())?;
+            match thread.name {
+                Some(ref name) => {
+                    w.write_all(&[1])?;
+                    write_bytes(&mut w, name.as_bytes())?;
+                }
+                None => w.write_all(&[0])?,
+            }
+            w.write_all(&thread.program_counter.to_le_bytes())?;
+            write_u32(&mut w, thread.registers.len())?;
+            for register in &thread.registers {
+                w.write_all(&register.to_le_bytes())?;
+            }
+        }
+        write_u32(&mut w, self.modules.len())?;
+        for module in &self.modules {
+            w.write_all(&module.load_address.to_le_bytes())?;
+            write_bytes(&mut w, module.path.as_bytes())?;
+        }
+        write_u32(&mut w, self.regions.len())?;
+        for region in &self.regions {
+            w.write_all(&region.address.to_le_bytes())?;
+            write_bytes(&mut w, &region.bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Read back a snapshot written by `write_to`.
+    pub fn read_from<R: Read>(mut r: R) -> Result<ChildSnapshot> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a child snapshot"));
+        }
+        let arch = read_string(&mut r)?;
+        let mut threads = Vec::new();
+        for _ in 0..read_u32(&mut r)? {
+            let id = read_u64(&mut r)?;
+            let mut has_name = [0];
+            r.read_exact(&mut has_name)?;
+            let name = if has_name[0] != 0 {
+                Some(read_string(&mut r)?)
+            } else {
+                None
+            };
+            let program_counter = read_u64(&mut r)?;
+            let mut registers = Vec::new();
+            for _ in 0..read_u32(&mut r)? {
+                registers.push(read_u64(&mut r)?);
+            }
+            threads.push(ThreadSnapshot {
+                id: id,
+                name: name,
+                program_counter: program_counter,
+                registers: registers,
+            });
+        }
+        let mut modules = Vec::new();
+        for _ in 0..read_u32(&mut r)? {
+            let load_address = read_u64(&mut r)?;
+            modules.push(Module {
+                load_address: load_address,
+                path: read_string(&mut r)?,
+            });
+        }
+        let mut regions = Vec::new();
+        for _ in 0..read_u32(&mut r)? {
+            let address = read_u64(&mut r)?;
+            regions.push(MemoryRegion {
+                address: address,
+                bytes: read_bytes(&mut r)?,
+            });
+        }
+        Ok(ChildSnapshot {
+            arch: arch,
+            threads: threads,
+            modules: modules,
+            regions: regions,
+        })
+    }
+}
+
+fn write_u32<W: Write>(w: &mut W, n: usize) -> Result<()> {
+    if n > u32::max_value() as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "snapshot field is too large"));
+    }
+    w.write_all(&(n as u32).to_le_bytes())
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> Result<()> {
+    write_u32(w, bytes.len())?;
+    w.write_all(bytes)
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read a length-prefixed byte string, without trusting the length enough
+/// to allocate it all up front.
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let len = read_u32(r)? as u64;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated child snapshot"));
+    }
+    Ok(bytes)
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String> {
+    String::from_utf8(read_bytes(r)?)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid string in child snapshot"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_reads_captured_memory() {
+        let snapshot = ChildSnapshot {
+            arch: ARCH.to_owned(),
+            threads: vec![ThreadSnapshot {
+                              id: 7,
+                              name: Some("main".to_owned()),
+                              program_counter: 0x1000,
+                              registers: vec![1, 2, 3],
+                          }],
+            modules: vec![Module {
+                              load_address: 0x1_0000_0000,
+                              path: "/bin/test".to_owned(),
+                          }],
+            regions: vec![MemoryRegion {
+                              address: 0x2000,
+                              bytes: vec![1, 2, 3, 4],
+                          }],
+        };
+        let mut blob = Vec::new();
+        snapshot.write_to(&mut blob).unwrap();
+        let loaded = ChildSnapshot::read_from(&blob[..]).unwrap();
+        assert_eq!(loaded, snapshot);
+
+        let mut buf = [0; 2];
+        loaded.read_memory(0x2002, &mut buf).unwrap();
+        assert_eq!(buf, [3, 4]);
+        assert!(loaded.read_memory(0x2003, &mut buf).is_err());
+        assert!(ChildSnapshot::read_from(&blob[..blob.len() - 1]).is_err());
+    }
+}
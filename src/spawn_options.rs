@@ -0,0 +1,282 @@
+//! Per-spawn configuration of the handshake.
+//!
+//! `spawn_with_task_port` always waits forever, under a random service
+//! name that it unregisters once the handshake is over, for a check-in
+//! that carries a copy of the child's task port. `SpawnOptions` lets a single spawn change any of
+//! that, through `CommandSpawnWithTask::spawn_get_task_port_with`.
+//!
+//! On macOS 11 and later, `task_flavor` switches the handshake to identity
+//! tokens: the child sends a token that grants nothing by itself, and the
+//! parent converts it to the least privileged task port it can use. A task
+//! name port needs no token: the child sends it directly, on any release.
+
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use mach::message::{MACH_MSG_TIMEOUT_NONE, MACH_MSG_TYPE_COPY_SEND, MACH_MSG_TYPE_MOVE_SEND,
+                    mach_msg_timeout_t, mach_msg_type_name_t};
+use mach::port::mach_port_t;
+
+use bootstrap_swap;
+use identity;
+use registration;
+use {ExceptionMask, MachPort, RegistrationMethod, ServiceName, TaskFlavor, TrailerType};
+
+/// How the child puts its task port in the check-in message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PortDisposition {
+    /// Send a copy of the child's send right, which it keeps.
+    CopySend,
+    /// Hand over the child's own send right. The child can't use
+    /// `mach_task_self` again until it has exec'd, so only use this when no
+    /// other `pre_exec` hook needs it.
+    MoveSend,
+}
+
+impl PortDisposition {
+    pub(crate) fn to_raw(self) -> mach_msg_type_name_t {
+        match self {
+            PortDisposition::CopySend => MACH_MSG_TYPE_COPY_SEND,
+            PortDisposition::MoveSend => MACH_MSG_TYPE_MOVE_SEND,
+        }
+    }
+}
+
+/// What the child does when it can't send its check-in in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SendTimeoutAction {
+    /// Fail before exec, so that `spawn` fails with `ErrorKind::TimedOut`.
+    Abort,
+    /// Exec anyway, without the parent getting the task port.
+    Proceed,
+}
+
+/// Options for a single spawn's handshake.
+///
+/// The defaults are what `spawn_with_task_port` does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpawnOptions {
+    pub(crate) receive_timeout: Option<Duration>,
+    pub(crate) verify_audit: bool,
+    service_name: Option<String>,
+    pub(crate) unregister: bool,
+    pub(crate) disposition: PortDisposition,
+    send_timeout: Option<Duration>,
+    pub(crate) on_send_timeout: SendTimeoutAction,
+    pub(crate) clear_exception_ports: ExceptionMask,
+    pub(crate) trailer: TrailerType,
+    pub(crate) task_flavor: Option<TaskFlavor>,
+    registration: Option<RegistrationMethod>,
+    pub(crate) bootstrap_port: Option<mach_port_t>,
+}
+
+impl Default for SpawnOptions {
+    fn default() -> SpawnOptions {
+        SpawnOptions {
+            receive_timeout: None,
+            verify_audit: true,
+            service_name: None,
+            unregister: true,
+            disposition: PortDisposition::CopySend,
+            send_timeout: None,
+            on_send_timeout: SendTimeoutAction::Abort,
+            clear_exception_ports: ExceptionMask::from_bits(0),
+            trailer: TrailerType::Audit,
+            task_flavor: None,
+            registration: None,
+            bootstrap_port: None,
+        }
+    }
+}
+
+impl SpawnOptions {
+    pub fn new() -> SpawnOptions {
+        SpawnOptions::default()
+    }
+
+    /// Give up waiting for the check-in after `timeout`, killing the
+    /// child and failing with `SpawnTaskPortError::ReceiveTimeout`.
+    pub fn receive_timeout(&mut self, timeout: Duration) -> &mut SpawnOptions {
+        self.receive_timeout = Some(timeout);
+        self
+    }
+
+    /// Whether to check that the check-in came from the spawned process,
+    /// by the audit trailer where the kernel provides one. This is on by
+    /// default; a failed check kills the child and fails with
+    /// `SpawnTaskPortError::AuditMismatch`.
+    pub fn verify_audit(&mut self, verify: bool) -> &mut SpawnOptions {
+        self.verify_audit = verify;
+        self
+    }
+
+    /// Which trailer to receive the check-in with, for
+    /// `TaskPortReceiver::recv_with_trailer` to return. Trailers without
+    /// the audit token leave `verify_audit` to trust the pid the child put
+    /// in its check-in.
+    pub fn trailer(&mut self, trailer: TrailerType) -> &mut SpawnOptions {
+        self.trailer = trailer;
+        self
+    }
+
+    /// Register the parent's port as `name` instead of a random name.
+    ///
+    /// Only one spawn at a time can use a name, and a name kept registered
+    /// with `unregister(false)` can't be used again. Names longer than 127
+    /// bytes fail the spawn.
+    ///
+    /// Sandboxed children can only look up the names their entitlements
+    /// list, so this is how to spawn them. To spawn many children under
+    /// the same name, possibly at once, use
+    /// `MachPortBroker::with_service_name` instead.
+    pub fn service_name(&mut self, name: &str) -> &mut SpawnOptions {
+        self.service_name = Some(name.to_owned());
+        self
+    }
+
+    /// Whether to unregister the service name as soon as the check-in has
+    /// arrived, or the handshake has failed, by destroying the port it
+    /// names. This is on by default; otherwise the name and its port stay
+    /// until the parent exits.
+    ///
+    /// A name registered with `RegistrationMethod::CheckIn` can outlive its
+    /// port regardless; see `set_registration_method`.
+    pub fn unregister(&mut self, unregister: bool) -> &mut SpawnOptions {
+        self.unregister = unregister;
+        self
+    }
+
+    /// How the child sends its task port.
+    pub fn disposition(&mut self, disposition: PortDisposition) -> &mut SpawnOptions {
+        self.disposition = disposition;
+        self
+    }
+
+    /// Have the child give up sending its check-in if the parent's queue
+    /// stays full for `timeout`, and then do what `action` says.
+    ///
+    /// Without this, a child whose parent stops receiving waits before
+    /// `exec` for as long as the parent is alive. Use `Proceed` only along
+    /// with a `receive_timeout`, or the parent waits forever instead.
+    pub fn send_timeout(&mut self,
+                        timeout: Duration,
+                        action: SendTimeoutAction)
+                        -> &mut SpawnOptions {
+        self.send_timeout = Some(timeout);
+        self.on_send_timeout = action;
+        self
+    }
+
+    /// Have the child remove the exception handlers for `mask` that it
+    /// inherited, before it checks in and execs.
+    ///
+    /// Task exception ports survive both `fork` and `exec`, so a parent
+    /// running under Crashpad or a debugger otherwise passes its handlers
+    /// on. `ExceptionMask::ALL` gives the child a clean slate, after which
+    /// its crashes go to the host's handler, ReportCrash, as usual. An
+    /// `ExceptionServer` attached after the spawn is unaffected.
+    pub fn clear_exception_ports(&mut self, mask: ExceptionMask) -> &mut SpawnOptions {
+        self.clear_exception_ports = mask;
+        self
+    }
+
+    /// Have the child check in with an identity token instead of its task
+    /// control port, and convert the token to a task port of `flavor` once
+    /// it arrives, so that a control right only travels between processes
+    /// if `TaskFlavor::Control` is asked for. `disposition` doesn't apply
+    /// to tokens.
+    ///
+    /// The spawn fails if the running OS doesn't support identity tokens,
+    /// or the parent isn't allowed a port of `flavor` for the child. For
+    /// `TaskFlavor::Name`, the child sends its name port instead of a
+    /// token, which works everywhere.
+    pub fn task_flavor(&mut self, flavor: TaskFlavor) -> &mut SpawnOptions {
+        self.task_flavor = Some(flavor);
+        self
+    }
+
+    /// Register the service name with `method`, rather than as
+    /// `set_registration_method` chose for the whole process.
+    pub fn registration_method(&mut self, method: RegistrationMethod) -> &mut SpawnOptions {
+        self.registration = Some(method);
+        self
+    }
+
+    /// Register the service name with `port` instead of the parent's own
+    /// bootstrap port, and spawn the child with `port` as its bootstrap
+    /// port, so that it finds the name there. This is meant for a
+    /// `BootstrapSubset`, which keeps the name out of the per-user
+    /// namespace.
+    ///
+    /// The child keeps `port` as its bootstrap port after the handshake.
+    /// The parent's bootstrap port is swapped while it spawns, as with
+    /// `BootstrapSwapSpawnWithTask`, so don't spawn from other threads
+    /// meanwhile. `port` must stay valid for as long as the options are
+    /// used.
+    pub fn bootstrap_port(&mut self, port: mach_port_t) -> &mut SpawnOptions {
+        self.bootstrap_port = Some(port);
+        self
+    }
+
+    /// Fail if the running OS can't do what the options ask for.
+    pub(crate) fn check_supported(&self) -> Result<()> {
+        if self.token_flavor().is_some() && !identity::available() {
+            return Err(identity::unavailable());
+        }
+        Ok(())
+    }
+
+    /// The flavor to convert the child's identity token to, if it should
+    /// send one.
+    pub(crate) fn token_flavor(&self) -> Option<TaskFlavor> {
+        self.task_flavor.filter(|&flavor| flavor != TaskFlavor::Name)
+    }
+
+    /// The send timeout in milliseconds, as `mach_msg` takes it.
+    pub(crate) fn send_timeout_ms(&self) -> mach_msg_timeout_t {
+        match self.send_timeout {
+            // A timeout of zero would mean no timeout at all.
+            Some(timeout) => timeout.as_millis().max(1).min(u32::max_value() as u128) as u32,
+            None => MACH_MSG_TIMEOUT_NONE,
+        }
+    }
+
+    /// How to register the service name.
+    pub(crate) fn registration(&self) -> RegistrationMethod {
+        self.registration.unwrap_or_else(registration::registration_method)
+    }
+
+    /// Create the port to register as `name`, with the bootstrap port and
+    /// method the options ask for.
+    pub(crate) fn create_service(&self, name: &CStr) -> Result<MachPort> {
+        match self.bootstrap_port {
+            Some(port) => registration::create_service_in(port, name, self.registration()),
+            None => registration::create_service_with(name, self.registration()),
+        }
+    }
+
+    /// Run `spawn`, with the bootstrap port the options ask for, if any,
+    /// for the child to inherit.
+    pub(crate) fn spawn_in_bootstrap<T, F>(&self, spawn: F) -> Result<T>
+        where F: FnOnce() -> Result<T>
+    {
+        match self.bootstrap_port {
+            Some(port) => bootstrap_swap::with_bootstrap_port(port, spawn),
+            None => spawn(),
+        }
+    }
+
+    /// The name to register, either the one given or a new random one.
+    pub(crate) fn make_service_name(&self) -> Result<ServiceName> {
+        match self.service_name {
+            Some(ref name) => {
+                ServiceName::new(name).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput,
+                               format!("invalid bootstrap service name {:?}", name))
+                })
+            }
+            None => ServiceName::random(),
+        }
+    }
+}
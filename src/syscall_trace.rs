@@ -0,0 +1,197 @@
+//! Watching a child for Mach traps the kernel doesn't implement.
+//!
+//! `EXC_SYSCALL` and `EXC_MACH_SYSCALL` look like they would make a
+//! syscall tracer, but xnu only raises them for Mach traps it has no
+//! handler for: `EXC_SYSCALL` for a trap number past the end of the trap
+//! table, and `EXC_MACH_SYSCALL` for an unused slot inside it. Ordinary
+//! system calls never reach an exception handler, and an unimplemented BSD
+//! system call becomes `SIGSYS` without raising one either. So this is not
+//! `dtruss`; use DTrace to see every call. What it does catch is code built
+//! for a different OS release calling a trap this kernel lacks, which
+//! otherwise shows up as an unexplained crash.
+
+use std::io::Result;
+
+use exception::{ExceptionKind, ExceptionMask};
+use exception_server::ExceptionServer;
+use {TaskPort, ThreadPort};
+
+/// The names of the Mach traps, from `osfmk/kern/syscall_sw.c`. The table
+/// is indexed by the trap number, which is negated in the register the
+/// trap is made with; unused slots are `None`.
+static MACH_TRAP_NAMES: [Option<&'static str>; 101] = [
+    None, None, None, None, None, None, None, None, None, None,
+    // 10
+    Some("_kernelrpc_mach_vm_allocate_trap"),
+    Some("_kernelrpc_mach_vm_purgable_control_trap"),
+    Some("_kernelrpc_mach_vm_deallocate_trap"),
+    Some("task_dyld_process_info_notify_get_trap"),
+    Some("_kernelrpc_mach_vm_protect_trap"),
+    Some("_kernelrpc_mach_vm_map_trap"),
+    Some("_kernelrpc_mach_port_allocate_trap"),
+    None,
+    Some("_kernelrpc_mach_port_deallocate_trap"),
+    Some("_kernelrpc_mach_port_mod_refs_trap"),
+    // 20
+    Some("_kernelrpc_mach_port_move_member_trap"),
+    Some("_kernelrpc_mach_port_insert_right_trap"),
+    Some("_kernelrpc_mach_port_insert_member_trap"),
+    Some("_kernelrpc_mach_port_extract_member_trap"),
+    Some("_kernelrpc_mach_port_construct_trap"),
+    Some("_kernelrpc_mach_port_destruct_trap"),
+    Some("mach_reply_port"),
+    Some("thread_self_trap"),
+    Some("task_self_trap"),
+    Some("host_self_trap"),
+    // 30
+    None,
+    Some("mach_msg_trap"),
+    Some("mach_msg_overwrite_trap"),
+    Some("semaphore_signal_trap"),
+    Some("semaphore_signal_all_trap"),
+    Some("semaphore_signal_thread_trap"),
+    Some("semaphore_wait_trap"),
+    Some("semaphore_wait_signal_trap"),
+    Some("semaphore_timedwait_trap"),
+    Some("semaphore_timedwait_signal_trap"),
+    // 40
+    Some("_kernelrpc_mach_port_get_attributes_trap"),
+    Some("_kernelrpc_mach_port_guard_trap"),
+    Some("_kernelrpc_mach_port_unguard_trap"),
+    Some("mach_generate_activity_id"),
+    Some("task_name_for_pid"),
+    Some("task_for_pid"),
+    Some("pid_for_task"),
+    Some("mach_msg2_trap"),
+    Some("macx_swapon"),
+    Some("macx_swapoff"),
+    // 50
+    Some("thread_get_special_reply_port"),
+    Some("macx_triggers"),
+    Some("macx_backing_store_suspend"),
+    Some("macx_backing_store_recovery"),
+    None,
+    None,
+    None,
+    None,
+    Some("pfz_exit"),
+    Some("swtch_pri"),
+    // 60
+    Some("swtch"),
+    Some("thread_switch"),
+    Some("clock_sleep_trap"),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    // 70
+    Some("host_create_mach_voucher_trap"),
+    None,
+    Some("mach_voucher_extract_attr_recipe_trap"),
+    None,
+    None,
+    None,
+    Some("_kernelrpc_mach_port_type_trap"),
+    Some("_kernelrpc_mach_port_request_notification_trap"),
+    None,
+    None,
+    // 80
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    Some("exclaves_ctl_trap"),
+    Some("mach_timebase_info_trap"),
+    // 90
+    Some("mach_wait_until_trap"),
+    Some("mk_timer_create_trap"),
+    Some("mk_timer_destroy_trap"),
+    Some("mk_timer_arm_trap"),
+    Some("mk_timer_cancel_trap"),
+    Some("mk_timer_arm_leeway_trap"),
+    Some("debug_control_port_for_pid"),
+    None,
+    None,
+    None,
+    // 100
+    Some("iokit_user_client_trap"),
+];
+
+/// The name of Mach trap `number` on recent releases, if it has one.
+pub fn mach_trap_name(number: u32) -> Option<&'static str> {
+    MACH_TRAP_NAMES.get(number as usize).and_then(|name| *name)
+}
+
+/// A Mach trap the kernel refused.
+#[derive(Debug)]
+pub struct TrapEvent {
+    /// The pid of the process that made the trap.
+    pub pid: u32,
+    /// The thread that made it.
+    pub thread: ThreadPort,
+    /// `Syscall` for a trap number past the end of the kernel's table,
+    /// `MachSyscall` for an unused slot in it.
+    pub kind: ExceptionKind,
+    /// The trap number, as a positive index into the trap table.
+    pub number: u32,
+}
+
+impl TrapEvent {
+    /// The trap's name on the releases that implement it, if it's known.
+    pub fn name(&self) -> Option<&'static str> {
+        mach_trap_name(self.number)
+    }
+}
+
+/// Reports the Mach traps a task makes that the kernel doesn't implement.
+pub struct SyscallTracer {
+    server: ExceptionServer,
+}
+
+impl SyscallTracer {
+    /// Start watching `task`.
+    pub fn attach(task: &TaskPort) -> Result<SyscallTracer> {
+        let mask = ExceptionMask::SYSCALL | ExceptionMask::MACH_SYSCALL;
+        Ok(SyscallTracer { server: ExceptionServer::attach(task, mask)? })
+    }
+
+    /// Block until the task makes a trap the kernel doesn't implement.
+    ///
+    /// The exception is passed on before this returns, so the task sees
+    /// the same failure it would have without the tracer.
+    pub fn next(&self) -> Result<TrapEvent> {
+        let event = self.server.receive()?;
+        let exception = event.exception().clone();
+        let thread = event.thread().try_clone()?;
+        event.pass_on()?;
+        let number = exception.codes.first().map_or(0, |&code| (code as i32).unsigned_abs());
+        Ok(TrapEvent {
+            pid: exception.pid,
+            thread: thread,
+            kind: exception.kind,
+            number: number,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_mach_traps() {
+        assert_eq!(mach_trap_name(26), Some("mach_reply_port"));
+        assert_eq!(mach_trap_name(31), Some("mach_msg_trap"));
+        assert_eq!(mach_trap_name(89), Some("mach_timebase_info_trap"));
+        assert_eq!(mach_trap_name(100), Some("iokit_user_client_trap"));
+        assert_eq!(mach_trap_name(0), None);
+        assert_eq!(mach_trap_name(500), None);
+    }
+}
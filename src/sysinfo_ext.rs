@@ -0,0 +1,67 @@
+//! Augmenting `sysinfo`'s process data with numbers from the task port,
+//! enabled by the `sysinfo` feature.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use sysinfo::Process;
+
+use TaskPort;
+
+/// Information about a process we spawned, combining `sysinfo`'s data with
+/// more accurate numbers read through its task port.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ExtendedProcessInfo {
+    /// The process ID.
+    pub pid: u32,
+    /// The process name, from `sysinfo`.
+    pub name: String,
+    /// CPU usage in percent, from `sysinfo`.
+    pub cpu_usage: f32,
+    /// Resident memory size in bytes, from `sysinfo`.
+    pub memory: u64,
+    /// Virtual memory size in bytes, from `sysinfo`.
+    pub virtual_memory: u64,
+    /// The process' physical footprint in bytes, from the task port. This
+    /// is what Activity Monitor reports as "Memory", and it counts
+    /// compressed memory that the resident size misses.
+    pub phys_footprint: u64,
+    /// Total user time of all of the process' threads, live and
+    /// terminated, from the task port.
+    pub user_time: Duration,
+    /// Total system time of all of the process' threads, live and
+    /// terminated, from the task port.
+    pub system_time: Duration,
+}
+
+/// An extension to `sysinfo::Process` for processes whose task port we
+/// hold.
+pub trait ProcessTaskExt {
+    /// Combine this process' `sysinfo` data with data read through
+    /// `task_port`, which must be the task port for the same process.
+    fn extended_info(&self, task_port: &TaskPort) -> Result<ExtendedProcessInfo>;
+}
+
+impl ProcessTaskExt for Process {
+    fn extended_info(&self, task_port: &TaskPort) -> Result<ExtendedProcessInfo> {
+        let pid = self.pid().as_u32();
+        if task_port.pid()? != pid {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "task port belongs to a different process"));
+        }
+        let basic = task_port.basic_info()?;
+        let live = task_port.thread_times()?;
+        let vm = task_port.vm_info()?;
+        Ok(ExtendedProcessInfo {
+            pid: pid,
+            name: self.name().to_string(),
+            cpu_usage: self.cpu_usage(),
+            memory: self.memory(),
+            virtual_memory: self.virtual_memory(),
+            phys_footprint: vm.phys_footprint,
+            user_time: basic.user_time + live.user_time,
+            system_time: basic.system_time + live.system_time,
+        })
+    }
+}
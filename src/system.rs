@@ -0,0 +1,151 @@
+//! The machine's security configuration.
+//!
+//! Whether another process' task port can be had at all depends as much on
+//! how the machine is configured as on this crate: System Integrity
+//! Protection (SIP) keeps platform binaries out of reach unless it has been
+//! relaxed, and on macOS 13 and later, debugging needs Developer Mode.
+//! `doctor` uses these to explain failures, and tools built on this crate
+//! can use them to tell their users that "your machine's configuration
+//! prevents this".
+
+use std::mem;
+use std::ops::BitOr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+/// The things that SIP can be configured to allow, like `csr_config_t`
+/// from `<sys/csr.h>`, which `csrutil` sets from the recovery OS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CsrConfig(u32);
+
+impl CsrConfig {
+    pub const ALLOW_UNTRUSTED_KEXTS: CsrConfig = CsrConfig(1 << 0);
+    pub const ALLOW_UNRESTRICTED_FS: CsrConfig = CsrConfig(1 << 1);
+    pub const ALLOW_TASK_FOR_PID: CsrConfig = CsrConfig(1 << 2);
+    pub const ALLOW_KERNEL_DEBUGGER: CsrConfig = CsrConfig(1 << 3);
+    pub const ALLOW_APPLE_INTERNAL: CsrConfig = CsrConfig(1 << 4);
+    pub const ALLOW_UNRESTRICTED_DTRACE: CsrConfig = CsrConfig(1 << 5);
+    pub const ALLOW_UNRESTRICTED_NVRAM: CsrConfig = CsrConfig(1 << 6);
+    pub const ALLOW_DEVICE_CONFIGURATION: CsrConfig = CsrConfig(1 << 7);
+    pub const ALLOW_ANY_RECOVERY_OS: CsrConfig = CsrConfig(1 << 8);
+    pub const ALLOW_UNAPPROVED_KEXTS: CsrConfig = CsrConfig(1 << 9);
+    pub const ALLOW_EXECUTABLE_POLICY_OVERRIDE: CsrConfig = CsrConfig(1 << 10);
+    pub const ALLOW_UNAUTHENTICATED_ROOT: CsrConfig = CsrConfig(1 << 11);
+
+    /// The raw `csr_config_t`.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Wrap a raw `csr_config_t`.
+    pub fn from_bits(bits: u32) -> CsrConfig {
+        CsrConfig(bits)
+    }
+
+    /// Whether nothing is allowed, which is how SIP ships.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether everything in `other` is allowed.
+    pub fn contains(self, other: CsrConfig) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for CsrConfig {
+    type Output = CsrConfig;
+
+    fn bitor(self, other: CsrConfig) -> CsrConfig {
+        CsrConfig(self.0 | other.0)
+    }
+}
+
+/// How SIP is configured, as `csrutil status` reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SipStatus {
+    /// What the active configuration allows.
+    pub config: CsrConfig,
+    /// Whether SIP is in effect at all. It counts as disabled once it
+    /// allows changes to protected files.
+    pub enabled: bool,
+    /// Whether SIP stops `task_for_pid` on platform binaries.
+    pub task_for_pid_restricted: bool,
+    /// Whether SIP stops DTrace from tracing protected processes.
+    pub dtrace_restricted: bool,
+}
+
+impl SipStatus {
+    /// Whether SIP is enabled, but with some protections turned off, which
+    /// `csrutil` calls a custom configuration.
+    pub fn is_custom(&self) -> bool {
+        self.enabled && !self.config.is_empty()
+    }
+}
+
+extern "C" {
+    fn csr_check(mask: u32) -> c_int;
+    fn csr_get_active_config(config: *mut u32) -> c_int;
+    fn sysctlbyname(name: *const c_char,
+                    oldp: *mut c_void,
+                    oldlenp: *mut usize,
+                    newp: *mut c_void,
+                    newlen: usize)
+                    -> c_int;
+}
+
+/// Whether the running kernel lets SIP allow everything in `config`.
+///
+/// This can differ from what the active configuration says, for example
+/// on internal builds of macOS.
+pub fn sip_allows(config: CsrConfig) -> bool {
+    unsafe { csr_check(config.bits()) == 0 }
+}
+
+/// The SIP configuration, and which of its protections are in effect.
+pub fn sip_status() -> SipStatus {
+    let mut config = 0;
+    if unsafe { csr_get_active_config(&mut config) } != 0 {
+        config = 0;
+    }
+    SipStatus {
+        config: CsrConfig(config),
+        enabled: !sip_allows(CsrConfig::ALLOW_UNRESTRICTED_FS),
+        task_for_pid_restricted: !sip_allows(CsrConfig::ALLOW_TASK_FOR_PID),
+        dtrace_restricted: !sip_allows(CsrConfig::ALLOW_UNRESTRICTED_DTRACE),
+    }
+}
+
+/// Whether Developer Mode is enabled, or `None` before macOS 13, which
+/// doesn't have it.
+///
+/// Without Developer Mode, only Apple-signed debuggers may use the task
+/// ports of processes that allow it with `get-task-allow`.
+pub fn developer_mode_enabled() -> Option<bool> {
+    let mut status: c_int = 0;
+    let mut len = mem::size_of::<c_int>();
+    let ret = unsafe {
+        sysctlbyname(b"security.mac.amfi.developer_mode_status\0".as_ptr() as *const c_char,
+                     &mut status as *mut c_int as *mut c_void,
+                     &mut len,
+                     ptr::null_mut(),
+                     0)
+    };
+    if ret != 0 { None } else { Some(status != 0) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csr_config_contains() {
+        let config = CsrConfig::ALLOW_TASK_FOR_PID | CsrConfig::ALLOW_UNRESTRICTED_DTRACE;
+        assert!(config.contains(CsrConfig::ALLOW_TASK_FOR_PID));
+        assert!(!config.contains(CsrConfig::ALLOW_TASK_FOR_PID | CsrConfig::ALLOW_UNRESTRICTED_FS));
+        assert!(!config.is_empty());
+        assert!(CsrConfig::from_bits(0).is_empty());
+    }
+}
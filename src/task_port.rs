@@ -0,0 +1,159 @@
+//! An owned send right to a Mach task port.
+
+use std::fmt;
+use std::io::Result;
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::port::{mach_port_t, MACH_PORT_RIGHT_SEND};
+use mach::task::{task_resume, task_suspend};
+use mach::traps::mach_task_self;
+
+use rights::{self, Counters, RightCounts};
+use {MachPort, SpawnTaskPortError, mach_port_mod_refs, pid_for_task};
+
+extern "C" {
+    fn task_terminate(target_task: mach_port_t) -> kern_return_t;
+}
+
+/// A send right to a task's Mach task port, which is deallocated when the
+/// `TaskPort` is dropped.
+///
+/// `TaskPort`s are counted as they are created, cloned, given up and
+/// dropped, both by `rights` and by `task_port_rights`.
+pub struct TaskPort {
+    port: MachPort,
+    /// The counts this shares with the `TaskPort` it was cloned from and
+    /// that one's other clones, or `None` if it is borrowed.
+    rights: Option<Arc<Counters>>,
+}
+
+impl TaskPort {
+    /// Take ownership of a send right to a task port.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own a send right to `port`, and give it up: the
+    /// right is deallocated when the `TaskPort` is dropped.
+    pub unsafe fn from_raw(port: mach_port_t) -> TaskPort {
+        TaskPort::from_port(MachPort(port))
+    }
+
+    /// Treat `port` as a `TaskPort` without taking ownership of it, for
+    /// code that wants a `&TaskPort`. It isn't counted as a `TaskPort`.
+    pub(crate) unsafe fn borrow_raw(port: mach_port_t) -> ManuallyDrop<TaskPort> {
+        ManuallyDrop::new(TaskPort {
+            port: MachPort(port),
+            rights: None,
+        })
+    }
+
+    /// Wrap a port that the crate already owns.
+    pub(crate) fn from_port(port: MachPort) -> TaskPort {
+        TaskPort {
+            port: port,
+            rights: Some(rights::created()),
+        }
+    }
+
+    /// The raw port, which remains owned by this `TaskPort`.
+    pub fn as_raw(&self) -> mach_port_t {
+        self.port.0
+    }
+
+    /// Give up ownership of the send right, returning the raw port.
+    pub fn into_raw(self) -> mach_port_t {
+        // Skip our own `Drop`, along with the `MachPort`'s.
+        let mut this = ManuallyDrop::new(self);
+        if let Some(handle) = this.rights.take() {
+            rights::transferred(&handle);
+        }
+        this.as_raw()
+    }
+
+    /// Get another `TaskPort` for the same task, by adding a reference to
+    /// the send right.
+    pub fn try_clone(&self) -> Result<TaskPort> {
+        unsafe {
+            ktry!(mach_port_mod_refs(mach_task_self(), self.as_raw(), MACH_PORT_RIGHT_SEND, 1));
+        }
+        // A clone of a borrowed `TaskPort` is the first one to own a right,
+        // so it starts counts of its own.
+        let handle = match self.rights {
+            Some(ref handle) => {
+                rights::cloned(handle);
+                handle.clone()
+            }
+            None => rights::created(),
+        };
+        Ok(TaskPort {
+            port: MachPort(self.as_raw()),
+            rights: Some(handle),
+        })
+    }
+
+    /// How many `TaskPort`s this one and the clones made from it, or from
+    /// its clones, have added up to, so that leaked rights can be traced
+    /// back to the handle they came from. `task_port_rights` has the
+    /// totals for the whole process.
+    pub fn rights(&self) -> RightCounts {
+        self.rights.as_ref().map_or_else(RightCounts::default, |handle| handle.load())
+    }
+
+    /// Suspend all of the task's threads. Suspensions are counted, so the
+    /// task only runs again once each has been matched by a `resume`.
+    pub fn suspend(&self) -> Result<()> {
+        unsafe {
+            ktry!(task_suspend(self.as_raw()));
+        }
+        Ok(())
+    }
+
+    /// Undo one `suspend`.
+    pub fn resume(&self) -> Result<()> {
+        unsafe {
+            ktry!(task_resume(self.as_raw()));
+        }
+        Ok(())
+    }
+
+    /// Terminate the task at once, like `SIGKILL` but without going
+    /// through its pid, which may have been reused if the process has
+    /// already exited.
+    pub fn terminate(&self) -> Result<()> {
+        unsafe {
+            ktry!(task_terminate(self.as_raw()));
+        }
+        Ok(())
+    }
+
+    /// The pid of the process this task belongs to.
+    pub fn pid(&self) -> Result<u32> {
+        let mut pid = 0;
+        let kr = unsafe { pid_for_task(self.as_raw(), &mut pid) };
+        if kr != KERN_SUCCESS {
+            return Err(SpawnTaskPortError::KernError {
+                    call: "pid_for_task",
+                    code: kr,
+                }
+                .into());
+        }
+        Ok(pid as u32)
+    }
+}
+
+impl Drop for TaskPort {
+    fn drop(&mut self) {
+        // The `MachPort` deallocates the right itself.
+        if let Some(ref handle) = self.rights {
+            rights::released(handle);
+        }
+    }
+}
+
+impl fmt::Debug for TaskPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TaskPort").field(&self.as_raw()).finish()
+    }
+}
@@ -0,0 +1,243 @@
+//! A command builder that spawns with `posix_spawn` attributes throughout.
+//!
+//! `TaskPortCommand` is `Command` and `PosixSpawnOptions` in one: the usual
+//! arguments, environment and stdio, and the spawn attributes and file
+//! actions on top, without keeping the two in step by hand. Spawning it
+//! does the same handshake as `PosixSpawnWithTask::spawn_with_task_posix`,
+//! so the program starts with the signal mask, session, process group and
+//! descriptors it was given, and the parent still gets its task port.
+
+use std::ffi::OsStr;
+use std::io::Result;
+use std::os::raw::c_int;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use libc::mode_t;
+
+use {Architecture, ChildWithTask, PosixSpawnOptions, PosixSpawnWithTask, ProcessType, TaskFlavor};
+
+/// A builder for a child spawned with `posix_spawn`, whose task port the
+/// parent receives.
+#[derive(Debug)]
+pub struct TaskPortCommand {
+    command: Command,
+    env_clear: bool,
+    stdin: Option<Stdio>,
+    stdout: Option<Stdio>,
+    stderr: Option<Stdio>,
+    options: PosixSpawnOptions,
+}
+
+impl TaskPortCommand {
+    /// A command to run `program`, inheriting the parent's environment,
+    /// working directory and stdio, as `Command::new` would.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> TaskPortCommand {
+        TaskPortCommand {
+            command: Command::new(program),
+            env_clear: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            options: PosixSpawnOptions::new(),
+        }
+    }
+
+    /// Add an argument to pass to the program.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut TaskPortCommand {
+        self.command.arg(arg);
+        self
+    }
+
+    /// Add several arguments to pass to the program.
+    pub fn args<I, S>(&mut self, args: I) -> &mut TaskPortCommand
+        where I: IntoIterator<Item = S>,
+              S: AsRef<OsStr>
+    {
+        self.command.args(args);
+        self
+    }
+
+    /// Set the environment variable `key` to `value` in the child.
+    pub fn env<K, V>(&mut self, key: K, value: V) -> &mut TaskPortCommand
+        where K: AsRef<OsStr>,
+              V: AsRef<OsStr>
+    {
+        self.command.env(key, value);
+        self
+    }
+
+    /// Remove the environment variable `key` from the child's environment.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut TaskPortCommand {
+        self.command.env_remove(key);
+        self
+    }
+
+    /// Start the child with an empty environment, apart from what is set
+    /// afterwards.
+    pub fn env_clear(&mut self) -> &mut TaskPortCommand {
+        self.command.env_clear();
+        self.env_clear = true;
+        self
+    }
+
+    /// Set the child's working directory.
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut TaskPortCommand {
+        self.command.current_dir(dir);
+        self
+    }
+
+    /// Set the child's stdin, for the next spawn only.
+    pub fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut TaskPortCommand {
+        self.stdin = Some(cfg.into());
+        self
+    }
+
+    /// Set the child's stdout, for the next spawn only.
+    pub fn stdout<T: Into<Stdio>>(&mut self, cfg: T) -> &mut TaskPortCommand {
+        self.stdout = Some(cfg.into());
+        self
+    }
+
+    /// Set the child's stderr, for the next spawn only.
+    pub fn stderr<T: Into<Stdio>>(&mut self, cfg: T) -> &mut TaskPortCommand {
+        self.stderr = Some(cfg.into());
+        self
+    }
+
+    /// Open `path` as `fd` in the child. See `PosixSpawnOptions::open`.
+    pub fn open<P: AsRef<Path>>(&mut self,
+                                fd: RawFd,
+                                path: P,
+                                flags: c_int,
+                                mode: mode_t)
+                                -> &mut TaskPortCommand {
+        self.options.open(fd, path, flags, mode);
+        self
+    }
+
+    /// Close `fd` in the child.
+    pub fn close(&mut self, fd: RawFd) -> &mut TaskPortCommand {
+        self.options.close(fd);
+        self
+    }
+
+    /// Close every descriptor from `first` up in the child.
+    pub fn close_from(&mut self, first: RawFd) -> &mut TaskPortCommand {
+        self.options.close_from(first);
+        self
+    }
+
+    /// Duplicate `from` onto `to` in the child.
+    pub fn dup2(&mut self, from: RawFd, to: RawFd) -> &mut TaskPortCommand {
+        self.options.dup2(from, to);
+        self
+    }
+
+    /// Change the child's working directory to `fd`, as the file actions
+    /// run.
+    pub fn fchdir(&mut self, fd: RawFd) -> &mut TaskPortCommand {
+        self.options.fchdir(fd);
+        self
+    }
+
+    /// Start the program with exactly `signals` blocked.
+    pub fn signal_mask(&mut self, signals: &[c_int]) -> &mut TaskPortCommand {
+        self.options.signal_mask(signals);
+        self
+    }
+
+    /// Reset `signal` to its default action in the program.
+    pub fn default_signal(&mut self, signal: c_int) -> &mut TaskPortCommand {
+        self.options.default_signal(signal);
+        self
+    }
+
+    /// Put the child in the process group `pgroup`, or a new one if it is 0.
+    pub fn process_group(&mut self, pgroup: i32) -> &mut TaskPortCommand {
+        self.options.process_group(pgroup);
+        self
+    }
+
+    /// Start the child in a new session, as `setsid` does.
+    pub fn setsid(&mut self) -> &mut TaskPortCommand {
+        self.options.new_session();
+        self
+    }
+
+    /// Set the scheduling role the kernel gives the program.
+    pub fn process_type(&mut self, process_type: ProcessType) -> &mut TaskPortCommand {
+        self.options.process_type(process_type);
+        self
+    }
+
+    /// Set the program's nice value.
+    pub fn nice(&mut self, nice: i32) -> &mut TaskPortCommand {
+        self.options.nice(nice);
+        self
+    }
+
+    /// Run the slice of a universal program for the first of
+    /// `architectures` it has. See `PosixSpawnOptions::architecture_preference`.
+    pub fn architecture_preference(&mut self,
+                                   architectures: &[Architecture])
+                                   -> &mut TaskPortCommand {
+        self.options.architecture_preference(architectures);
+        self
+    }
+
+    /// Return a task port of `flavor` rather than the control port. See
+    /// `PosixSpawnOptions::task_flavor`.
+    pub fn task_flavor(&mut self, flavor: TaskFlavor) -> &mut TaskPortCommand {
+        self.options.task_flavor(flavor);
+        self
+    }
+
+    /// Leave the program suspended before its first instruction, until
+    /// `ChildWithTask::resume`.
+    pub fn start_suspended(&mut self) -> &mut TaskPortCommand {
+        self.options.start_suspended();
+        self
+    }
+
+    /// Spawn the child, returning once the parent has its task port.
+    ///
+    /// Each call spawns another child with the same configuration, except
+    /// that stdio set for one spawn isn't reused, since a `Stdio` can't be
+    /// copied: later children inherit the parent's.
+    pub fn spawn(&mut self) -> Result<ChildWithTask> {
+        // `spawn_with_task_posix` adds a hook to the command, which would
+        // pile up across spawns, so each spawn works on a copy.
+        let mut command = self.clone_command();
+        if let Some(stdin) = self.stdin.take() {
+            command.stdin(stdin);
+        }
+        if let Some(stdout) = self.stdout.take() {
+            command.stdout(stdout);
+        }
+        if let Some(stderr) = self.stderr.take() {
+            command.stderr(stderr);
+        }
+        command.spawn_with_task_posix(&self.options)
+    }
+
+    /// A copy of the command, which `Command` can't make itself.
+    fn clone_command(&self) -> Command {
+        let mut command = Command::new(self.command.get_program());
+        command.args(self.command.get_args());
+        if self.env_clear {
+            command.env_clear();
+        }
+        for (key, value) in self.command.get_envs() {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(dir) = self.command.get_current_dir() {
+            command.current_dir(dir);
+        }
+        command
+    }
+}
@@ -0,0 +1,207 @@
+//! An owned send right to a Mach thread port.
+
+use std::fmt;
+use std::io::Result;
+use std::mem;
+use std::slice;
+
+use mach::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach::message::mach_msg_type_number_t;
+use mach::port::{mach_port_t, MACH_PORT_RIGHT_SEND};
+use mach::task::task_threads;
+use mach::traps::mach_task_self;
+use mach::types::thread_act_array_t;
+use mach::vm::mach_vm_deallocate;
+
+use {MachPort, TaskPort, mach_port_mod_refs};
+
+/// `THREAD_BASIC_INFO`, `THREAD_IDENTIFIER_INFO` and `THREAD_EXTENDED_INFO`
+/// from `<mach/thread_info.h>`.
+const THREAD_BASIC_INFO: u32 = 3;
+const THREAD_IDENTIFIER_INFO: u32 = 4;
+const THREAD_EXTENDED_INFO: u32 = 5;
+
+/// `struct thread_basic_info`, with its `time_value_t`s as pairs.
+#[allow(dead_code)]
+#[repr(C)]
+struct thread_basic_info {
+    user_time: [i32; 2],
+    system_time: [i32; 2],
+    cpu_usage: i32,
+    policy: i32,
+    run_state: i32,
+    flags: i32,
+    suspend_count: i32,
+    sleep_time: i32,
+}
+
+/// `struct thread_identifier_info`
+#[repr(C)]
+struct thread_identifier_info {
+    thread_id: u64,
+    thread_handle: u64,
+    dispatch_qaddr: u64,
+}
+
+/// `struct thread_extended_info`
+#[repr(C)]
+struct thread_extended_info {
+    pth_user_time: u64,
+    pth_system_time: u64,
+    pth_cpu_usage: i32,
+    pth_policy: i32,
+    pth_run_state: i32,
+    pth_flags: i32,
+    pth_sleep_time: i32,
+    pth_curpri: i32,
+    pth_priority: i32,
+    pth_maxpriority: i32,
+    pth_name: [u8; 64],
+}
+
+extern "C" {
+    fn thread_info(thread: mach_port_t,
+                   flavor: u32,
+                   info: *mut i32,
+                   count: *mut mach_msg_type_number_t)
+                   -> kern_return_t;
+    fn thread_suspend(thread: mach_port_t) -> kern_return_t;
+    fn thread_resume(thread: mach_port_t) -> kern_return_t;
+}
+
+/// A send right to a thread's port, which is deallocated when the
+/// `ThreadPort` is dropped.
+pub struct ThreadPort(MachPort);
+
+impl ThreadPort {
+    /// Take ownership of a send right to a thread port.
+    ///
+    /// # Safety
+    ///
+    /// The right will be deallocated when the `ThreadPort` is dropped, so
+    /// the caller must actually own it.
+    pub unsafe fn from_raw(port: mach_port_t) -> ThreadPort {
+        ThreadPort(MachPort(port))
+    }
+
+    /// The raw port, which remains owned by this `ThreadPort`.
+    pub fn as_raw(&self) -> mach_port_t {
+        (self.0).0
+    }
+
+    /// Give up ownership of the send right, returning the raw port.
+    pub fn into_raw(self) -> mach_port_t {
+        self.0.into_raw()
+    }
+
+    /// Get another `ThreadPort` for the same thread, by adding a reference
+    /// to the send right.
+    pub fn try_clone(&self) -> Result<ThreadPort> {
+        unsafe {
+            ktry!(mach_port_mod_refs(mach_task_self(), self.as_raw(), MACH_PORT_RIGHT_SEND, 1));
+            Ok(ThreadPort::from_raw(self.as_raw()))
+        }
+    }
+
+    /// The thread's system-wide unique ID, as shown by crash reports and
+    /// `pthread_threadid_np`.
+    pub fn id(&self) -> Result<u64> {
+        Ok(self.identifier_info()?.thread_id)
+    }
+
+    /// The base of the thread's thread-specific data in its task, which
+    /// libpthread registers with the kernel, or 0 if it hasn't yet.
+    pub(crate) fn tsd_base(&self) -> Result<u64> {
+        Ok(self.identifier_info()?.thread_handle)
+    }
+
+    /// How many times the thread has been suspended with `suspend`, not
+    /// counting suspensions of its whole task.
+    pub fn suspend_count(&self) -> Result<i32> {
+        unsafe {
+            let mut info: thread_basic_info = mem::zeroed();
+            let mut count = (mem::size_of::<thread_basic_info>() / 4) as mach_msg_type_number_t;
+            ktry!(thread_info(self.as_raw(),
+                              THREAD_BASIC_INFO,
+                              &mut info as *mut _ as *mut i32,
+                              &mut count));
+            Ok(info.suspend_count)
+        }
+    }
+
+    /// Suspend the thread. Suspensions are counted, and the thread only
+    /// runs again once each has been matched by a `resume`.
+    pub fn suspend(&self) -> Result<()> {
+        unsafe {
+            ktry!(thread_suspend(self.as_raw()));
+        }
+        Ok(())
+    }
+
+    /// Undo one `suspend`.
+    pub fn resume(&self) -> Result<()> {
+        unsafe {
+            ktry!(thread_resume(self.as_raw()));
+        }
+        Ok(())
+    }
+
+    fn identifier_info(&self) -> Result<thread_identifier_info> {
+        unsafe {
+            let mut info: thread_identifier_info = mem::zeroed();
+            let mut count =
+                (mem::size_of::<thread_identifier_info>() / 4) as mach_msg_type_number_t;
+            ktry!(thread_info(self.as_raw(),
+                              THREAD_IDENTIFIER_INFO,
+                              &mut info as *mut _ as *mut i32,
+                              &mut count));
+            Ok(info)
+        }
+    }
+
+    /// The thread's name, as set with `pthread_setname_np`, or `None` if
+    /// it has none.
+    pub fn name(&self) -> Result<Option<String>> {
+        let info = unsafe {
+            let mut info: thread_extended_info = mem::zeroed();
+            let mut count = (mem::size_of::<thread_extended_info>() / 4) as mach_msg_type_number_t;
+            ktry!(thread_info(self.as_raw(),
+                              THREAD_EXTENDED_INFO,
+                              &mut info as *mut _ as *mut i32,
+                              &mut count));
+            info
+        };
+        let end = info.pth_name.iter().position(|&b| b == 0).unwrap_or(info.pth_name.len());
+        if end == 0 {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&info.pth_name[..end]).into_owned()))
+    }
+}
+
+impl fmt::Debug for ThreadPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ThreadPort").field(&self.as_raw()).finish()
+    }
+}
+
+impl TaskPort {
+    /// Get the task's threads, in the order the kernel lists them, which
+    /// starts with the main thread.
+    pub fn threads(&self) -> Result<Vec<ThreadPort>> {
+        unsafe {
+            let mut list: thread_act_array_t = mem::zeroed();
+            let mut count: mach_msg_type_number_t = 0;
+            ktry!(task_threads(self.as_raw(), &mut list, &mut count));
+            let threads = slice::from_raw_parts(list, count as usize)
+                .iter()
+                .map(|&port| ThreadPort::from_raw(port))
+                .collect();
+            // The list itself was allocated in our address space.
+            mach_vm_deallocate(mach_task_self(),
+                               list as u64,
+                               (count as usize * mem::size_of::<mach_port_t>()) as u64);
+            Ok(threads)
+        }
+    }
+}
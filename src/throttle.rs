@@ -0,0 +1,107 @@
+//! Capping a child's CPU use by suspending it for part of every window.
+//!
+//! macOS has no CPU quota that one process can put on another: the CPU
+//! usage monitor only reports overuse, and QoS classes and core
+//! preferences only change where and when the child runs, not how much.
+//! A `Throttle` therefore lets the child run for a fraction of each window
+//! and keeps it suspended for the rest, which caps its CPU use at that
+//! fraction however many threads it has. Background helpers like indexers
+//! can be kept well out of the way of foreground work like this, at the
+//! cost of the child's latency: it stops for up to the rest of a window at
+//! a time.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use TaskPort;
+
+/// The window `TaskPort::throttle` divides between running and being
+/// suspended.
+const DEFAULT_WINDOW: Duration = Duration::from_millis(100);
+
+/// A thread that suspends and resumes a task to cap its CPU use, which
+/// stops when dropped or when the task exits, leaving the task running.
+pub struct Throttle {
+    duty_cycle: f64,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TaskPort {
+    /// Let the task run for only `duty_cycle` of every 100 milliseconds,
+    /// which must be more than 0 and at most 1, until the returned
+    /// `Throttle` is dropped.
+    pub fn throttle(&self, duty_cycle: f64) -> Result<Throttle> {
+        self.throttle_with_window(duty_cycle, DEFAULT_WINDOW)
+    }
+
+    /// Like `throttle`, but with a different window. Shorter windows make
+    /// the child less jerky, and cost more context switches.
+    pub fn throttle_with_window(&self, duty_cycle: f64, window: Duration) -> Result<Throttle> {
+        if !(duty_cycle > 0.0 && duty_cycle <= 1.0) || window == Duration::from_secs(0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid throttle duty cycle"));
+        }
+        let task = self.try_clone()?;
+        let running = window.mul_f64(duty_cycle);
+        let suspended = window - running;
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if suspended == Duration::from_secs(0) {
+                let _ = stopped.recv();
+                return;
+            }
+            loop {
+                match stopped.recv_timeout(running) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                if task.suspend().is_err() {
+                    // The task has exited.
+                    return;
+                }
+                let wake = stopped.recv_timeout(suspended);
+                // Always undo our own suspension, so the task's suspend
+                // count is back where the caller left it.
+                let _ = task.resume();
+                match wake {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+        Ok(Throttle {
+            duty_cycle: duty_cycle,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Throttle {
+    /// The fraction of each window the task runs for.
+    pub fn duty_cycle(&self) -> f64 {
+        self.duty_cycle
+    }
+}
+
+impl fmt::Debug for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Throttle")
+            .field("duty_cycle", &self.duty_cycle)
+            .finish()
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        // Hanging up wakes the thread, which resumes the task if it had
+        // it suspended.
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Support for `tokio::process::Command`, enabled by the `tokio` feature.
+//!
+//! Spawning itself is quick, but waiting for the check-in is a blocking
+//! `mach_msg` receive, which mustn't run on the runtime's worker threads.
+//! Instead, the runtime's reactor watches the `TaskPortReceiver`'s kqueue,
+//! and the message is only received once it has arrived.
+
+use std::future::Future;
+use std::io::{Error, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::unix::AsyncFd;
+use tokio::process::{Child, Command};
+
+use {SpawnOptions, SpawnTaskPortError, TaskPort, TaskPortReceiver, pre_exec_hook};
+
+/// An extension to `tokio::process::Command` to spawn a process and get
+/// back access to its Mach task port without blocking the runtime.
+pub trait TokioCommandSpawnWithTask {
+    /// Executes the command as a child process, returning a future that
+    /// resolves to both the `Child` as well as a `TaskPort` that owns the
+    /// process' Mach task port once the child has checked in.
+    ///
+    /// This must be called from within a Tokio runtime.
+    fn spawn_with_task_port(&mut self) -> SpawnWithTaskPort;
+}
+
+impl TokioCommandSpawnWithTask for Command {
+    fn spawn_with_task_port(&mut self) -> SpawnWithTaskPort {
+        SpawnWithTaskPort { state: Some(spawn(self)) }
+    }
+}
+
+fn spawn(command: &mut Command) -> Result<Receiving> {
+    let (mut receiver, check_in) = TaskPortReceiver::register_for(command.as_std_mut(),
+                                                                  &SpawnOptions::new())?;
+    let child = unsafe { command.pre_exec(pre_exec_hook(check_in)) }.spawn()
+        .map_err(SpawnTaskPortError::from_spawn)?;
+    // The child can't have been reaped yet, so it still has its pid.
+    receiver.set_pid(child.id().unwrap_or(0));
+    let fd = AsyncFd::new(receiver.as_raw_fd())?;
+    Ok(Receiving {
+        child: child,
+        fd: fd,
+        receiver: receiver,
+    })
+}
+
+impl TaskPortReceiver {
+    /// Wait for the child to check in without blocking the runtime, and
+    /// return its task port.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub fn recv_async<'a>(&'a self) -> RecvAsync<'a> {
+        RecvAsync {
+            receiver: self,
+            fd: AsyncFd::new(self.as_raw_fd()),
+        }
+    }
+
+    /// Return the task port if the child has checked in, or arrange for
+    /// `cx` to be woken once it has.
+    fn poll_recv(&self, fd: &AsyncFd<RawFd>, cx: &mut Context) -> Poll<Result<TaskPort>> {
+        loop {
+            if let Some(task_port) = self.try_recv()? {
+                return Poll::Ready(Ok(task_port));
+            }
+            match fd.poll_read_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                // The kqueue can stay readable for a while after the
+                // message has been received.
+                Poll::Ready(Ok(mut guard)) => guard.clear_ready(),
+            }
+        }
+    }
+}
+
+/// The future returned by `TaskPortReceiver::recv_async`.
+pub struct RecvAsync<'a> {
+    receiver: &'a TaskPortReceiver,
+    /// The receiver's kqueue registered with the runtime, or why that
+    /// failed.
+    fd: Result<AsyncFd<RawFd>>,
+}
+
+impl<'a> Future for RecvAsync<'a> {
+    type Output = Result<TaskPort>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.fd {
+            Ok(ref fd) => this.receiver.poll_recv(fd, cx),
+            Err(ref e) => Poll::Ready(Err(Error::new(e.kind(), e.to_string()))),
+        }
+    }
+}
+
+/// The future returned by `TokioCommandSpawnWithTask::spawn_with_task_port`.
+pub struct SpawnWithTaskPort {
+    /// Either the error spawning failed with, or the spawned child.
+    state: Option<Result<Receiving>>,
+}
+
+struct Receiving {
+    child: Child,
+    /// Deregistered before `receiver` closes the kqueue.
+    fd: AsyncFd<RawFd>,
+    receiver: TaskPortReceiver,
+}
+
+impl Future for SpawnWithTaskPort {
+    type Output = Result<(Child, TaskPort)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let received = match this.state {
+            Some(Ok(ref receiving)) => {
+                match receiving.receiver.poll_recv(&receiving.fd, cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(received) => Some(received),
+                }
+            }
+            Some(Err(_)) => None,
+            None => panic!("`SpawnWithTaskPort` polled after completion"),
+        };
+        Poll::Ready(match (this.state.take().unwrap(), received) {
+            (Err(e), _) => Err(e),
+            (Ok(receiving), Some(Ok(task_port))) => Ok((receiving.child, task_port)),
+            (Ok(mut receiving), Some(Err(e))) => {
+                // Like `spawn_with_task_port`, don't leave a child behind
+                // that the caller never gets.
+                let _ = receiving.child.start_kill();
+                Err(e)
+            }
+            (Ok(_), None) => unreachable!(),
+        })
+    }
+}
@@ -0,0 +1,243 @@
+//! The trailers the kernel appends to received check-ins.
+//!
+//! Every Mach message arrives with a trailer, and the receiver chooses how
+//! much it holds: nothing beyond its own size, or successively the
+//! sender's credentials, its audit token, the port's context value and
+//! its MAC labels. The parent asks for the audit trailer by default, to
+//! check who sent a check-in; `SpawnOptions::trailer` asks for another,
+//! and `TaskPortReceiver::recv_with_trailer` returns what arrived.
+
+use mach::message::mach_msg_option_t;
+
+/// The `MACH_RCV_TRAILER_*` element counts, which `mach` doesn't define.
+const MACH_RCV_TRAILER_NULL: mach_msg_option_t = 0;
+const MACH_RCV_TRAILER_SENDER: mach_msg_option_t = 2;
+const MACH_RCV_TRAILER_AUDIT: mach_msg_option_t = 3;
+const MACH_RCV_TRAILER_CTX: mach_msg_option_t = 4;
+const MACH_RCV_TRAILER_LABELS: mach_msg_option_t = 8;
+
+/// The sizes the kernel reports for each kind of trailer.
+const SENDER_TRAILER_SIZE: u32 = 20;
+const AUDIT_TRAILER_SIZE: u32 = 52;
+const CONTEXT_TRAILER_SIZE: u32 = 60;
+const LABELS_TRAILER_SIZE: u32 = 68;
+
+/// How much trailer to ask the kernel for when receiving a check-in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrailerType {
+    /// No trailer beyond its size.
+    None,
+    /// The sender's user and group ids.
+    Sender,
+    /// The sender's credentials and its audit token, which identifies the
+    /// process. This is the default.
+    Audit,
+    /// Everything `Audit` has, and the context value of the receiving port.
+    Context,
+    /// Everything `Context` has, and the sender's MAC labels.
+    Labels,
+}
+
+// Deriving this needs `#[default]`, which is newer than Rust 1.60.
+#[allow(clippy::derivable_impls)]
+impl Default for TrailerType {
+    fn default() -> TrailerType {
+        TrailerType::Audit
+    }
+}
+
+impl TrailerType {
+    /// The `MACH_RCV_TRAILER_ELEMENTS` option that asks for this trailer.
+    pub(crate) fn to_option(self) -> mach_msg_option_t {
+        let elements = match self {
+            TrailerType::None => MACH_RCV_TRAILER_NULL,
+            TrailerType::Sender => MACH_RCV_TRAILER_SENDER,
+            TrailerType::Audit => MACH_RCV_TRAILER_AUDIT,
+            TrailerType::Context => MACH_RCV_TRAILER_CTX,
+            TrailerType::Labels => MACH_RCV_TRAILER_LABELS,
+        };
+        (elements & 0xf) << 24
+    }
+}
+
+/// A process' audit token, as the kernel recorded it when the process sent
+/// a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AuditToken {
+    val: [u32; 8],
+}
+
+impl AuditToken {
+    /// The raw words of the token, as `audit_token_t` holds them.
+    pub fn as_raw(&self) -> [u32; 8] {
+        self.val
+    }
+
+    /// The sender's effective user id.
+    pub fn euid(&self) -> u32 {
+        self.val[1]
+    }
+
+    /// The sender's pid.
+    pub fn pid(&self) -> u32 {
+        self.val[5]
+    }
+
+    /// The version of the sender's pid, which changes when the pid is
+    /// reused, or the process executes a new image.
+    pub fn pid_version(&self) -> u32 {
+        self.val[7]
+    }
+}
+
+/// The trailer of a received check-in, as much of it as was asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum MessageTrailer {
+    /// Nothing beyond the trailer's size.
+    None,
+    /// The sender's user and group ids.
+    Sender { uid: u32, gid: u32 },
+    /// The sender's credentials and audit token.
+    Audit {
+        uid: u32,
+        gid: u32,
+        audit_token: AuditToken,
+    },
+    /// The sender's credentials and audit token, and the context value of
+    /// the port the check-in arrived on.
+    Context {
+        uid: u32,
+        gid: u32,
+        audit_token: AuditToken,
+        context: u64,
+    },
+    /// Everything `Context` has, and the sender's MAC labels handle.
+    Labels {
+        uid: u32,
+        gid: u32,
+        audit_token: AuditToken,
+        context: u64,
+        labels: u32,
+    },
+}
+
+impl MessageTrailer {
+    /// The sender's user id, if the trailer has its credentials.
+    pub fn uid(&self) -> Option<u32> {
+        match *self {
+            MessageTrailer::None => None,
+            MessageTrailer::Sender { uid, .. } |
+            MessageTrailer::Audit { uid, .. } |
+            MessageTrailer::Context { uid, .. } |
+            MessageTrailer::Labels { uid, .. } => Some(uid),
+        }
+    }
+
+    /// The sender's audit token, if the trailer has it.
+    pub fn audit_token(&self) -> Option<AuditToken> {
+        match *self {
+            MessageTrailer::None |
+            MessageTrailer::Sender { .. } => None,
+            MessageTrailer::Audit { audit_token, .. } |
+            MessageTrailer::Context { audit_token, .. } |
+            MessageTrailer::Labels { audit_token, .. } => Some(audit_token),
+        }
+    }
+}
+
+/// The largest trailer, `mach_msg_mac_trailer_t`, as the receive buffer
+/// holds it. Only as much as was asked for is filled in.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct RawTrailer {
+    msgh_trailer_type: u32,
+    msgh_trailer_size: u32,
+    msgh_seqno: u32,
+    msgh_sender: [u32; 2],
+    msgh_audit: [u32; 8],
+    /// A `mach_port_context_t`, split so that the struct keeps the
+    /// kernel's 4-byte packing.
+    msgh_context: [u32; 2],
+    msgh_ad: i32,
+    msgh_labels: u32,
+}
+
+impl RawTrailer {
+    /// An audit trailer from the process `pid`, as the kernel would fill
+    /// it in.
+    #[cfg(test)]
+    pub(crate) fn with_audit_pid(pid: u32) -> RawTrailer {
+        let mut raw: RawTrailer = unsafe { ::std::mem::zeroed() };
+        raw.msgh_trailer_size = AUDIT_TRAILER_SIZE;
+        raw.msgh_audit[5] = pid;
+        raw
+    }
+
+    /// What the kernel filled in, going by the size it reported.
+    pub(crate) fn parse(&self) -> MessageTrailer {
+        let size = self.msgh_trailer_size;
+        if size < SENDER_TRAILER_SIZE {
+            return MessageTrailer::None;
+        }
+        let (uid, gid) = (self.msgh_sender[0], self.msgh_sender[1]);
+        if size < AUDIT_TRAILER_SIZE {
+            return MessageTrailer::Sender { uid: uid, gid: gid };
+        }
+        let audit_token = AuditToken { val: self.msgh_audit };
+        if size < CONTEXT_TRAILER_SIZE {
+            return MessageTrailer::Audit {
+                uid: uid,
+                gid: gid,
+                audit_token: audit_token,
+            };
+        }
+        let context = self.msgh_context[0] as u64 | (self.msgh_context[1] as u64) << 32;
+        if size < LABELS_TRAILER_SIZE {
+            return MessageTrailer::Context {
+                uid: uid,
+                gid: gid,
+                audit_token: audit_token,
+                context: context,
+            };
+        }
+        MessageTrailer::Labels {
+            uid: uid,
+            gid: gid,
+            audit_token: audit_token,
+            context: context,
+            labels: self.msgh_labels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn parses_as_much_as_the_kernel_filled_in() {
+        assert_eq!(mem::size_of::<RawTrailer>() as u32, LABELS_TRAILER_SIZE);
+        let mut raw: RawTrailer = unsafe { mem::zeroed() };
+        raw.msgh_sender = [501, 20];
+        raw.msgh_audit[5] = 1234;
+        raw.msgh_context = [1, 2];
+        raw.msgh_trailer_size = 8;
+        assert_eq!(raw.parse(), MessageTrailer::None);
+        raw.msgh_trailer_size = SENDER_TRAILER_SIZE;
+        assert_eq!(raw.parse(), MessageTrailer::Sender { uid: 501, gid: 20 });
+        assert_eq!(raw.parse().audit_token(), None);
+        raw.msgh_trailer_size = AUDIT_TRAILER_SIZE;
+        assert_eq!(raw.parse().audit_token().map(|token| token.pid()), Some(1234));
+        raw.msgh_trailer_size = CONTEXT_TRAILER_SIZE;
+        match raw.parse() {
+            MessageTrailer::Context { context, .. } => assert_eq!(context, 0x2_0000_0001),
+            other => panic!("unexpected trailer {:?}", other),
+        }
+        raw.msgh_trailer_size = LABELS_TRAILER_SIZE;
+        assert_eq!(raw.parse().uid(), Some(501));
+    }
+}
@@ -0,0 +1,148 @@
+//! Reading many small ranges of a task's memory at once.
+//!
+//! Unwinders and scanners read a child's memory a few bytes at a time,
+//! and each `read_memory` is a Mach call of its own. `read_vectored` takes
+//! all the ranges up front and reads them in as few calls as it can: it
+//! sorts them, merges ranges that overlap, touch or share a page, and
+//! reads each merged span in one go, handing every range its part.
+//!
+//! Merging can make a span cover memory no range asked for, but never past
+//! the page the last range ends in, so a span only fails where one of its
+//! ranges would have. If it does, its ranges are read one by one instead,
+//! so each range's result is the one it would have had on its own.
+
+use std::io::{Error, ErrorKind, Result};
+
+use TaskPort;
+
+/// Ranges are merged across gaps up to the end of the page the span so
+/// far ends in, going by the smallest page size, as `read_c_string` does.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The most a merged span reads in one call.
+const MAX_SPAN: u64 = 1 << 20;
+
+/// A run of ranges read with one call.
+#[derive(Debug, PartialEq, Eq)]
+struct Span {
+    start: u64,
+    end: u64,
+    /// The indices of the ranges it covers.
+    members: Vec<usize>,
+}
+
+/// The end of the page containing the last byte before `end`.
+fn page_end(end: u64) -> u64 {
+    end.checked_add(PAGE_SIZE - 1).map_or(u64::max_value(), |end| end & !(PAGE_SIZE - 1))
+}
+
+/// Group the non-empty `ranges` into spans to read.
+fn coalesce(ranges: &[(u64, usize)]) -> Vec<Span> {
+    let mut order = (0..ranges.len()).filter(|&i| ranges[i].1 != 0).collect::<Vec<_>>();
+    order.sort_by_key(|&i| ranges[i].0);
+    let mut spans: Vec<Span> = vec![];
+    for i in order {
+        let (start, len) = ranges[i];
+        let end = start.saturating_add(len as u64);
+        if let Some(span) = spans.last_mut() {
+            if start <= page_end(span.end) && end.max(span.end) - span.start <= MAX_SPAN {
+                span.end = end.max(span.end);
+                span.members.push(i);
+                continue;
+            }
+        }
+        spans.push(Span {
+            start: start,
+            end: end,
+            members: vec![i],
+        });
+    }
+    spans
+}
+
+impl TaskPort {
+    /// Read each of `ranges`, given as an address and a length, returning
+    /// what was read from each, or why it couldn't be, in the same order.
+    ///
+    /// Ranges may overlap and come in any order. See the module
+    /// documentation for how they are batched.
+    pub fn read_vectored(&self, ranges: &[(u64, usize)]) -> Vec<Result<Vec<u8>>> {
+        let mut results = ranges.iter().map(|_| Ok(vec![])).collect::<Vec<_>>();
+        for span in coalesce(ranges) {
+            let read_alone = |i: usize| {
+                let (address, len) = ranges[i];
+                if address.checked_add(len as u64).is_none() {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          "range wraps around the address space"));
+                }
+                let mut buf = vec![0; len];
+                self.read_memory(address, &mut buf).map(|()| buf)
+            };
+            if span.members.len() == 1 {
+                results[span.members[0]] = read_alone(span.members[0]);
+                continue;
+            }
+            let mut buf = vec![0; (span.end - span.start) as usize];
+            match self.read_memory(span.start, &mut buf) {
+                Ok(()) => {
+                    for &i in &span.members {
+                        let (address, len) = ranges[i];
+                        let offset = (address - span.start) as usize;
+                        results[i] = Ok(buf[offset..offset + len].to_vec());
+                    }
+                }
+                Err(_) => {
+                    for &i in &span.members {
+                        results[i] = read_alone(i);
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    /// Like `read_vectored`, but with the task suspended for the whole
+    /// pass, so that every range is read as of the same moment.
+    pub fn read_vectored_suspended(&self,
+                                   ranges: &[(u64, usize)])
+                                   -> Result<Vec<Result<Vec<u8>>>> {
+        self.suspend()?;
+        let results = self.read_vectored(ranges);
+        self.resume()?;
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_ranges_that_share_a_page() {
+        let ranges = [(0x2000, 8),
+                      (0x1000, 16),
+                      (0x1008, 4),
+                      (0x1ff0, 8),
+                      (0x5000, 8),
+                      (0x7000, 0)];
+        let spans = coalesce(&ranges);
+        assert_eq!(spans,
+                   vec![Span {
+                            start: 0x1000,
+                            end: 0x2008,
+                            members: vec![1, 2, 3, 0],
+                        },
+                        Span {
+                            start: 0x5000,
+                            end: 0x5008,
+                            members: vec![4],
+                        }]);
+    }
+
+    #[test]
+    fn caps_spans() {
+        let ranges = [(0, MAX_SPAN as usize), (MAX_SPAN, 8)];
+        assert_eq!(coalesce(&ranges).len(), 2);
+        assert_eq!(page_end(u64::max_value() - 1), u64::max_value());
+    }
+}
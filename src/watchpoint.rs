@@ -0,0 +1,291 @@
+//! Hardware watchpoints on a thread's data accesses.
+//!
+//! A watchpoint makes a thread raise `EXC_BREAKPOINT` when it touches up
+//! to eight bytes of memory, without patching any of its code. There are
+//! only a few watchpoint registers per thread, four on every current Mac,
+//! so `ThreadPort::set_watchpoint` picks one that isn't enabled yet and
+//! fails when they are all taken. The thread's debug state is the record
+//! of which registers are in use, so watchpoints set by anything else,
+//! such as a debugger, are never overwritten.
+//!
+//! On ARM64 the access is reported before it happens: resuming the thread
+//! as is raises the exception again. Clear the watchpoint, single-step the
+//! thread and set it again to let the access through.
+
+use std::io::{Error, ErrorKind, Result};
+#[cfg(target_arch = "aarch64")]
+use std::mem;
+#[cfg(target_arch = "aarch64")]
+use std::os::raw::{c_char, c_int, c_void};
+#[cfg(target_arch = "aarch64")]
+use std::ptr;
+
+use {DebugState, ThreadPort};
+
+/// Which accesses a watchpoint catches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum WatchKind {
+    /// Loads only. x86-64 can't watch for these.
+    Read,
+    /// Stores only.
+    Write,
+    /// Loads and stores.
+    ReadWrite,
+}
+
+/// A watchpoint set on a thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Watchpoint {
+    /// The watchpoint register it uses.
+    pub index: usize,
+    /// The first byte watched.
+    pub address: u64,
+    /// How many bytes are watched.
+    pub len: usize,
+    /// Which accesses are caught.
+    pub kind: WatchKind,
+}
+
+#[cfg(target_arch = "aarch64")]
+extern "C" {
+    fn sysctlbyname(name: *const c_char,
+                    oldp: *mut c_void,
+                    oldlenp: *mut usize,
+                    newp: *mut c_void,
+                    newlen: usize)
+                    -> c_int;
+}
+
+/// The number of watchpoint registers each thread has.
+#[cfg(target_arch = "aarch64")]
+pub fn watchpoint_count() -> usize {
+    let mut count: u32 = 0;
+    let mut len = mem::size_of::<u32>();
+    let ret = unsafe {
+        sysctlbyname(b"hw.optional.watchpoint\0".as_ptr() as *const c_char,
+                     &mut count as *mut u32 as *mut c_void,
+                     &mut len,
+                     ptr::null_mut(),
+                     0)
+    };
+    // Every ARM64 Mac so far has four.
+    if ret != 0 || count == 0 {
+        4
+    } else {
+        (count as usize).min(16)
+    }
+}
+
+/// The number of watchpoint registers each thread has.
+#[cfg(target_arch = "x86_64")]
+pub fn watchpoint_count() -> usize {
+    4
+}
+
+/// The enable bit of `DBGWCR<n>_EL1`.
+#[cfg(target_arch = "aarch64")]
+const WCR_E: u64 = 1;
+/// `DBGWCR<n>_EL1.PAC` for EL0, the only privilege level the kernel allows.
+#[cfg(target_arch = "aarch64")]
+const WCR_PAC_EL0: u64 = 0b10 << 1;
+
+#[cfg(target_arch = "aarch64")]
+fn is_enabled(state: &DebugState, index: usize) -> bool {
+    state.wcr[index] & WCR_E != 0
+}
+
+#[cfg(target_arch = "aarch64")]
+fn decode(state: &DebugState, index: usize) -> Watchpoint {
+    let wcr = state.wcr[index];
+    let bas = (wcr >> 5) & 0xff;
+    let first = bas.trailing_zeros() as u64;
+    Watchpoint {
+        index: index,
+        address: state.wvr[index] + first,
+        len: bas.count_ones() as usize,
+        kind: match (wcr >> 3) & 0b11 {
+            0b01 => WatchKind::Read,
+            0b10 => WatchKind::Write,
+            _ => WatchKind::ReadWrite,
+        },
+    }
+}
+
+/// Program watchpoint register `index` to watch `len` bytes at `address`,
+/// which must all be within one aligned doubleword.
+#[cfg(target_arch = "aarch64")]
+fn encode(state: &mut DebugState,
+          index: usize,
+          address: u64,
+          len: usize,
+          kind: WatchKind)
+          -> Result<()> {
+    let offset = address & 7;
+    if len == 0 || offset + len as u64 > 8 {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "watched bytes must be within one aligned doubleword"));
+    }
+    let lsc = match kind {
+        WatchKind::Read => 0b01,
+        WatchKind::Write => 0b10,
+        WatchKind::ReadWrite => 0b11,
+    };
+    let bas = ((1u64 << len) - 1) << offset;
+    state.wvr[index] = address - offset;
+    state.wcr[index] = WCR_E | WCR_PAC_EL0 | lsc << 3 | bas << 5;
+    Ok(())
+}
+
+#[cfg(target_arch = "aarch64")]
+fn disable(state: &mut DebugState, index: usize) {
+    state.wvr[index] = 0;
+    state.wcr[index] = 0;
+}
+
+/// `DR7`'s local enable bit for `index`.
+#[cfg(target_arch = "x86_64")]
+fn dr7_enable(index: usize) -> u64 {
+    1 << (2 * index)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn is_enabled(state: &DebugState, index: usize) -> bool {
+    state.dr[7] & dr7_enable(index) != 0
+}
+
+#[cfg(target_arch = "x86_64")]
+fn decode(state: &DebugState, index: usize) -> Watchpoint {
+    let bits = state.dr[7] >> (16 + 4 * index);
+    Watchpoint {
+        index: index,
+        address: state.dr[index],
+        len: match (bits >> 2) & 0b11 {
+            0b00 => 1,
+            0b01 => 2,
+            0b10 => 8,
+            _ => 4,
+        },
+        kind: if bits & 0b11 == 0b01 {
+            WatchKind::Write
+        } else {
+            WatchKind::ReadWrite
+        },
+    }
+}
+
+/// Program debug register `index` to watch `len` bytes at `address`,
+/// which must be aligned to `len`.
+#[cfg(target_arch = "x86_64")]
+fn encode(state: &mut DebugState,
+          index: usize,
+          address: u64,
+          len: usize,
+          kind: WatchKind)
+          -> Result<()> {
+    let len_bits = match len {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        8 => 0b10,
+        _ => {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "watchpoints must be 1, 2, 4 or 8 bytes"));
+        }
+    };
+    if address % len as u64 != 0 {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "watchpoint address must be aligned to its length"));
+    }
+    let rw_bits = match kind {
+        WatchKind::Read => {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "x86-64 can't watch for reads only"));
+        }
+        WatchKind::Write => 0b01,
+        WatchKind::ReadWrite => 0b11,
+    };
+    let shift = 16 + 4 * index;
+    state.dr[index] = address;
+    state.dr[7] &= !(0b1111 << shift);
+    state.dr[7] |= (len_bits << 2 | rw_bits) << shift | dr7_enable(index);
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn disable(state: &mut DebugState, index: usize) {
+    state.dr[index] = 0;
+    state.dr[7] &= !(dr7_enable(index) | 0b1111 << (16 + 4 * index));
+}
+
+impl ThreadPort {
+    /// The watchpoints currently set on the thread, by this crate or
+    /// anything else.
+    pub fn watchpoints(&self) -> Result<Vec<Watchpoint>> {
+        let state = self.debug_state()?;
+        Ok((0..watchpoint_count())
+            .filter(|&index| is_enabled(&state, index))
+            .map(|index| decode(&state, index))
+            .collect())
+    }
+
+    /// Watch `len` bytes at `address` for `kind` accesses, using the first
+    /// free watchpoint register.
+    ///
+    /// On ARM64 the bytes must be within one 8-byte aligned doubleword; on
+    /// x86-64, `len` must be 1, 2, 4 or 8 and `address` aligned to it.
+    pub fn set_watchpoint(&self, address: u64, len: usize, kind: WatchKind) -> Result<Watchpoint> {
+        let mut state = self.debug_state()?;
+        let count = watchpoint_count();
+        let index = match (0..count).find(|&index| !is_enabled(&state, index)) {
+            Some(index) => index,
+            None => {
+                return Err(Error::new(ErrorKind::Other,
+                                      format!("all {} watchpoint registers are in use", count)));
+            }
+        };
+        encode(&mut state, index, address, len, kind)?;
+        self.set_debug_state(&state)?;
+        Ok(decode(&state, index))
+    }
+
+    /// Clear a watchpoint, freeing its register.
+    pub fn clear_watchpoint(&self, watchpoint: &Watchpoint) -> Result<()> {
+        let mut state = self.debug_state()?;
+        disable(&mut state, watchpoint.index);
+        self.set_debug_state(&state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode_watchpoints() {
+        let mut state = DebugState::default();
+        encode(&mut state, 1, 0x1000, 8, WatchKind::Write).unwrap();
+        encode(&mut state, 2, 0x2004, 4, WatchKind::ReadWrite).unwrap();
+        assert!(!is_enabled(&state, 0));
+        assert!(is_enabled(&state, 1));
+        assert_eq!(decode(&state, 1),
+                   Watchpoint {
+                       index: 1,
+                       address: 0x1000,
+                       len: 8,
+                       kind: WatchKind::Write,
+                   });
+        assert_eq!(decode(&state, 2),
+                   Watchpoint {
+                       index: 2,
+                       address: 0x2004,
+                       len: 4,
+                       kind: WatchKind::ReadWrite,
+                   });
+        disable(&mut state, 1);
+        assert!(!is_enabled(&state, 1));
+        assert!(is_enabled(&state, 2));
+        assert!(encode(&mut state, 0, 0x1006, 4, WatchKind::Write).is_err());
+    }
+}
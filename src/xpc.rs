@@ -0,0 +1,399 @@
+//! A handshake over XPC, enabled by the `xpc` feature.
+//!
+//! Apps that are already built around XPC would rather not register
+//! bootstrap services by hand. An `XpcTaskPortReceiver` is an anonymous
+//! XPC listener: nothing is registered anywhere, and the only way to reach
+//! it is through its endpoint, which the parent passes to the child over
+//! whatever XPC connection the two already share. The child hands it to
+//! `send_task_port_xpc`, which connects and sends its task port, and the
+//! receiver checks that the port belongs to the process that sent it.
+//!
+//! libxpc can't be used between `fork` and `exec`, so unlike the bootstrap
+//! handshake this needs the child's cooperation after it has started.
+//!
+//! `XpcSpawnWithTask` makes this a spawn backend of its own: it spawns the
+//! command, has the caller deliver the endpoint, and waits for that
+//! child's check-in in particular. An endpoint can only travel inside an
+//! XPC message, so a freshly spawned child can't inherit it the way it
+//! inherits its bootstrap port; the caller delivers it over a connection
+//! the child already has, such as one to an XPC service both of them use.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mach::port::{mach_port_t, MACH_PORT_NULL};
+use mach::traps::mach_task_self;
+
+use {SpawnTaskPortError, SpawnedProcess, TaskPort};
+
+/// `xpc_object_t`, which `xpc_connection_t` and `xpc_endpoint_t` are too.
+#[allow(non_camel_case_types)]
+pub type xpc_object_t = *mut c_void;
+
+/// The dictionary key the task port is sent under.
+const TASK_PORT_KEY: &'static [u8] = b"task_port\0";
+
+extern "C" {
+    static _NSConcreteStackBlock: [*const c_void; 32];
+    static _xpc_type_connection: u8;
+    static _xpc_type_dictionary: u8;
+    static _xpc_error_connection_invalid: u8;
+
+    fn xpc_connection_create(name: *const c_char, queue: *mut c_void) -> xpc_object_t;
+    fn xpc_connection_create_from_endpoint(endpoint: xpc_object_t) -> xpc_object_t;
+    fn xpc_connection_set_event_handler(connection: xpc_object_t, handler: *mut c_void);
+    fn xpc_connection_resume(connection: xpc_object_t);
+    fn xpc_connection_cancel(connection: xpc_object_t);
+    fn xpc_connection_get_pid(connection: xpc_object_t) -> c_int;
+    fn xpc_connection_send_message(connection: xpc_object_t, message: xpc_object_t);
+    fn xpc_connection_send_message_with_reply_sync(connection: xpc_object_t,
+                                                   message: xpc_object_t)
+                                                   -> xpc_object_t;
+    fn xpc_endpoint_create(connection: xpc_object_t) -> xpc_object_t;
+    fn xpc_get_type(object: xpc_object_t) -> *const c_void;
+    fn xpc_dictionary_create(keys: *const *const c_char,
+                             values: *const xpc_object_t,
+                             count: usize)
+                             -> xpc_object_t;
+    fn xpc_dictionary_create_reply(original: xpc_object_t) -> xpc_object_t;
+    fn xpc_dictionary_get_remote_connection(dictionary: xpc_object_t) -> xpc_object_t;
+    fn xpc_dictionary_set_mach_send(dictionary: xpc_object_t,
+                                    key: *const c_char,
+                                    port: mach_port_t);
+    fn xpc_dictionary_copy_mach_send(dictionary: xpc_object_t, key: *const c_char) -> mach_port_t;
+    fn xpc_release(object: xpc_object_t);
+}
+
+fn is_type(object: xpc_object_t, ty: &'static u8) -> bool {
+    unsafe { ptr::eq(xpc_get_type(object), ty as *const u8 as *const c_void) }
+}
+
+fn is_connection_invalid(event: xpc_object_t) -> bool {
+    unsafe { ptr::eq(event as *const u8, &_xpc_error_connection_invalid) }
+}
+
+/// `struct Block_descriptor_1`
+#[repr(C)]
+struct BlockDescriptor {
+    reserved: c_ulong,
+    size: c_ulong,
+}
+
+/// An event handler block capturing a single pointer, laid out the way
+/// the blocks runtime expects, since Rust can't write blocks itself.
+#[repr(C)]
+struct HandlerBlock {
+    isa: *const c_void,
+    flags: c_int,
+    reserved: c_int,
+    invoke: unsafe extern "C" fn(*mut HandlerBlock, xpc_object_t),
+    descriptor: *const BlockDescriptor,
+    context: *const c_void,
+}
+
+static HANDLER_DESCRIPTOR: BlockDescriptor = BlockDescriptor {
+    reserved: 0,
+    size: mem::size_of::<HandlerBlock>() as c_ulong,
+};
+
+/// Set `connection`'s event handler to call `invoke` with `context`.
+///
+/// The block is built on the stack; XPC copies it to the heap, and since
+/// the context is a plain pointer, copying the bytes is all that takes.
+unsafe fn set_event_handler(connection: xpc_object_t,
+                            invoke: unsafe extern "C" fn(*mut HandlerBlock, xpc_object_t),
+                            context: *const c_void) {
+    let mut block = HandlerBlock {
+        isa: &_NSConcreteStackBlock as *const _ as *const c_void,
+        flags: 0,
+        reserved: 0,
+        invoke: invoke,
+        descriptor: &HANDLER_DESCRIPTOR,
+        context: context,
+    };
+    xpc_connection_set_event_handler(connection, &mut block as *mut HandlerBlock as *mut c_void);
+}
+
+/// What the listener and its peers share. Each connection's handler holds
+/// a strong reference, which it releases on the connection's last event.
+struct Shared {
+    check_ins: Mutex<Sender<Result<(u32, TaskPort)>>>,
+}
+
+/// Handle an event on the listener, which is either a new peer or its
+/// cancellation.
+unsafe extern "C" fn listener_event(block: *mut HandlerBlock, event: xpc_object_t) {
+    let context = (*block).context;
+    if is_type(event, &_xpc_type_connection) {
+        let shared = Arc::from_raw(context as *const Shared);
+        let peer_context = Arc::into_raw(shared.clone()) as *const c_void;
+        mem::forget(shared);
+        set_event_handler(event, peer_event, peer_context);
+        xpc_connection_resume(event);
+    } else if is_connection_invalid(event) {
+        // The listener was cancelled, and this is its last event.
+        drop(Arc::from_raw(context as *const Shared));
+    }
+}
+
+/// Handle an event on a peer connection, which is either a check-in or
+/// the connection going away.
+unsafe extern "C" fn peer_event(block: *mut HandlerBlock, event: xpc_object_t) {
+    let context = (*block).context;
+    if is_type(event, &_xpc_type_dictionary) {
+        let shared = &*(context as *const Shared);
+        let check_in = receive_check_in(event);
+        let reply = xpc_dictionary_create_reply(event);
+        if !reply.is_null() {
+            xpc_connection_send_message(xpc_dictionary_get_remote_connection(event), reply);
+            xpc_release(reply);
+        }
+        // The receiver may be gone, in which case the port is dropped.
+        let _ = shared.check_ins.lock().unwrap_or_else(|e| e.into_inner()).send(check_in);
+    } else if is_connection_invalid(event) {
+        drop(Arc::from_raw(context as *const Shared));
+    }
+}
+
+/// Take the task port out of a check-in message, and check that it
+/// belongs to the sender.
+unsafe fn receive_check_in(message: xpc_object_t) -> Result<(u32, TaskPort)> {
+    let port = xpc_dictionary_copy_mach_send(message, TASK_PORT_KEY.as_ptr() as *const c_char);
+    if port == MACH_PORT_NULL {
+        return Err(Error::new(ErrorKind::InvalidData, "received a check-in without a task port"));
+    }
+    let task = TaskPort::from_raw(port);
+    let sender = xpc_connection_get_pid(xpc_dictionary_get_remote_connection(message)) as u32;
+    let pid = task.pid()?;
+    if pid != sender {
+        return Err(SpawnTaskPortError::AuditMismatch {
+                expected: sender,
+                actual: pid,
+            }
+            .into());
+    }
+    Ok((pid, task))
+}
+
+/// An anonymous XPC listener that receives task ports sent with
+/// `send_task_port_xpc`.
+pub struct XpcTaskPortReceiver {
+    listener: xpc_object_t,
+    endpoint: xpc_object_t,
+    check_ins: Mutex<CheckIns>,
+}
+
+/// The check-ins the listener has passed on.
+struct CheckIns {
+    receiver: Receiver<Result<(u32, TaskPort)>>,
+    /// Those set aside while waiting for a particular process, to be
+    /// returned first by `receive`.
+    pending: Vec<(u32, TaskPort)>,
+}
+
+impl CheckIns {
+    /// Take the next check-in, waiting until `deadline` if given, and
+    /// returning `None` if it passes.
+    fn next(&mut self, deadline: Option<Instant>) -> Result<Option<Result<(u32, TaskPort)>>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(Ok(self.pending.remove(0))));
+        }
+        let received = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                let timeout = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+                self.receiver.recv_timeout(timeout)
+            }
+            None => self.receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(check_in) => Ok(Some(check_in)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::new(ErrorKind::BrokenPipe, "XPC listener went away"))
+            }
+        }
+    }
+}
+
+// XPC objects may be used from any thread.
+unsafe impl Send for XpcTaskPortReceiver {}
+unsafe impl Sync for XpcTaskPortReceiver {}
+
+impl XpcTaskPortReceiver {
+    /// Start listening.
+    pub fn new() -> Result<XpcTaskPortReceiver> {
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared { check_ins: Mutex::new(sender) });
+        unsafe {
+            let listener = xpc_connection_create(ptr::null(), ptr::null_mut());
+            if listener.is_null() {
+                return Err(Error::new(ErrorKind::Other, "failed to create an XPC listener"));
+            }
+            set_event_handler(listener, listener_event, Arc::into_raw(shared) as *const c_void);
+            xpc_connection_resume(listener);
+            Ok(XpcTaskPortReceiver {
+                listener: listener,
+                endpoint: xpc_endpoint_create(listener),
+                check_ins: Mutex::new(CheckIns {
+                    receiver: receiver,
+                    pending: vec![],
+                }),
+            })
+        }
+    }
+
+    /// The listener's endpoint, an `xpc_endpoint_t` to send to the child
+    /// for `send_task_port_xpc`. It remains owned by the receiver.
+    pub fn endpoint(&self) -> xpc_object_t {
+        self.endpoint
+    }
+
+    /// Block until a task port arrives, returning the pid of its task
+    /// along with it.
+    pub fn receive(&self) -> Result<(u32, TaskPort)> {
+        let mut check_ins = self.check_ins.lock().unwrap_or_else(|e| e.into_inner());
+        check_ins.next(None)?.expect("waited without a deadline")
+    }
+
+    /// Like `receive`, but give up and return `None` if nothing arrives
+    /// within `timeout`.
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<Option<(u32, TaskPort)>> {
+        let mut check_ins = self.check_ins.lock().unwrap_or_else(|e| e.into_inner());
+        match check_ins.next(Some(Instant::now() + timeout))? {
+            Some(check_in) => check_in.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Block until the process `pid` sends its task port, failing with
+    /// `SpawnTaskPortError::ReceiveTimeout` if it hasn't within `timeout`.
+    ///
+    /// Task ports from other processes that arrive meanwhile are kept for
+    /// `receive`, and failed check-ins from them are dropped.
+    pub fn receive_from(&self, pid: u32, timeout: Option<Duration>) -> Result<TaskPort> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut check_ins = self.check_ins.lock().unwrap_or_else(|e| e.into_inner());
+        let mut set_aside = vec![];
+        let received = loop {
+            match check_ins.next(deadline) {
+                Ok(Some(Ok((sender, task)))) => {
+                    if sender == pid {
+                        break Ok(task);
+                    }
+                    set_aside.push((sender, task));
+                }
+                Ok(Some(Err(e))) => {
+                    let from_pid = match SpawnTaskPortError::from_io(&e) {
+                        Some(&SpawnTaskPortError::AuditMismatch { expected, .. }) => {
+                            expected == pid
+                        }
+                        _ => false,
+                    };
+                    if from_pid {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => break Err(SpawnTaskPortError::ReceiveTimeout.into()),
+                Err(e) => break Err(e),
+            }
+        };
+        check_ins.pending.extend(set_aside);
+        received
+    }
+}
+
+impl fmt::Debug for XpcTaskPortReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("XpcTaskPortReceiver").field("listener", &self.listener).finish()
+    }
+}
+
+impl Drop for XpcTaskPortReceiver {
+    fn drop(&mut self) {
+        unsafe {
+            xpc_release(self.endpoint);
+            xpc_connection_cancel(self.listener);
+            xpc_release(self.listener);
+        }
+    }
+}
+
+/// Ignore events on the child's connection; the reply is all it needs.
+unsafe extern "C" fn ignore_event(_block: *mut HandlerBlock, _event: xpc_object_t) {}
+
+/// Send the current process' task port to the `XpcTaskPortReceiver` whose
+/// endpoint is `endpoint`, returning once it has been received.
+///
+/// # Safety
+///
+/// `endpoint` must be a valid `xpc_endpoint_t`. It remains owned by the
+/// caller.
+pub unsafe fn send_task_port_xpc(endpoint: xpc_object_t) -> Result<()> {
+    let connection = xpc_connection_create_from_endpoint(endpoint);
+    if connection.is_null() {
+        return Err(Error::new(ErrorKind::InvalidInput, "not an XPC endpoint"));
+    }
+    set_event_handler(connection, ignore_event, ptr::null());
+    xpc_connection_resume(connection);
+    let message = xpc_dictionary_create(ptr::null(), ptr::null(), 0);
+    xpc_dictionary_set_mach_send(message,
+                                 TASK_PORT_KEY.as_ptr() as *const c_char,
+                                 mach_task_self());
+    let reply = xpc_connection_send_message_with_reply_sync(connection, message);
+    let delivered = is_type(reply, &_xpc_type_dictionary);
+    xpc_release(reply);
+    xpc_release(message);
+    xpc_connection_cancel(connection);
+    xpc_release(connection);
+    if !delivered {
+        return Err(Error::new(ErrorKind::ConnectionRefused, "the XPC listener went away"));
+    }
+    Ok(())
+}
+
+/// An extension to `std::process::Command` to get a child's task port over
+/// XPC instead of through the bootstrap server.
+pub trait XpcSpawnWithTask {
+    /// Executes the command as a child process, then calls `deliver` with
+    /// the child and `receiver`'s endpoint to get the endpoint to the
+    /// child, and waits up to `timeout` for the child to send its task
+    /// port with `send_task_port_xpc`.
+    ///
+    /// If `deliver` fails, or the task port doesn't arrive, the child is
+    /// killed and reaped.
+    fn spawn_with_task_port_xpc<F>(&mut self,
+                                   receiver: &XpcTaskPortReceiver,
+                                   timeout: Option<Duration>,
+                                   deliver: F)
+                                   -> Result<(Child, TaskPort)>
+        where F: FnOnce(&Child, xpc_object_t) -> Result<()>;
+}
+
+impl XpcSpawnWithTask for Command {
+    fn spawn_with_task_port_xpc<F>(&mut self,
+                                   receiver: &XpcTaskPortReceiver,
+                                   timeout: Option<Duration>,
+                                   deliver: F)
+                                   -> Result<(Child, TaskPort)>
+        where F: FnOnce(&Child, xpc_object_t) -> Result<()>
+    {
+        let mut child = self.spawn().map_err(|e| Error::from(SpawnTaskPortError::Spawn(e)))?;
+        let received = deliver(&child, receiver.endpoint())
+            .and_then(|()| receiver.receive_from(child.id(), timeout));
+        match received {
+            Ok(task) => Ok((child, task)),
+            Err(e) => {
+                child.abandon();
+                Err(e)
+            }
+        }
+    }
+}
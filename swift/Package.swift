@@ -0,0 +1,18 @@
+// swift-tools-version:5.5
+import PackageDescription
+
+let package = Package(
+    name: "SpawnTaskPort",
+    platforms: [.macOS(.v10_13)],
+    products: [
+        .library(name: "SpawnTaskPort", targets: ["SpawnTaskPort"]),
+    ],
+    targets: [
+        // The Rust static library, built with
+        // `cargo rustc --release --features ffi --crate-type staticlib`.
+        // Pass its directory to the linker, e.g.
+        // `swift build -Xlinker -L../target/release`.
+        .systemLibrary(name: "CSpawnTaskPort"),
+        .target(name: "SpawnTaskPort", dependencies: ["CSpawnTaskPort"]),
+    ]
+)
@@ -0,0 +1,50 @@
+/* C API for the spawn-task-port crate, built with the `ffi` feature.
+ *
+ * Functions that can fail return 0 on success, or an errno value on
+ * failure (EIO for Mach errors, which have no errno equivalent). */
+
+#ifndef SPAWN_TASK_PORT_H
+#define SPAWN_TASK_PORT_H
+
+#include <mach/mach.h>
+#include <stddef.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A spawned child process and its task port. */
+typedef struct stp_child stp_child;
+
+/* Spawn `path` with the `nargs` arguments in `args`, storing a new
+ * `stp_child` in `*out_child` on success. The child inherits the caller's
+ * stdio. Free the result with `stp_child_free`. */
+int stp_spawn(const char *path,
+              const char *const *args,
+              size_t nargs,
+              stp_child **out_child);
+
+/* The child's process ID. */
+pid_t stp_child_pid(const stp_child *child);
+
+/* The child's task port, which remains owned by `child`. Use
+ * `mach_port_mod_refs` to add a reference if it needs to outlive it. */
+mach_port_t stp_child_task_port(const stp_child *child);
+
+/* Forcibly kill the child. */
+int stp_child_kill(stp_child *child);
+
+/* Wait for the child to exit, storing its raw wait status (as from
+ * `waitpid`) in `*out_status` if that isn't NULL. */
+int stp_child_wait(stp_child *child, int *out_status);
+
+/* Free `child`, deallocating its task port. This doesn't wait for or kill
+ * the child process. Passing NULL is allowed. */
+void stp_child_free(stp_child *child);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SPAWN_TASK_PORT_H */
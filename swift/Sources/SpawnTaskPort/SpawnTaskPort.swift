@@ -0,0 +1,70 @@
+import CSpawnTaskPort
+import Darwin
+import Foundation
+
+/// A child process spawned so that its Mach task port is available to the
+/// parent.
+///
+/// ```swift
+/// let helper = try TaskPortProcess(
+///     executableURL: URL(fileURLWithPath: "/path/to/helper"),
+///     arguments: ["--flag"])
+/// print(helper.processIdentifier, helper.taskPort)
+/// let status = try helper.waitUntilExit()
+/// ```
+public final class TaskPortProcess {
+    private let child: OpaquePointer
+
+    /// Launch `executableURL` with `arguments`, inheriting this process'
+    /// standard input, output and error.
+    public init(executableURL: URL, arguments: [String] = []) throws {
+        let cArgs: [UnsafePointer<CChar>?] = arguments.map { UnsafePointer(strdup($0)) }
+        defer {
+            for arg in cArgs {
+                free(UnsafeMutablePointer(mutating: arg))
+            }
+        }
+        var out: OpaquePointer?
+        let err = cArgs.withUnsafeBufferPointer { args in
+            stp_spawn(executableURL.path, args.baseAddress, args.count, &out)
+        }
+        guard err == 0, let child = out else {
+            throw POSIXError(POSIXErrorCode(rawValue: err) ?? .EIO)
+        }
+        self.child = child
+    }
+
+    deinit {
+        stp_child_free(child)
+    }
+
+    /// The child's process ID.
+    public var processIdentifier: pid_t {
+        return stp_child_pid(child)
+    }
+
+    /// The child's task port. It stays owned by this object, so add a
+    /// reference with `mach_port_mod_refs` if you need it to outlive it.
+    public var taskPort: mach_port_t {
+        return stp_child_task_port(child)
+    }
+
+    /// Forcibly kill the child.
+    public func terminate() throws {
+        let err = stp_child_kill(child)
+        if err != 0 {
+            throw POSIXError(POSIXErrorCode(rawValue: err) ?? .EIO)
+        }
+    }
+
+    /// Block until the child exits, returning its raw wait status.
+    @discardableResult
+    public func waitUntilExit() throws -> Int32 {
+        var status: Int32 = 0
+        let err = stp_child_wait(child, &status)
+        if err != 0 {
+            throw POSIXError(POSIXErrorCode(rawValue: err) ?? .EIO)
+        }
+        return status
+    }
+}
@@ -0,0 +1,58 @@
+//! The bootstrap port swap, and spawning into a bootstrap subset, change
+//! the process' bootstrap port while they spawn, which other tests spawning
+//! at the same time would inherit, so they run in a test binary of their
+//! own.
+
+extern crate spawn_task_port;
+
+use spawn_task_port::{BootstrapSubset, BootstrapSwapSpawnWithTask, CommandSpawnWithTask, Heartbeat,
+                      SpawnOptions};
+use spawn_task_port::child::send_task_port;
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn test_process_path() -> Option<PathBuf> {
+    env::current_exe()
+        .ok()
+        .and_then(|p| {
+            p.parent().map(|p| {
+                p.with_file_name("test")
+                    .with_extension(env::consts::EXE_EXTENSION)
+            })
+        })
+}
+
+#[test]
+fn test_spawn_with_task_port_by_swap() {
+    // The child looks up the heartbeat service, which only works once it
+    // has its real bootstrap port back.
+    let heartbeat = Heartbeat::new().expect("failed to create heartbeat");
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = heartbeat
+        .configure(Command::new(&path).arg("heartbeat").stdin(Stdio::null()))
+        .spawn_with_task_port_by_swap()
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap(), child.id());
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+    assert!(heartbeat.last_heartbeat().is_some());
+}
+
+#[test]
+fn test_spawn_in_bootstrap_subset() {
+    let subset = BootstrapSubset::new().expect("failed to create bootstrap subset");
+    let name = format!("spawn-task-port.test.subset.{}", std::process::id());
+    let mut options = SpawnOptions::new();
+    options.service_name(&name).bootstrap_port(subset.as_raw());
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_get_task_port_with(&options)
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap(), child.id());
+    // The name only went into the subset, so it can't be found from here.
+    assert!(send_task_port(&name).is_err());
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
@@ -1,13 +1,54 @@
+#[cfg(feature = "command-group")]
+extern crate command_group;
+#[cfg(feature = "duct")]
+#[macro_use]
+extern crate duct;
+#[cfg(feature = "async-process")]
+extern crate futures_lite;
 extern crate libc;
 extern crate mach;
+#[cfg(feature = "mio")]
+extern crate mio;
+#[cfg(feature = "nix")]
+extern crate nix;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 extern crate spawn_task_port;
+#[cfg(feature = "sysinfo")]
+extern crate sysinfo;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
 use mach::kern_return::{kern_return_t, KERN_SUCCESS};
-use mach::types::task_t;
-use spawn_task_port::CommandSpawnWithTask;
+use mach::port::{mach_port_name_t, mach_port_t, MACH_PORT_RIGHT_SEND};
+use mach::traps::mach_task_self;
+use mach::types::{ipc_space_t, task_t};
+use mach::vm::mach_vm_deallocate;
+use spawn_task_port::{Architecture, BrokerEvent, Capabilities, ChildSnapshot, ChildStatus,
+                      CommandSpawnWithTask, CorePreference, EnvScrub, ErrorClass, ExceptionKind,
+                      ExceptionMask, ExceptionServer, ForkServer, Heartbeat, HostExceptionMonitor,
+                      IdentityTokenReceiver, IsolatedSpawnWithTask, Isolation,
+                      LaunchdHelperReceiver, MachPortBroker, MemoryThresholds, MemoryWatchdog,
+                      OsVersion, PortDisposition, PortRights, PosixSpawnOptions, PosixSpawnWithTask,
+                      PreparedSpawn, Problem, ProcessType, RegistrationMethod, RemoteMemory,
+                      RetryPolicy, RightCounts, SendTimeoutAction, SessionSpawnWithTask,
+                      SessionTarget, SharedMemory, SharedRingBuffer, ShutdownChannel,
+                      ShutdownOutcome, SpawnContext, SpawnMiddleware, SpawnOptions,
+                      SpawnTaskPortError, SyscallTracer, TaskFlavor, TaskPort, TaskPortCommand,
+                      TrailerType, VmTag, WatchKind, WatchdogAction, add_spawn_middleware, audit,
+                      capabilities, diagnostics, doctor, dump_port_info, parse_port_name, self_test,
+                      system, task_port_for_pid, task_port_rights, watchpoint_count};
 use std::env;
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
+use std::ptr;
+use std::sync::{mpsc, Arc, Barrier};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
 
 fn test_process_path() -> Option<PathBuf> {
     env::current_exe()
@@ -22,6 +63,59 @@ fn test_process_path() -> Option<PathBuf> {
 
 extern "C" {
     fn pid_for_task(task: task_t, pid: *mut libc::c_int) -> kern_return_t;
+    fn pthread_threadid_np(thread: *mut libc::c_void, id: *mut u64) -> libc::c_int;
+    fn mach_thread_self() -> mach_port_t;
+    fn mach_port_mod_refs(task: ipc_space_t,
+                          name: mach_port_name_t,
+                          right: u32,
+                          delta: libc::c_int)
+                          -> kern_return_t;
+    fn mach_port_names(task: ipc_space_t,
+                       names: *mut *mut mach_port_name_t,
+                       names_count: *mut u32,
+                       types: *mut *mut u32,
+                       types_count: *mut u32)
+                       -> kern_return_t;
+}
+
+/// The number of port names in this task's IPC space.
+fn port_name_count() -> usize {
+    unsafe {
+        let mut names = ptr::null_mut();
+        let mut names_count = 0;
+        let mut types = ptr::null_mut();
+        let mut types_count = 0;
+        assert_eq!(KERN_SUCCESS,
+                   mach_port_names(mach_task_self(),
+                                   &mut names,
+                                   &mut names_count,
+                                   &mut types,
+                                   &mut types_count));
+        mach_vm_deallocate(mach_task_self(),
+                           names as u64,
+                           (names_count as usize * mem::size_of::<mach_port_name_t>()) as u64);
+        mach_vm_deallocate(mach_task_self(),
+                           types as u64,
+                           (types_count as usize * mem::size_of::<u32>()) as u64);
+        names_count as usize
+    }
+}
+
+/// Spawn the test process through `broker`, check that we got its task
+/// port, and wait for it to exit.
+fn broker_spawn_and_wait(broker: &MachPortBroker, path: &Path) {
+    // The child blocks reading stdin until `wait` closes it, so it's still
+    // running when we check its task port.
+    let (mut child, task_port) = broker.spawn(Command::new(path).stdin(Stdio::piped()))
+        .expect("failed to spawn child");
+    unsafe {
+        let mut pid = 0;
+        assert_eq!(KERN_SUCCESS, pid_for_task(task_port.as_raw(), &mut pid));
+        assert_eq!(pid as u32, child.id());
+    }
+    drop(task_port);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success(), "Child should have exited normally");
 }
 
 #[test]
@@ -30,16 +124,2107 @@ fn test_process_pid() {
     let (mut child, task_port) = Command::new(&path)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn_get_task_port()
+        .spawn_with_task_port()
         .expect("failed to spawn child");
     // Simplest use of the task port I could come up with--just
     // ask for its PID and compare with the value from the `fork`.
     unsafe {
         let mut pid = 0;
-        assert_eq!(KERN_SUCCESS, pid_for_task(task_port, &mut pid));
+        assert_eq!(KERN_SUCCESS, pid_for_task(task_port.as_raw(), &mut pid));
         assert_eq!(pid as u32, child.id());
     }
     // wait will close the child's stdin, so it will terminate.
     let status = child.wait().expect("failed to wait for child");
     assert!(status.success(), "Child should have exited normally");
 }
+
+#[test]
+fn test_broker_concurrent_spawns() {
+    let path = test_process_path().unwrap();
+    let broker = Arc::new(MachPortBroker::new().expect("failed to create broker"));
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let broker = broker.clone();
+            let path = path.clone();
+            thread::spawn(move || broker_spawn_and_wait(&broker, &path))
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(broker.pending(), 0);
+    assert_eq!(broker.rejected(), 0);
+}
+
+#[test]
+fn test_concurrent_spawns() {
+    const THREADS: usize = 64;
+    let path = test_process_path().unwrap();
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let barrier = barrier.clone();
+            let path = path.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                let (mut child, task_port) = Command::new(&path)
+                    .stdin(Stdio::piped())
+                    .spawn_with_task_port()
+                    .expect("failed to spawn child");
+                // Each thread must get its own child's task port.
+                unsafe {
+                    let mut pid = 0;
+                    assert_eq!(KERN_SUCCESS, pid_for_task(task_port.as_raw(), &mut pid));
+                    assert_eq!(pid as u32, child.id());
+                }
+                child.wait().expect("failed to wait for child");
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+// This spawns 10,000 children, so it's too slow to run by default. Run it
+// with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn test_broker_stress_no_port_leaks() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    // Spawn once up front so any ports that get allocated lazily on the
+    // first spawn don't count as a leak.
+    broker_spawn_and_wait(&broker, &path);
+    // The broker remembers the task ports of exited children until enough
+    // pile up to be worth pruning, so only count the names it doesn't hold.
+    let before = port_name_count() - broker.remembered();
+    for _ in 0..10000 {
+        broker_spawn_and_wait(&broker, &path);
+    }
+    assert_eq!(broker.pending(), 0);
+    assert_eq!(port_name_count() - broker.remembered(), before);
+}
+
+#[test]
+fn test_fork_server_workers() {
+    let path = test_process_path().unwrap();
+    // Each worker exits once it has read a line from the stdin it shares
+    // with the server, so that it is still running while we look at it.
+    let mut server = ForkServer::spawn(Command::new(&path)
+            .arg("fork-worker")
+            .stdin(Stdio::piped()))
+        .expect("failed to spawn fork server");
+    let mut stdin = server.server().stdin.take().unwrap();
+    for _ in 0..3 {
+        let (pid, task_port) = server.fork().expect("failed to fork worker");
+        assert!(pid != server.server().id());
+        unsafe {
+            let mut task_pid = 0;
+            assert_eq!(KERN_SUCCESS, pid_for_task(task_port.as_raw(), &mut task_pid));
+            assert_eq!(task_pid as u32, pid);
+        }
+        drop(task_port);
+        stdin.write_all(b"\n").unwrap();
+        let status = server.wait().expect("failed to wait for worker");
+        assert!(status.success(), "Worker should have exited normally");
+    }
+    // Dropping the server kills and reaps it.
+    let server_pid = server.server().id();
+    drop(server);
+    assert_eq!(unsafe { libc::kill(server_pid as libc::pid_t, 0) }, -1);
+}
+
+#[test]
+fn test_spawn_with_task() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().pid().unwrap(), child.id());
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success(), "Child should have exited normally");
+}
+
+#[cfg(feature = "nix")]
+#[test]
+fn test_nix_signal() {
+    use nix::sys::signal::Signal;
+    use std::os::unix::process::ExitStatusExt;
+
+    let path = test_process_path().unwrap();
+    // The child blocks reading stdin until it's signalled.
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().nix_pid().unwrap(), child.nix_pid());
+    child.signal(Signal::SIGTERM).expect("failed to signal child");
+    let status = child.wait().expect("failed to wait for child");
+    assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+}
+
+#[cfg(feature = "command-group")]
+#[test]
+fn test_group_spawn() {
+    use spawn_task_port::GroupSpawnWithTask;
+
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .group_spawn_get_task_port()
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap(), child.id());
+    // The child leads its own process group. It's still running, since it
+    // blocks reading stdin until `wait` closes it.
+    assert_eq!(unsafe { libc::getpgid(child.id() as libc::pid_t) }, child.id() as libc::pid_t);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success(), "Child should have exited normally");
+}
+
+#[cfg(feature = "command-group")]
+#[test]
+fn test_broker_group_spawn_with_members() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (mut child, task_port) = broker.group_spawn_with_members(Command::new(&path)
+            .arg("spawn-descendant")
+            .stdin(Stdio::null()))
+        .expect("failed to spawn child");
+    let pid = child.id();
+    assert_eq!(task_port.pid().unwrap(), pid);
+    drop(task_port);
+    assert!(child.wait().expect("failed to wait for child").success());
+    // The grandchild joined the leader's group and checked in under it.
+    let descendants = broker.descendants(pid).expect("failed to list descendants");
+    assert_eq!(descendants.len(), 1);
+    let (member, ref member_port) = descendants[0];
+    assert_eq!(unsafe { libc::getpgid(member as libc::pid_t) }, pid as libc::pid_t);
+    member_port.terminate().expect("failed to terminate group member");
+    assert_eq!(broker.pending(), 0);
+}
+
+#[test]
+fn test_task_info() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    {
+        let task = child.task_port();
+        let basic = task.basic_info().expect("failed to get basic info");
+        assert!(basic.resident_size > 0);
+        assert!(basic.virtual_size >= basic.resident_size);
+        let vm = task.vm_info().expect("failed to get VM info");
+        assert!(vm.phys_footprint > 0);
+        assert!(vm.region_count > 0);
+        task.thread_times().expect("failed to get thread times");
+    }
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success(), "Child should have exited normally");
+}
+
+#[cfg(feature = "sysinfo")]
+#[test]
+fn test_sysinfo_extended_info() {
+    use spawn_task_port::ProcessTaskExt;
+    use sysinfo::{Pid, System};
+
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    {
+        let pid = Pid::from_u32(child.id());
+        let mut system = System::new();
+        assert!(system.refresh_process(pid));
+        let info = system.process(pid)
+            .unwrap()
+            .extended_info(child.task_port())
+            .expect("failed to get extended info");
+        assert_eq!(info.pid, child.id());
+        assert!(info.phys_footprint > 0);
+    }
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success(), "Child should have exited normally");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize_info() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let info = child.task_port().vm_info().expect("failed to get VM info");
+    let json = serde_json::to_value(info).unwrap();
+    assert_eq!(json["phys_footprint"], info.phys_footprint);
+    let json = serde_json::to_value(Capabilities::detect()).unwrap();
+    assert!(json["bootstrap_register2"].is_boolean());
+    drop(child.child_mut().stdin.take());
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success(), "Child should have exited normally");
+}
+
+#[cfg(feature = "xpc")]
+#[test]
+fn test_xpc_handshake() {
+    use spawn_task_port::{send_task_port_xpc, XpcTaskPortReceiver};
+
+    let receiver = XpcTaskPortReceiver::new().expect("failed to create XPC receiver");
+    // A real child would get the endpoint over an XPC connection; here the
+    // test sends its own task port from another thread.
+    let endpoint = receiver.endpoint() as usize;
+    let sender = thread::spawn(move || unsafe { send_task_port_xpc(endpoint as *mut _) });
+    let (pid, task) = receiver.receive_timeout(Duration::from_secs(10))
+        .expect("failed to receive task port")
+        .expect("timed out waiting for task port");
+    sender.join().unwrap().expect("failed to send task port");
+    assert_eq!(pid, std::process::id());
+    assert_eq!(task.pid().unwrap(), pid);
+}
+
+#[cfg(feature = "xpc")]
+#[test]
+fn test_xpc_receive_from() {
+    use spawn_task_port::{send_task_port_xpc, XpcSpawnWithTask, XpcTaskPortReceiver};
+
+    let receiver = XpcTaskPortReceiver::new().expect("failed to create XPC receiver");
+    let endpoint = receiver.endpoint() as usize;
+    let sender = thread::spawn(move || unsafe { send_task_port_xpc(endpoint as *mut _) });
+    // Waiting for another process sets our own check-in aside.
+    let err = receiver.receive_from(1, Some(Duration::from_secs(2)))
+        .expect_err("received a task port from the wrong process");
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    sender.join().unwrap().expect("failed to send task port");
+    let (pid, _task) = receiver.receive_timeout(Duration::from_secs(0))
+        .expect("failed to receive task port")
+        .expect("check-in wasn't kept");
+    assert_eq!(pid, std::process::id());
+
+    // A child the endpoint can't be delivered to is killed.
+    let path = test_process_path().unwrap();
+    let err = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_task_port_xpc(&receiver, None, |_, _| {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "no connection to the child"))
+        })
+        .expect_err("spawned without delivering the endpoint");
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+}
+
+#[cfg(feature = "duct")]
+#[test]
+fn test_duct_pipeline() {
+    use spawn_task_port::ExpressionSpawnWithTask;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    let path = test_process_path().unwrap();
+    // Hold the write end of the first child's stdin so that both children
+    // keep running until we close it.
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let (stdin, stdin_write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    let (handle, task_ports) = cmd!(&path)
+        .stdin_file(stdin)
+        .pipe(cmd!(&path))
+        .spawn_with_task_ports()
+        .expect("failed to start expression");
+    let pids = handle.pids();
+    assert_eq!(pids.len(), 2);
+    assert_eq!(task_ports.len(), 2);
+    for (pid, task_port) in pids.iter().zip(task_ports.iter()) {
+        assert_eq!(task_port.pid().unwrap(), *pid);
+    }
+    drop(stdin_write);
+    handle.wait().expect("failed to wait for expression");
+}
+
+#[cfg(feature = "async-process")]
+#[test]
+fn test_async_process_spawn_with_task_port() {
+    use spawn_task_port::AsyncCommandSpawnWithTask;
+
+    let path = test_process_path().unwrap();
+    let mut command = Command::new(&path).into_async_with_task_port()
+        .expect("failed to prepare command");
+    command.command_mut().stdin(Stdio::null());
+    let (mut child, task_port) = futures_lite::future::block_on(command.spawn_with_task_port())
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap(), child.id());
+    futures_lite::future::block_on(child.status()).expect("failed to wait for child");
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_tokio_spawn_with_task_port() {
+    use spawn_task_port::TokioCommandSpawnWithTask;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // Spawning needs a runtime to register the child with.
+    let _guard = runtime.enter();
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = runtime.block_on(tokio::process::Command::new(&path)
+            .stdin(Stdio::null())
+            .spawn_with_task_port())
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap(), child.id().unwrap());
+    runtime.block_on(child.wait()).expect("failed to wait for child");
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_tokio_recv_async() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let _guard = runtime.enter();
+    let path = test_process_path().unwrap();
+    let (mut child, receiver) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_receiver()
+        .expect("failed to spawn child");
+    let task_port = runtime.block_on(receiver.recv_async()).expect("failed to receive task port");
+    assert_eq!(task_port.pid().unwrap_or(child.id()), child.id());
+    child.wait().expect("failed to wait for child");
+}
+
+#[cfg(feature = "mio")]
+#[test]
+fn test_mio_receiver() {
+    use mio::{Events, Interest, Poll, Token};
+
+    let path = test_process_path().unwrap();
+    let (mut child, mut receiver) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_receiver()
+        .expect("failed to spawn child");
+    let mut poll = Poll::new().unwrap();
+    poll.registry()
+        .register(&mut receiver, Token(7), Interest::READABLE)
+        .expect("failed to register receiver");
+    let mut events = Events::with_capacity(1);
+    poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+    let event = events.iter().next().expect("child never checked in");
+    assert_eq!(event.token(), Token(7));
+    let task_port = receiver.try_recv()
+        .expect("failed to poll for task port")
+        .expect("receiver was readable before the child checked in");
+    assert_eq!(task_port.pid().unwrap_or(child.id()), child.id());
+    poll.registry().deregister(&mut receiver).unwrap();
+    child.wait().expect("failed to wait for child");
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn test_ffi_spawn() {
+    use mach::port::MACH_PORT_NULL;
+    use spawn_task_port::ffi::*;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(test_process_path().unwrap().as_os_str().as_bytes()).unwrap();
+    let mut child = ptr::null_mut();
+    unsafe {
+        assert_eq!(stp_spawn(path.as_ptr(), ptr::null(), 0, &mut child), 0);
+        // The child inherits our stdin, so it may or may not have exited
+        // already; either way it hasn't been reaped yet.
+        assert!(stp_child_pid(child) > 0);
+        assert!(stp_child_task_port(child) != MACH_PORT_NULL);
+        assert_eq!(stp_child_kill(child), 0);
+        assert_eq!(stp_child_wait(child, ptr::null_mut()), 0);
+        stp_child_free(child);
+    }
+}
+
+#[test]
+fn test_coalition_info() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let ids = child.task_port().coalition_ids().expect("failed to get coalition ids");
+    // Children join their parent's coalitions.
+    let own = spawn_task_port::coalition_ids(std::process::id()).unwrap();
+    assert_eq!(ids, own);
+    // Reading usage may need privileges the tests don't have.
+    if let Ok(usage) = child.task_port().coalition_resource_usage() {
+        assert!(usage.tasks_started >= 2);
+    }
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_core_placement() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let threads = child.task_port().threads().expect("failed to get threads");
+    assert!(!threads.is_empty());
+    threads[0].qos_class().expect("failed to get QoS class");
+    child.task_port()
+        .set_core_preference(CorePreference::Efficiency)
+        .expect("failed to set core preference");
+    // Performance core times need macOS 12.
+    if let Ok(usage) = child.task_port().core_usage() {
+        assert!(usage.performance <= usage.total);
+    }
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_handshake_diagnostics() {
+    diagnostics::set_enabled(true);
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let log = diagnostics::last_handshake_log().expect("no transcript was recorded");
+    let register = log.calls
+        .iter()
+        .find(|c| c.call.starts_with("bootstrap_register2"))
+        .expect("bootstrap_register2 wasn't recorded");
+    // The service name is shown as a string, the flags as a number.
+    assert_eq!(register.arguments.len(), 4);
+    assert!(register.arguments[1].starts_with('"'));
+    assert_eq!(register.arguments[3], "0x0");
+    assert!(log.calls.iter().all(|c| c.result == KERN_SUCCESS));
+    child.wait().expect("failed to wait for child");
+
+    // A failed handshake carries its transcript in the error.
+    let err = Command::new("/nonexistent/spawn-task-port-test")
+        .spawn_with_task_port()
+        .unwrap_err();
+    let handshake_err = err.get_ref()
+        .and_then(|e| e.downcast_ref::<diagnostics::HandshakeError>())
+        .expect("error should carry a transcript");
+    assert_eq!(handshake_err.error.kind(), io::ErrorKind::NotFound);
+    assert!(!handshake_err.log.calls.is_empty());
+}
+
+#[test]
+fn test_spawn_audit() {
+    audit::set_enabled(true);
+    let path = test_process_path().unwrap();
+    let (mut child, _task_port) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_task_port()
+        .expect("failed to spawn child");
+    let spawn = audit::last_spawn_audit().expect("no audit was recorded");
+    assert_eq!(spawn.pid(), Some(child.id()));
+    // By default, the name is unregistered once the handshake is over.
+    assert!(spawn.is_clean());
+    child.wait().expect("failed to wait for child");
+
+    let (mut child, _task_port) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_get_task_port_with(SpawnOptions::new().unregister(false))
+        .expect("failed to spawn child");
+    // Otherwise it stays registered, along with its receive right.
+    let spawn = audit::last_spawn_audit().expect("no audit was recorded");
+    let kinds = spawn.outstanding().iter().map(|r| r.kind).collect::<Vec<_>>();
+    assert_eq!(kinds,
+               vec![audit::ResourceKind::ReceiveRight, audit::ResourceKind::ServiceName]);
+    child.wait().expect("failed to wait for child");
+
+    let (mut child, receiver) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_receiver()
+        .expect("failed to spawn child");
+    let spawn = audit::last_spawn_audit().expect("no audit was recorded");
+    assert!(spawn.resources().iter().any(|r| r.kind == audit::ResourceKind::Kqueue));
+    receiver.recv(None).expect("failed to receive task port");
+    assert!(!spawn.is_clean());
+    drop(receiver);
+    assert!(spawn.is_clean());
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_capabilities() {
+    let caps = Capabilities::detect();
+    // Every other test here relies on these.
+    assert!(caps.bootstrap_register2);
+    assert!(caps.bootstrap_check_in);
+    assert!(caps.audit_trailers);
+    let version = caps.os_version.expect("failed to get the macOS version");
+    assert!(version >= OsVersion::new(10, 0, 0));
+    if version >= OsVersion::new(11, 0, 0) {
+        assert!(caps.task_read_port);
+        assert!(caps.task_inspect_port);
+    }
+}
+
+#[test]
+fn test_doctor() {
+    let path = test_process_path().unwrap();
+    let report = doctor(&path);
+    assert!(!report.likely_to_fail(), "{}", report);
+
+    let report = doctor("/nonexistent/spawn-task-port-test");
+    assert!(report.likely_to_fail());
+    assert!(report.problems.iter().any(|p| matches!(*p, Problem::TargetNotFound(_))));
+}
+
+#[test]
+fn test_self_test() {
+    self_test().expect("self-test failed");
+}
+
+#[test]
+fn test_sip_status() {
+    let sip = system::sip_status();
+    assert_eq!(sip.enabled, doctor("/bin/ls").sip_enabled);
+    // SIP can't be fully enabled while allowing changes to protected files.
+    if sip.config.is_empty() {
+        assert!(sip.enabled);
+        assert!(!sip.is_custom());
+    }
+    if let Some(version) = Capabilities::detect().os_version {
+        if version < OsVersion::new(13, 0, 0) {
+            assert_eq!(system::developer_mode_enabled(), None);
+        }
+    }
+}
+
+#[test]
+fn test_os_version_gating() {
+    // This runs the OS version-dependent fallbacks on whatever release the
+    // tests run on; see `capabilities::OLDEST_SUPPORTED`.
+    let version = OsVersion::current().expect("failed to get the macOS version");
+    assert!(version >= capabilities::OLDEST_SUPPORTED);
+
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    child.task_port().basic_info().expect("failed to get basic info");
+    let vm = child.task_port().vm_info().expect("failed to get VM info");
+    assert!(vm.phys_footprint > 0);
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_identity_token() {
+    if !Capabilities::detect().identity_tokens {
+        return;
+    }
+    let path = test_process_path().unwrap();
+    let (mut child, token) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_identity_token()
+        .expect("failed to spawn child");
+    for &flavor in &[TaskFlavor::Control, TaskFlavor::Read, TaskFlavor::Inspect] {
+        let task_port = token.task_port(flavor).expect("failed to convert token");
+        assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    }
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_spawn_get_task_name_port() {
+    let path = test_process_path().unwrap();
+    let (mut child, name_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_get_task_name_port()
+        .expect("failed to spawn child");
+    assert_eq!(name_port.pid().expect("failed to get pid"), child.id());
+    // A name port can't be used to control the task.
+    assert!(name_port.suspend().is_err());
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_spawn_unregisters_service() {
+    use spawn_task_port::child::send_task_port;
+
+    let path = test_process_path().unwrap();
+    let name = format!("spawn-task-port.test.unregister.{}", std::process::id());
+    let mut options = SpawnOptions::new();
+    options.service_name(&name);
+    // A name left registered by one spawn would fail the next.
+    for _ in 0..3 {
+        let (mut child, task_port) = Command::new(&path)
+            .stdin(Stdio::null())
+            .spawn_get_task_port_with(&options)
+            .expect("failed to spawn child");
+        assert_eq!(task_port.pid().unwrap_or(child.id()), child.id());
+        assert!(send_task_port(&name).is_err(), "{} is still registered", name);
+        child.wait().expect("failed to wait for child");
+    }
+}
+
+#[test]
+fn test_registration_check_in() {
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_get_task_port_with(SpawnOptions::new()
+            .registration_method(RegistrationMethod::CheckIn))
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap(), child.id());
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_spawn_options_task_flavor() {
+    if !Capabilities::detect().identity_tokens {
+        return;
+    }
+    let path = test_process_path().unwrap();
+    let mut options = SpawnOptions::new();
+    options.task_flavor(TaskFlavor::Inspect);
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_get_task_port_with(&options)
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task_flavor(TaskFlavor::Read)
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+
+    let mut posix_options = PosixSpawnOptions::new();
+    posix_options.task_flavor(TaskFlavor::Inspect);
+    let mut child = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_task_posix(&posix_options)
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().pid().expect("failed to get pid"), child.id());
+    assert!(child.wait().expect("failed to wait for child").success());
+    posix_options.start_suspended();
+    assert!(Command::new(&path).spawn_with_task_posix(&posix_options).is_err());
+
+    let (mut child, receiver) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_receiver_with(&options)
+        .expect("failed to spawn child");
+    let task_port = receiver.recv(None).expect("failed to receive task port");
+    assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[cfg(feature = "helper")]
+#[test]
+fn test_helper() {
+    let (mut child, task_port) = spawn_task_port::helper::helper_command()
+        .expect("failed to find helper")
+        .spawn_with_task_port()
+        .expect("failed to spawn helper");
+    assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_prepared_spawn() {
+    let path = test_process_path().unwrap();
+    let mut command = Command::new(&path);
+    command.stdin(Stdio::null());
+    let mut options = SpawnOptions::new();
+    options.service_name(&format!("spawn-task-port.test.prepared.{}", std::process::id()));
+    let mut prepared = PreparedSpawn::prepare(command, &options).expect("failed to prepare");
+    assert!(prepared.service_name().starts_with("spawn-task-port.test.prepared."));
+    let (mut child, task_port) = prepared.launch().expect("failed to launch");
+    assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    assert!(child.wait().expect("failed to wait for child").success());
+    // Launching again reuses the registration.
+    let (mut child, task_port) = prepared.launch().expect("failed to launch again");
+    assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    assert!(child.wait().expect("failed to wait for child").success());
+
+    let mut missing = PreparedSpawn::prepare(Command::new("/nonexistent"), &SpawnOptions::new())
+        .expect("failed to prepare");
+    assert!(missing.launch().is_err());
+}
+
+#[test]
+fn test_forward_identity_token() {
+    if !Capabilities::detect().identity_tokens {
+        return;
+    }
+    let receiver = IdentityTokenReceiver::new().expect("failed to register receiver");
+    let path = test_process_path().unwrap();
+    let (mut child, token) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_identity_token()
+        .expect("failed to spawn child");
+    token.forward_to(receiver.service_name()).expect("failed to forward token");
+    let (pid, forwarded) = receiver.receive().expect("failed to receive token");
+    assert_eq!(pid, child.id());
+    let task_port = forwarded.task_port(TaskFlavor::Read).expect("failed to convert token");
+    assert_eq!(task_port.pid().expect("failed to get pid"), child.id());
+    child.kill().expect("failed to kill child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_syscall_tracer() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .arg("bad-trap")
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let tracer = SyscallTracer::attach(child.task_port()).expect("failed to attach tracer");
+    // Closing stdin lets the child go on to make the trap.
+    drop(child.child_mut().stdin.take());
+    let event = tracer.next().expect("failed to receive trap");
+    assert_eq!(event.pid, child.id());
+    assert_eq!(event.kind, ExceptionKind::Syscall);
+    assert_eq!(event.number, 200);
+    assert_eq!(event.name(), None);
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_single_step() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let server = ExceptionServer::attach(child.task_port(), ExceptionMask::BREAKPOINT)
+        .expect("failed to attach exception server");
+    let thread = child.task_port().threads().expect("failed to get threads").remove(0);
+    thread.single_step(true).expect("failed to turn on single-stepping");
+    // The child is blocked reading stdin; closing it lets it run again.
+    drop(child.child_mut().stdin.take());
+    let mut pcs = Vec::new();
+    for i in 0..10 {
+        let event = server.receive().expect("failed to receive step");
+        assert!(event.is_single_step());
+        pcs.push(event.thread().program_counter().expect("failed to get pc"));
+        // Turn stepping off before resuming the last step, so no more
+        // exceptions are raised once the server is gone.
+        if i == 9 {
+            event.thread().single_step(false).expect("failed to turn off single-stepping");
+        }
+        event.resume().expect("failed to resume");
+    }
+    assert!(pcs.windows(2).any(|w| w[0] != w[1]));
+    drop(server);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_watchpoint_allocation() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let thread = child.task_port().threads().expect("failed to get threads").remove(0);
+    assert!(thread.watchpoints().expect("failed to get watchpoints").is_empty());
+    // The addresses don't need to be mapped; the child never touches them.
+    let watchpoints: Vec<_> = (0..watchpoint_count())
+        .map(|i| {
+            thread.set_watchpoint(0x1_0000_0000 + 0x100 * i as u64, 8, WatchKind::ReadWrite)
+                .expect("failed to set watchpoint")
+        })
+        .collect();
+    assert_eq!(thread.watchpoints().unwrap(), watchpoints);
+    assert!(thread.set_watchpoint(0x2_0000_0000, 8, WatchKind::Write).is_err());
+    thread.clear_watchpoint(&watchpoints[1]).expect("failed to clear watchpoint");
+    let reused = thread.set_watchpoint(0x2_0000_0000, 8, WatchKind::Write)
+        .expect("failed to set watchpoint");
+    assert_eq!(reused.index, watchpoints[1].index);
+    for watchpoint in thread.watchpoints().unwrap() {
+        thread.clear_watchpoint(&watchpoint).expect("failed to clear watchpoint");
+    }
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_tagged_memory() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let task = child.task_port();
+    let tag = VmTag::application_specific(3).unwrap();
+    let remote = RemoteMemory::allocate(task, 4096, tag).expect("failed to allocate memory");
+    remote.write(16, b"hello").expect("failed to write memory");
+    let mut buf = [0; 5];
+    remote.read(16, &mut buf).expect("failed to read memory");
+    assert_eq!(&buf, b"hello");
+    assert!(remote.write(4094, b"hello").is_err());
+    assert_eq!(task.vm_tag(remote.address()).unwrap(), tag);
+
+    let shared = SharedMemory::new(task, 4096, VmTag::DEFAULT).expect("failed to share memory");
+    unsafe {
+        *shared.as_ptr().offset(8) = 42;
+    }
+    let mut byte = [0];
+    task.read_memory(shared.remote_address() + 8, &mut byte).expect("failed to read memory");
+    assert_eq!(byte[0], 42);
+    assert_eq!(task.vm_tag(shared.remote_address()).unwrap(), VmTag::DEFAULT);
+    drop(remote);
+    drop(shared);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_read_vectored() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let task = child.task_port();
+    let remote = RemoteMemory::allocate(task, 4096, VmTag::DEFAULT)
+        .expect("failed to allocate memory");
+    remote.write(0, b"hello, world").expect("failed to write memory");
+    let base = remote.address();
+    // Address 0 is never mapped, so only that range fails.
+    let ranges = [(base + 7, 5), (base, 5), (0, 8), (base + 3, 4), (base + 4000, 0)];
+    let results = task.read_vectored(&ranges);
+    assert_eq!(results[0].as_ref().unwrap(), b"world");
+    assert_eq!(results[1].as_ref().unwrap(), b"hello");
+    assert!(results[2].is_err());
+    assert_eq!(results[3].as_ref().unwrap(), b"lo, ");
+    assert!(results[4].as_ref().unwrap().is_empty());
+    let results = task.read_vectored_suspended(&ranges[..2]).expect("failed to suspend task");
+    assert_eq!(results[1].as_ref().unwrap(), b"hello");
+    assert_eq!(task.basic_info().unwrap().suspend_count, 0);
+    drop(remote);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_shared_ring_buffer() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .arg("ring")
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let mut ring = SharedRingBuffer::new(child.task_port(), 4096)
+        .expect("failed to create ring buffer");
+    {
+        let stdin = child.child_mut().stdin.as_mut().unwrap();
+        write!(stdin, "{}", ring.remote_address()).unwrap();
+    }
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+    for i in 0..10 {
+        let record = ring.pop().expect("failed to pop").expect("missing record");
+        assert_eq!(record, format!("record {}", i).into_bytes());
+    }
+    assert_eq!(ring.pop().unwrap(), None);
+}
+
+#[test]
+fn test_donate_importance() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let donation = child.task_port().donate_importance().expect("failed to donate importance");
+    drop(donation);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_cpu_monitor() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    child.task_port()
+        .set_cpu_monitor(50, Duration::from_secs(60))
+        .expect("failed to set CPU monitor");
+    child.task_port().disable_cpu_monitor().expect("failed to disable CPU monitor");
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_throttle() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    assert!(child.task_port().throttle(0.0).is_err());
+    assert!(child.task_port().throttle(1.5).is_err());
+    let throttle = child.task_port()
+        .throttle_with_window(0.5, Duration::from_millis(20))
+        .expect("failed to throttle child");
+    assert_eq!(throttle.duty_cycle(), 0.5);
+    thread::sleep(Duration::from_millis(100));
+    drop(throttle);
+    // Stopping the throttle leaves the child running.
+    let info = child.task_port().basic_info().expect("failed to get task info");
+    assert_eq!(info.suspend_count, 0);
+    drop(child.child_mut().stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_spawn_for_debugger() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_for_debugger(|attach| {
+            assert!(attach.lldb_command().ends_with(&attach.pid().to_string()));
+            assert!(!attach.is_debugger_attached()?);
+            // Nothing is going to attach, so this times out.
+            let err = attach.wait_for_debugger(Some(Duration::from_millis(200))).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            Ok(())
+        })
+        .expect("failed to spawn child");
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_exit_details() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .arg("abort")
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    child.watch_for_crash().expect("failed to watch for crash");
+    assert_eq!(child.exit_details().unwrap(), None);
+    // The child aborts once its stdin is closed.
+    drop(child.child_mut().stdin.take());
+    child.wait().expect("failed to wait for child");
+    let details = child.exit_details().unwrap().expect("child should have exited");
+    assert_eq!(details.signal, Some(6));
+    let crash = details.crash.expect("child should have crashed");
+    assert_eq!(crash.signal, 6);
+    assert!(crash.thread_id.is_some());
+}
+
+#[test]
+fn test_task_port_for_pid() {
+    // `task_for_pid` always works on the current process.
+    let task = task_port_for_pid(std::process::id()).expect("failed to get own task port");
+    assert_eq!(task.pid().unwrap(), std::process::id());
+    // For a child, it takes root, or the fallback does.
+    if !Capabilities::detect().processor_set_tasks {
+        return;
+    }
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn child");
+    let task = task_port_for_pid(child.id()).expect("failed to find child's task port");
+    assert_eq!(task.pid().unwrap(), child.id());
+    drop(child.stdin.take());
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_thread_name() {
+    let (tx, rx) = std::sync::mpsc::channel::<()>();
+    let named = std::thread::Builder::new()
+        .name("named-for-test".to_string())
+        .spawn(move || rx.recv())
+        .unwrap();
+    let task = task_port_for_pid(std::process::id()).expect("failed to get own task port");
+    let names = task.threads()
+        .expect("failed to get threads")
+        .iter()
+        .map(|thread| thread.name().expect("failed to get thread name"))
+        .collect::<Vec<_>>();
+    assert!(names.contains(&Some("named-for-test".to_string())),
+            "no named thread in {:?}",
+            names);
+    drop(tx);
+    named.join().unwrap().unwrap_err();
+}
+
+#[test]
+fn test_thread_errno() {
+    let (id_tx, id_rx) = std::sync::mpsc::channel();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let failing = std::thread::spawn(move || {
+        let mut id = 0;
+        unsafe { pthread_threadid_np(std::ptr::null_mut(), &mut id) };
+        // Leaves `errno` set to `ENOENT`.
+        std::fs::File::open("/nonexistent").unwrap_err();
+        id_tx.send(id).unwrap();
+        done_rx.recv()
+    });
+    let id = id_rx.recv().unwrap();
+    let task = task_port_for_pid(std::process::id()).expect("failed to get own task port");
+    let thread = task.threads()
+        .expect("failed to get threads")
+        .into_iter()
+        .find(|thread| thread.id().expect("failed to get thread id") == id)
+        .expect("no thread with the id");
+    assert_eq!(task.thread_errno(&thread).expect("failed to read errno"),
+               Some(libc::ENOENT));
+    assert_eq!(task.dyld_error().expect("failed to read dyld error"), None);
+    drop(done_tx);
+    failing.join().unwrap().unwrap_err();
+}
+
+#[test]
+fn test_modules() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    // dyld only fills the list in once it has started the child.
+    let mut modules = Vec::new();
+    for _ in 0..50 {
+        modules = child.task_port().modules().expect("failed to list modules");
+        if modules.iter().any(|module| module.path.contains("libSystem")) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(Path::new(&modules[0].path).file_name(), path.file_name());
+    assert!(modules.iter().any(|module| module.path.contains("libSystem")));
+    drop(child.child_mut().stdin.take());
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_child_snapshot() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    // Wait for dyld to list the main executable, whose header to capture.
+    let mut header = None;
+    for _ in 0..50 {
+        let modules = child.task_port().modules().expect("failed to list modules");
+        if let Some(module) = modules.first() {
+            header = Some(module.load_address);
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let header = header.expect("no modules listed");
+    let snapshot = ChildSnapshot::capture(child.task_port(), &[(header, 32)])
+        .expect("failed to capture snapshot");
+    drop(child.child_mut().stdin.take());
+    child.wait().expect("failed to wait for child");
+
+    // The child is gone, but the snapshot can still be inspected.
+    let mut blob = Vec::new();
+    snapshot.write_to(&mut blob).expect("failed to write snapshot");
+    let snapshot = ChildSnapshot::read_from(&blob[..]).expect("failed to read snapshot");
+    assert!(!snapshot.threads().is_empty());
+    assert!(snapshot.threads().iter().all(|thread| !thread.registers().is_empty()));
+    assert_eq!(snapshot.modules()[0].load_address, header);
+    let mut magic = [0; 4];
+    snapshot.read_memory(header, &mut magic).expect("failed to read captured memory");
+    assert_eq!(u32::from_le_bytes(magic), 0xfeedfacf);
+    assert!(snapshot.read_memory(header + 32, &mut magic).is_err());
+}
+
+#[test]
+fn test_launchd_helper_check_in() {
+    let name = format!("spawn-task-port.test.{}", std::process::id());
+    let receiver = LaunchdHelperReceiver::register(&name).expect("failed to register service");
+    // Stand in for launchd by starting the helper directly, without the
+    // `pre_exec` handshake.
+    let path = test_process_path().unwrap();
+    let mut helper = Command::new(&path)
+        .arg("check-in")
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn helper");
+    helper.stdin.take().unwrap().write_all(name.as_bytes()).unwrap();
+    let (handle, task) = receiver.receive().expect("failed to receive check-in");
+    assert_eq!(handle.id(), helper.id());
+    assert_eq!(task.pid().unwrap(), helper.id());
+    let status = helper.wait().expect("failed to wait for helper");
+    assert!(status.success());
+    assert!(!handle.is_running());
+}
+
+#[test]
+fn test_child_send_task_port() {
+    let name = format!("spawn-task-port.test.child.{}", std::process::id());
+    let receiver = LaunchdHelperReceiver::register(&name).expect("failed to register service");
+    // A plain spawn, which the child checks in from at run time.
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .arg("send-task-port")
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn child");
+    child.stdin.take().unwrap().write_all(name.as_bytes()).unwrap();
+    let (handle, task) = receiver.receive().expect("failed to receive check-in");
+    assert_eq!(handle.id(), child.id());
+    assert_eq!(task.pid().unwrap(), child.id());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_spawn_in_session() {
+    let session = SessionTarget::current().expect("failed to get audit session");
+    // Joining one's own session needs no privileges.
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task_in_session(&session)
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().pid().unwrap(), child.id());
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_spawn_isolated() {
+    let mut isolation = Isolation::new();
+    isolation.chdir("/usr")
+        .sandbox_profile("(version 1) (allow default) (deny file-write* (subpath (param \"DIR\")))")
+        .sandbox_parameter("DIR", "/usr");
+    let mut child = Command::new("/bin/pwd")
+        .stdout(Stdio::piped())
+        .spawn_with_task_isolated(&isolation)
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().pid().unwrap_or(child.id()), child.id());
+    let mut out = String::new();
+    child.child_mut().stdout.take().unwrap().read_to_string(&mut out).unwrap();
+    assert_eq!(out.trim(), "/usr");
+    assert!(child.wait().expect("failed to wait for child").success());
+
+    // A profile that doesn't parse fails the spawn, after the check-in.
+    let mut isolation = Isolation::new();
+    isolation.sandbox_profile("(version 1");
+    let err = Command::new("/bin/pwd")
+        .stdout(Stdio::null())
+        .spawn_with_task_isolated(&isolation)
+        .expect_err("spawned with a broken sandbox profile");
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn test_posix_spawn_file_actions() {
+    let mut options = PosixSpawnOptions::new();
+    // Swap stdout and stderr, so that `pwd` writes to the piped stderr.
+    options.chdir("/usr").dup2_all(&[(1, 2), (2, 1)]).close_from(3);
+    let mut child = Command::new("pwd")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn_with_task_posix(&options)
+        .expect("failed to spawn child");
+    let mut output = String::new();
+    child.child_mut().stderr.take().unwrap().read_to_string(&mut output).unwrap();
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+    assert_eq!(output, "/usr\n");
+}
+
+#[test]
+fn test_posix_spawn_attributes() {
+    let path = test_process_path().unwrap();
+    let mut options = PosixSpawnOptions::new();
+    options.memory_limit(512).process_type(ProcessType::DaemonBackground).nice(5);
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task_posix(&options)
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().pid().unwrap(), child.id());
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, child.id()) };
+    assert_eq!(nice, 5);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_posix_spawn_start_suspended() {
+    let path = test_process_path().unwrap();
+    let mut options = PosixSpawnOptions::new();
+    options.start_suspended();
+    // With nothing on stdin, the child would exit straight away if it ran.
+    let mut child = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_task_posix(&options)
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().basic_info().expect("failed to get task info").suspend_count,
+               1);
+    assert!(child.wait_timeout(Duration::from_millis(100)).unwrap().is_none());
+    child.resume().expect("failed to resume child");
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_task_port_command() {
+    let path = test_process_path().unwrap();
+    let mut command = TaskPortCommand::new(&path);
+    command.stdin(Stdio::null())
+        .setsid()
+        .signal_mask(&[libc::SIGUSR1])
+        .default_signal(libc::SIGPIPE)
+        .start_suspended();
+    let mut child = command.spawn().expect("failed to spawn child");
+    let pid = child.id() as libc::pid_t;
+    assert_eq!(unsafe { libc::getsid(pid) }, pid);
+    assert_eq!(unsafe { libc::getpgid(pid) }, pid);
+    // Blocked, so it stays pending rather than killing the child.
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGUSR1) }, 0);
+    child.resume().expect("failed to resume child");
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_posix_spawn_architecture_preference() {
+    // `arch` is universal, and prints the architecture it is running as.
+    let (native, name) = if cfg!(target_arch = "aarch64") {
+        (Architecture::Arm64, "arm64")
+    } else {
+        (Architecture::X86_64, "i386")
+    };
+    let mut options = PosixSpawnOptions::new();
+    options.architecture_preference(&[native]);
+    let mut child = Command::new("/usr/bin/arch")
+        .stdout(Stdio::piped())
+        .spawn_with_task_posix(&options)
+        .expect("failed to spawn child");
+    let mut output = String::new();
+    child.child_mut().stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    assert!(child.wait().expect("failed to wait for child").success());
+    assert_eq!(output.trim(), name);
+}
+
+#[test]
+fn test_env_scrub() {
+    let mut scrub = EnvScrub::new();
+    scrub.allow("DYLD_PRINT_TO_FILE");
+    let mut command = Command::new("/usr/bin/env");
+    command.env("DYLD_INSERT_LIBRARIES", "/tmp/inject.dylib")
+        .env("MallocStackLogging", "1")
+        .env("DYLD_PRINT_TO_FILE", "/dev/null")
+        .env("SPAWN_TASK_PORT_TEST", "1")
+        .stdout(Stdio::piped());
+    let mut child = scrub.apply(&mut command).spawn_with_task().expect("failed to spawn child");
+    let mut output = String::new();
+    child.child_mut().stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    assert!(child.wait().expect("failed to wait for child").success());
+    let names = output.lines().filter_map(|line| line.split('=').next()).collect::<Vec<_>>();
+    assert!(!names.contains(&"DYLD_INSERT_LIBRARIES"));
+    assert!(!names.contains(&"MallocStackLogging"));
+    assert!(names.contains(&"DYLD_PRINT_TO_FILE"));
+    assert!(names.contains(&"SPAWN_TASK_PORT_TEST"));
+}
+
+#[test]
+fn test_retry_policy() {
+    let path = test_process_path().unwrap();
+    let policy = RetryPolicy::new(3).retry_on(&[ErrorClass::Mach]);
+    let mut child = policy.spawn_with_task(|| {
+            let mut command = Command::new(&path);
+            command.stdin(Stdio::piped());
+            command
+        })
+        .expect("failed to spawn child");
+    assert_eq!(child.task_port().pid().unwrap(), child.id());
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_heartbeat() {
+    let heartbeat = Heartbeat::new().expect("failed to create heartbeat");
+    let (stale_sender, stale) = mpsc::channel();
+    heartbeat.on_stale(Duration::from_millis(200), move || {
+        let _ = stale_sender.send(());
+    });
+    let path = test_process_path().unwrap();
+    let mut child = heartbeat.configure(Command::new(&path).arg("heartbeat").stdin(Stdio::null()))
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    for _ in 0..100 {
+        if heartbeat.last_heartbeat().is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(heartbeat.last_heartbeat().is_some());
+    assert!(!heartbeat.is_stale(Duration::from_millis(200)));
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+    stale.recv_timeout(Duration::from_secs(5)).expect("heartbeat never went stale");
+    assert!(heartbeat.is_stale(Duration::from_millis(200)));
+}
+
+#[test]
+fn test_heartbeat_ignores_other_messages() {
+    let heartbeat = Heartbeat::new().expect("failed to create heartbeat");
+    let path = test_process_path().unwrap();
+    let mut command = Command::new(&path);
+    heartbeat.configure(command.arg("heartbeat").stdin(Stdio::null()));
+    // A check-in is larger than a ping, and carries a port.
+    let name = command.get_envs()
+        .find(|&(key, _)| key == "SPAWN_TASK_PORT_HEARTBEAT_SERVICE")
+        .and_then(|(_, value)| value)
+        .expect("no heartbeat service name")
+        .to_str()
+        .unwrap()
+        .to_owned();
+    spawn_task_port::child::send_task_port(&name).expect("failed to send task port");
+    let mut child = command.spawn_with_task().expect("failed to spawn child");
+    for _ in 0..100 {
+        if heartbeat.last_heartbeat().is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(heartbeat.last_heartbeat().is_some());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_request_shutdown() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let path = test_process_path().unwrap();
+    let channel = ShutdownChannel::new().expect("failed to create shutdown channel");
+    let mut child = channel.configure(Command::new(&path).arg("shutdown").stdin(Stdio::null()))
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    match channel.request_shutdown(&mut child, Duration::from_secs(5))
+        .expect("failed to shut down child") {
+        ShutdownOutcome::Exited(status) => assert!(status.success()),
+        outcome => panic!("child didn't exit by itself: {:?}", outcome),
+    }
+
+    // A child that never listens is sent `SIGTERM` once the deadline passes.
+    let mut channel = ShutdownChannel::new().expect("failed to create shutdown channel");
+    channel.term_grace(Duration::from_secs(5));
+    let mut child = channel.configure(Command::new(&path).stdin(Stdio::piped()))
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let outcome = channel.request_shutdown(&mut child, Duration::from_millis(100))
+        .expect("failed to shut down child");
+    match outcome {
+        ShutdownOutcome::Terminated(status) => {
+            assert_eq!(status.signal(), Some(libc::SIGTERM))
+        }
+        outcome => panic!("child wasn't sent SIGTERM: {:?}", outcome),
+    }
+}
+
+#[test]
+fn test_sched_snapshot() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let task_port = child.task_port();
+    let snapshot = task_port.sched_snapshot().expect("failed to snapshot scheduling state");
+    assert_eq!(snapshot.suspend_count, 0);
+    assert!(!snapshot.threads.is_empty());
+    assert!(snapshot.threads.iter().all(|t| t.suspend_count == 0));
+
+    // Freeze the child as a sampler would, then put it back.
+    let threads = task_port.threads().expect("failed to get threads");
+    task_port.suspend().expect("failed to suspend task");
+    threads[0].suspend().expect("failed to suspend thread");
+    threads[0].suspend().expect("failed to suspend thread");
+    assert_eq!(threads[0].suspend_count().unwrap(), 2);
+    task_port.restore_sched(&snapshot).expect("failed to restore scheduling state");
+    assert_eq!(task_port.sched_snapshot().unwrap(), snapshot);
+
+    drop(child.child_mut().stdin.take());
+    let status = child.wait().expect("failed to wait for child");
+    assert!(status.success());
+}
+
+#[test]
+fn test_memory_watchdog() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    // Any process is over a single byte.
+    let thresholds = MemoryThresholds::new()
+        .warn_at(1)
+        .kill_at(2)
+        .interval(Duration::from_millis(10));
+    let (sender, events) = mpsc::channel();
+    let _watchdog = MemoryWatchdog::start(child.task_port(), thresholds, move |event| {
+            let _ = sender.send(event);
+        })
+        .expect("failed to start watchdog");
+    let warned = events.recv_timeout(Duration::from_secs(5)).expect("no warning");
+    assert_eq!(warned.action, WatchdogAction::Warn);
+    assert!(warned.footprint > 1);
+    let killed = events.recv_timeout(Duration::from_secs(5)).expect("no kill");
+    assert_eq!(killed.action, WatchdogAction::Kill);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(!status.success());
+}
+
+#[test]
+fn test_wait_with_rusage() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let (status, usage) = child.wait_with_rusage().expect("failed to wait for child");
+    assert!(status.success());
+    assert!(usage.max_rss > 0);
+    // The status is remembered, but the rusage is gone with the child.
+    assert!(child.wait().unwrap().success());
+    assert!(child.wait_with_rusage().is_err());
+    assert!(child.kill().is_err());
+}
+
+#[test]
+fn test_typed_errors() {
+    let err = Command::new("/nonexistent/spawn-task-port-test")
+        .spawn_with_task_port()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    match SpawnTaskPortError::from_io(&err) {
+        Some(SpawnTaskPortError::Spawn(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        other => panic!("expected a spawn error, got {:?}", other),
+    }
+    // Looking up a service that doesn't exist fails in the bootstrap server.
+    let err = spawn_task_port::check_in_with_service("spawn-task-port.nonexistent").unwrap_err();
+    match SpawnTaskPortError::from_io(&err) {
+        Some(&SpawnTaskPortError::BootstrapError { code, .. }) => assert!(code != KERN_SUCCESS),
+        other => panic!("expected a bootstrap error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_wait_timeout_and_status() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    // The child waits for its stdin to close.
+    assert_eq!(child.wait_timeout(Duration::from_millis(100)).unwrap(), None);
+    assert_eq!(child.status().unwrap(), ChildStatus::Running);
+    drop(child.child_mut().stdin.take());
+    let status = child.wait_timeout(Duration::from_secs(5))
+        .unwrap()
+        .expect("child should have exited");
+    assert!(status.success());
+    assert_eq!(child.status().unwrap(), ChildStatus::Exited(status));
+}
+
+#[test]
+fn test_terminate_tree() {
+    let mut child = Command::new("/bin/sh")
+        .args(["-c", "sleep 60 & sleep 60 & wait"])
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    // Give the shell time to start its children.
+    thread::sleep(Duration::from_millis(500));
+    let descendants = child.terminate_tree().expect("failed to terminate tree");
+    assert_eq!(descendants.len(), 2);
+    assert!(!child.wait().expect("failed to wait for child").success());
+    for pid in descendants {
+        // launchd reaps the orphaned descendants.
+        let mut gone = false;
+        for _ in 0..500 {
+            if unsafe { libc::kill(pid as libc::pid_t, 0) } != 0 {
+                gone = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(gone, "descendant {} is still running", pid);
+    }
+}
+
+#[test]
+fn test_spawn_options() {
+    let path = test_process_path().unwrap();
+    let name = format!("spawn-task-port.test.{}", unsafe { libc::getpid() });
+    let mut options = SpawnOptions::new();
+    options.receive_timeout(Duration::from_secs(10))
+        .service_name(&name)
+        .unregister(true)
+        .disposition(PortDisposition::MoveSend)
+        .send_timeout(Duration::from_secs(5), SendTimeoutAction::Abort);
+    // The name is unregistered after each handshake, so it can be reused.
+    for _ in 0..2 {
+        let (mut child, task_port) = Command::new(&path)
+            .stdin(Stdio::piped())
+            .spawn_get_task_port_with(&options)
+            .expect("failed to spawn child");
+        assert_eq!(task_port.pid().unwrap(), child.id());
+        drop(child.stdin.take());
+        assert!(child.wait().expect("failed to wait for child").success());
+    }
+
+    options.service_name(&"x".repeat(200));
+    let err = Command::new(&path).spawn_get_task_port_with(&options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_broker_with_service_name() {
+    let path = test_process_path().unwrap();
+    let name = format!("spawn-task-port.test.broker.{}", std::process::id());
+    let broker = MachPortBroker::with_service_name(&name).expect("failed to create broker");
+    for _ in 0..2 {
+        broker_spawn_and_wait(&broker, &path);
+    }
+    // The name is taken for as long as the broker lives.
+    assert!(MachPortBroker::with_service_name(&name).is_err());
+    drop(broker);
+
+    let err = MachPortBroker::with_service_name(&"x".repeat(200))
+        .err()
+        .expect("registered an overlong name");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_broker_reaps_children() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (pid, task_port) = broker.spawn_reaped(Command::new(&path).stdin(Stdio::null()))
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap_or(pid), pid);
+    // Even a zombie can be signalled, so this only fails once the child
+    // has been reaped.
+    let mut reaped = false;
+    for _ in 0..500 {
+        if unsafe { libc::kill(pid as libc::pid_t, 0) } != 0 {
+            reaped = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(reaped, "child {} was never reaped", pid);
+}
+
+#[test]
+fn test_broker_task_port_for_pid() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (mut child, task_port) = broker.spawn(Command::new(&path).stdin(Stdio::piped()))
+        .expect("failed to spawn child");
+    let pid = child.id();
+    let found = broker.task_port_for_pid(pid)
+        .expect("failed to look up task port")
+        .expect("no task port for child");
+    assert_eq!(found.as_raw(), task_port.as_raw());
+    assert!(broker.task_port_for_pid(pid + 1).unwrap().is_none());
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+    // Once the child has exited, its pid no longer finds anything.
+    assert!(broker.task_port_for_pid(pid).unwrap().is_none());
+    assert!(broker.forget_pid(pid).is_none());
+}
+
+#[test]
+fn test_broker_spawn_following_execs() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (mut child, task_port) = broker.spawn_following_execs(Command::new(&path)
+            .arg("exec")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped()))
+        .expect("failed to spawn child");
+    drop(task_port);
+    let pid = child.id();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    assert_eq!(line.trim(), "checked in");
+    // The image the child executed checked in before saying so.
+    let task_port = broker.task_port_for_pid(pid)
+        .expect("failed to look up task port")
+        .expect("no task port for child");
+    assert_eq!(task_port.pid().expect("failed to get pid"), pid);
+    assert!(!task_port.modules().expect("failed to list modules").is_empty());
+    assert_eq!(broker.pending(), 0);
+    assert_eq!(broker.rejected(), 0);
+    task_port.terminate().expect("failed to terminate child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_child_check_in() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (mut child, task_port) = broker.spawn_following_execs(Command::new(&path)
+            .arg("child-check-in")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped()))
+        .expect("failed to spawn child");
+    drop(task_port);
+    let pid = child.id();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    assert_eq!(line.trim(), "checked in");
+    let task_port = broker.task_port_for_pid(pid)
+        .expect("failed to look up task port")
+        .expect("no task port for child");
+    assert_eq!(task_port.pid().expect("failed to get pid"), pid);
+    assert_eq!(broker.rejected(), 0);
+    task_port.terminate().expect("failed to terminate child");
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_broker_poll() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    assert_eq!(broker.poll(Duration::from_millis(0)).expect("failed to poll"), vec![]);
+    let (mut child, task_port) = broker.spawn_following_execs(Command::new(&path)
+            .arg("exec")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped()))
+        .expect("failed to spawn child");
+    drop(task_port);
+    let pid = child.id();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    assert_eq!(line.trim(), "checked in");
+    assert_eq!(broker.poll(Duration::from_secs(1)).expect("failed to poll"),
+               vec![BrokerEvent::Registered(pid)]);
+    broker.task_port_for_pid(pid)
+        .expect("failed to look up task port")
+        .expect("no task port for child")
+        .terminate()
+        .expect("failed to terminate child");
+    child.wait().expect("failed to wait for child");
+    assert_eq!(broker.poll(Duration::from_millis(0)).expect("failed to poll"),
+               vec![BrokerEvent::Invalidated(pid)]);
+    assert_eq!(broker.remembered(), 0);
+}
+
+#[test]
+fn test_broker_force_release_all() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (mut child, task_port) = broker.spawn(Command::new(&path).stdin(Stdio::piped()))
+        .expect("failed to spawn child");
+    let pid = child.id();
+    assert_eq!(broker.remembered(), 1);
+    assert_eq!(broker.force_release_all(), 1);
+    assert_eq!(broker.remembered(), 0);
+    assert!(broker.task_port_for_pid(pid).unwrap().is_none());
+    // The caller's own right is unaffected.
+    assert_eq!(task_port.pid().expect("failed to get pid"), pid);
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_task_port_rights() {
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task_port()
+        .expect("failed to spawn child");
+    assert_eq!(task_port.rights().live(), 1);
+    let before = task_port_rights();
+    let clone = task_port.try_clone().expect("failed to clone task port");
+    let raw = clone.try_clone().expect("failed to clone task port").into_raw();
+    drop(clone);
+    // The clones are counted against the handle they came from.
+    assert_eq!(task_port.rights(),
+               RightCounts {
+                   created: 1,
+                   cloned: 2,
+                   transferred: 1,
+                   released: 1,
+               });
+    let taken = unsafe { TaskPort::from_raw(raw) };
+    assert_eq!(taken.rights().live(), 1);
+    drop(taken);
+    // Other tests create and drop task ports at the same time, so only
+    // check that the totals moved at least as far as this test moved them.
+    let after = task_port_rights();
+    assert!(after.cloned >= before.cloned + 2);
+    assert!(after.transferred > before.transferred);
+    assert!(after.released >= before.released + 2);
+    assert!(after.created > before.created);
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_broker_descendants() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (mut child, task_port) = broker.spawn_with_descendants(Command::new(&path)
+            .arg("spawn-descendant")
+            .stdin(Stdio::null()))
+        .expect("failed to spawn child");
+    drop(task_port);
+    let pid = child.id();
+    assert!(child.wait().expect("failed to wait for child").success());
+    // The grandchild checked in before its parent exited, so it is still
+    // listed under it.
+    let descendants = broker.descendants(pid).expect("failed to list descendants");
+    assert_eq!(descendants.len(), 1);
+    let (grandchild, ref grandchild_port) = descendants[0];
+    assert_eq!(grandchild_port.pid().expect("failed to get pid"), grandchild);
+    assert!(broker.task_port_for_pid(grandchild).unwrap().is_some());
+    grandchild_port.terminate().expect("failed to terminate grandchild");
+    assert_eq!(broker.pending(), 0);
+}
+
+#[test]
+fn test_broker_spawn_daemon() {
+    let path = test_process_path().unwrap();
+    let broker = MachPortBroker::new().expect("failed to create broker");
+    let (pid, task_port) = broker.spawn_daemon(Command::new(&path)
+            .arg("daemonize")
+            .stdin(Stdio::null()))
+        .expect("failed to spawn daemon");
+    assert_eq!(task_port.pid().unwrap(), pid);
+    // The daemon is the child's grandchild, and has been re-parented.
+    let info = task_port.process_info().expect("failed to get process info");
+    assert_ne!(info.ppid, unsafe { libc::getpid() } as u32);
+    assert_eq!(broker.pending(), 0);
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+#[test]
+fn test_spawn_with_task_port_timeout() {
+    let path = test_process_path().unwrap();
+    let mut command = Command::new(&path);
+    // Hooks run in the order they were added, so this one kills the child
+    // before it can check in.
+    unsafe {
+        command.pre_exec(|| {
+            libc::kill(libc::getpid(), libc::SIGKILL);
+            Ok(())
+        });
+    }
+    let err = command.spawn_with_task_port_timeout(Duration::from_millis(200)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    match SpawnTaskPortError::from_io(&err) {
+        Some(&SpawnTaskPortError::ReceiveTimeout) => {}
+        other => panic!("unexpected error {:?}", other),
+    }
+
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task_port_timeout(Duration::from_secs(10))
+        .expect("failed to spawn child");
+    assert_eq!(task_port.pid().unwrap(), child.id());
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_process_info() {
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .arg("some-arg")
+        .current_dir("/tmp")
+        .stdin(Stdio::piped())
+        .spawn_with_task()
+        .expect("failed to spawn child");
+    let info = child.process_info().expect("failed to get process info");
+    assert_eq!(info.pid, child.id());
+    assert_eq!(info.ppid, unsafe { libc::getpid() } as u32);
+    assert_eq!(info.exe.canonicalize().unwrap(), path.canonicalize().unwrap());
+    assert_eq!(info.cmdline.get(1).map(|arg| &arg[..]), Some("some-arg"));
+    assert_eq!(info.cwd.canonicalize().unwrap(), Path::new("/tmp").canonicalize().unwrap());
+    assert!(info.resident_size > 0);
+    assert!(info.threads >= 1);
+    // At least stdin, stdout and stderr.
+    assert!(info.open_fds >= 3);
+    drop(child.child_mut().stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_spawn_with_receiver() {
+    let path = test_process_path().unwrap();
+    let (mut child, receiver) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_receiver()
+        .expect("failed to spawn child");
+    assert_eq!(receiver.pid(), child.id());
+    let task_port = receiver.recv(Some(Duration::from_secs(10)))
+        .expect("failed to receive task port");
+    assert_eq!(task_port.pid().unwrap(), child.id());
+    // The child only checks in once.
+    assert!(receiver.try_recv().expect("failed to poll for task port").is_none());
+    let err = receiver.recv(Some(Duration::from_millis(10))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_receive_trailers() {
+    let path = test_process_path().unwrap();
+    let (mut child, receiver) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_receiver()
+        .expect("failed to spawn child");
+    let (_, trailer) = receiver.recv_with_trailer(Some(Duration::from_secs(10)))
+        .expect("failed to receive task port");
+    assert_eq!(trailer.audit_token().map(|token| token.pid()), Some(child.id()));
+    assert!(child.wait().expect("failed to wait for child").success());
+
+    let mut options = SpawnOptions::new();
+    options.trailer(TrailerType::Sender);
+    let (mut child, receiver) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_receiver_with(&options)
+        .expect("failed to spawn child");
+    let (_, trailer) = receiver.recv_with_trailer(Some(Duration::from_secs(10)))
+        .expect("failed to receive task port");
+    assert_eq!(trailer.uid(), Some(unsafe { libc::getuid() }));
+    assert_eq!(trailer.audit_token(), None);
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_receiver_fd_becomes_readable() {
+    use std::os::unix::io::AsRawFd;
+
+    let path = test_process_path().unwrap();
+    let (mut child, receiver) = Command::new(&path)
+        .stdin(Stdio::null())
+        .spawn_with_receiver()
+        .expect("failed to spawn child");
+    let mut fd = libc::pollfd {
+        fd: receiver.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    assert_eq!(unsafe { libc::poll(&mut fd, 1, 10_000) }, 1);
+    let task_port = receiver.try_recv()
+        .expect("failed to poll for task port")
+        .expect("fd was readable before the child checked in");
+    assert_eq!(task_port.pid().unwrap_or(child.id()), child.id());
+    child.wait().expect("failed to wait for child");
+}
+
+#[test]
+fn test_extmod_info() {
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task_port()
+        .expect("failed to spawn child");
+    let info = task_port.extmod_info().expect("failed to get extmod info");
+    assert_ne!(info.uuid, [0; 16]);
+    assert!(!info.was_modified());
+    assert_eq!(info.thread_creation_caller_count, 0);
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_port_info() {
+    let path = test_process_path().unwrap();
+    let (mut child, task_port) = Command::new(&path)
+        .stdin(Stdio::piped())
+        .spawn_with_task_port()
+        .expect("failed to spawn child");
+    let info = dump_port_info(task_port.as_raw()).expect("failed to describe port");
+    assert!(info.rights.contains(PortRights::SEND));
+    assert!(!info.rights.contains(PortRights::RECEIVE));
+    assert!(info.send_refs >= 1);
+    if info.kobject_type.is_some() {
+        assert_eq!(info.kobject_type, Some(2));
+        assert!(info.same_object(&info.clone()));
+    }
+    assert_eq!(parse_port_name(&format!("{:#x}", info.name)), Some(task_port.as_raw()));
+    assert!(info.to_string().starts_with(&format!("{:#x} send", info.name)));
+    drop(child.stdin.take());
+    assert!(child.wait().expect("failed to wait for child").success());
+}
+
+#[test]
+fn test_clear_exception_ports() {
+    use spawn_task_port::TaskPort;
+
+    // Install a handler on ourselves for an exception that never happens,
+    // for children to inherit.
+    let this_task = unsafe {
+        assert_eq!(mach_port_mod_refs(mach_task_self(), mach_task_self(), MACH_PORT_RIGHT_SEND, 1),
+                   KERN_SUCCESS);
+        TaskPort::from_raw(mach_task_self())
+    };
+    let server = ExceptionServer::attach(&this_task, ExceptionMask::RPC_ALERT)
+        .expect("failed to attach exception server");
+    let path = test_process_path().unwrap();
+    let handled = |options: &SpawnOptions| {
+        let (mut child, task_port) = Command::new(&path)
+            .stdin(Stdio::piped())
+            .spawn_get_task_port_with(options)
+            .expect("failed to spawn child");
+        let handled = task_port.handled_exceptions().expect("failed to get exception ports");
+        drop(child.stdin.take());
+        child.wait().expect("failed to wait for child");
+        handled
+    };
+    let rpc_alert = ExceptionMask::RPC_ALERT.bits();
+    assert_ne!(handled(&SpawnOptions::new()).bits() & rpc_alert, 0);
+    let cleared = handled(SpawnOptions::new().clear_exception_ports(ExceptionMask::ALL));
+    assert_eq!(cleared.bits(), 0);
+    drop(server);
+}
+
+#[test]
+fn test_thread_exception_ports() {
+    use spawn_task_port::{TaskPort, ThreadPort};
+
+    let this_task = unsafe {
+        assert_eq!(mach_port_mod_refs(mach_task_self(), mach_task_self(), MACH_PORT_RIGHT_SEND, 1),
+                   KERN_SUCCESS);
+        TaskPort::from_raw(mach_task_self())
+    };
+    let (sender, receiver) = mpsc::channel();
+    let (done, finish) = mpsc::channel::<()>();
+    let worker = thread::spawn(move || {
+        sender.send(unsafe { mach_thread_self() }).unwrap();
+        let _ = finish.recv();
+    });
+    let thread = unsafe { ThreadPort::from_raw(receiver.recv().unwrap()) };
+    let this_thread = unsafe { ThreadPort::from_raw(mach_thread_self()) };
+    let rpc_alert = ExceptionMask::RPC_ALERT.bits();
+    let handled = |thread: &ThreadPort| {
+        thread.handled_exceptions().expect("failed to get exception ports").bits() & rpc_alert
+    };
+    let server = ExceptionServer::attach_threads(&this_task,
+                                                 &[thread.try_clone().unwrap()],
+                                                 ExceptionMask::RPC_ALERT)
+        .expect("failed to attach exception server");
+    // Only the worker thread got the handler.
+    assert_ne!(handled(&thread), 0);
+    assert_eq!(handled(&this_thread), 0);
+    assert_eq!(this_task.handled_exceptions().unwrap().bits() & rpc_alert, 0);
+    drop(server);
+    assert_eq!(handled(&thread), 0);
+
+    let server = ExceptionServer::attach_threads(&this_task, &[thread.try_clone().unwrap()],
+                                                 ExceptionMask::RPC_ALERT)
+        .expect("failed to attach exception server");
+    thread.clear_exception_ports(ExceptionMask::ALL).expect("failed to clear exception ports");
+    assert_eq!(handled(&thread), 0);
+    drop(server);
+    drop(done);
+    worker.join().unwrap();
+}
+
+#[test]
+fn test_host_exception_monitor() {
+    // This needs root, which the tests don't normally run as.
+    if !Capabilities::detect().host_exception_ports {
+        return;
+    }
+    let monitor = match HostExceptionMonitor::install(ExceptionMask::CRASHES) {
+        Ok(monitor) => monitor,
+        // Some systems refuse even root.
+        Err(_) => return,
+    };
+    let path = test_process_path().unwrap();
+    let mut child = Command::new(&path)
+        .arg("abort")
+        .stdin(Stdio::null())
+        .spawn()
+        .expect("failed to spawn child");
+    let exception = monitor.receive_for_pid(child.id()).expect("failed to receive exception");
+    assert_eq!(exception.kind, ExceptionKind::Crash);
+    let status = child.wait().expect("failed to wait for child");
+    assert!(!status.success());
+}
+
+/// Middleware that only acts on spawns of the test process with a
+/// `middleware-*` argument, since it sees every other test's spawns too.
+struct TestMiddleware {
+    received: Arc<AtomicU32>,
+}
+
+impl SpawnMiddleware for TestMiddleware {
+    fn pre_register(&self, spawn: &mut SpawnContext, command: &mut Command) -> io::Result<()> {
+        if command.get_args().any(|arg| arg == "middleware-veto") {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "vetoed"));
+        }
+        if command.get_args().any(|arg| arg == "middleware-annotate") {
+            spawn.annotate("stage", "pre-register");
+        }
+        Ok(())
+    }
+
+    fn post_register(&self, spawn: &mut SpawnContext) -> io::Result<()> {
+        if spawn.annotation("stage").is_some() {
+            assert!(spawn.service_name().is_some());
+            spawn.annotate("stage", "post-register");
+        }
+        Ok(())
+    }
+
+    fn post_receive(&self, spawn: &SpawnContext, task_port: &TaskPort) -> io::Result<()> {
+        if spawn.annotation("stage") == Some("post-register") {
+            assert_eq!(spawn.pid(), task_port.pid().ok());
+            self.received.store(spawn.pid().unwrap(), Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_spawn_middleware() {
+    let path = test_process_path().unwrap();
+    let received = Arc::new(AtomicU32::new(0));
+    add_spawn_middleware(TestMiddleware { received: received.clone() });
+    let err = Command::new(&path)
+        .arg("middleware-veto")
+        .stdin(Stdio::null())
+        .spawn_with_task_port()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+    let (mut child, _task_port) = Command::new(&path)
+        .arg("middleware-annotate")
+        .stdin(Stdio::null())
+        .spawn_with_task_port()
+        .expect("failed to spawn child");
+    assert_eq!(received.load(Ordering::SeqCst), child.id());
+    assert!(child.wait().expect("failed to wait for child").success());
+}